@@ -21,7 +21,7 @@ pub use libc::{
 pub const KERNEL_VERSION: u32 = 7;
 
 /// Minor version number of this interface.
-pub const KERNEL_MINOR_VERSION: u32 = 33;
+pub const KERNEL_MINOR_VERSION: u32 = 38;
 
 /// Init reply size is FUSE_COMPAT_INIT_OUT_SIZE
 pub const KERNEL_MINOR_VERSION_INIT_OUT_SIZE: u32 = 5;
@@ -84,6 +84,9 @@ const FOPEN_CACHE_DIR: u32 = 8;
 /// the file is stream-like (no file position at all)
 const FOPEN_STREAM: u32 = 16;
 
+/// Read and write the file with the backing file registered with the FUSE device.
+const FOPEN_PASSTHROUGH: u32 = 128;
+
 bitflags! {
     /// Options controlling the behavior of files opened by the server in response
     /// to an open or create request.
@@ -98,6 +101,9 @@ bitflags! {
         const CACHE_DIR = FOPEN_CACHE_DIR;
         /// the file is stream-like (no file position at all)
         const STREAM = FOPEN_STREAM;
+        /// Read and write the file with the backing file whose id is in the reply, see
+        /// `FsOptions::PASSTHROUGH`.
+        const PASSTHROUGH = FOPEN_PASSTHROUGH;
     }
 }
 
@@ -191,12 +197,27 @@ const SUBMOUNTS: u64 = 0x800_0000;
 // Filesystem responsible for clearing security.capability xattr and setuid/setgid bits.
 const HANDLE_KILLPRIV_V2: u64 = 0x1000_0000;
 
+// Extended fuse_setxattr_in request with setxattr_flags.
+const SETXATTR_EXT: u64 = 0x2000_0000;
+
 // This flag indicates whether the fuse_init_in is extended
 const INIT_EXT: u64 = 0x4000_0000;
 
+// Add security contexts of new files to create, mkdir, mknod and symlink requests.
+const SECURITY_CTX: u64 = 0x1_0000_0000;
+
 // This flag indicates whether the guest kernel enable per-file dax
 const PERFILE_DAX: u64 = 0x2_0000_0000;
 
+// Add supplementary group info to create, mkdir, mknod and symlink requests.
+const CREATE_SUPP_GROUP: u64 = 0x4_0000_0000;
+
+// Kernel supports expiry-only entry invalidations.
+const HAS_EXPIRE_ONLY: u64 = 0x8_0000_0000;
+
+// Kernel reads and writes files opened with FOPEN_PASSTHROUGH through their backing files.
+const PASSTHROUGH: u64 = 1_u64 << 37;
+
 // this flag indicates whether the guest kernel enable resend
 const HAS_RESEND: u64 = 1_u64 << 39;
 
@@ -450,20 +471,51 @@ bitflags! {
         ///  -. write has WRITE_KILL_PRIV
         const HANDLE_KILLPRIV_V2 = HANDLE_KILLPRIV_V2;
 
+        /// Indicates the kernel sends the extended `fuse_setxattr_in` request.
+        ///
+        /// If this feature is enabled, the kernel passes `SETXATTR_ACL_KILL_SGID` when the setgid
+        /// bit must be cleared while setting the `system.posix_acl_access` xattr.
+        const SETXATTR_EXT = SETXATTR_EXT;
+
         /// Indicates the kernel support fuse fd passthrough.
         const FD_PASSTHROUGH = FD_PASSTHROUGH;
 
         /// The fuse_init_in is extended.
         const INIT_EXT = INIT_EXT;
 
+        /// Indicates the kernel sends the security contexts of new files.
+        ///
+        /// If this feature is enabled, create, mkdir, mknod and symlink requests end with a
+        /// `SecctxHeader` followed by the security contexts to set as extended attributes.
+        const SECURITY_CTX = SECURITY_CTX;
+
         /// Indicates whether the guest kernel enable per-file dax
         ///
         /// If this feature is enabled, filesystem will notify guest kernel whether file
         /// enable DAX by EntryOut.Attr.flags of inode when lookup
         const PERFILE_DAX = PERFILE_DAX;
 
+        /// Indicates the kernel sends the supplementary group of the caller.
+        ///
+        /// If this feature is enabled, create, mkdir, mknod and symlink requests end with the
+        /// supplementary group matching the group of the parent directory, when the caller is a
+        /// member of it but it isn't the fsgid of the caller.
+        const CREATE_SUPP_GROUP = CREATE_SUPP_GROUP;
+
+        /// Indicates the kernel supports `FUSE_EXPIRE_ONLY` in entry invalidation notifications,
+        /// to expire entries without dropping them.
+        const HAS_EXPIRE_ONLY = HAS_EXPIRE_ONLY;
+
         /// indicates whether the kernel support resend inflight request
         const HAS_RESEND = HAS_RESEND;
+
+        /// Indicates the kernel supports reading and writing files directly with backing files.
+        ///
+        /// If this feature is enabled, files registered with the `FUSE_DEV_IOC_BACKING_OPEN`
+        /// ioctl of the FUSE device may be opened with `OpenOptions::PASSTHROUGH`, so that reads
+        /// and writes of the file don't go through the file system daemon. The kernel doesn't
+        /// enable it along with `WRITEBACK_CACHE`.
+        const PASSTHROUGH = PASSTHROUGH;
     }
 }
 
@@ -474,6 +526,18 @@ pub const RELEASE_FLOCK_UNLOCK: u32 = 2;
 // Getattr flags.
 pub const GETATTR_FH: u32 = 1;
 
+// Statx masks, from include/uapi/linux/stat.h.
+
+/// The fields of `Statx` also returned by `stat(2)`.
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+/// `Statx::btime` is valid.
+pub const STATX_BTIME: u32 = 0x800;
+
+// Entry invalidation flags.
+
+/// Expire the entry without dropping it, with `FsOptions::HAS_EXPIRE_ONLY`.
+pub const EXPIRE_ONLY: u32 = 1;
+
 // Lock flags.
 pub const LK_FLOCK: u32 = 1;
 
@@ -488,6 +552,11 @@ pub const WRITE_LOCKOWNER: u32 = 2;
 /// kill suid and sgid bits
 pub const WRITE_KILL_PRIV: u32 = 4;
 
+// Setxattr flags.
+
+/// Clear sgid when the `system.posix_acl_access` xattr is set.
+pub const SETXATTR_ACL_KILL_SGID: u32 = 1;
+
 // Read flags.
 pub const READ_LOCKOWNER: u32 = 2;
 
@@ -736,7 +805,12 @@ pub enum Opcode {
     CopyFileRange = 47,
     SetupMapping = 48,
     RemoveMapping = 49,
-    MaxOpcode = 50,
+    Syncfs = 50,
+    /// Create an unnamed temporary file, the request body is the same as `Create`.
+    Tmpfile = 51,
+    /// Get extended attributes of an inode, like `statx(2)`.
+    Statx = 52,
+    MaxOpcode = 53,
 
     /* Reserved opcodes: helpful to detect structure endian-ness in case of e.g. virtiofs */
     CuseInitBswapReserved = 1_048_576, /* CUSE_INIT << 8 */
@@ -1007,6 +1081,70 @@ pub struct SetxattrIn {
 }
 unsafe impl ByteValued for SetxattrIn {}
 
+/// Extension of `SetxattrIn`, sent after it when `FsOptions::SETXATTR_EXT` is negotiated.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SetxattrIn2 {
+    pub setxattr_flags: u32,
+    pub padding: u32,
+}
+unsafe impl ByteValued for SetxattrIn2 {}
+
+/// Header of the security contexts sent when `FsOptions::SECURITY_CTX` is negotiated.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SecctxHeader {
+    /// Size of the header and all security contexts, aligned to 8 bytes.
+    pub size: u32,
+    pub nr_secctx: u32,
+}
+unsafe impl ByteValued for SecctxHeader {}
+
+/// A security context, followed by its nul terminated name and its value of `size` bytes.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Secctx {
+    pub size: u32,
+    pub padding: u32,
+}
+unsafe impl ByteValued for Secctx {}
+
+/// Types of request extensions above this are security contexts, see `ExtHeader`.
+pub const MAX_NR_SECCTX: u32 = 31;
+
+/// Type of the extension holding the supplementary groups of the caller, see `SuppGroups`.
+pub const EXT_GROUPS: u32 = 32;
+
+/// Header of a request extension, aligned to 8 bytes. Security contexts are an extension whose
+/// `SecctxHeader` takes the place of this header, with a `type_` of at most `MAX_NR_SECCTX`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ExtHeader {
+    /// Size of the header and the extension.
+    pub size: u32,
+    pub type_: u32,
+}
+unsafe impl ByteValued for ExtHeader {}
+
+/// Supplementary groups of the caller, followed by `nr_groups` group ids.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SuppGroups {
+    pub nr_groups: u32,
+}
+unsafe impl ByteValued for SuppGroups {}
+
+/// Argument of the `FUSE_DEV_IOC_BACKING_OPEN` ioctl of the FUSE device, registering `fd` as a
+/// backing file.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BackingMap {
+    pub fd: i32,
+    pub flags: u32,
+    pub padding: u64,
+}
+unsafe impl ByteValued for BackingMap {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct GetxattrIn {
@@ -1082,7 +1220,8 @@ pub struct InitOut {
     pub max_pages: u16,
     pub map_alignment: u16,
     pub flags2: u32,
-    pub unused: [u32; 7],
+    pub max_stack_depth: u32,
+    pub unused: [u32; 6],
 }
 unsafe impl ByteValued for InitOut {}
 
@@ -1185,7 +1324,9 @@ pub struct InHeader {
     pub uid: u32,
     pub gid: u32,
     pub pid: u32,
-    pub padding: u32,
+    /// Size of the extensions at the end of the request, in units of 8 bytes.
+    pub total_extlen: u16,
+    pub padding: u16,
 }
 unsafe impl ByteValued for InHeader {}
 
@@ -1193,8 +1334,8 @@ impl Debug for InHeader {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "InHeader {{ len: {}, opcode: {}, unique: {}, nodeid: 0x{:x}, uid: {}, gid: {}, pid: {}, padding: {} }}",
-            self.len, self.opcode, self.unique, self.nodeid, self.uid, self.gid, self.pid, self.padding
+            "InHeader {{ len: {}, opcode: {}, unique: {}, nodeid: 0x{:x}, uid: {}, gid: {}, pid: {}, total_extlen: {} }}",
+            self.len, self.opcode, self.unique, self.nodeid, self.uid, self.gid, self.pid, self.total_extlen
         )
     }
 }
@@ -1241,7 +1382,7 @@ unsafe impl ByteValued for NotifyInvalInodeOut {}
 pub struct NotifyInvalEntryOut {
     pub parent: u64,
     pub namelen: u32,
-    pub padding: u32,
+    pub flags: u32,
 }
 unsafe impl ByteValued for NotifyInvalEntryOut {}
 
@@ -1320,6 +1461,104 @@ pub struct CopyFileRangeIn {
 }
 unsafe impl ByteValued for CopyFileRangeIn {}
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SyncfsIn {
+    pub padding: u64,
+}
+unsafe impl ByteValued for SyncfsIn {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SxTime {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub reserved: i32,
+}
+unsafe impl ByteValued for SxTime {}
+
+/// Attributes returned by `Opcode::Statx`, the fields are only valid if set in `mask`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub spare0: u16,
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: SxTime,
+    pub btime: SxTime,
+    pub ctime: SxTime,
+    pub mtime: SxTime,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare2: [u64; 14],
+}
+unsafe impl ByteValued for Statx {}
+
+impl From<stat64> for Statx {
+    fn from(st: stat64) -> Statx {
+        let time = |tv_sec, tv_nsec| SxTime {
+            tv_sec,
+            tv_nsec: tv_nsec as u32,
+            reserved: 0,
+        };
+
+        Statx {
+            mask: STATX_BASIC_STATS,
+            blksize: st.st_blksize as u32,
+            // See `Attr::with_flags()`.
+            #[allow(clippy::unnecessary_cast)]
+            nlink: st.st_nlink as u32,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            mode: st.st_mode as u16,
+            ino: st.st_ino,
+            size: st.st_size as u64,
+            blocks: st.st_blocks as u64,
+            atime: time(st.st_atime, st.st_atime_nsec),
+            ctime: time(st.st_ctime, st.st_ctime_nsec),
+            mtime: time(st.st_mtime, st.st_mtime_nsec),
+            rdev_major: libc::major(st.st_rdev),
+            rdev_minor: libc::minor(st.st_rdev),
+            dev_major: libc::major(st.st_dev),
+            dev_minor: libc::minor(st.st_dev),
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxIn {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+unsafe impl ByteValued for StatxIn {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxOut {
+    pub attr_valid: u64, /* Cache timeout for the attributes */
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: Statx,
+}
+unsafe impl ByteValued for StatxOut {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1339,6 +1578,9 @@ mod tests {
         assert_eq!(std::mem::size_of::<MkdirIn>(), 8);
         assert_eq!(std::mem::size_of::<InHeader>(), 40);
         assert_eq!(std::mem::size_of::<OutHeader>(), 16);
+        assert_eq!(std::mem::size_of::<Statx>(), 256);
+        assert_eq!(std::mem::size_of::<StatxIn>(), 24);
+        assert_eq!(std::mem::size_of::<StatxOut>(), 288);
     }
 
     #[test]
@@ -0,0 +1,1182 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! File system trait with asynchronous methods, to serve requests on a multi-threaded tokio
+//! runtime.
+//!
+//! [TokioFileSystem](trait.TokioFileSystem.html) mirrors all methods of
+//! [FileSystem](../filesystem/trait.FileSystem.html) but returns futures, so that a file system
+//! waiting for IO doesn't hold up other requests. Every `FileSystem` is also a `TokioFileSystem`
+//! running its blocking methods in place. `transport::AsyncServer` serves requests with an
+//! `TokioFileSystem`.
+//!
+//! Unlike `filesystem::AsyncFileSystem`, which extends a `FileSystem` with io-uring based methods
+//! for a single-threaded executor, this trait stands on its own.
+
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::task::block_in_place;
+
+use super::filesystem::{
+    Context, DirEntry, Entry, FileLock, FileSystem, GetxattrReply, IoctlData, ListxattrReply,
+    ZeroCopyReader, ZeroCopyWriter,
+};
+use crate::abi::fuse_abi::{stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid};
+#[cfg(feature = "virtiofs")]
+use crate::abi::virtio_fs::RemovemappingOne;
+#[cfg(feature = "virtiofs")]
+use crate::transport::FsCacheReqHandler;
+
+/// The main trait that connects a file system with a transport, with asynchronous methods.
+///
+/// The futures are `Send`, so that they can be spawned onto the worker threads of the runtime, and
+/// so are the buffers and callbacks of the request they borrow.
+#[allow(unused_variables)]
+#[async_trait]
+pub trait TokioFileSystem: Send + Sync {
+    /// Represents a location in the filesystem tree, see [`FileSystem::Inode`].
+    type Inode: From<u64> + Into<u64> + Send;
+
+    /// Represents a file or directory that is open for reading/writing.
+    type Handle: From<u64> + Into<u64> + Send;
+
+    /// Initialize the file system.
+    ///
+    /// See [`FileSystem::init`] for details.
+    async fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        Ok(FsOptions::empty())
+    }
+
+    /// Clean up the file system.
+    ///
+    /// See [`FileSystem::destroy`] for details.
+    async fn destroy(&self) {}
+
+    /// Look up a directory entry by name and get its attributes.
+    ///
+    /// See [`FileSystem::lookup`] for details.
+    async fn lookup(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Forget about an inode.
+    ///
+    /// See [`FileSystem::forget`] for details.
+    async fn forget(&self, ctx: &Context, inode: Self::Inode, count: u64) {}
+
+    /// Forget about multiple inodes.
+    ///
+    /// See [`FileSystem::batch_forget`] for details.
+    async fn batch_forget(&self, ctx: &Context, requests: Vec<(Self::Inode, u64)>) {
+        for (inode, count) in requests {
+            self.forget(ctx, inode, count).await
+        }
+    }
+
+    /// Get attributes for a file / directory.
+    ///
+    /// See [`FileSystem::getattr`] for details.
+    async fn getattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(stat64, Duration)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Set attributes for a file / directory.
+    ///
+    /// See [`FileSystem::setattr`] for details.
+    async fn setattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        attr: stat64,
+        handle: Option<Self::Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Read a symbolic link.
+    ///
+    /// See [`FileSystem::readlink`] for details.
+    async fn readlink(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a symbolic link.
+    ///
+    /// See [`FileSystem::symlink`] for details.
+    async fn symlink(
+        &self,
+        ctx: &Context,
+        linkname: &CStr,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a file node.
+    ///
+    /// See [`FileSystem::mknod`] for details.
+    async fn mknod(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a directory.
+    ///
+    /// See [`FileSystem::mkdir`] for details.
+    async fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Remove a file.
+    ///
+    /// See [`FileSystem::unlink`] for details.
+    async fn unlink(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Remove a directory.
+    ///
+    /// See [`FileSystem::rmdir`] for details.
+    async fn rmdir(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Rename a file / directory.
+    ///
+    /// See [`FileSystem::rename`] for details.
+    async fn rename(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a hard link.
+    ///
+    /// See [`FileSystem::link`] for details.
+    async fn link(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        newparent: Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Open a file.
+    ///
+    /// See [`FileSystem::open`] for details.
+    async fn open(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        // Matches the behavior of libfuse.
+        Ok((None, OpenOptions::empty(), None))
+    }
+
+    /// Create and open a file.
+    ///
+    /// See [`FileSystem::create`] for details.
+    #[allow(clippy::type_complexity)]
+    async fn create(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create an unnamed temporary file in the directory `parent` and open it, like `open(2)` with
+    /// `O_TMPFILE`.
+    ///
+    /// See [`FileSystem::tmpfile`] for details.
+    #[allow(clippy::type_complexity)]
+    async fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Read data from a file.
+    ///
+    /// See [`FileSystem::read`] for details.
+    #[allow(clippy::too_many_arguments)]
+    async fn read(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        w: &mut (dyn ZeroCopyWriter + Send),
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Write data to a file.
+    ///
+    /// See [`FileSystem::write`] for details.
+    #[allow(clippy::too_many_arguments)]
+    async fn write(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        r: &mut (dyn ZeroCopyReader + Send),
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Flush the contents of a file.
+    ///
+    /// See [`FileSystem::flush`] for details.
+    async fn flush(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Synchronize file contents.
+    ///
+    /// See [`FileSystem::fsync`] for details.
+    async fn fsync(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Allocate requested space for file data.
+    ///
+    /// See [`FileSystem::fallocate`] for details.
+    async fn fallocate(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Release an open file.
+    ///
+    /// See [`FileSystem::release`] for details.
+    #[allow(clippy::too_many_arguments)]
+    async fn release(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Get information about the file system.
+    ///
+    /// See [`FileSystem::statfs`] for details.
+    async fn statfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<statvfs64> {
+        // Safe because we are zero-initializing a struct with only POD fields.
+        let mut st: statvfs64 = unsafe { mem::zeroed() };
+
+        // This matches the behavior of libfuse as it returns these values if the
+        // filesystem doesn't implement this method.
+        st.f_namemax = 255;
+        st.f_bsize = 512;
+
+        Ok(st)
+    }
+
+    /// Set an extended attribute.
+    ///
+    /// See [`FileSystem::setxattr`] for details.
+    async fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+        setxattr_flags: u32,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Get an extended attribute.
+    ///
+    /// See [`FileSystem::getxattr`] for details.
+    async fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// List extended attribute names.
+    ///
+    /// See [`FileSystem::listxattr`] for details.
+    async fn listxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Remove an extended attribute.
+    ///
+    /// See [`FileSystem::removexattr`] for details.
+    async fn removexattr(&self, ctx: &Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Open a directory for reading.
+    ///
+    /// See [`FileSystem::opendir`] for details.
+    async fn opendir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        // Matches the behavior of libfuse.
+        Ok((None, OpenOptions::empty()))
+    }
+
+    /// Read a directory.
+    ///
+    /// See [`FileSystem::readdir`] for details.
+    async fn readdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut (dyn FnMut(DirEntry) -> io::Result<usize> + Send),
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Read a directory with entry attributes.
+    ///
+    /// See [`FileSystem::readdirplus`] for details.
+    async fn readdirplus(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut (dyn FnMut(DirEntry, Entry) -> io::Result<usize> + Send),
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Synchronize the contents of a directory.
+    ///
+    /// See [`FileSystem::fsyncdir`] for details.
+    async fn fsyncdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Synchronize the whole file system containing `inode`, like `syncfs(2)`.
+    ///
+    /// See [`FileSystem::syncfs`] for details.
+    async fn syncfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Release an open directory.
+    ///
+    /// See [`FileSystem::releasedir`] for details.
+    async fn releasedir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Setup a mapping so that guest can access files in DAX style.
+    ///
+    /// See [`FileSystem::setupmapping`] for details.
+    #[cfg(feature = "virtiofs")]
+    #[allow(clippy::too_many_arguments)]
+    async fn setupmapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Teardown a mapping which was setup for guest DAX style access.
+    ///
+    /// See [`FileSystem::removemapping`] for details.
+    #[cfg(feature = "virtiofs")]
+    async fn removemapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Check file access permissions.
+    ///
+    /// See [`FileSystem::access`] for details.
+    async fn access(&self, ctx: &Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Reposition read/write file offset.
+    ///
+    /// See [`FileSystem::lseek`] for details.
+    async fn lseek(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Copy a range of data from one file to another.
+    ///
+    /// See [`FileSystem::copy_file_range`] for details.
+    #[allow(clippy::too_many_arguments)]
+    async fn copy_file_range(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Query file lock status
+    ///
+    /// See [`FileSystem::getlk`] for details.
+    async fn getlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Grab a file read lock
+    ///
+    /// See [`FileSystem::setlk`] for details.
+    async fn setlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Grab a file write lock
+    ///
+    /// See [`FileSystem::setlkw`] for details.
+    async fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Apply or remove a BSD-style advisory lock on an open file.
+    ///
+    /// See [`FileSystem::flock`] for details.
+    async fn flock(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        operation: i32,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// send ioctl to the file
+    ///
+    /// See [`FileSystem::ioctl`] for details.
+    #[allow(clippy::too_many_arguments)]
+    async fn ioctl<'a>(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData<'a>,
+        out_size: u32,
+    ) -> io::Result<IoctlData<'a>> {
+        // Rather than ENOSYS, let's return ENOTTY so simulate that the ioctl call is implemented
+        // but no ioctl number is supported.
+        Err(io::Error::from_raw_os_error(libc::ENOTTY))
+    }
+
+    /// Query a file's block mapping info
+    ///
+    /// See [`FileSystem::bmap`] for details.
+    async fn bmap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        block: u64,
+        blocksize: u32,
+    ) -> io::Result<u64> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Poll a file's events
+    ///
+    /// See [`FileSystem::poll`] for details.
+    async fn poll(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        khandle: Self::Handle,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle the reply to a notification sent to the kernel.
+    ///
+    /// See [`FileSystem::notify_reply`] for details.
+    async fn notify_reply(&self) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Remap the external IDs in context to internal IDs.
+    ///
+    /// See [`FileSystem::id_remap`] for details.
+    async fn id_remap(&self, ctx: &mut Context) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Every `FileSystem` is a `TokioFileSystem` running its methods with
+/// `tokio::task::block_in_place()`.
+///
+/// The methods borrow the request, so they can't be moved to the blocking threads with
+/// `tokio::task::spawn_blocking()`. The futures must be run by a multi-threaded runtime or outside
+/// of any runtime, `block_in_place()` panics in a current-thread runtime.
+#[async_trait]
+impl<T: FileSystem + Send + Sync> TokioFileSystem for T
+where
+    T::Inode: Send,
+    T::Handle: Send,
+{
+    type Inode = T::Inode;
+    type Handle = T::Handle;
+
+    async fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        block_in_place(|| <T as FileSystem>::init(self, capable))
+    }
+
+    async fn destroy(&self) {
+        block_in_place(|| <T as FileSystem>::destroy(self))
+    }
+
+    async fn lookup(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
+        block_in_place(|| <T as FileSystem>::lookup(self, ctx, parent, name))
+    }
+
+    async fn forget(&self, ctx: &Context, inode: Self::Inode, count: u64) {
+        block_in_place(|| <T as FileSystem>::forget(self, ctx, inode, count))
+    }
+
+    async fn batch_forget(&self, ctx: &Context, requests: Vec<(Self::Inode, u64)>) {
+        block_in_place(|| <T as FileSystem>::batch_forget(self, ctx, requests))
+    }
+
+    async fn getattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(stat64, Duration)> {
+        block_in_place(|| <T as FileSystem>::getattr(self, ctx, inode, handle))
+    }
+
+    async fn setattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        attr: stat64,
+        handle: Option<Self::Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        block_in_place(|| <T as FileSystem>::setattr(self, ctx, inode, attr, handle, valid))
+    }
+
+    async fn readlink(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        block_in_place(|| <T as FileSystem>::readlink(self, ctx, inode))
+    }
+
+    async fn symlink(
+        &self,
+        ctx: &Context,
+        linkname: &CStr,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        block_in_place(|| <T as FileSystem>::symlink(self, ctx, linkname, parent, name))
+    }
+
+    async fn mknod(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        block_in_place(|| <T as FileSystem>::mknod(self, ctx, inode, name, mode, rdev, umask))
+    }
+
+    async fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        block_in_place(|| <T as FileSystem>::mkdir(self, ctx, parent, name, mode, umask))
+    }
+
+    async fn unlink(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::unlink(self, ctx, parent, name))
+    }
+
+    async fn rmdir(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::rmdir(self, ctx, parent, name))
+    }
+
+    async fn rename(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        block_in_place(|| {
+            <T as FileSystem>::rename(self, ctx, olddir, oldname, newdir, newname, flags)
+        })
+    }
+
+    async fn link(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        newparent: Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        block_in_place(|| <T as FileSystem>::link(self, ctx, inode, newparent, newname))
+    }
+
+    async fn open(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        block_in_place(|| <T as FileSystem>::open(self, ctx, inode, flags, fuse_flags))
+    }
+
+    async fn create(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        block_in_place(|| <T as FileSystem>::create(self, ctx, parent, name, args))
+    }
+
+    async fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        block_in_place(|| <T as FileSystem>::tmpfile(self, ctx, parent, args))
+    }
+
+    async fn read(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        w: &mut (dyn ZeroCopyWriter + Send),
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        block_in_place(|| {
+            <T as FileSystem>::read(self, ctx, inode, handle, w, size, offset, lock_owner, flags)
+        })
+    }
+
+    async fn write(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        r: &mut (dyn ZeroCopyReader + Send),
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        block_in_place(|| {
+            <T as FileSystem>::write(
+                self,
+                ctx,
+                inode,
+                handle,
+                r,
+                size,
+                offset,
+                lock_owner,
+                delayed_write,
+                flags,
+                fuse_flags,
+            )
+        })
+    }
+
+    async fn flush(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::flush(self, ctx, inode, handle, lock_owner))
+    }
+
+    async fn fsync(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::fsync(self, ctx, inode, datasync, handle))
+    }
+
+    async fn fallocate(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        block_in_place(|| {
+            <T as FileSystem>::fallocate(self, ctx, inode, handle, mode, offset, length)
+        })
+    }
+
+    async fn release(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        block_in_place(|| {
+            <T as FileSystem>::release(
+                self,
+                ctx,
+                inode,
+                flags,
+                handle,
+                flush,
+                flock_release,
+                lock_owner,
+            )
+        })
+    }
+
+    async fn statfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<statvfs64> {
+        block_in_place(|| <T as FileSystem>::statfs(self, ctx, inode))
+    }
+
+    async fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+        setxattr_flags: u32,
+    ) -> io::Result<()> {
+        block_in_place(|| {
+            <T as FileSystem>::setxattr(self, ctx, inode, name, value, flags, setxattr_flags)
+        })
+    }
+
+    async fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        block_in_place(|| <T as FileSystem>::getxattr(self, ctx, inode, name, size))
+    }
+
+    async fn listxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        block_in_place(|| <T as FileSystem>::listxattr(self, ctx, inode, size))
+    }
+
+    async fn removexattr(&self, ctx: &Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::removexattr(self, ctx, inode, name))
+    }
+
+    async fn opendir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        block_in_place(|| <T as FileSystem>::opendir(self, ctx, inode, flags))
+    }
+
+    async fn readdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut (dyn FnMut(DirEntry) -> io::Result<usize> + Send),
+    ) -> io::Result<()> {
+        block_in_place(|| {
+            <T as FileSystem>::readdir(self, ctx, inode, handle, size, offset, add_entry)
+        })
+    }
+
+    async fn readdirplus(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut (dyn FnMut(DirEntry, Entry) -> io::Result<usize> + Send),
+    ) -> io::Result<()> {
+        block_in_place(|| {
+            <T as FileSystem>::readdirplus(self, ctx, inode, handle, size, offset, add_entry)
+        })
+    }
+
+    async fn fsyncdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::fsyncdir(self, ctx, inode, datasync, handle))
+    }
+
+    async fn syncfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::syncfs(self, ctx, inode))
+    }
+
+    async fn releasedir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::releasedir(self, ctx, inode, flags, handle))
+    }
+
+    #[cfg(feature = "virtiofs")]
+    async fn setupmapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        block_in_place(|| {
+            <T as FileSystem>::setupmapping(
+                self, ctx, inode, handle, foffset, len, flags, moffset, vu_req,
+            )
+        })
+    }
+
+    #[cfg(feature = "virtiofs")]
+    async fn removemapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::removemapping(self, ctx, inode, requests, vu_req))
+    }
+
+    async fn access(&self, ctx: &Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::access(self, ctx, inode, mask))
+    }
+
+    async fn lseek(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        block_in_place(|| <T as FileSystem>::lseek(self, ctx, inode, handle, offset, whence))
+    }
+
+    async fn copy_file_range(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        block_in_place(|| {
+            <T as FileSystem>::copy_file_range(
+                self, ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len,
+                flags,
+            )
+        })
+    }
+
+    async fn getlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        block_in_place(|| <T as FileSystem>::getlk(self, ctx, inode, handle, owner, lock, flags))
+    }
+
+    async fn setlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::setlk(self, ctx, inode, handle, owner, lock, flags))
+    }
+
+    async fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::setlkw(self, ctx, inode, handle, owner, lock, flags))
+    }
+
+    async fn flock(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        operation: i32,
+    ) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::flock(self, ctx, inode, handle, owner, operation))
+    }
+
+    async fn ioctl<'a>(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData<'a>,
+        out_size: u32,
+    ) -> io::Result<IoctlData<'a>> {
+        block_in_place(|| {
+            <T as FileSystem>::ioctl(self, ctx, inode, handle, flags, cmd, data, out_size)
+        })
+    }
+
+    async fn bmap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        block: u64,
+        blocksize: u32,
+    ) -> io::Result<u64> {
+        block_in_place(|| <T as FileSystem>::bmap(self, ctx, inode, block, blocksize))
+    }
+
+    async fn poll(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        khandle: Self::Handle,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        block_in_place(|| <T as FileSystem>::poll(self, ctx, inode, handle, khandle, flags, events))
+    }
+
+    async fn notify_reply(&self) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::notify_reply(self))
+    }
+
+    async fn id_remap(&self, ctx: &mut Context) -> io::Result<()> {
+        block_in_place(|| <T as FileSystem>::id_remap(self, ctx))
+    }
+}
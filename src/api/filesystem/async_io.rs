@@ -477,6 +477,35 @@ pub trait AsyncFileSystem: FileSystem {
         length: u64,
     ) -> io::Result<()>;
 
+    /// Copy a range of data from one file to another, see `FileSystem::copy_file_range()`.
+    ///
+    /// The default implementation calls `FileSystem::copy_file_range()`, which blocks the current
+    /// thread until the data has been copied.
+    #[allow(clippy::too_many_arguments)]
+    fn async_copy_file_range<'a, 'b, 'async_trait>(
+        &'a self,
+        ctx: &'b Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> Pin<Box<dyn Future<Output = io::Result<usize>> + Send + 'async_trait>>
+    where
+        'a: 'async_trait,
+        'b: 'async_trait,
+        Self: 'async_trait,
+    {
+        // Inodes and handles may not be `Send`, so copy before building the future.
+        let res = self.copy_file_range(
+            ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len, flags,
+        );
+        Box::pin(async move { res })
+    }
+
     /*
         /// Release an open file.
         ///
@@ -1005,6 +1034,28 @@ impl<FS: AsyncFileSystem> AsyncFileSystem for Arc<FS> {
             .async_fallocate(ctx, inode, handle, mode, offset, length)
     }
 
+    fn async_copy_file_range<'a, 'b, 'async_trait>(
+        &'a self,
+        ctx: &'b Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> Pin<Box<dyn Future<Output = io::Result<usize>> + Send + 'async_trait>>
+    where
+        'a: 'async_trait,
+        'b: 'async_trait,
+        Self: 'async_trait,
+    {
+        self.deref().async_copy_file_range(
+            ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len, flags,
+        )
+    }
+
     fn async_fsyncdir<'a, 'b, 'async_trait>(
         &'a self,
         ctx: &'b Context,
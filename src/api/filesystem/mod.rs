@@ -8,8 +8,12 @@
 //! The [FileSystem](trait.FileSystem.html) trait is the connection between the transport layer
 //! and the backend filesystem server. Other structs are used to pass information from the
 
+use std::borrow::Cow;
 use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs::File;
 use std::io;
+use std::os::unix::io::AsRawFd;
 use std::time::Duration;
 
 use crate::abi::fuse_abi as fuse;
@@ -96,6 +100,38 @@ impl Default for Entry {
     }
 }
 
+/// Attributes of an inode returned by `statx`, the attributes of `stat64` and the ones it lacks.
+#[derive(Copy, Clone)]
+pub struct StatxResult {
+    /// The basic attributes, as returned by `getattr`.
+    pub st: stat64,
+
+    /// Time of creation of the inode, or `None` if the file system doesn't record it.
+    pub btime: Option<libc::timespec>,
+}
+
+impl From<stat64> for StatxResult {
+    fn from(st: stat64) -> Self {
+        StatxResult { st, btime: None }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<StatxResult> for fuse::Statx {
+    fn from(res: StatxResult) -> fuse::Statx {
+        let mut stx: fuse::Statx = res.st.into();
+        if let Some(btime) = res.btime {
+            stx.mask |= fuse::STATX_BTIME;
+            stx.btime = fuse::SxTime {
+                tv_sec: btime.tv_sec,
+                tv_nsec: btime.tv_nsec as u32,
+                reserved: 0,
+            };
+        }
+        stx
+    }
+}
+
 /// Represents information about an entry in a directory.
 #[derive(Copy, Clone)]
 pub struct DirEntry<'a> {
@@ -153,13 +189,54 @@ impl From<FileLock> for fuse::FileLock {
     }
 }
 
+/// End offset of a fuse lock which extends to the end of the file, `OFFSET_MAX` in the kernel.
+#[cfg(target_os = "linux")]
+const LOCK_OFFSET_MAX: u64 = i64::MAX as u64;
+
+#[cfg(target_os = "linux")]
+impl From<FileLock> for libc::flock {
+    fn from(l: FileLock) -> libc::flock {
+        // Safe because libc::flock is a plain old data struct.
+        let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+        fl.l_type = l.lock_type as libc::c_short;
+        fl.l_whence = libc::SEEK_SET as libc::c_short;
+        fl.l_start = l.start as libc::off_t;
+        // Fuse lock ranges are inclusive, while a zero length means up to the end of file.
+        fl.l_len = if l.end >= LOCK_OFFSET_MAX {
+            0
+        } else {
+            (l.end - l.start + 1) as libc::off_t
+        };
+        fl.l_pid = l.pid as libc::pid_t;
+        fl
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<libc::flock> for FileLock {
+    fn from(fl: libc::flock) -> FileLock {
+        let start = fl.l_start as u64;
+        FileLock {
+            start,
+            end: if fl.l_len == 0 {
+                LOCK_OFFSET_MAX
+            } else {
+                start + fl.l_len as u64 - 1
+            },
+            lock_type: fl.l_type as u32,
+            // Open file description locks report a pid of -1, which means nothing to the client.
+            pid: if fl.l_pid > 0 { fl.l_pid as u32 } else { 0 },
+        }
+    }
+}
+
 /// ioctl data and result
 #[derive(Default, Clone)]
 pub struct IoctlData<'a> {
     /// ioctl result
     pub result: i32,
-    /// ioctl data
-    pub data: Option<&'a [u8]>,
+    /// ioctl data, borrowed from the request or owned by the reply
+    pub data: Option<Cow<'a, [u8]>>,
 }
 
 /// A reply to a `getxattr` method call.
@@ -280,6 +357,37 @@ pub trait ZeroCopyReader: io::Read {
             }
         }
     }
+
+    /// Whether `splice_write()` is supported.
+    fn supports_splice(&self) -> bool {
+        false
+    }
+
+    /// Moves at most `count` bytes from `self` into `f` at offset `off` with `splice(2)`, without
+    /// copying them through userspace. The return value has the same meaning as for `read_to()`.
+    ///
+    /// # Errors
+    ///
+    /// If any error is returned then the implementation must guarantee that no bytes were moved
+    /// from `self`, so that the caller can fall back to `read_to()`.
+    fn splice_write(&mut self, _f: &dyn AsRawFd, _count: usize, _off: u64) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Copies at most `count` bytes from `self` into `f` at offset `off` with a single
+    /// `pwritev2(2)` over the buffers of `self`, passing the `RWF_*` `flags`. The return value and
+    /// errors have the same meaning as for `read_to()`.
+    ///
+    /// The default implementation ignores `flags` and calls `read_to()`.
+    fn read_to_vectored(
+        &mut self,
+        f: &mut File,
+        count: usize,
+        off: u64,
+        _flags: i32,
+    ) -> io::Result<usize> {
+        self.read_to(f, count, off)
+    }
 }
 
 /// A trait for directly copying data from a `File` into the fuse transport without first storing
@@ -380,10 +488,52 @@ pub trait ZeroCopyWriter: io::Write {
     ///
     /// Useful for buffer fixed writers, such as FuseDevWriter, VirtioFsWriter
     fn available_bytes(&self) -> usize;
+
+    /// Whether `splice_read()` is supported.
+    fn supports_splice(&self) -> bool {
+        false
+    }
+
+    /// Moves at most `count` bytes from `f` at offset `off` into `self` with `splice(2)`, without
+    /// copying them through userspace. The return value has the same meaning as for
+    /// `write_from()`.
+    ///
+    /// # Errors
+    ///
+    /// If any error is returned then the implementation must guarantee that no bytes were moved
+    /// from `f`, so that the caller can fall back to `write_from()`.
+    fn splice_read(&mut self, _f: &dyn AsRawFd, _count: usize, _off: u64) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Copies at most `count` bytes from `f` at offset `off` into `self` with a single
+    /// `preadv2(2)` into the buffers of `self`, passing the `RWF_*` `flags`. The return value and
+    /// errors have the same meaning as for `write_from()`.
+    ///
+    /// The default implementation ignores `flags` and calls `write_from()`.
+    fn write_from_vectored(
+        &mut self,
+        f: &mut File,
+        count: usize,
+        off: u64,
+        _flags: i32,
+    ) -> io::Result<usize> {
+        self.write_from(f, count, off)
+    }
+}
+
+/// A security context to label a new file with, e.g. the SELinux label chosen by the client.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct SecContext {
+    /// The name of the extended attribute, e.g. `security.selinux`.
+    pub name: CString,
+
+    /// The value of the extended attribute.
+    pub value: Vec<u8>,
 }
 
 /// Additional context associated with requests.
-#[derive(Default, Clone, Copy, Debug)]
+#[derive(Default, Clone, Debug)]
 pub struct Context {
     /// The user ID of the calling process.
     pub uid: libc::uid_t,
@@ -393,6 +543,15 @@ pub struct Context {
 
     /// The thread group ID of the calling process.
     pub pid: libc::pid_t,
+
+    /// The security contexts of the file to create, sent with create, mkdir, mknod and symlink
+    /// requests when `FsOptions::SECURITY_CTX` is negotiated.
+    pub secctx: Vec<SecContext>,
+
+    /// Supplementary groups of the calling process, sent with create, mkdir, mknod and symlink
+    /// requests when `FsOptions::CREATE_SUPP_GROUP` is negotiated. The kernel only sends the
+    /// group of the parent directory, if the caller is a member of it.
+    pub supp_groups: Vec<libc::gid_t>,
 }
 
 impl Context {
@@ -408,6 +567,8 @@ impl From<&fuse::InHeader> for Context {
             uid: source.uid,
             gid: source.gid,
             pid: source.pid as i32,
+            secctx: Vec::new(),
+            supp_groups: Vec::new(),
         }
     }
 }
@@ -427,6 +588,7 @@ mod tests {
             uid: 3,
             gid: 4,
             pid: 5,
+            total_extlen: 0,
             padding: 0,
         };
         let header: Context = fuse_header.into();
@@ -452,4 +614,36 @@ mod tests {
         assert_eq!(fuse_entry.nodeid, 1);
         assert_eq!(fuse_entry.generation, 2);
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_file_lock_flock_conversion() {
+        let lock = FileLock {
+            start: 10,
+            end: 19,
+            lock_type: libc::F_WRLCK as u32,
+            pid: 0,
+        };
+        let fl: libc::flock = lock.into();
+        assert_eq!(fl.l_start, 10);
+        assert_eq!(fl.l_len, 10);
+        assert_eq!(fl.l_whence, libc::SEEK_SET as libc::c_short);
+        let back: FileLock = fl.into();
+        assert_eq!(back.start, 10);
+        assert_eq!(back.end, 19);
+        assert_eq!(back.lock_type, libc::F_WRLCK as u32);
+
+        let lock = FileLock {
+            start: 4096,
+            end: i64::MAX as u64,
+            lock_type: libc::F_RDLCK as u32,
+            pid: 0,
+        };
+        let mut fl: libc::flock = lock.into();
+        assert_eq!(fl.l_len, 0);
+        fl.l_pid = -1;
+        let back: FileLock = fl.into();
+        assert_eq!(back.end, i64::MAX as u64);
+        assert_eq!(back.pid, 0);
+    }
 }
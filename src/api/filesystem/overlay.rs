@@ -119,6 +119,7 @@ pub trait Layer: FileSystem {
             to_cstring(OPAQUE_XATTR)?.as_c_str(),
             b"y",
             0,
+            0,
         )
     }
 
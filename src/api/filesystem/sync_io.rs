@@ -11,8 +11,8 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use super::{
-    Context, DirEntry, Entry, FileLock, GetxattrReply, IoctlData, ListxattrReply, ZeroCopyReader,
-    ZeroCopyWriter,
+    Context, DirEntry, Entry, FileLock, GetxattrReply, IoctlData, ListxattrReply, StatxResult,
+    ZeroCopyReader, ZeroCopyWriter,
 };
 use crate::abi::fuse_abi::{stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid};
 #[cfg(feature = "virtiofs")]
@@ -116,6 +116,26 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Get the attributes of a file / directory, like `statx(2)`, including the ones `getattr`
+    /// can't return such as the birth time.
+    ///
+    /// `flags` holds the `AT_STATX_*` synchronization flags of the caller and `mask` the
+    /// `STATX_*` attributes it asked for, file systems may return more attributes than asked
+    /// for. `handle` is the same as for `getattr`.
+    ///
+    /// If the file system returns an `ENOSYS` error, then the attributes are returned by
+    /// `getattr` instead, without the extended ones.
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(StatxResult, Duration)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Set attributes for a file / directory.
     ///
     /// If `handle` is not `None`, then it contains the handle previously returned by the
@@ -343,6 +363,25 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Create an unnamed temporary file in the directory `parent` and open it, like `open(2)` with
+    /// `O_TMPFILE`.
+    ///
+    /// `args` is interpreted as for `create`. The returned `Entry` refers to an inode without any
+    /// name, it can be given one later by `link`. This increases the lookup count for the `Inode`
+    /// associated with the file by 1.
+    ///
+    /// If the file system returns an `ENOSYS` error, then the kernel will treat this method as
+    /// unimplemented and all future `O_TMPFILE` opens fail with `EOPNOTSUPP`.
+    #[allow(clippy::type_complexity)]
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Read data from a file.
     ///
     /// Returns `size` bytes of data starting from offset `off` from the file associated with
@@ -540,6 +579,9 @@ pub trait FileSystem {
     ///
     /// Valid values for flags are the same as those accepted by the `setxattr(2)` system call and
     /// have the same behavior.
+    ///
+    /// `setxattr_flags` contains the `SETXATTR_*` flags of the request, such as
+    /// `SETXATTR_ACL_KILL_SGID`. It's always 0 unless `FsOptions::SETXATTR_EXT` is negotiated.
     fn setxattr(
         &self,
         ctx: &Context,
@@ -547,6 +589,7 @@ pub trait FileSystem {
         name: &CStr,
         value: &[u8],
         flags: u32,
+        setxattr_flags: u32,
     ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
@@ -726,6 +769,17 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Synchronize the whole file system containing `inode`, like `syncfs(2)`.
+    ///
+    /// The kernel sends the request for the root inode of the mount.
+    ///
+    /// If this method returns an `ENOSYS` error then the kernel will treat it as success and all
+    /// subsequent calls to `syncfs` will be handled by the kernel without being forwarded to the
+    /// file system.
+    fn syncfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Release an open directory.
     ///
     /// For every `opendir` call there will be exactly one `releasedir` call (unless the file system
@@ -813,6 +867,54 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Copy a range of data from one file to another.
+    ///
+    /// Copies up to `len` bytes starting at `offset_in` of the file referred to by `inode_in` and
+    /// `handle_in` into the file referred to by `inode_out` and `handle_out` at `offset_out`,
+    /// without passing the data through the client. Returns the number of bytes copied, which may
+    /// be less than `len`.
+    ///
+    /// If this method returns an `ENOSYS` error, then the kernel will fall back to copying the data
+    /// with regular `read` and `write` requests and will not send this request again.
+    #[allow(clippy::too_many_arguments)]
+    fn copy_file_range(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Clone a range of data from one file to another, sharing the data on disk.
+    ///
+    /// Like `copy_file_range()`, except that file systems supporting copy-on-write may share the
+    /// extents of the source file with the destination file, as `ioctl(FICLONERANGE)` does,
+    /// instead of copying the data. Returns the number of bytes cloned or copied, which may be
+    /// less than `len`.
+    ///
+    /// FUSE has no request for clones, so this isn't called by the `Server`, see `seal()`.
+    #[allow(clippy::too_many_arguments)]
+    fn clone_range(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Query file lock status
     fn getlk(
         &self,
@@ -852,18 +954,62 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Apply or remove a BSD-style advisory lock on an open file.
+    ///
+    /// This method is only called if the `FsOptions::FLOCK_LOCKS` feature is enabled. `operation`
+    /// takes the same values as the `operation` argument of `flock(2)`: one of `LOCK_SH`,
+    /// `LOCK_EX` or `LOCK_UN`, optionally combined with `LOCK_NB`.
+    fn flock(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        operation: i32,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Add seals to an open file, as `fcntl(F_ADD_SEALS)` does.
+    ///
+    /// `seals` is a combination of `F_SEAL_SEAL`, `F_SEAL_SHRINK`, `F_SEAL_GROW`, `F_SEAL_WRITE`
+    /// and `F_SEAL_FUTURE_WRITE`. Seals can't be removed once added.
+    ///
+    /// FUSE has no request for seals, so this isn't called by the `Server`. A daemon may forward
+    /// them either through `FUSE_IOCTL` with a command of its own, handled by the `ioctl()` method
+    /// of its file system, or through an opcode extension understood by its own client.
+    fn seal(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        seals: u32,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Get the seals of an open file, as `fcntl(F_GET_SEALS)` does. See `seal()`.
+    fn get_seals(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+    ) -> io::Result<u32> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// send ioctl to the file
     #[allow(clippy::too_many_arguments)]
-    fn ioctl(
+    fn ioctl<'a>(
         &self,
         ctx: &Context,
         inode: Self::Inode,
         handle: Self::Handle,
         flags: u32,
         cmd: u32,
-        data: IoctlData,
+        data: IoctlData<'a>,
         out_size: u32,
-    ) -> io::Result<IoctlData> {
+    ) -> io::Result<IoctlData<'a>> {
         // Rather than ENOSYS, let's return ENOTTY so simulate that the ioctl call is implemented
         // but no ioctl number is supported.
         Err(io::Error::from_raw_os_error(libc::ENOTTY))
@@ -937,6 +1083,17 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().getattr(ctx, inode, handle)
     }
 
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(StatxResult, Duration)> {
+        self.deref().statx(ctx, inode, handle, flags, mask)
+    }
+
     fn setattr(
         &self,
         ctx: &Context,
@@ -1036,6 +1193,15 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().create(ctx, parent, name, args)
     }
 
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        self.deref().tmpfile(ctx, parent, args)
+    }
+
     fn read(
         &self,
         ctx: &Context,
@@ -1138,8 +1304,10 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         name: &CStr,
         value: &[u8],
         flags: u32,
+        setxattr_flags: u32,
     ) -> io::Result<()> {
-        self.deref().setxattr(ctx, inode, name, value, flags)
+        self.deref()
+            .setxattr(ctx, inode, name, value, flags, setxattr_flags)
     }
 
     fn getxattr(
@@ -1210,6 +1378,10 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().fsyncdir(ctx, inode, datasync, handle)
     }
 
+    fn syncfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        self.deref().syncfs(ctx, inode)
+    }
+
     fn releasedir(
         &self,
         ctx: &Context,
@@ -1263,6 +1435,41 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().lseek(ctx, inode, handle, offset, whence)
     }
 
+    #[allow(clippy::too_many_arguments)]
+    fn copy_file_range(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        self.deref().copy_file_range(
+            ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len, flags,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn clone_range(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+    ) -> io::Result<usize> {
+        self.deref().clone_range(
+            ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len,
+        )
+    }
+
     /// Query file lock status
     fn getlk(
         &self,
@@ -1302,18 +1509,49 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().setlkw(ctx, inode, handle, owner, lock, flags)
     }
 
+    /// Apply or remove a BSD-style advisory lock
+    fn flock(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        operation: i32,
+    ) -> io::Result<()> {
+        self.deref().flock(ctx, inode, handle, owner, operation)
+    }
+
+    fn seal(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        seals: u32,
+    ) -> io::Result<()> {
+        self.deref().seal(ctx, inode, handle, seals)
+    }
+
+    fn get_seals(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+    ) -> io::Result<u32> {
+        self.deref().get_seals(ctx, inode, handle)
+    }
+
     /// send ioctl to the file
     #[allow(clippy::too_many_arguments)]
-    fn ioctl(
+    fn ioctl<'a>(
         &self,
         ctx: &Context,
         inode: Self::Inode,
         handle: Self::Handle,
         flags: u32,
         cmd: u32,
-        data: IoctlData,
+        data: IoctlData<'a>,
         out_size: u32,
-    ) -> io::Result<IoctlData> {
+    ) -> io::Result<IoctlData<'a>> {
         self.deref()
             .ioctl(ctx, inode, handle, flags, cmd, data, out_size)
     }
@@ -0,0 +1,242 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Counters of FUSE requests handled by file systems, exported in the OpenMetrics text format.
+//!
+//! [FuseMetrics](struct.FuseMetrics.html) counts requests and failed requests of each opcode, and
+//! optionally keeps a histogram of their latency. Rates and latency percentiles are left to the
+//! monitoring system, e.g. `rate()` and `histogram_quantile()` of Prometheus.
+//!
+//! The metrics observe the requests dispatched by a [Server](../server/struct.Server.html), so
+//! they account requests of any file system.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::abi::fuse_abi::Opcode;
+use crate::api::server::ServerObserver;
+
+const NR_OPCODES: usize = Opcode::MaxOpcode as usize;
+
+/// Upper bounds of the latency histogram buckets, in microseconds.
+const LATENCY_BUCKETS_US: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 100_000, 1_000_000,
+];
+
+#[derive(Default)]
+struct OpMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    latency_sum_us: AtomicU64,
+    // Requests in each latency bucket, the last one counts requests slower than all bounds.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+}
+
+/// Request and error counters for each FUSE opcode.
+pub struct FuseMetrics {
+    ops: Vec<OpMetrics>,
+    latency: bool,
+}
+
+impl FuseMetrics {
+    /// Create metrics counting requests and errors.
+    pub fn new() -> Self {
+        FuseMetrics {
+            ops: (0..NR_OPCODES).map(|_| OpMetrics::default()).collect(),
+            latency: false,
+        }
+    }
+
+    /// Create metrics counting requests and errors, and recording the latency of requests.
+    pub fn with_latency() -> Self {
+        FuseMetrics {
+            latency: true,
+            ..Self::new()
+        }
+    }
+
+    /// Account a request of `opcode` handled in `latency`, which failed if `failed` is true.
+    pub fn record(&self, opcode: Opcode, latency: Duration, failed: bool) {
+        let op = match self.ops.get(opcode as usize) {
+            Some(op) => op,
+            None => return,
+        };
+
+        op.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            op.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if self.latency {
+            let us = latency.as_micros() as u64;
+            let bucket = LATENCY_BUCKETS_US
+                .iter()
+                .position(|&bound| us <= bound)
+                .unwrap_or(LATENCY_BUCKETS_US.len());
+            op.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+            op.latency_sum_us.fetch_add(us, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the number of requests of `opcode`.
+    pub fn requests(&self, opcode: Opcode) -> u64 {
+        self.ops
+            .get(opcode as usize)
+            .map_or(0, |op| op.requests.load(Ordering::Relaxed))
+    }
+
+    /// Get the number of failed requests of `opcode`.
+    pub fn errors(&self, opcode: Opcode) -> u64 {
+        self.ops
+            .get(opcode as usize)
+            .map_or(0, |op| op.errors.load(Ordering::Relaxed))
+    }
+
+    /// Encode the metrics in the OpenMetrics text format.
+    ///
+    /// Only opcodes which have been requested at least once are listed.
+    pub fn encode_prometheus(&self) -> String {
+        // Opcodes are only accounted from valid `Opcode` values, so converting back is safe.
+        let ops: Vec<(String, &OpMetrics)> = self
+            .ops
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| op.requests.load(Ordering::Relaxed) > 0)
+            .map(|(i, op)| (opcode_name(Opcode::from(i as u32)), op))
+            .collect();
+
+        // Writing to a String never fails.
+        let mut out = String::new();
+        out.push_str("# TYPE fuse_requests counter\n");
+        out.push_str("# HELP fuse_requests FUSE requests handled.\n");
+        for (name, op) in ops.iter() {
+            let _ = writeln!(
+                out,
+                "fuse_requests_total{{opcode=\"{}\"}} {}",
+                name,
+                op.requests.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str("# TYPE fuse_errors counter\n");
+        out.push_str("# HELP fuse_errors FUSE requests failed with an error.\n");
+        for (name, op) in ops.iter() {
+            let _ = writeln!(
+                out,
+                "fuse_errors_total{{opcode=\"{}\"}} {}",
+                name,
+                op.errors.load(Ordering::Relaxed)
+            );
+        }
+
+        if self.latency {
+            out.push_str("# TYPE fuse_request_duration_seconds histogram\n");
+            out.push_str("# UNIT fuse_request_duration_seconds seconds\n");
+            out.push_str("# HELP fuse_request_duration_seconds Latency of FUSE requests.\n");
+            for (name, op) in ops.iter() {
+                let mut count = 0;
+                for (i, bucket) in op.latency_buckets.iter().enumerate() {
+                    count += bucket.load(Ordering::Relaxed);
+                    let le = match LATENCY_BUCKETS_US.get(i) {
+                        Some(&us) => Duration::from_micros(us).as_secs_f64().to_string(),
+                        None => "+Inf".to_string(),
+                    };
+                    let _ = writeln!(
+                        out,
+                        "fuse_request_duration_seconds_bucket{{opcode=\"{}\",le=\"{}\"}} {}",
+                        name, le, count
+                    );
+                }
+                let sum = Duration::from_micros(op.latency_sum_us.load(Ordering::Relaxed));
+                let _ = writeln!(
+                    out,
+                    "fuse_request_duration_seconds_sum{{opcode=\"{}\"}} {}",
+                    name,
+                    sum.as_secs_f64()
+                );
+                let _ = writeln!(
+                    out,
+                    "fuse_request_duration_seconds_count{{opcode=\"{}\"}} {}",
+                    name, count
+                );
+            }
+        }
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+impl Default for FuseMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for FuseMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FuseMetrics")
+            .field("latency", &self.latency)
+            .finish()
+    }
+}
+
+impl ServerObserver for FuseMetrics {
+    fn on_request(&self, _opcode: Opcode) {}
+
+    fn on_response(
+        &self,
+        opcode: Opcode,
+        result: std::result::Result<usize, i32>,
+        latency: Duration,
+    ) {
+        self.record(opcode, latency, result.is_err());
+    }
+}
+
+// Convert an opcode into a snake case label, e.g. `CopyFileRange` into `copy_file_range`.
+fn opcode_name(opcode: Opcode) -> String {
+    let mut name = String::new();
+    for (i, c) in format!("{:?}", opcode).chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse_metrics() {
+        let metrics = FuseMetrics::with_latency();
+        metrics.record(Opcode::Read, Duration::from_micros(20), false);
+        metrics.record(Opcode::Read, Duration::from_secs(2), true);
+        metrics.record(Opcode::CopyFileRange, Duration::ZERO, false);
+        assert_eq!(metrics.requests(Opcode::Read), 2);
+        assert_eq!(metrics.errors(Opcode::Read), 1);
+        assert_eq!(metrics.requests(Opcode::Write), 0);
+
+        let text = metrics.encode_prometheus();
+        assert!(text.contains("fuse_requests_total{opcode=\"read\"} 2\n"));
+        assert!(text.contains("fuse_errors_total{opcode=\"read\"} 1\n"));
+        assert!(text.contains("fuse_requests_total{opcode=\"copy_file_range\"} 1\n"));
+        assert!(!text.contains("opcode=\"write\""));
+        assert!(text
+            .contains("fuse_request_duration_seconds_bucket{opcode=\"read\",le=\"0.000025\"} 1\n"));
+        assert!(
+            text.contains("fuse_request_duration_seconds_bucket{opcode=\"read\",le=\"+Inf\"} 2\n")
+        );
+        assert!(text.contains("fuse_request_duration_seconds_count{opcode=\"read\"} 2\n"));
+        assert!(text.ends_with("# EOF\n"));
+
+        // Without latency, there is no histogram.
+        let metrics = FuseMetrics::new();
+        metrics.record(Opcode::Read, Duration::from_micros(20), false);
+        assert!(!metrics
+            .encode_prometheus()
+            .contains("fuse_request_duration_seconds"));
+    }
+}
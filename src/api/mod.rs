@@ -16,9 +16,13 @@ mod pseudo_fs;
 
 pub mod vfs;
 pub use vfs::{
-    validate_path_component, BackFileSystem, BackendFileSystem, Vfs, VfsIndex, VfsOptions,
-    CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR, PROC_SELF_FD_CSTR, SLASH_ASCII, VFS_MAX_INO,
+    validate_path_component, BackFileSystem, BackendFileSystem, Vfs, VfsIndex, VfsNotification,
+    VfsNotifier, VfsOptions, CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR, PROC_SELF_FD_CSTR,
+    SLASH_ASCII, VFS_MAX_INO,
 };
 
+#[cfg(feature = "async-io")]
+pub mod async_filesystem;
 pub mod filesystem;
+pub mod metrics;
 pub mod server;
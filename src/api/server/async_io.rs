@@ -10,9 +10,10 @@ use async_trait::async_trait;
 use vm_memory::ByteValued;
 
 use crate::abi::fuse_abi::{
-    stat64, AttrOut, CreateIn, EntryOut, FallocateIn, FsyncIn, GetattrIn, Opcode, OpenIn, OpenOut,
-    OutHeader, ReadIn, SetattrIn, SetattrValid, WriteIn, WriteOut, FATTR_FH, GETATTR_FH,
-    KERNEL_MINOR_VERSION_LOOKUP_NEGATIVE_ENTRY_ZERO, READ_LOCKOWNER, WRITE_CACHE, WRITE_LOCKOWNER,
+    stat64, AttrOut, CopyFileRangeIn, CreateIn, EntryOut, FallocateIn, FsyncIn, GetattrIn, Opcode,
+    OpenIn, OpenOut, OutHeader, ReadIn, SetattrIn, SetattrValid, WriteIn, WriteOut, FATTR_FH,
+    GETATTR_FH, KERNEL_MINOR_VERSION_LOOKUP_NEGATIVE_ENTRY_ZERO, READ_LOCKOWNER, WRITE_CACHE,
+    WRITE_LOCKOWNER,
 };
 use crate::api::filesystem::{
     AsyncFileSystem, AsyncZeroCopyReader, AsyncZeroCopyWriter, ZeroCopyReader, ZeroCopyWriter,
@@ -191,16 +192,21 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
             x if x == Opcode::Rename2 as u32 => self.rename2(ctx),
             #[cfg(target_os = "linux")]
             x if x == Opcode::Lseek as u32 => self.lseek(ctx),
+            #[cfg(target_os = "linux")]
+            x if x == Opcode::CopyFileRange as u32 => self.async_copy_file_range(ctx).await,
+            #[cfg(target_os = "linux")]
+            x if x == Opcode::Tmpfile as u32 => self.tmpfile(ctx),
+            #[cfg(target_os = "linux")]
+            x if x == Opcode::Syncfs as u32 => self.syncfs(ctx),
+            #[cfg(target_os = "linux")]
+            x if x == Opcode::Statx as u32 => self.statx(ctx),
             #[cfg(feature = "virtiofs")]
             x if x == Opcode::SetupMapping as u32 => self.setupmapping(ctx, vu_req),
             #[cfg(feature = "virtiofs")]
             x if x == Opcode::RemoveMapping as u32 => self.removemapping(ctx, vu_req),
             // Group reqeusts don't need reply together
             x => match x {
-                x if x == Opcode::Interrupt as u32 => {
-                    self.interrupt(ctx);
-                    Ok(0)
-                }
+                x if x == Opcode::Interrupt as u32 => self.interrupt(ctx),
                 x if x == Opcode::Destroy as u32 => {
                     self.destroy(ctx);
                     Ok(0)
@@ -469,6 +475,7 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
                 return Err(e);
             }
         };
+        self.take_create_ext(&mut ctx, &buf[name.to_bytes_with_nul().len()..])?;
 
         let result = self
             .fs
@@ -521,6 +528,48 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
             Err(e) => ctx.async_reply_error(e).await,
         }
     }
+
+    #[cfg(target_os = "linux")]
+    async fn async_copy_file_range<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+    ) -> Result<usize> {
+        let CopyFileRangeIn {
+            fh_in,
+            offset_in,
+            nodeid_out,
+            fh_out,
+            offset_out,
+            len,
+            flags,
+        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let result = self
+            .fs
+            .async_copy_file_range(
+                ctx.context(),
+                ctx.nodeid(),
+                fh_in.into(),
+                offset_in,
+                nodeid_out.into(),
+                fh_out.into(),
+                offset_out,
+                len,
+                flags,
+            )
+            .await;
+
+        match result {
+            Ok(count) => {
+                let out = WriteOut {
+                    size: count as u32,
+                    ..Default::default()
+                };
+
+                ctx.async_reply_ok(Some(out), None).await
+            }
+            Err(e) => ctx.async_reply_error(e).await,
+        }
+    }
 }
 
 impl<'a, F: AsyncFileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
@@ -621,7 +670,7 @@ mod tests {
     #[test]
     fn test_vfs_async_invalid_header() {
         let vfs = Vfs::default();
-        let server = Server::new(vfs);
+        let server = Server::new(vfs, None);
         let mut r_buf = [0u8];
         let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
         let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
@@ -17,16 +17,26 @@
 //! adopting interior-mutability. And the arcswap crate is used to implement interior-mutability.
 
 use std::ffi::CStr;
+use std::fs::File;
 use std::io::{self, Read};
 use std::marker::PhantomData;
 use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use arc_swap::ArcSwap;
+use vm_memory::ByteValued;
 
 use crate::abi::fuse_abi::*;
-use crate::api::filesystem::{Context, FileSystem, ZeroCopyReader, ZeroCopyWriter};
+use crate::api::filesystem::{Context, FileSystem, SecContext, ZeroCopyReader, ZeroCopyWriter};
 use crate::file_traits::FileReadWriteVolatile;
+#[cfg(target_os = "linux")]
+use crate::file_traits::RwfFile;
+#[cfg(all(feature = "fusedev", target_os = "linux"))]
+use crate::transport::InterruptMap;
 use crate::transport::{Reader, Writer};
 use crate::{bytes_to_cstr, BitmapSlice, Error, Result};
 
@@ -51,21 +61,114 @@ pub const MAX_REQ_PAGES: u16 = 256; // 1MB
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
     vers: ArcSwap<ServerVersion>,
+    // Whether the kernel sends the extended `fuse_setxattr_in`.
+    #[cfg(target_os = "linux")]
+    setxattr_ext: AtomicBool,
+    // Whether the kernel sends the security contexts of new files.
+    #[cfg(target_os = "linux")]
+    security_ctx: AtomicBool,
+    // Whether the kernel sends the supplementary groups of the callers creating files.
+    #[cfg(target_os = "linux")]
+    create_supp_group: AtomicBool,
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    interrupts: Option<Arc<InterruptMap>>,
+    observer: Option<Arc<dyn ServerObserver>>,
 }
 
 impl<F: FileSystem + Sync> Server<F> {
     /// Create a Server instance from a filesystem driver object.
-    pub fn new(fs: F) -> Server<F> {
+    ///
+    /// The requests handled by `handle_message()` are reported to `observer` if any.
+    pub fn new(fs: F, observer: Option<Arc<dyn ServerObserver>>) -> Server<F> {
         Server {
             fs,
             vers: ArcSwap::new(Arc::new(ServerVersion {
                 major: KERNEL_VERSION,
                 minor: KERNEL_MINOR_VERSION,
             })),
+            #[cfg(target_os = "linux")]
+            setxattr_ext: AtomicBool::new(false),
+            #[cfg(target_os = "linux")]
+            security_ctx: AtomicBool::new(false),
+            #[cfg(target_os = "linux")]
+            create_supp_group: AtomicBool::new(false),
+            #[cfg(all(feature = "fusedev", target_os = "linux"))]
+            interrupts: None,
+            observer,
+        }
+    }
+
+    /// Track in-flight requests in `map`, so that `FUSE_INTERRUPT` requests cancel the blocked
+    /// operations they target.
+    ///
+    /// Only requests handled by `handle_message()` can be interrupted.
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    pub fn set_interrupt_map(&mut self, map: Arc<InterruptMap>) {
+        self.interrupts = Some(map);
+    }
+
+    // Attach the security contexts and the supplementary groups in `ext`, what follows the names
+    // of a create, mkdir, mknod or symlink request, to the context of the request.
+    #[cfg(target_os = "linux")]
+    fn take_create_ext<S: BitmapSlice>(
+        &self,
+        ctx: &mut SrvContext<'_, F, S>,
+        ext: &[u8],
+    ) -> Result<()> {
+        let einval = || Error::DecodeMessage(io::Error::from_raw_os_error(libc::EINVAL));
+        let security_ctx = self.security_ctx.load(Ordering::Relaxed);
+
+        // Before FUSE 7.38, security contexts directly follow the names, without any extension
+        // header.
+        if ctx.in_header.total_extlen == 0 {
+            if security_ctx {
+                ctx.context.secctx = ServerUtil::parse_secctx(ext)?;
+            }
+            return Ok(());
         }
+
+        // Extensions are at the end of the request.
+        let ext_len = ctx.in_header.total_extlen as usize * 8;
+        let mut ext = ext
+            .len()
+            .checked_sub(ext_len)
+            .map(|start| &ext[start..])
+            .ok_or_else(einval)?;
+        while !ext.is_empty() {
+            let header: ExtHeader = read_obj_at(ext, 0).ok_or_else(einval)?;
+            let size = header.size as usize;
+            if size < size_of::<ExtHeader>() || size > ext.len() {
+                return Err(einval());
+            }
+            let (record, rest) = ext.split_at(size);
+            match header.type_ {
+                t if t <= MAX_NR_SECCTX && security_ctx => {
+                    ctx.context.secctx = ServerUtil::parse_secctx(record)?;
+                }
+                EXT_GROUPS if self.create_supp_group.load(Ordering::Relaxed) => {
+                    ctx.context.supp_groups =
+                        ServerUtil::parse_supp_groups(&record[size_of::<ExtHeader>()..])?;
+                }
+                // Extensions the file system didn't ask for are ignored.
+                _ => {}
+            }
+            ext = rest;
+        }
+
+        Ok(())
     }
 }
 
+// Read an object at `pos` of `buf`, copying it out as fields of messages aren't aligned.
+#[cfg(target_os = "linux")]
+pub(crate) fn read_obj_at<T: ByteValued>(buf: &[u8], pos: usize) -> Option<T> {
+    let mut obj = T::default();
+    let len = obj.as_slice().len();
+    obj.as_mut_slice()
+        .copy_from_slice(buf.get(pos..pos.checked_add(len)?)?);
+    Some(obj)
+}
+
 struct ZcReader<'a, S: BitmapSlice = ()>(Reader<'a, S>);
 
 impl<'a, S: BitmapSlice> ZeroCopyReader for ZcReader<'a, S> {
@@ -77,6 +180,26 @@ impl<'a, S: BitmapSlice> ZeroCopyReader for ZcReader<'a, S> {
     ) -> io::Result<usize> {
         self.0.read_to_at(f, count, off)
     }
+
+    fn supports_splice(&self) -> bool {
+        self.0.supports_splice()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn splice_write(&mut self, f: &dyn AsRawFd, count: usize, off: u64) -> io::Result<usize> {
+        self.0.splice_to(f.as_raw_fd(), count, off)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_to_vectored(
+        &mut self,
+        f: &mut File,
+        count: usize,
+        off: u64,
+        flags: i32,
+    ) -> io::Result<usize> {
+        self.0.read_to_at(RwfFile::new(f, flags), count, off)
+    }
 }
 
 impl<'a, S: BitmapSlice> io::Read for ZcReader<'a, S> {
@@ -100,6 +223,25 @@ impl<'a, S: BitmapSlice> ZeroCopyWriter for ZcWriter<'a, S> {
     fn available_bytes(&self) -> usize {
         self.0.available_bytes()
     }
+
+    fn supports_splice(&self) -> bool {
+        self.0.supports_splice()
+    }
+
+    fn splice_read(&mut self, f: &dyn AsRawFd, count: usize, off: u64) -> io::Result<usize> {
+        self.0.splice_from(f.as_raw_fd(), count, off)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_from_vectored(
+        &mut self,
+        f: &mut File,
+        count: usize,
+        off: u64,
+        flags: i32,
+    ) -> io::Result<usize> {
+        self.0.write_from_at(RwfFile::new(f, flags), count, off)
+    }
 }
 
 impl<'a, S: BitmapSlice> io::Write for ZcWriter<'a, S> {
@@ -156,9 +298,114 @@ impl ServerUtil {
             libc::EINVAL,
         )))
     }
+
+    // Parse a `SecctxHeader` and the security contexts following it.
+    #[cfg(target_os = "linux")]
+    fn parse_secctx(buf: &[u8]) -> Result<Vec<SecContext>> {
+        let einval = || Error::DecodeMessage(io::Error::from_raw_os_error(libc::EINVAL));
+
+        if buf.is_empty() {
+            return Ok(Vec::new());
+        }
+        let header: SecctxHeader = read_obj_at(buf, 0).ok_or_else(einval)?;
+        let buf = buf.get(..header.size as usize).ok_or_else(einval)?;
+
+        let mut pos = size_of::<SecctxHeader>();
+        let mut secctx = Vec::new();
+        for _ in 0..header.nr_secctx {
+            let Secctx { size, .. } = read_obj_at(buf, pos).ok_or_else(einval)?;
+            pos += size_of::<Secctx>();
+            let name = bytes_to_cstr(buf.get(pos..).ok_or_else(einval)?)?;
+            pos += name.to_bytes_with_nul().len();
+            let value = pos
+                .checked_add(size as usize)
+                .and_then(|end| buf.get(pos..end))
+                .ok_or_else(einval)?;
+            pos += value.len();
+            secctx.push(SecContext {
+                name: name.to_owned(),
+                value: value.to_vec(),
+            });
+        }
+
+        Ok(secctx)
+    }
+
+    // Parse a `SuppGroups` and the group ids following it.
+    #[cfg(target_os = "linux")]
+    fn parse_supp_groups(buf: &[u8]) -> Result<Vec<libc::gid_t>> {
+        let einval = || Error::DecodeMessage(io::Error::from_raw_os_error(libc::EINVAL));
+        let SuppGroups { nr_groups } = read_obj_at(buf, 0).ok_or_else(einval)?;
+        (0..nr_groups as usize)
+            .map(|i| {
+                let pos = size_of::<SuppGroups>() + i * size_of::<u32>();
+                read_obj_at::<u32>(buf, pos).ok_or_else(einval)
+            })
+            .collect()
+    }
+}
+
+// Encode the notification `opcode` with the arguments `args`, to be written at once.
+fn encode_notification(opcode: NotifyOpcode, args: &[&[u8]]) -> Vec<u8> {
+    let len = size_of::<OutHeader>() + args.iter().map(|a| a.len()).sum::<usize>();
+    let header = OutHeader {
+        len: len as u32,
+        error: opcode as i32,
+        unique: 0,
+    };
+    let mut buf = Vec::with_capacity(len);
+    buf.extend_from_slice(header.as_slice());
+    for arg in args {
+        buf.extend_from_slice(arg);
+    }
+    buf
+}
+
+/// Encode `FUSE_NOTIFY_INVAL_INODE`, see `Server::notify_inval_inode()`.
+pub(crate) fn encode_notify_inval_inode(inode: u64, off: i64, len: i64) -> Vec<u8> {
+    let out = NotifyInvalInodeOut {
+        ino: inode,
+        off,
+        len,
+    };
+    encode_notification(NotifyOpcode::InvalInode, &[out.as_slice()])
+}
+
+/// Encode `FUSE_NOTIFY_INVAL_ENTRY`, see `Server::notify_inval_entry()`.
+#[cfg(feature = "fusedev")]
+pub(crate) fn encode_notify_inval_entry(parent: u64, name: &CStr) -> Vec<u8> {
+    let out = NotifyInvalEntryOut {
+        parent,
+        // The name length doesn't count the NUL byte.
+        namelen: name.to_bytes().len() as u32,
+        flags: 0,
+    };
+    encode_notification(
+        NotifyOpcode::InvalEntry,
+        &[out.as_slice(), name.to_bytes_with_nul()],
+    )
+}
+
+/// Encode `FUSE_NOTIFY_DELETE` of the entry `name` of `child` in the directory `parent`.
+#[cfg(all(feature = "fusedev", target_os = "linux"))]
+pub(crate) fn encode_notify_delete(parent: u64, child: u64, name: &CStr) -> Vec<u8> {
+    let out = NotifyDeleteOut {
+        parent,
+        child,
+        namelen: name.to_bytes().len() as u32,
+        padding: 0,
+    };
+    encode_notification(
+        NotifyOpcode::Delete,
+        &[out.as_slice(), name.to_bytes_with_nul()],
+    )
 }
 
 /// Provide concrete backend filesystem a way to catch information/metrics from fuse.
+///
+/// Prefer a [ServerObserver](trait.ServerObserver.html) passed to `Server::new()`, which is also
+/// told the result and the latency of each request, rather than a hook passed to every
+/// `handle_message()` call.
 pub trait MetricsHook {
     /// `collect()` will be invoked before the real request is processed
     fn collect(&self, ih: &InHeader);
@@ -166,6 +413,26 @@ pub trait MetricsHook {
     fn release(&self, oh: Option<&OutHeader>);
 }
 
+/// Observe the requests handled by a [Server](struct.Server.html), e.g. to count requests and
+/// errors of each opcode, or to trace them.
+///
+/// The callbacks run on the thread handling the request, so they should be cheap.
+pub trait ServerObserver: Send + Sync {
+    /// Called before a request of `opcode` is handled.
+    fn on_request(&self, opcode: Opcode);
+
+    /// Called after a request of `opcode` has been handled in `latency`.
+    ///
+    /// `result` is the length of the reply, or the errno the request failed with, whether it has
+    /// been replied or not.
+    fn on_response(
+        &self,
+        opcode: Opcode,
+        result: std::result::Result<usize, i32>,
+        latency: Duration,
+    );
+}
+
 struct SrvContext<'a, F, S: BitmapSlice = ()> {
     in_header: InHeader,
     context: Context,
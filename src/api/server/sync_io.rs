@@ -3,30 +3,44 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::io::{self, IoSlice, Read, Write};
 use std::mem::size_of;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use vm_memory::ByteValued;
 
 use super::{
-    MetricsHook, Server, ServerUtil, ServerVersion, SrvContext, ZcReader, ZcWriter,
-    BUFFER_HEADER_SIZE, DIRENT_PADDING, MAX_BUFFER_SIZE, MAX_REQ_PAGES, MIN_READ_BUFFER,
+    encode_notify_inval_inode, MetricsHook, Server, ServerUtil, ServerVersion, SrvContext,
+    ZcReader, ZcWriter, BUFFER_HEADER_SIZE, DIRENT_PADDING, MAX_BUFFER_SIZE, MAX_REQ_PAGES,
+    MIN_READ_BUFFER,
 };
 use crate::abi::fuse_abi::*;
 #[cfg(feature = "virtiofs")]
 use crate::abi::virtio_fs::{RemovemappingIn, RemovemappingOne, SetupmappingIn};
 use crate::api::filesystem::{
-    DirEntry, Entry, FileSystem, GetxattrReply, IoctlData, ListxattrReply,
+    DirEntry, Entry, FileSystem, GetxattrReply, IoctlData, ListxattrReply, StatxResult,
 };
 #[cfg(feature = "fusedev")]
 use crate::transport::FuseDevWriter;
 use crate::transport::{pagesize, FsCacheReqHandler, Reader, Writer};
 use crate::{bytes_to_cstr, encode_io_error_kind, BitmapSlice, Error, Result};
 
+thread_local! {
+    // The errno replied to the request handled by the current thread, reported to the observer.
+    static REPLY_ERROR: Cell<i32> = const { Cell::new(0) };
+}
+
 impl<F: FileSystem + Sync> Server<F> {
     #[cfg(feature = "fusedev")]
-    /// Use to send notify msg to kernel fuse
+    /// Send a notification to the kernel to invalidate the cached dentry `name` in the directory
+    /// `parent`, e.g. when the entry has been changed out-of-band.
+    ///
+    /// The kernel may have already forgotten `parent`, in which case the write to the fuse device
+    /// fails with `ENOENT`. That's harmless, as there is nothing left to invalidate.
     pub fn notify_inval_entry<S: BitmapSlice>(
         &self,
         mut w: FuseDevWriter<'_, S>,
@@ -34,30 +48,32 @@ impl<F: FileSystem + Sync> Server<F> {
         name: &std::ffi::CStr,
     ) -> Result<usize> {
         let mut buffer_writer = w.split_at(0).map_err(Error::FailedToSplitWriter)?;
-        let mut entry = NotifyInvalEntryOut::default();
-        let mut header = OutHeader::default();
-        let name_with_null = name.to_bytes_with_nul();
-        header.unique = 0;
-        header.error = NotifyOpcode::InvalEntry as i32;
-        header.len = std::mem::size_of::<OutHeader>() as u32
-            + std::mem::size_of::<NotifyInvalEntryOut>() as u32
-            + name_with_null.len() as u32;
-        // the namelne don't contains the nul
-        entry.namelen = (name_with_null.len() - 1) as u32;
-        entry.parent = parent;
-
-        buffer_writer
-            .write_obj(header)
-            .map_err(Error::FailedToWrite)?;
-        buffer_writer
-            .write_obj(entry)
-            .map_err(Error::FailedToWrite)?;
         buffer_writer
-            .write(name_with_null)
+            .write_all(&super::encode_notify_inval_entry(parent, name))
             .map_err(Error::FailedToWrite)?;
         buffer_writer.commit(None).map_err(Error::InvalidMessage)
     }
 
+    /// Send a notification to the kernel to invalidate the cached attributes of `inode` and its
+    /// cached data in the range of `len` bytes at offset `off`, e.g. when the file has been changed
+    /// out-of-band. A negative `off` only invalidates the attributes, and a `len` of `0` or less
+    /// invalidates the data up to the end of the file.
+    ///
+    /// The kernel may have already forgotten `inode`, in which case the write to the fuse device
+    /// fails with `ENOENT`. That's harmless, as there is nothing left to invalidate.
+    pub fn notify_inval_inode<S: BitmapSlice>(
+        &self,
+        mut w: Writer<'_, S>,
+        inode: u64,
+        off: i64,
+        len: i64,
+    ) -> Result<usize> {
+        w.write_all(&encode_notify_inval_inode(inode, off, len))
+            .map_err(Error::FailedToWrite)?;
+        w.commit(None).map_err(Error::InvalidMessage)?;
+        Ok(w.bytes_written())
+    }
+
     #[cfg(feature = "fusedev")]
     /// Send a resend notification message to the kernel via FUSE. This function should be invoked as part of
     /// the crash recovery routine. Given that FUSE initialization does not occur again during recovery,
@@ -79,6 +95,29 @@ impl<F: FileSystem + Sync> Server<F> {
         Ok(())
     }
 
+    /// Send a poll wakeup notification to the kernel, asking it to poll the file associated with
+    /// the kernel poll handle `kh` again.
+    pub fn notify_poll_wakeup<S: BitmapSlice>(
+        &self,
+        mut w: Writer<'_, S>,
+        kh: u64,
+    ) -> Result<usize> {
+        let out = NotifyPollWakeupOut { kh };
+        let header = OutHeader {
+            len: (size_of::<OutHeader>() + size_of::<NotifyPollWakeupOut>()) as u32,
+            error: NotifyOpcode::Poll as i32,
+            unique: 0,
+        };
+
+        w.write_vectored(&[
+            IoSlice::new(header.as_slice()),
+            IoSlice::new(out.as_slice()),
+        ])
+        .map_err(Error::FailedToWrite)?;
+        w.commit(None).map_err(Error::InvalidMessage)?;
+        Ok(w.bytes_written())
+    }
+
     /// Main entrance to handle requests from the transport layer.
     ///
     /// It receives Fuse requests from transport layers, parses the request according to Fuse ABI,
@@ -119,7 +158,29 @@ impl<F: FileSystem + Sync> Server<F> {
             h.collect(&in_header);
         }
 
+        let observed = self.observer.as_ref().map(|o| {
+            let opcode = Opcode::from(in_header.opcode);
+            o.on_request(opcode);
+            REPLY_ERROR.with(|e| e.set(0));
+            (o, opcode, Instant::now())
+        });
+
+        // Let the interrupt guard in scope until the reply is sent.
+        #[cfg(all(feature = "fusedev", target_os = "linux"))]
+        let _interrupt = match self.interrupts.as_ref() {
+            Some(map) if in_header.opcode != Opcode::Interrupt as u32 => {
+                Some(map.register(in_header.unique))
+            }
+            _ => None,
+        };
+        #[cfg(all(feature = "fusedev", target_os = "linux"))]
+        let interrupted = _interrupt.as_ref().is_some_and(|g| g.is_interrupted());
+        #[cfg(not(all(feature = "fusedev", target_os = "linux")))]
+        let interrupted = false;
+
         let res = match in_header.opcode {
+            // The kernel sent the interrupt before the request, don't even start it.
+            _ if interrupted => ctx.reply_error(io::Error::from_raw_os_error(libc::EINTR)),
             x if x == Opcode::Lookup as u32 => self.lookup(ctx),
             x if x == Opcode::Forget as u32 => self.forget(ctx), // No reply.
             x if x == Opcode::Getattr as u32 => self.getattr(ctx),
@@ -165,16 +226,21 @@ impl<F: FileSystem + Sync> Server<F> {
             x if x == Opcode::Rename2 as u32 => self.rename2(ctx),
             #[cfg(target_os = "linux")]
             x if x == Opcode::Lseek as u32 => self.lseek(ctx),
+            #[cfg(target_os = "linux")]
+            x if x == Opcode::CopyFileRange as u32 => self.copy_file_range(ctx),
+            #[cfg(target_os = "linux")]
+            x if x == Opcode::Tmpfile as u32 => self.tmpfile(ctx),
+            #[cfg(target_os = "linux")]
+            x if x == Opcode::Syncfs as u32 => self.syncfs(ctx),
+            #[cfg(target_os = "linux")]
+            x if x == Opcode::Statx as u32 => self.statx(ctx),
             #[cfg(feature = "virtiofs")]
             x if x == Opcode::SetupMapping as u32 => self.setupmapping(ctx, vu_req),
             #[cfg(feature = "virtiofs")]
             x if x == Opcode::RemoveMapping as u32 => self.removemapping(ctx, vu_req),
             // Group reqeusts don't need reply together
             x => match x {
-                x if x == Opcode::Interrupt as u32 => {
-                    self.interrupt(ctx);
-                    Ok(0)
-                }
+                x if x == Opcode::Interrupt as u32 => self.interrupt(ctx),
                 x if x == Opcode::Destroy as u32 => {
                     self.destroy(ctx);
                     Ok(0)
@@ -183,6 +249,16 @@ impl<F: FileSystem + Sync> Server<F> {
             },
         };
 
+        if let Some((o, opcode, start)) = observed {
+            let latency = start.elapsed();
+            let result = match (&res, REPLY_ERROR.with(|e| e.replace(0))) {
+                (Ok(len), 0) => Ok(*len),
+                (Err(_), 0) => Err(libc::EIO),
+                (_, errno) => Err(errno),
+            };
+            o.on_response(opcode, result, latency);
+        }
+
         // Pass `None` because current API handler's design does not allow us to catch
         // the `out_header`. Hopefully, we can reach to `out_header` after some
         // refactoring work someday.
@@ -278,6 +354,11 @@ impl<F: FileSystem + Sync> Server<F> {
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
         // The name and linkname are encoded one after another and separated by a nul character.
         let (name, linkname) = ServerUtil::extract_two_cstrs(&buf)?;
+        #[cfg(target_os = "linux")]
+        self.take_create_ext(
+            &mut ctx,
+            &buf[name.to_bytes_with_nul().len() + linkname.to_bytes_with_nul().len()..],
+        )?;
 
         match self.fs.symlink(ctx.context(), linkname, ctx.nodeid(), name) {
             Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
@@ -295,6 +376,8 @@ impl<F: FileSystem + Sync> Server<F> {
             error!("fuse: bytes to cstr error: {:?}, {:?}", buf, e);
             e
         })?;
+        #[cfg(target_os = "linux")]
+        self.take_create_ext(&mut ctx, &buf[name.to_bytes_with_nul().len()..])?;
 
         match self
             .fs
@@ -313,6 +396,8 @@ impl<F: FileSystem + Sync> Server<F> {
             error!("fuse: bytes to cstr error: {:?}, {:?}", buf, e);
             e
         })?;
+        #[cfg(target_os = "linux")]
+        self.take_create_ext(&mut ctx, &buf[name.to_bytes_with_nul().len()..])?;
 
         match self
             .fs
@@ -579,8 +664,20 @@ impl<F: FileSystem + Sync> Server<F> {
 
     pub(super) fn setxattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let SetxattrIn { size, flags } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let buf =
-            ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<SetxattrIn>())?;
+        #[cfg(target_os = "macos")]
+        let (setxattr_flags, sub_hdr_sz) = (0, size_of::<SetxattrIn>());
+        #[cfg(target_os = "linux")]
+        let (setxattr_flags, sub_hdr_sz) = if self.setxattr_ext.load(Ordering::Relaxed) {
+            let SetxattrIn2 { setxattr_flags, .. } =
+                ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+            (
+                setxattr_flags,
+                size_of::<SetxattrIn>() + size_of::<SetxattrIn2>(),
+            )
+        } else {
+            (0, size_of::<SetxattrIn>())
+        };
+        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, sub_hdr_sz)?;
 
         // The name and value and encoded one after another and separated by a '\0' character.
         let split_pos = buf
@@ -599,10 +696,14 @@ impl<F: FileSystem + Sync> Server<F> {
             e
         })?;
 
-        match self
-            .fs
-            .setxattr(ctx.context(), ctx.nodeid(), name, value, flags)
-        {
+        match self.fs.setxattr(
+            ctx.context(),
+            ctx.nodeid(),
+            name,
+            value,
+            flags,
+            setxattr_flags,
+        ) {
             Ok(()) => ctx.reply_ok(None::<u8>, None),
             Err(e) => ctx.reply_error(e),
         }
@@ -709,14 +810,29 @@ impl<F: FileSystem + Sync> Server<F> {
         let mut flags_u64 = flags as u64;
         #[cfg(target_os = "linux")]
         if flags_u64 & FsOptions::INIT_EXT.bits() != 0 {
-            let InitIn2 { flags2, unused: _ } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-            flags_u64 |= (flags2 as u64) << 32;
+            // Kernels before 7.36 send the shorter struct, without the high word of flags.
+            if ctx.r.available_bytes() >= size_of::<InitIn2>() {
+                let InitIn2 { flags2, unused: _ } =
+                    ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+                flags_u64 |= (flags2 as u64) << 32;
+            } else {
+                warn!("fuse: INIT_EXT without the extended init struct, ignore it");
+                flags_u64 &= !FsOptions::INIT_EXT.bits();
+            }
         }
         let capable = FsOptions::from_bits_truncate(flags_u64);
 
         match self.fs.init(capable) {
             Ok(want) => {
+                #[cfg(target_os = "macos")]
                 let enabled = capable & want;
+                #[cfg(target_os = "linux")]
+                let mut enabled = capable & want;
+                // The kernel only reads the high word of flags if INIT_EXT is replied.
+                #[cfg(target_os = "linux")]
+                if enabled.bits() >> 32 != 0 {
+                    enabled |= FsOptions::INIT_EXT;
+                }
                 info!(
                     "FUSE INIT major {} minor {}\n in_opts: {:?}\nout_opts: {:?}",
                     major, minor, capable, enabled
@@ -749,6 +865,28 @@ impl<F: FileSystem + Sync> Server<F> {
                     out.max_pages = MAX_REQ_PAGES;
                     out.max_write = MAX_REQ_PAGES as u32 * pagesize() as u32; // 1MB
                 }
+                #[cfg(target_os = "linux")]
+                if enabled.contains(FsOptions::MAP_ALIGNMENT) {
+                    // log2 of the alignment of DAX mappings.
+                    out.map_alignment = pagesize().trailing_zeros() as u16;
+                }
+                #[cfg(target_os = "linux")]
+                if enabled.contains(FsOptions::PASSTHROUGH) {
+                    // Backing files may not be on stacked file systems such as overlayfs, so
+                    // the FUSE mount only adds one level to the stack.
+                    out.max_stack_depth = 1;
+                }
+                #[cfg(target_os = "linux")]
+                self.setxattr_ext
+                    .store(enabled.contains(FsOptions::SETXATTR_EXT), Ordering::Relaxed);
+                #[cfg(target_os = "linux")]
+                self.security_ctx
+                    .store(enabled.contains(FsOptions::SECURITY_CTX), Ordering::Relaxed);
+                #[cfg(target_os = "linux")]
+                self.create_supp_group.store(
+                    enabled.contains(FsOptions::CREATE_SUPP_GROUP),
+                    Ordering::Relaxed,
+                );
                 let vers = ServerVersion { major, minor };
                 self.vers.store(Arc::new(vers));
                 if minor < KERNEL_MINOR_VERSION_INIT_OUT_SIZE {
@@ -923,6 +1061,10 @@ impl<F: FileSystem + Sync> Server<F> {
             lk_flags,
             ..
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+
+        if lk_flags & LK_FLOCK != 0 {
+            return self.flock(ctx, fh, owner, lk.type_, false);
+        }
         match self.fs.setlk(
             ctx.context(),
             ctx.nodeid(),
@@ -944,7 +1086,11 @@ impl<F: FileSystem + Sync> Server<F> {
             lk_flags,
             ..
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        match self.fs.setlk(
+
+        if lk_flags & LK_FLOCK != 0 {
+            return self.flock(ctx, fh, owner, lk.type_, true);
+        }
+        match self.fs.setlkw(
             ctx.context(),
             ctx.nodeid(),
             fh.into(),
@@ -957,6 +1103,33 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
+    fn flock<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+        fh: u64,
+        owner: u64,
+        lock_type: u32,
+        block: bool,
+    ) -> Result<usize> {
+        let mut operation = match lock_type {
+            t if t == libc::F_RDLCK as u32 => libc::LOCK_SH,
+            t if t == libc::F_WRLCK as u32 => libc::LOCK_EX,
+            t if t == libc::F_UNLCK as u32 => libc::LOCK_UN,
+            _ => return ctx.reply_error(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        if !block {
+            operation |= libc::LOCK_NB;
+        }
+
+        match self
+            .fs
+            .flock(ctx.context(), ctx.nodeid(), fh.into(), owner, operation)
+        {
+            Ok(()) => ctx.reply_ok(None::<u8>, None),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
     pub(super) fn access<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let AccessIn { mask, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
 
@@ -974,33 +1147,101 @@ impl<F: FileSystem + Sync> Server<F> {
             error!("fuse: bytes to cstr error: {:?}, {:?}", buf, e);
             e
         })?;
+        #[cfg(target_os = "linux")]
+        self.take_create_ext(&mut ctx, &buf[name.to_bytes_with_nul().len()..])?;
 
-        match self.fs.create(ctx.context(), ctx.nodeid(), name, args) {
-            Ok((entry, handle, opts, passthrough)) => {
-                let entry_out = EntryOut {
-                    nodeid: entry.inode,
-                    generation: entry.generation,
-                    entry_valid: entry.entry_timeout.as_secs(),
-                    attr_valid: entry.attr_timeout.as_secs(),
-                    entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
-                    attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
-                    attr: entry.attr.into(),
-                };
+        let res = self.fs.create(ctx.context(), ctx.nodeid(), name, args);
+        ctx.handle_create_result(res)
+    }
 
-                let open_out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
-                    passthrough: passthrough.unwrap_or_default(),
-                };
+    pub(super) fn tmpfile<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let args: CreateIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        // The name following `CreateIn` means nothing for an unnamed file, skip it.
+        ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<CreateIn>())?;
 
-                // Kind of a hack to write both structs.
-                ctx.reply_ok(Some(entry_out), Some(open_out.as_slice()))
+        let res = self.fs.tmpfile(ctx.context(), ctx.nodeid(), args);
+        ctx.handle_create_result(res)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn syncfs<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let _: SyncfsIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+
+        match self.fs.syncfs(ctx.context(), ctx.nodeid()) {
+            Ok(()) => ctx.reply_ok(None::<u8>, None),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn statx<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let StatxIn {
+            getattr_flags,
+            fh,
+            sx_flags,
+            sx_mask,
+            ..
+        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let handle = || {
+            if (getattr_flags & GETATTR_FH) != 0 {
+                Some(fh.into())
+            } else {
+                None
+            }
+        };
+
+        let result = match self
+            .fs
+            .statx(ctx.context(), ctx.nodeid(), handle(), sx_flags, sx_mask)
+        {
+            // The kernel would stop sending statx requests to the whole mount, fall back to
+            // getattr instead, so file systems behind a vfs may still implement statx.
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => self
+                .fs
+                .getattr(ctx.context(), ctx.nodeid(), handle())
+                .map(|(st, timeout)| (StatxResult::from(st), timeout)),
+            result => result,
+        };
+
+        match result {
+            Ok((res, timeout)) => {
+                let out = StatxOut {
+                    attr_valid: timeout.as_secs(),
+                    attr_valid_nsec: timeout.subsec_nanos(),
+                    stat: res.into(),
+                    ..Default::default()
+                };
+                ctx.reply_ok(Some(out), None)
             }
             Err(e) => ctx.reply_error(e),
         }
     }
 
-    pub(super) fn interrupt<S: BitmapSlice>(&self, _ctx: SrvContext<'_, F, S>) {}
+    #[allow(unused_mut, unused_variables)]
+    pub(super) fn interrupt<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        #[cfg(all(feature = "fusedev", target_os = "linux"))]
+        if let Some(map) = self.interrupts.as_ref() {
+            let InterruptIn { unique } = match ctx.r.read_obj() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("fuse: failed to decode interrupt request, {}", e);
+                    return Ok(0);
+                }
+            };
+            match map.interrupt(unique) {
+                Ok(true) => trace!("fuse: interrupted request {}", unique),
+                // The request has completed already, or has not been dispatched yet. In the
+                // latter case it fails once dispatched. Reply EAGAIN as the protocol asks for,
+                // so the kernel sends the interrupt again unless the request has completed.
+                Ok(false) => {
+                    trace!("fuse: no request {} to interrupt", unique);
+                    return ctx.reply_error(io::Error::from_raw_os_error(libc::EAGAIN));
+                }
+                Err(e) => warn!("fuse: failed to interrupt request {}, {}", unique, e),
+            }
+        }
+        Ok(0)
+    }
 
     pub(super) fn bmap<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let BmapIn {
@@ -1029,6 +1270,11 @@ impl<F: FileSystem + Sync> Server<F> {
             in_size,
             out_size,
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        // Unrestricted ioctls need the retry mechanism to fetch data the ioctl points to, which
+        // is not supported.
+        if flags & IoctlFlags::IOCTL_UNRESTRICTED.bits() != 0 {
+            return ctx.reply_error(io::Error::from_raw_os_error(libc::ENOTTY));
+        }
         // TODO: check fs capability of FUSE_CAP_IOCTL_DIR and return ENOTTY if unsupported.
         let mut buf = IoctlData {
             ..Default::default()
@@ -1042,7 +1288,7 @@ impl<F: FileSystem + Sync> Server<F> {
         if in_size > 0 {
             let size = ctx.r.read(&mut data).map_err(Error::DecodeMessage)?;
             if size > 0 {
-                buf.data = Some(&data[..size]);
+                buf.data = Some(Cow::Borrowed(&data[..size]));
             }
         }
         match self.fs.ioctl(
@@ -1054,12 +1300,19 @@ impl<F: FileSystem + Sync> Server<F> {
             buf,
             out_size,
         ) {
+            Ok(res) if res.data.as_ref().map_or(0, |d| d.len()) > out_size as usize => {
+                error!(
+                    "fuse: ioctl {:#x} replied more data than the {} bytes requested",
+                    cmd, out_size
+                );
+                ctx.reply_error(io::Error::from_raw_os_error(libc::EIO))
+            }
             Ok(res) => ctx.reply_ok(
                 Some(IoctlOut {
                     result: res.result,
                     ..Default::default()
                 }),
-                res.data,
+                res.data.as_deref(),
             ),
             Err(e) => ctx.reply_error(e),
         }
@@ -1177,6 +1430,44 @@ impl<F: FileSystem + Sync> Server<F> {
             Err(e) => ctx.reply_error(e),
         }
     }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn copy_file_range<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+    ) -> Result<usize> {
+        let CopyFileRangeIn {
+            fh_in,
+            offset_in,
+            nodeid_out,
+            fh_out,
+            offset_out,
+            len,
+            flags,
+        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+
+        match self.fs.copy_file_range(
+            ctx.context(),
+            ctx.nodeid(),
+            fh_in.into(),
+            offset_in,
+            nodeid_out.into(),
+            fh_out.into(),
+            offset_out,
+            len,
+            flags,
+        ) {
+            Ok(count) => {
+                let out = WriteOut {
+                    size: count as u32,
+                    ..Default::default()
+                };
+
+                ctx.reply_ok(Some(out), None)
+            }
+            Err(e) => ctx.reply_error(e),
+        }
+    }
 }
 
 #[cfg(feature = "virtiofs")]
@@ -1298,6 +1589,7 @@ impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
                 .unwrap_or_else(|| encode_io_error_kind(err.kind())),
             unique: self.unique(),
         };
+        REPLY_ERROR.with(|e| e.set(-header.error));
 
         if explicit || err.raw_os_error().is_none() {
             error!("fuse: reply error header {:?}, error {:?}", header, err);
@@ -1328,6 +1620,36 @@ impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
         self.do_reply_error(err, true)
     }
 
+    #[allow(clippy::type_complexity)]
+    fn handle_create_result(
+        &mut self,
+        result: io::Result<(Entry, Option<F::Handle>, OpenOptions, Option<u32>)>,
+    ) -> Result<usize> {
+        match result {
+            Ok((entry, handle, opts, passthrough)) => {
+                let entry_out = EntryOut {
+                    nodeid: entry.inode,
+                    generation: entry.generation,
+                    entry_valid: entry.entry_timeout.as_secs(),
+                    attr_valid: entry.attr_timeout.as_secs(),
+                    entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
+                    attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
+                    attr: entry.attr.into(),
+                };
+
+                let open_out = OpenOut {
+                    fh: handle.map(Into::into).unwrap_or(0),
+                    open_flags: opts.bits(),
+                    passthrough: passthrough.unwrap_or_default(),
+                };
+
+                // Kind of a hack to write both structs.
+                self.reply_ok(Some(entry_out), Some(open_out.as_slice()))
+            }
+            Err(e) => self.reply_error(e),
+        }
+    }
+
     fn handle_attr_result(&mut self, result: io::Result<(stat64, Duration)>) -> Result<usize> {
         match result {
             Ok((st, timeout)) => {
@@ -1408,11 +1730,16 @@ mod tests {
     #[cfg(all(feature = "fusedev", target_os = "linux"))]
     mod tests_fusedev {
         use super::super::*;
+        use crate::api::filesystem::Context;
+        use crate::api::Vfs;
         use crate::passthrough::{Config, PassthroughFs};
         use crate::transport::FuseBuf;
 
+        use std::ffi::CString;
         use std::fs::File;
-        use std::os::unix::io::AsRawFd;
+        use std::io::{Seek, SeekFrom, Write};
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        use vmm_sys_util::tempdir::TempDir;
         use vmm_sys_util::tempfile::TempFile;
 
         fn prepare_srvcontext<'a>(
@@ -1432,7 +1759,7 @@ mod tests {
         #[test]
         fn test_server_init() {
             let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
-            let server = Server::new(fs);
+            let server = Server::new(fs, None);
 
             let mut read_buf = [
                 0x8u8, 0x0, 0x0, 0x0, // major = 0x0008
@@ -1459,10 +1786,71 @@ mod tests {
             assert_eq!(res, 24);
         }
 
+        fn init_reply(server: &Server<PassthroughFs>, flags: u64, ext: bool) -> InitOut {
+            let mut read_buf = InitIn {
+                major: KERNEL_VERSION,
+                minor: KERNEL_MINOR_VERSION,
+                max_readahead: 0,
+                flags: flags as u32,
+            }
+            .as_slice()
+            .to_vec();
+            if ext {
+                let ext = InitIn2 {
+                    flags2: (flags >> 32) as u32,
+                    unused: [0; 11],
+                };
+                read_buf.extend_from_slice(ext.as_slice());
+            }
+            let mut write_buf = [0u8; 4096];
+            let (ctx, mut file) = prepare_srvcontext(&mut read_buf, &mut write_buf);
+            let res = server.init(ctx).unwrap();
+            assert_eq!(res, size_of::<OutHeader>() + size_of::<InitOut>());
+
+            let mut out = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut out).unwrap();
+            *InitOut::from_slice(&out[size_of::<OutHeader>()..]).unwrap()
+        }
+
+        #[test]
+        fn test_server_init_flags2() {
+            let cfg = Config {
+                xattr: true,
+                do_import: false,
+                ..Default::default()
+            };
+            let server = Server::new(PassthroughFs::<()>::new(cfg).unwrap(), None);
+
+            // Kernels before 7.36 only send the low word of flags.
+            let flags = (FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO).bits();
+            let out = init_reply(&server, flags, false);
+            assert_eq!(out.minor, KERNEL_MINOR_VERSION);
+            assert_eq!(out.flags as u64, flags);
+            assert_eq!(out.flags2, 0);
+
+            // A kernel claiming INIT_EXT without the extended struct is handled as an old one.
+            let flags = flags | FsOptions::INIT_EXT.bits();
+            let out = init_reply(&server, flags, false);
+            assert_eq!(out.flags & FsOptions::INIT_EXT.bits() as u32, 0);
+            assert_eq!(out.flags2, 0);
+
+            // High flags are negotiated in flags2, which the kernel only reads with INIT_EXT.
+            let flags = flags | FsOptions::SECURITY_CTX.bits() | FsOptions::HAS_EXPIRE_ONLY.bits();
+            let out = init_reply(&server, flags, true);
+            assert_eq!(
+                out.flags as u64,
+                (FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO | FsOptions::INIT_EXT)
+                    .bits()
+            );
+            assert_eq!(out.flags2 as u64, FsOptions::SECURITY_CTX.bits() >> 32);
+            assert!(server.security_ctx.load(Ordering::Relaxed));
+        }
+
         #[test]
         fn test_server_write() {
             let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
-            let server = Server::new(fs);
+            let server = Server::new(fs, None);
 
             let mut read_buf = [0u8; 4096];
             let mut write_buf = [0u8; 4096];
@@ -1475,7 +1863,7 @@ mod tests {
         #[test]
         fn test_server_read() {
             let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
-            let server = Server::new(fs);
+            let server = Server::new(fs, None);
 
             let mut read_buf = [0u8; 4096];
             let mut write_buf = [0u8; 4096];
@@ -1485,10 +1873,439 @@ mod tests {
             assert_eq!(res, 16);
         }
 
+        #[test]
+        fn test_server_read_splice() {
+            let source = TempDir::new().unwrap();
+            std::fs::write(source.as_path().join("file"), b"spliced data").unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let ctx = Context::default();
+            let name = CString::new("file").unwrap();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let (handle, _, _) = fs
+                .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+                .unwrap();
+            let server = Server::new(fs, None);
+
+            let mut body = ReadIn {
+                fh: handle.unwrap(),
+                offset: 8,
+                size: 4096,
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec();
+            let mut write_buf = [0u8; 8192];
+            let mut file = TempFile::new().unwrap().into_file();
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                nodeid: entry.inode,
+                unique: 7,
+                ..Default::default()
+            };
+            let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut body)).unwrap();
+            let writer = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut write_buf).unwrap();
+            let ctx = SrvContext::<PassthroughFs>::new(in_header, reader, writer.into());
+            assert_eq!(server.read(ctx).unwrap(), size_of::<OutHeader>() + 4);
+
+            let mut out = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut out).unwrap();
+            let header = OutHeader::from_slice(&out[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.len as usize, out.len());
+            assert_eq!(header.error, 0);
+            assert_eq!(header.unique, 7);
+            assert_eq!(&out[size_of::<OutHeader>()..], b"data");
+            // Nothing is left in the buffer, the data has been spliced.
+            assert!(write_buf[size_of::<OutHeader>()..].iter().all(|b| *b == 0));
+        }
+
+        #[test]
+        fn test_server_write_splice() {
+            let source = TempDir::new().unwrap();
+            std::fs::write(source.as_path().join("file"), b"").unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let ctx = Context::default();
+            let name = CString::new("file").unwrap();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let (handle, _, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+            let server = Server::new(fs, None);
+
+            // The data of the request is left in a pipe, fed by another thread.
+            let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+            let (pipe_r, pipe_w) = nix::unistd::pipe().unwrap();
+            let pipe_r = unsafe { File::from_raw_fd(pipe_r) };
+            let mut pipe_w = unsafe { File::from_raw_fd(pipe_w) };
+            let feeder = {
+                let data = data.clone();
+                std::thread::spawn(move || pipe_w.write_all(&data).unwrap())
+            };
+
+            let mut body = WriteIn {
+                fh: handle.unwrap(),
+                offset: 0,
+                size: data.len() as u32,
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec();
+            body.resize(body.len() + data.len(), 0);
+            let mut write_buf = [0u8; 4096];
+            let mut file = TempFile::new().unwrap().into_file();
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                nodeid: entry.inode,
+                unique: 7,
+                ..Default::default()
+            };
+            let reader = Reader::<()>::from_spliced_fuse_buffer(
+                FuseBuf::new(&mut body),
+                pipe_r.as_raw_fd(),
+                data.len(),
+            )
+            .unwrap();
+            let writer = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut write_buf).unwrap();
+            let ctx = SrvContext::<PassthroughFs>::new(in_header, reader, writer.into());
+            assert_eq!(
+                server.write(ctx).unwrap(),
+                size_of::<OutHeader>() + size_of::<WriteOut>()
+            );
+            feeder.join().unwrap();
+
+            let mut out = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut out).unwrap();
+            let header = OutHeader::from_slice(&out[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.error, 0);
+            let out = WriteOut::from_slice(&out[size_of::<OutHeader>()..]).unwrap();
+            assert_eq!(out.size as usize, data.len());
+            assert_eq!(std::fs::read(source.as_path().join("file")).unwrap(), data);
+            // Nothing has been read into the buffer, the data has been spliced.
+            assert!(body[size_of::<WriteIn>()..].iter().all(|b| *b == 0));
+        }
+
+        #[test]
+        fn test_server_poll() {
+            let source = TempDir::new().unwrap();
+            std::fs::write(source.as_path().join("file"), b"data").unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let ctx = Context::default();
+            let name = CString::new("file").unwrap();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let (handle, _, _) = fs
+                .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+                .unwrap();
+            let server = Server::new(fs, None);
+
+            let mut body = PollIn {
+                fh: handle.unwrap(),
+                kh: 5,
+                flags: POLL_SCHEDULE_NOTIFY,
+                events: (libc::POLLIN | libc::POLLOUT) as u32,
+            }
+            .as_slice()
+            .to_vec();
+            let mut write_buf = [0u8; 4096];
+            let mut file = TempFile::new().unwrap().into_file();
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: Opcode::Poll as u32,
+                nodeid: entry.inode,
+                unique: 9,
+                ..Default::default()
+            };
+            let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut body)).unwrap();
+            let writer = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut write_buf).unwrap();
+            let ctx = SrvContext::<PassthroughFs>::new(in_header, reader, writer.into());
+            let len = size_of::<OutHeader>() + size_of::<PollOut>();
+            assert_eq!(server.poll(ctx).unwrap(), len);
+
+            let mut out = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut out).unwrap();
+            assert_eq!(out.len(), len);
+            let header = OutHeader::from_slice(&out[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.error, 0);
+            assert_eq!(header.unique, 9);
+            // Regular files are always ready, so the kernel handle isn't watched.
+            let out = PollOut::from_slice(&out[size_of::<OutHeader>()..]).unwrap();
+            assert_eq!(out.revents, (libc::POLLIN | libc::POLLOUT) as u32);
+            let timeout = Some(std::time::Duration::from_millis(0));
+            assert!(server.fs.wait_poll_handles(timeout).unwrap().is_empty());
+        }
+
+        #[test]
+        fn test_notify_inval_entry() {
+            let server = Server::new(PassthroughFs::<()>::new(Config::default()).unwrap(), None);
+            let mut buf = [0u8; 64];
+            let mut file = TempFile::new().unwrap().into_file();
+            let writer = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+            let name = CString::new("foo").unwrap();
+            assert_eq!(
+                server.notify_inval_entry(writer, 0x1234, &name).unwrap(),
+                36
+            );
+
+            let mut msg = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut msg).unwrap();
+            let mut expected = Vec::new();
+            expected.extend_from_slice(&36u32.to_ne_bytes());
+            expected.extend_from_slice(&3i32.to_ne_bytes());
+            expected.extend_from_slice(&0u64.to_ne_bytes());
+            expected.extend_from_slice(&0x1234u64.to_ne_bytes());
+            expected.extend_from_slice(&3u32.to_ne_bytes());
+            expected.extend_from_slice(&0u32.to_ne_bytes());
+            expected.extend_from_slice(b"foo\0");
+            assert_eq!(msg, expected);
+        }
+
+        #[test]
+        fn test_notify_inval_inode() {
+            let server = Server::new(PassthroughFs::<()>::new(Config::default()).unwrap(), None);
+            let mut buf = [0u8; 64];
+            let mut file = TempFile::new().unwrap().into_file();
+            let writer = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+            assert_eq!(
+                server
+                    .notify_inval_inode(writer.into(), 0x1234, -1, 0)
+                    .unwrap(),
+                40
+            );
+
+            let mut msg = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut msg).unwrap();
+            let mut expected = Vec::new();
+            expected.extend_from_slice(&40u32.to_ne_bytes());
+            expected.extend_from_slice(&2i32.to_ne_bytes());
+            expected.extend_from_slice(&0u64.to_ne_bytes());
+            expected.extend_from_slice(&0x1234u64.to_ne_bytes());
+            expected.extend_from_slice(&(-1i64).to_ne_bytes());
+            expected.extend_from_slice(&0i64.to_ne_bytes());
+            assert_eq!(msg, expected);
+        }
+
+        #[test]
+        fn test_server_syncfs() {
+            let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
+            fs.import().unwrap();
+            let server = Server::new(fs, None);
+
+            let mut read_buf = [0u8; 8];
+            let mut write_buf = [0u8; 4096];
+            let (ctx, _file) = prepare_srvcontext(&mut read_buf, &mut write_buf);
+
+            let res = server.syncfs(ctx).unwrap();
+            assert_eq!(res, 16);
+
+            // SyncfsIn is missing.
+            let mut read_buf = [0u8; 4];
+            let (ctx, _file) = prepare_srvcontext(&mut read_buf, &mut write_buf);
+            assert!(server.syncfs(ctx).is_err());
+        }
+
+        #[test]
+        fn test_server_setxattr_ext() {
+            let source = TempDir::new().unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                xattr: true,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let server = Server::new(fs, None);
+
+            for (ext, value) in [(false, b"a"), (true, b"b")] {
+                server.setxattr_ext.store(ext, Ordering::Relaxed);
+                let mut body = SetxattrIn { size: 1, flags: 0 }.as_slice().to_vec();
+                if ext {
+                    body.extend_from_slice(SetxattrIn2::default().as_slice());
+                }
+                body.extend_from_slice(b"user.test\0");
+                body.extend_from_slice(value);
+
+                let mut write_buf = [0u8; 4096];
+                let file = TempFile::new().unwrap().into_file();
+                let in_header = InHeader {
+                    len: (size_of::<InHeader>() + body.len()) as u32,
+                    nodeid: ROOT_ID,
+                    ..Default::default()
+                };
+                let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut body)).unwrap();
+                let writer = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut write_buf).unwrap();
+                let ctx = SrvContext::<PassthroughFs>::new(in_header, reader, writer.into());
+
+                let res = server.setxattr(ctx).unwrap();
+                assert_eq!(res, 16);
+                let mut buf = [0u8; 8];
+                let path = CString::new(source.as_path().to_str().unwrap()).unwrap();
+                let name = CString::new("user.test").unwrap();
+                // Safe because this only writes into buf and we check the return value.
+                let res = unsafe {
+                    libc::getxattr(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                assert_eq!(&buf[..res as usize], value);
+            }
+        }
+
+        #[test]
+        fn test_server_secctx() {
+            let source = TempDir::new().unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                xattr: true,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let server = Server::new(fs, None);
+            server.security_ctx.store(true, Ordering::Relaxed);
+
+            let mut secctx = Secctx {
+                size: 5,
+                padding: 0,
+            }
+            .as_slice()
+            .to_vec();
+            secctx.extend_from_slice(b"user.label\0label");
+            secctx.resize((secctx.len() + 7) & !7, 0);
+            let header = SecctxHeader {
+                size: (size_of::<SecctxHeader>() + secctx.len()) as u32,
+                nr_secctx: 1,
+            };
+            let mut body = MkdirIn {
+                mode: 0o755,
+                umask: 0,
+            }
+            .as_slice()
+            .to_vec();
+            body.extend_from_slice(b"dir\0");
+            body.extend_from_slice(header.as_slice());
+            body.extend_from_slice(&secctx);
+
+            let mut write_buf = [0u8; 4096];
+            let file = TempFile::new().unwrap().into_file();
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                nodeid: ROOT_ID,
+                ..Default::default()
+            };
+            let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut body)).unwrap();
+            let writer = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut write_buf).unwrap();
+            let ctx = SrvContext::<PassthroughFs>::new(in_header, reader, writer.into());
+            server.mkdir(ctx).unwrap();
+
+            let mut buf = [0u8; 8];
+            let path = CString::new(source.as_path().join("dir").to_str().unwrap()).unwrap();
+            let name = CString::new("user.label").unwrap();
+            // Safe because this only writes into buf and we check the return value.
+            let res = unsafe {
+                libc::getxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            assert_eq!(&buf[..res as usize], b"label");
+
+            // Truncated security contexts are rejected.
+            assert!(ServerUtil::parse_secctx(&header.as_slice()[..4]).is_err());
+            let mut truncated = header.as_slice().to_vec();
+            truncated.extend_from_slice(&secctx[..12]);
+            assert!(ServerUtil::parse_secctx(&truncated).is_err());
+        }
+
+        #[test]
+        fn test_server_create_ext() {
+            let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
+            let server = Server::new(fs, None);
+            server.security_ctx.store(true, Ordering::Relaxed);
+            server.create_supp_group.store(true, Ordering::Relaxed);
+
+            // The security context header doubles as the header of its extension.
+            let mut secctx = Secctx {
+                size: 5,
+                padding: 0,
+            }
+            .as_slice()
+            .to_vec();
+            secctx.extend_from_slice(b"user.label\0label");
+            secctx.resize((secctx.len() + 7) & !7, 0);
+            let mut ext = SecctxHeader {
+                size: (size_of::<SecctxHeader>() + secctx.len()) as u32,
+                nr_secctx: 1,
+            }
+            .as_slice()
+            .to_vec();
+            ext.extend_from_slice(&secctx);
+            ext.extend_from_slice(
+                ExtHeader {
+                    size: 16,
+                    type_: EXT_GROUPS,
+                }
+                .as_slice(),
+            );
+            ext.extend_from_slice(SuppGroups { nr_groups: 1 }.as_slice());
+            ext.extend_from_slice(4242u32.as_slice());
+            // An extension unknown to the server is skipped.
+            ext.extend_from_slice(ExtHeader { size: 8, type_: 99 }.as_slice());
+
+            let mut body = b"dir\0".to_vec();
+            let names_len = body.len();
+            body.extend_from_slice(&ext);
+            let mut read_buf = [0u8; 4096];
+            let mut write_buf = [0u8; 4096];
+            let file = TempFile::new().unwrap().into_file();
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                nodeid: ROOT_ID,
+                total_extlen: (ext.len() / 8) as u16,
+                ..Default::default()
+            };
+            let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut read_buf)).unwrap();
+            let writer = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut write_buf).unwrap();
+            let mut ctx = SrvContext::<PassthroughFs>::new(in_header, reader, writer.into());
+            server.take_create_ext(&mut ctx, &body).unwrap();
+            assert_eq!(ctx.context.supp_groups, vec![4242]);
+            assert_eq!(ctx.context.secctx.len(), 1);
+            assert_eq!(ctx.context.secctx[0].value, b"label");
+            assert_eq!(&body[..names_len], b"dir\0");
+
+            // Groups aren't taken unless negotiated, and overlong extensions are rejected.
+            server.create_supp_group.store(false, Ordering::Relaxed);
+            ctx.context.supp_groups.clear();
+            server.take_create_ext(&mut ctx, &body).unwrap();
+            assert!(ctx.context.supp_groups.is_empty());
+            ctx.in_header.total_extlen = (body.len() / 8 + 1) as u16;
+            assert!(server.take_create_ext(&mut ctx, &body).is_err());
+        }
+
         #[test]
         fn test_server_readdir() {
             let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
-            let server = Server::new(fs);
+            let server = Server::new(fs, None);
 
             let mut read_buf = [0u8; 4096];
             let mut write_buf = [0u8; 4096];
@@ -1501,7 +2318,7 @@ mod tests {
         #[test]
         fn test_server_ioctl() {
             let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
-            let server = Server::new(fs);
+            let server = Server::new(fs, None);
 
             let mut read_buf = [0u8; 4096];
             let mut write_buf = [0u8; 4096];
@@ -1528,7 +2345,7 @@ mod tests {
         #[test]
         fn test_server_batch_forget() {
             let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
-            let server = Server::new(fs);
+            let server = Server::new(fs, None);
 
             let mut read_buf = [0u8; 4096];
             let mut write_buf = [0u8; 4096];
@@ -1540,7 +2357,7 @@ mod tests {
         #[test]
         fn test_server_forget() {
             let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
-            let server = Server::new(fs);
+            let server = Server::new(fs, None);
 
             let mut read_buf = [0x1u8, 0x2u8, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0];
             let mut write_buf = [0u8; 4096];
@@ -1548,5 +2365,356 @@ mod tests {
 
             assert_eq!(server.forget(ctx).unwrap(), 0);
         }
+
+        // Compose a FUSE request message from its header fields and argument.
+        fn fuse_request<T: ByteValued>(
+            opcode: Opcode,
+            unique: u64,
+            nodeid: u64,
+            arg: T,
+        ) -> Vec<u8> {
+            let header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<T>()) as u32,
+                opcode: opcode as u32,
+                unique,
+                nodeid,
+                ..Default::default()
+            };
+            let mut buf = header.as_slice().to_vec();
+            buf.extend_from_slice(arg.as_slice());
+            buf
+        }
+
+        #[test]
+        fn test_server_interrupt_setlkw() {
+            use crate::abi::fuse_abi::ROOT_ID;
+            use crate::api::filesystem::Context;
+            use crate::transport::InterruptMap;
+            use std::ffi::CString;
+            use std::io::{Read, Seek, SeekFrom};
+            use std::thread;
+            use vmm_sys_util::tempdir::TempDir;
+
+            let source = TempDir::new().unwrap();
+            let fs_cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                do_import: true,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+            fs.import().unwrap();
+
+            let holder = File::create(source.as_path().join("lockfile")).unwrap();
+            let name = CString::new("lockfile").unwrap();
+            let ctx = Context::default();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let (handle, _, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+
+            // Hold a conflicting lock through another open file description.
+            let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+            fl.l_type = libc::F_WRLCK as libc::c_short;
+            fl.l_whence = libc::SEEK_SET as libc::c_short;
+            assert_eq!(
+                unsafe { libc::fcntl(holder.as_raw_fd(), libc::F_OFD_SETLK, &fl) },
+                0
+            );
+
+            let mut server = Server::new(fs, None);
+            server.set_interrupt_map(Arc::new(InterruptMap::new()));
+            let server = Arc::new(server);
+            let mut reply = TempFile::new().unwrap().into_file();
+
+            let lk_in = LkIn {
+                fh: handle.unwrap(),
+                owner: 1,
+                lk: FileLock {
+                    start: 0,
+                    end: u64::MAX,
+                    type_: libc::F_WRLCK as u32,
+                    pid: 0,
+                },
+                ..Default::default()
+            };
+            let mut read_buf = fuse_request(Opcode::Setlkw, 5, entry.inode, lk_in);
+            let (srv, reply_fd) = (server.clone(), reply.as_raw_fd());
+            let blocked = thread::spawn(move || {
+                let mut write_buf = [0u8; 4096];
+                let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut read_buf)).unwrap();
+                let writer = FuseDevWriter::<()>::new(reply_fd, &mut write_buf).unwrap();
+                srv.handle_message(reader, writer.into(), None, None)
+                    .unwrap();
+            });
+
+            // The kernel sends the interrupt again as long as it gets EAGAIN, i.e. until the
+            // request has been seen. Stop there, the request may not block yet but must still be
+            // interrupted.
+            while !blocked.is_finished() {
+                let mut read_buf = fuse_request(Opcode::Interrupt, 6, 0, InterruptIn { unique: 5 });
+                let mut write_buf = [0u8; 4096];
+                let interrupt_reply = TempFile::new().unwrap().into_file();
+                let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut read_buf)).unwrap();
+                let writer =
+                    FuseDevWriter::<()>::new(interrupt_reply.as_raw_fd(), &mut write_buf).unwrap();
+                server
+                    .handle_message(reader, writer.into(), None, None)
+                    .unwrap();
+                if interrupt_reply.metadata().unwrap().len() == 0 {
+                    break;
+                }
+            }
+            blocked.join().unwrap();
+
+            let mut buf = [0u8; 32];
+            reply.seek(SeekFrom::Start(0)).unwrap();
+            assert_eq!(reply.read(&mut buf).unwrap(), size_of::<OutHeader>());
+            let header = OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.unique, 5);
+            assert_eq!(header.error, -libc::EINTR);
+        }
+
+        #[test]
+        fn test_server_interrupt_early() {
+            use crate::abi::fuse_abi::ROOT_ID;
+            use crate::transport::InterruptMap;
+            use std::io::{Read, Seek, SeekFrom};
+
+            let fs = PassthroughFs::<()>::new(Config::default()).unwrap();
+            fs.import().unwrap();
+            let mut server = Server::new(fs, None);
+            server.set_interrupt_map(Arc::new(InterruptMap::new()));
+
+            let send = |mut msg: Vec<u8>| {
+                let mut reply = TempFile::new().unwrap().into_file();
+                let mut write_buf = [0u8; 4096];
+                let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut msg)).unwrap();
+                let writer = FuseDevWriter::<()>::new(reply.as_raw_fd(), &mut write_buf).unwrap();
+                server
+                    .handle_message(reader, writer.into(), None, None)
+                    .unwrap();
+                let mut out = Vec::new();
+                reply.seek(SeekFrom::Start(0)).unwrap();
+                reply.read_to_end(&mut out).unwrap();
+                *OutHeader::from_slice(&out[..size_of::<OutHeader>()]).unwrap()
+            };
+
+            // The interrupt of a request not seen yet is answered with EAGAIN.
+            let header = send(fuse_request(
+                Opcode::Interrupt,
+                8,
+                0,
+                InterruptIn { unique: 7 },
+            ));
+            assert_eq!((header.unique, header.error), (8, -libc::EAGAIN));
+
+            // The request fails once it shows up, without being handled.
+            let header = send(fuse_request(
+                Opcode::Getattr,
+                7,
+                ROOT_ID,
+                GetattrIn::default(),
+            ));
+            assert_eq!((header.unique, header.error), (7, -libc::EINTR));
+
+            // The interrupt is used up.
+            let header = send(fuse_request(
+                Opcode::Getattr,
+                7,
+                ROOT_ID,
+                GetattrIn::default(),
+            ));
+            assert_eq!((header.unique, header.error), (7, 0));
+        }
+
+        #[test]
+        fn test_server_observer() {
+            use crate::abi::fuse_abi::ROOT_ID;
+            use crate::api::server::ServerObserver;
+            use std::sync::atomic::AtomicUsize;
+            use std::sync::Mutex;
+
+            #[derive(Default)]
+            struct LookupCounter {
+                requests: AtomicUsize,
+                errors: Mutex<Vec<i32>>,
+            }
+
+            impl ServerObserver for LookupCounter {
+                fn on_request(&self, opcode: Opcode) {
+                    if let Opcode::Lookup = opcode {
+                        self.requests.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+
+                fn on_response(
+                    &self,
+                    opcode: Opcode,
+                    result: std::result::Result<usize, i32>,
+                    _latency: Duration,
+                ) {
+                    if let (Opcode::Lookup, Err(e)) = (opcode, result) {
+                        self.errors.lock().unwrap().push(e);
+                    }
+                }
+            }
+
+            let source = TempDir::new().unwrap();
+            std::fs::write(source.as_path().join("file"), b"data").unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let observer = Arc::new(LookupCounter::default());
+            let server = Server::new(fs, Some(observer.clone()));
+
+            send_request(&server, Opcode::Lookup, ROOT_ID, b"file\0");
+            send_request(&server, Opcode::Lookup, ROOT_ID, b"missing\0");
+            send_request(
+                &server,
+                Opcode::Getattr,
+                ROOT_ID,
+                GetattrIn::default().as_slice(),
+            );
+            assert_eq!(observer.requests.load(Ordering::Relaxed), 2);
+            assert_eq!(*observer.errors.lock().unwrap(), vec![libc::ENOENT]);
+        }
+
+        #[test]
+        fn test_server_metrics() {
+            use crate::api::metrics::FuseMetrics;
+
+            let source = TempDir::new().unwrap();
+            std::fs::write(source.as_path().join("file"), b"data").unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let metrics = Arc::new(FuseMetrics::with_latency());
+            let server = Server::new(fs, Some(metrics.clone()));
+
+            let ctx = Context::default();
+            let name = CString::new("file").unwrap();
+            let entry = server.fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let (handle, _, _) = server.fs.open(&ctx, entry.inode, 0, 0).unwrap();
+            let read_in = |fh: u64| ReadIn {
+                fh,
+                size: 4,
+                ..Default::default()
+            };
+
+            send_request(
+                &server,
+                Opcode::Read,
+                entry.inode,
+                read_in(handle.unwrap()).as_slice(),
+            );
+            assert_eq!(metrics.requests(Opcode::Read), 1);
+            assert_eq!(metrics.errors(Opcode::Read), 0);
+            send_request(
+                &server,
+                Opcode::Read,
+                entry.inode,
+                read_in(u64::MAX).as_slice(),
+            );
+            assert_eq!(metrics.requests(Opcode::Read), 2);
+            assert_eq!(metrics.errors(Opcode::Read), 1);
+            assert_eq!(metrics.requests(Opcode::Lookup), 0);
+            assert!(metrics
+                .encode_prometheus()
+                .contains("fuse_errors_total{opcode=\"read\"} 1\n"));
+        }
+
+        fn send_request<F: FileSystem + Sync>(
+            server: &Server<F>,
+            opcode: Opcode,
+            nodeid: u64,
+            body: &[u8],
+        ) {
+            let header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid,
+                ..Default::default()
+            };
+            let mut msg = header.as_slice().to_vec();
+            msg.extend_from_slice(body);
+            let reply = TempFile::new().unwrap().into_file();
+            let mut write_buf = [0u8; 4096];
+            let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut msg)).unwrap();
+            let writer = FuseDevWriter::<()>::new(reply.as_raw_fd(), &mut write_buf).unwrap();
+            server
+                .handle_message(reader, writer.into(), None, None)
+                .unwrap();
+        }
+
+        fn send_statx<F: FileSystem + Sync>(server: &Server<F>, nodeid: u64) -> Vec<u8> {
+            let body = StatxIn {
+                sx_mask: STATX_BASIC_STATS | STATX_BTIME,
+                ..Default::default()
+            };
+            let header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<StatxIn>()) as u32,
+                opcode: Opcode::Statx as u32,
+                unique: 1,
+                nodeid,
+                ..Default::default()
+            };
+            let mut msg = header.as_slice().to_vec();
+            msg.extend_from_slice(body.as_slice());
+            let mut reply = TempFile::new().unwrap().into_file();
+            let mut write_buf = [0u8; 4096];
+            let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut msg)).unwrap();
+            let writer = FuseDevWriter::<()>::new(reply.as_raw_fd(), &mut write_buf).unwrap();
+            server
+                .handle_message(reader, writer.into(), None, None)
+                .unwrap();
+
+            let mut out = Vec::new();
+            reply.seek(SeekFrom::Start(0)).unwrap();
+            reply.read_to_end(&mut out).unwrap();
+            out
+        }
+
+        #[test]
+        fn test_server_statx() {
+            let source = TempDir::new().unwrap();
+            std::fs::write(source.as_path().join("file"), b"data").unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let name = CString::new("file").unwrap();
+            let entry = fs.lookup(&Context::default(), ROOT_ID, &name).unwrap();
+            let server = Server::new(fs, None);
+
+            let out = send_statx(&server, entry.inode);
+            assert_eq!(out.len(), size_of::<OutHeader>() + size_of::<StatxOut>());
+            let header = OutHeader::from_slice(&out[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.error, 0);
+            let out = StatxOut::from_slice(&out[size_of::<OutHeader>()..]).unwrap();
+            assert_eq!(out.stat.mask & STATX_BASIC_STATS, STATX_BASIC_STATS);
+            assert_eq!(out.stat.ino, entry.attr.st_ino);
+            assert_eq!(out.stat.size, 4);
+            assert_eq!(out.stat.mode as u32, entry.attr.st_mode);
+            if out.stat.mask & STATX_BTIME != 0 {
+                assert!(out.stat.btime.tv_sec != 0 || out.stat.btime.tv_nsec != 0);
+            }
+
+            // The pseudo fs of a vfs doesn't implement statx, getattr is used instead.
+            let server = Server::new(Vfs::default(), None);
+            let out = send_statx(&server, ROOT_ID);
+            let header = OutHeader::from_slice(&out[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.error, 0);
+            let out = StatxOut::from_slice(&out[size_of::<OutHeader>()..]).unwrap();
+            assert_eq!(out.stat.mask, STATX_BASIC_STATS);
+            assert_eq!(out.stat.ino, ROOT_ID);
+            assert_eq!(out.stat.mode as u32 & libc::S_IFMT, libc::S_IFDIR);
+        }
     }
 }
@@ -192,6 +192,57 @@ impl AsyncFileSystem for Vfs {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
+    async fn async_copy_file_range(
+        &self,
+        ctx: &Context,
+        inode_in: <Self as FileSystem>::Inode,
+        handle_in: <Self as FileSystem>::Handle,
+        offset_in: u64,
+        inode_out: <Self as FileSystem>::Inode,
+        handle_out: <Self as FileSystem>::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> Result<usize> {
+        let (root, idata_in) = self.get_real_rootfs(inode_in)?;
+        let (_, idata_out) = self.get_real_rootfs(inode_out)?;
+
+        // Data can't be copied across backend file systems, let the kernel fall back to
+        // read/write.
+        if idata_in.fs_idx() != idata_out.fs_idx() {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        match root {
+            Left(fs) => fs.copy_file_range(
+                ctx,
+                idata_in.ino(),
+                handle_in,
+                offset_in,
+                idata_out.ino(),
+                handle_out,
+                offset_out,
+                len,
+                flags,
+            ),
+            Right(fs) => {
+                fs.async_copy_file_range(
+                    ctx,
+                    idata_in.ino(),
+                    handle_in,
+                    offset_in,
+                    idata_out.ino(),
+                    handle_out,
+                    offset_out,
+                    len,
+                    flags,
+                )
+                .await
+            }
+        }
+    }
+
     async fn async_fsyncdir(
         &self,
         ctx: &Context,
@@ -222,6 +273,7 @@ mod tests {
             uid: 0,
             gid: 0,
             pid: 0,
+            ..Default::default()
         };
 
         assert!(vfs.mount(Box::new(fs), "/x/y").is_ok());
@@ -22,11 +22,12 @@ use std::fmt;
 use std::io;
 use std::io::{Error, ErrorKind, Result};
 use std::ops::Deref;
-use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 
 use crate::abi::fuse_abi::*;
 use crate::api::filesystem::*;
@@ -34,8 +35,11 @@ use crate::api::pseudo_fs::PseudoFs;
 
 #[cfg(feature = "async-io")]
 mod async_io;
+mod notify;
 mod sync_io;
 
+pub use self::notify::{VfsNotification, VfsNotifier, VfsNotifyCallback};
+
 /// Current directory
 pub const CURRENT_DIR_CSTR: &[u8] = b".\0";
 /// Parent directory
@@ -47,13 +51,16 @@ pub const PROC_SELF_FD_CSTR: &[u8] = b"/proc/self/fd\0";
 /// ASCII for slash('/')
 pub const SLASH_ASCII: u8 = 47;
 
-/// Maximum inode number supported by the VFS for backend file system
+/// Maximum inode number supported by the VFS for backend file system, with the default
+/// `VfsOptions::fs_index_bits`.
 pub const VFS_MAX_INO: u64 = 0xff_ffff_ffff_ffff;
 
 // The 64bit inode number for VFS is divided into two parts:
-// 1. an 8-bit file-system index, to identify mounted backend file systems.
-// 2. the left bits are reserved for backend file systems, and it's limited to VFS_MAX_INO.
-const VFS_INDEX_SHIFT: u8 = 56;
+// 1. a file-system index of `VfsOptions::fs_index_bits`, to identify mounted backend file systems.
+// 2. the left bits are reserved for backend file systems, and it's limited to
+//    `InodeLayout::max_ino()`, VFS_MAX_INO by default.
+const VFS_DEFAULT_INDEX_BITS: u8 = 8;
+const VFS_MAX_INDEX_BITS: u8 = 16;
 const VFS_PSEUDO_FS_IDX: VfsIndex = 0;
 
 type ArcBackFs = Arc<BackFileSystem>;
@@ -62,10 +69,7 @@ type VfsEitherFs<'a> = Either<&'a PseudoFs, ArcBackFs>;
 
 type VfsHandle = u64;
 /// Vfs backend file system index
-pub type VfsIndex = u8;
-
-// VfsIndex is type of 'u8', so maximum 256 entries.
-const MAX_VFS_INDEX: usize = 256;
+pub type VfsIndex = u16;
 
 /// Data struct to store inode number for the VFS filesystem.
 #[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
@@ -143,22 +147,76 @@ pub fn validate_path_component(name: &CStr) -> io::Result<()> {
     }
 }
 
-impl VfsInode {
-    fn new(fs_idx: VfsIndex, ino: u64) -> Self {
-        assert_eq!(ino & !VFS_MAX_INO, 0);
-        VfsInode(((fs_idx as u64) << VFS_INDEX_SHIFT) | ino)
+// Split of the inodes of the vfs between the index of their file system and their inode number
+// in that file system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct InodeLayout {
+    shift: u32,
+}
+
+impl InodeLayout {
+    fn new(fs_index_bits: u8) -> Self {
+        let bits = fs_index_bits.clamp(1, VFS_MAX_INDEX_BITS);
+        if bits != fs_index_bits {
+            warn!(
+                "vfs: unsupported fs_index_bits {}, using {}",
+                fs_index_bits, bits
+            );
+        }
+        InodeLayout {
+            shift: 64 - bits as u32,
+        }
     }
 
+    // Number of file system indexes, including the one of the pseudo fs.
+    fn nr_indexes(&self) -> usize {
+        1 << (64 - self.shift)
+    }
+
+    // Maximum inode number of backend file systems.
+    fn max_ino(&self) -> u64 {
+        (1 << self.shift) - 1
+    }
+
+    fn inode(&self, fs_idx: VfsIndex, ino: u64) -> VfsInode {
+        assert_eq!(ino & !self.max_ino(), 0);
+        VfsInode(((fs_idx as u64) << self.shift) | ino)
+    }
+
+    fn decode(&self, inode: VfsInode) -> RealInode {
+        RealInode {
+            inode,
+            fs_idx: (inode.0 >> self.shift) as VfsIndex,
+            ino: inode.0 & self.max_ino(),
+        }
+    }
+}
+
+// An inode of the vfs, and the index of its file system and its inode number in that file system.
+#[derive(Clone, Copy, Debug)]
+struct RealInode {
+    inode: VfsInode,
+    fs_idx: VfsIndex,
+    ino: u64,
+}
+
+impl RealInode {
     fn is_pseudo_fs(&self) -> bool {
-        (self.0 >> VFS_INDEX_SHIFT) as VfsIndex == VFS_PSEUDO_FS_IDX
+        self.fs_idx == VFS_PSEUDO_FS_IDX
     }
 
     fn fs_idx(&self) -> VfsIndex {
-        (self.0 >> VFS_INDEX_SHIFT) as VfsIndex
+        self.fs_idx
     }
 
     fn ino(&self) -> u64 {
-        self.0 & VFS_MAX_INO
+        self.ino
+    }
+}
+
+impl From<RealInode> for u64 {
+    fn from(val: RealInode) -> Self {
+        val.inode.0
     }
 }
 
@@ -220,7 +278,7 @@ struct MountPointData {
     fs_idx: VfsIndex,
     ino: u64,
     root_entry: Entry,
-    _path: String,
+    path: String,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -257,6 +315,13 @@ pub struct VfsOptions {
     /// comments for HANDLE_KILLPRIV_V2
     #[cfg(target_os = "linux")]
     pub killpriv_v2: bool,
+    /// Number of high bits of inodes holding the index of their backend file system, from 1 to 16.
+    /// Up to `2^fs_index_bits - 1` file systems may be mounted, and their inodes may go up to
+    /// `2^(64 - fs_index_bits) - 1`. Backends declaring larger inodes are mounted, but their
+    /// requests returning inodes beyond that range fail.
+    ///
+    /// The default value for this option is `8`.
+    pub fs_index_bits: u8,
 }
 
 impl VfsOptions {
@@ -295,6 +360,7 @@ impl Default for VfsOptions {
             in_opts: FsOptions::empty(),
             out_opts,
             id_mapping: (0, 0, 0),
+            fs_index_bits: VFS_DEFAULT_INDEX_BITS,
         }
     }
 
@@ -307,18 +373,22 @@ impl Default for VfsOptions {
             in_opts: FsOptions::empty(),
             out_opts,
             id_mapping: (0, 0, 0),
+            fs_index_bits: VFS_DEFAULT_INDEX_BITS,
         }
     }
 }
 
 /// A union fs that combines multiple backend file systems.
 pub struct Vfs {
-    next_super: AtomicU8,
+    next_super: AtomicU16,
+    layout: InodeLayout,
     root: PseudoFs,
     // mountpoints maps from pseudo fs inode to mounted fs mountpoint data
     mountpoints: ArcSwap<HashMap<u64, Arc<MountPointData>>>,
     // superblocks keeps track of all mounted file systems
-    superblocks: ArcSuperBlock,
+    superblocks: Arc<ArcSuperBlock>,
+    // delivers the notifications of backends, shared with their notifiers
+    notify_callback: Arc<ArcSwapOption<VfsNotifyCallback>>,
     opts: ArcSwap<VfsOptions>,
     initialized: AtomicBool,
     lock: Mutex<()>,
@@ -335,10 +405,13 @@ impl Default for Vfs {
 impl Vfs {
     /// Create a new vfs instance
     pub fn new(opts: VfsOptions) -> Self {
+        let layout = InodeLayout::new(opts.fs_index_bits);
         Vfs {
-            next_super: AtomicU8::new(VFS_PSEUDO_FS_IDX + 1),
+            next_super: AtomicU16::new(VFS_PSEUDO_FS_IDX + 1),
+            layout,
             mountpoints: ArcSwap::new(Arc::new(HashMap::new())),
-            superblocks: ArcSwap::new(Arc::new(vec![None; MAX_VFS_INDEX])),
+            superblocks: Arc::new(ArcSwap::new(Arc::new(vec![None; layout.nr_indexes()]))),
+            notify_callback: Arc::new(ArcSwapOption::empty()),
             root: PseudoFs::new(),
             opts: ArcSwap::new(Arc::new(opts)),
             lock: Mutex::new(()),
@@ -396,7 +469,7 @@ impl Vfs {
             fs_idx,
             ino: real_root_ino,
             root_entry: entry,
-            _path: path.to_string(),
+            path: path.to_string(),
         });
         mountpoints.insert(inode, mountpoint);
         self.mountpoints.store(Arc::new(mountpoints));
@@ -407,11 +480,9 @@ impl Vfs {
     /// Mount a backend file system to path
     pub fn mount(&self, fs: BackFileSystem, path: &str) -> VfsResult<VfsIndex> {
         let (entry, ino) = fs.mount().map_err(VfsError::Mount)?;
-        if ino > VFS_MAX_INO {
+        if let Err(e) = self.check_max_ino(ino) {
             fs.destroy();
-            return Err(VfsError::InodeIndex(format!(
-                "Unsupported max inode number, requested {ino} supported {VFS_MAX_INO}"
-            )));
+            return Err(e);
         }
 
         // Serialize mount operations. Do not expect poisoned lock here.
@@ -433,15 +504,8 @@ impl Vfs {
     #[cfg(feature = "persist")]
     pub fn restore_mount(&self, fs: BackFileSystem, fs_idx: VfsIndex, path: &str) -> Result<()> {
         let (entry, ino) = fs.mount()?;
-        if ino > VFS_MAX_INO {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!(
-                    "Unsupported max inode number, requested {} supported {}",
-                    ino, VFS_MAX_INO
-                ),
-            ));
-        }
+        self.check_max_ino(ino)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
 
         let _guard = self.lock.lock().unwrap();
         self.insert_mount_locked(fs, entry, fs_idx, path)
@@ -499,6 +563,82 @@ impl Vfs {
         Ok((inode, parent))
     }
 
+    /// Replace the backend file system mounted at `path` by `fs`, e.g. to upgrade the backend
+    /// without unmounting it.
+    ///
+    /// `fs` gets an index of its own, listed by `mount_points()`, so inodes of the old backend,
+    /// its root included, never resolve to inodes of `fs`: requests referring to them fail with
+    /// `ENOENT`. Requests in flight keep using the old backend until they complete.
+    ///
+    /// Return the inode of the mountpoint in the pseudo fs and its parent, to invalidate the entry
+    /// of the mountpoint with `Server::notify_inval_entry()`, so that the kernel drops the inodes
+    /// of the old backend and looks up the root of `fs`.
+    pub fn remount(&self, path: &str, fs: BackFileSystem) -> VfsResult<(u64, u64)> {
+        let (mut entry, ino) = fs.mount().map_err(VfsError::Mount)?;
+        if let Err(e) = self.check_max_ino(ino) {
+            fs.destroy();
+            return Err(e);
+        }
+
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        let inode = self
+            .root
+            .path_walk(path)
+            .map_err(VfsError::PathWalk)?
+            .ok_or_else(|| VfsError::NotFound(path.to_string()))?;
+        let parent = self
+            .root
+            .get_parent_inode(inode)
+            .ok_or(VfsError::NotFound(format!(
+                "{}'s parent inode does not exist",
+                inode
+            )))?;
+        let mut mountpoints = self.mountpoints.load().deref().deref().clone();
+        let old_idx = match mountpoints.get(&inode) {
+            Some(mnt) => mnt.fs_idx,
+            None => {
+                error!("{} is not a mount point.", path);
+                return Err(VfsError::NotFound(path.to_string()));
+            }
+        };
+        let fs_idx = self.allocate_fs_idx().map_err(VfsError::FsIndex)?;
+        let real_root_ino = entry.inode;
+        self.convert_entry(fs_idx, entry.inode, &mut entry)
+            .map_err(VfsError::Mount)?;
+        if self.initialized() {
+            let opts = self.opts.load().deref().out_opts;
+            if let Err(e) = fs.init(opts) {
+                fs.destroy();
+                return Err(VfsError::Initialize(format!(
+                    "Can't initialize with opts {opts:?}, {e:?}"
+                )));
+            }
+        }
+
+        // Requests already holding the old backend go on with it, the following ones get the new
+        // one.
+        let mut superblocks = self.superblocks.load().deref().deref().clone();
+        let old = superblocks[old_idx as usize].take();
+        superblocks[fs_idx as usize] = Some(Arc::new(fs));
+        self.superblocks.store(Arc::new(superblocks));
+        let mountpoint = Arc::new(MountPointData {
+            fs_idx,
+            ino: real_root_ino,
+            root_entry: entry,
+            path: path.to_string(),
+        });
+        mountpoints.insert(inode, mountpoint);
+        self.mountpoints.store(Arc::new(mountpoints));
+        trace!("remount fs_idx {} -> {} inode {}", old_idx, fs_idx, inode);
+
+        if let Some(old) = old {
+            old.destroy();
+        }
+
+        Ok((inode, parent))
+    }
+
     /// Get the mounted backend file system alongside the path if there's one.
     pub fn get_rootfs(&self, path: &str) -> VfsResult<Option<Arc<BackFileSystem>>> {
         // Serialize mount operations. Do not expect poisoned lock here.
@@ -519,28 +659,61 @@ impl Vfs {
         }
     }
 
+    /// Get the paths of the backend file systems currently mounted, and their indexes, sorted by
+    /// path. Paths are as given to `mount()` or `remount()`.
+    pub fn mount_points(&self) -> Vec<(PathBuf, VfsIndex)> {
+        let mut mount_points: Vec<(PathBuf, VfsIndex)> = self
+            .mountpoints
+            .load()
+            .values()
+            .map(|mnt| (PathBuf::from(&mnt.path), mnt.fs_idx))
+            .collect();
+        mount_points.sort();
+        mount_points
+    }
+
     /// Get the root pseudo fs's reference in vfs
     pub fn get_root_pseudofs(&self) -> &PseudoFs {
         &self.root
     }
 
+    // Check the largest inode number of a backend file system being mounted. Backends with inodes
+    // beyond the range of the vfs are still mounted, as long as they don't go beyond the largest
+    // inode number supported by default, in case they never use such inodes.
+    fn check_max_ino(&self, ino: u64) -> VfsResult<()> {
+        let max_ino = self.layout.max_ino();
+        if ino > max_ino.max(VFS_MAX_INO) {
+            return Err(VfsError::InodeIndex(format!(
+                "Unsupported max inode number, requested {ino} supported {max_ino}"
+            )));
+        }
+        if ino > max_ino {
+            warn!(
+                "vfs: max inode number {} of backend file system beyond {}, larger inodes are refused",
+                ino, max_ino
+            );
+        }
+        Ok(())
+    }
+
     // Inode converting rules:
     // 1. Pseudo fs inode is not hashed
     // 2. Index is always larger than 0 so that pseudo fs inodes are never affected
     //    and can be found directly
-    // 3. Other inodes are hashed via (index << 56 | inode)
+    // 3. Other inodes are hashed via (index << (64 - fs_index_bits) | inode)
     fn convert_inode(&self, fs_idx: VfsIndex, inode: u64) -> Result<u64> {
         // Do not hash negative dentry
         if inode == 0 {
             return Ok(inode);
         }
-        if inode > VFS_MAX_INO {
+        let max_ino = self.layout.max_ino();
+        if inode > max_ino {
             return Err(Error::new(
                 ErrorKind::Other,
-                format!("Inode number {inode} too large, max supported {VFS_MAX_INO}"),
+                format!("Inode number {inode} too large, max supported {max_ino}"),
             ));
         }
-        let ino: u64 = ((fs_idx as u64) << VFS_INDEX_SHIFT) | inode;
+        let ino = u64::from(self.layout.inode(fs_idx, inode));
         trace!(
             "fuse: vfs fs_idx {} inode {} fuse ino {:#x}",
             fs_idx,
@@ -603,31 +776,18 @@ impl Vfs {
     }
 
     fn allocate_fs_idx(&self) -> Result<VfsIndex> {
-        let superblocks = self.superblocks.load().deref().deref().clone();
-        let start = self.next_super.load(Ordering::SeqCst);
-        let mut found = false;
-
-        loop {
-            let index = self.next_super.fetch_add(1, Ordering::Relaxed);
-            if index == start {
-                if found {
-                    // There's no available file system index
-                    break;
-                } else {
-                    found = true;
-                }
-            }
+        let superblocks = self.superblocks.load();
+        let start = self.next_super.load(Ordering::SeqCst) as usize;
 
-            if index == VFS_PSEUDO_FS_IDX {
-                // Skip the pseudo fs index
-                continue;
-            }
-            if (index as usize) < superblocks.len() && superblocks[index as usize].is_some() {
-                // Skip if it's allocated
+        for i in 0..superblocks.len() {
+            let index = (start + i) % superblocks.len();
+            // Skip the pseudo fs index, and the allocated ones
+            if index == VFS_PSEUDO_FS_IDX as usize || superblocks[index].is_some() {
                 continue;
-            } else {
-                return Ok(index);
             }
+            let next = (index + 1) % superblocks.len();
+            self.next_super.store(next as VfsIndex, Ordering::SeqCst);
+            return Ok(index as VfsIndex);
         }
 
         Err(Error::new(
@@ -646,13 +806,15 @@ impl Vfs {
         Err(Error::from_raw_os_error(libc::ENOENT))
     }
 
-    fn get_real_rootfs(&self, inode: VfsInode) -> Result<(VfsEitherFs<'_>, VfsInode)> {
+    fn get_real_rootfs(&self, inode: VfsInode) -> Result<(VfsEitherFs<'_>, RealInode)> {
+        let inode = self.layout.decode(inode);
         if inode.is_pseudo_fs() {
             // ROOT_ID is special, we need to check if we have a mountpoint on the vfs root
             if inode.ino() == ROOT_ID {
                 if let Some(mnt) = self.mountpoints.load().get(&inode.ino()).cloned() {
                     let fs = self.get_fs_by_idx(mnt.fs_idx)?;
-                    return Ok((Right(fs), VfsInode::new(mnt.fs_idx, mnt.ino)));
+                    let root = self.layout.inode(mnt.fs_idx, mnt.ino);
+                    return Ok((Right(fs), self.layout.decode(root)));
                 }
             }
             Ok((Left(&self.root), inode))
@@ -665,7 +827,7 @@ impl Vfs {
     fn lookup_pseudo(
         &self,
         fs: &PseudoFs,
-        idata: VfsInode,
+        idata: RealInode,
         ctx: &Context,
         name: &CStr,
     ) -> Result<Entry> {
@@ -706,7 +868,7 @@ pub mod persist {
     use crate::api::{
         filesystem::FsOptions,
         pseudo_fs::persist::PseudoFsState,
-        vfs::{VfsError, VfsResult},
+        vfs::{InodeLayout, VfsError, VfsIndex, VfsResult, VFS_DEFAULT_INDEX_BITS},
         Vfs, VfsOptions,
     };
 
@@ -717,8 +879,18 @@ pub mod persist {
         options: VfsOptionsState,
         /// Vfs root
         root: Vec<u8>,
-        /// next super block index
+        /// next super block index, truncated since version 2
         next_super: u8,
+        /// next super block index
+        #[version(start = 2, default_fn = "default_next_super_idx")]
+        next_super_idx: VfsIndex,
+    }
+
+    impl VfsState {
+        // Snapshots of version 1 only have `next_super`.
+        fn default_next_super_idx(_source_version: u16) -> VfsIndex {
+            0
+        }
     }
 
     #[derive(Versionize, Debug, Default)]
@@ -739,6 +911,14 @@ pub mod persist {
         no_writeback: bool,
         #[cfg(target_os = "linux")]
         killpriv_v2: bool,
+        #[version(start = 2, default_fn = "default_fs_index_bits")]
+        fs_index_bits: u8,
+    }
+
+    impl VfsOptionsState {
+        fn default_fs_index_bits(_source_version: u16) -> u8 {
+            VFS_DEFAULT_INDEX_BITS
+        }
     }
 
     impl VfsOptions {
@@ -760,6 +940,7 @@ pub mod persist {
                 no_writeback: self.no_writeback,
                 #[cfg(target_os = "linux")]
                 killpriv_v2: self.killpriv_v2,
+                fs_index_bits: self.fs_index_bits,
             }
         }
 
@@ -787,6 +968,7 @@ pub mod persist {
                 no_writeback: state.no_writeback,
                 #[cfg(target_os = "linux")]
                 killpriv_v2: state.killpriv_v2,
+                fs_index_bits: state.fs_index_bits,
             })
         }
     }
@@ -799,6 +981,12 @@ pub mod persist {
                 .set_type_version(PseudoFsState::type_id(), 1)
                 .set_type_version(VfsOptionsState::type_id(), 1);
 
+            // Version 2: fs_index_bits and next_super_idx.
+            version_map
+                .new_version()
+                .set_type_version(VfsState::type_id(), 2)
+                .set_type_version(VfsOptionsState::type_id(), 2);
+
             // more versions for the future
 
             version_map
@@ -868,7 +1056,8 @@ pub mod persist {
             let vfs_state = VfsState {
                 options: self.opts.load().deref().deref().save(),
                 root: root_state,
-                next_super: self.next_super.load(Ordering::SeqCst),
+                next_super: self.next_super.load(Ordering::SeqCst) as u8,
+                next_super_idx: self.next_super.load(Ordering::SeqCst),
             };
 
             let vm = Vfs::get_version_map();
@@ -892,11 +1081,21 @@ pub mod persist {
                     })?
                     .0;
             let opts = VfsOptions::restore(&state.options)?;
+            if InodeLayout::new(opts.fs_index_bits) != self.layout {
+                return Err(VfsError::Persist(format!(
+                    "Failed to restore Vfs, saved with fs_index_bits {}",
+                    opts.fs_index_bits
+                )));
+            }
             self.initialized
                 .store(!opts.in_opts.is_empty(), Ordering::Release);
             self.opts.store(Arc::new(opts));
 
-            self.next_super.store(state.next_super, Ordering::SeqCst);
+            let next_super = match state.next_super_idx {
+                0 => state.next_super as VfsIndex,
+                idx => idx,
+            };
+            self.next_super.store(next_super, Ordering::SeqCst);
             self.root
                 .restore_from_bytes(&mut state.root)
                 .map_err(|e| VfsError::Persist(format!("Failed to restore Vfs root: {:?}", e)))?;
@@ -1400,6 +1599,239 @@ mod tests {
             }
         }
 
+        #[allow(unused_variables)]
+        #[async_trait]
+        impl AsyncFileSystem for FakeSyncFs {
+            async fn async_lookup(
+                &self,
+                ctx: &Context,
+                parent: <Self as FileSystem>::Inode,
+                name: &CStr,
+            ) -> Result<Entry> {
+                Err(std::io::Error::from_raw_os_error(libc::EINVAL))
+            }
+
+            async fn async_getattr(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                handle: Option<<Self as FileSystem>::Handle>,
+            ) -> Result<(libc::stat64, Duration)> {
+                unimplemented!()
+            }
+
+            async fn async_setattr(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                attr: libc::stat64,
+                handle: Option<<Self as FileSystem>::Handle>,
+                valid: SetattrValid,
+            ) -> Result<(libc::stat64, Duration)> {
+                unimplemented!()
+            }
+
+            async fn async_open(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                flags: u32,
+                fuse_flags: u32,
+            ) -> Result<(Option<<Self as FileSystem>::Handle>, OpenOptions)> {
+                unimplemented!()
+            }
+
+            async fn async_create(
+                &self,
+                ctx: &Context,
+                parent: <Self as FileSystem>::Inode,
+                name: &CStr,
+                args: CreateIn,
+            ) -> Result<(Entry, Option<<Self as FileSystem>::Handle>, OpenOptions)> {
+                unimplemented!()
+            }
+
+            async fn async_read(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                handle: <Self as FileSystem>::Handle,
+                w: &mut (dyn AsyncZeroCopyWriter + Send),
+                size: u32,
+                offset: u64,
+                lock_owner: Option<u64>,
+                flags: u32,
+            ) -> Result<usize> {
+                unimplemented!()
+            }
+
+            async fn async_write(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                handle: <Self as FileSystem>::Handle,
+                r: &mut (dyn AsyncZeroCopyReader + Send),
+                size: u32,
+                offset: u64,
+                lock_owner: Option<u64>,
+                delayed_write: bool,
+                flags: u32,
+                fuse_flags: u32,
+            ) -> Result<usize> {
+                unimplemented!()
+            }
+
+            async fn async_fsync(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                datasync: bool,
+                handle: <Self as FileSystem>::Handle,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+
+            async fn async_fallocate(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                handle: <Self as FileSystem>::Handle,
+                mode: u32,
+                offset: u64,
+                length: u64,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+
+            async fn async_fsyncdir(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                datasync: bool,
+                handle: <Self as FileSystem>::Handle,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+        }
+
+        #[allow(unused_variables)]
+        #[async_trait]
+        impl AsyncFileSystem for FakeMaxInoFs {
+            async fn async_lookup(
+                &self,
+                ctx: &Context,
+                parent: <Self as FileSystem>::Inode,
+                name: &CStr,
+            ) -> Result<Entry> {
+                Ok(Entry {
+                    inode: self.0,
+                    ..Default::default()
+                })
+            }
+
+            async fn async_getattr(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                handle: Option<<Self as FileSystem>::Handle>,
+            ) -> Result<(libc::stat64, Duration)> {
+                unimplemented!()
+            }
+
+            async fn async_setattr(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                attr: libc::stat64,
+                handle: Option<<Self as FileSystem>::Handle>,
+                valid: SetattrValid,
+            ) -> Result<(libc::stat64, Duration)> {
+                unimplemented!()
+            }
+
+            async fn async_open(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                flags: u32,
+                fuse_flags: u32,
+            ) -> Result<(Option<<Self as FileSystem>::Handle>, OpenOptions)> {
+                unimplemented!()
+            }
+
+            async fn async_create(
+                &self,
+                ctx: &Context,
+                parent: <Self as FileSystem>::Inode,
+                name: &CStr,
+                args: CreateIn,
+            ) -> Result<(Entry, Option<<Self as FileSystem>::Handle>, OpenOptions)> {
+                unimplemented!()
+            }
+
+            async fn async_read(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                handle: <Self as FileSystem>::Handle,
+                w: &mut (dyn AsyncZeroCopyWriter + Send),
+                size: u32,
+                offset: u64,
+                lock_owner: Option<u64>,
+                flags: u32,
+            ) -> Result<usize> {
+                unimplemented!()
+            }
+
+            async fn async_write(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                handle: <Self as FileSystem>::Handle,
+                r: &mut (dyn AsyncZeroCopyReader + Send),
+                size: u32,
+                offset: u64,
+                lock_owner: Option<u64>,
+                delayed_write: bool,
+                flags: u32,
+                fuse_flags: u32,
+            ) -> Result<usize> {
+                unimplemented!()
+            }
+
+            async fn async_fsync(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                datasync: bool,
+                handle: <Self as FileSystem>::Handle,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+
+            async fn async_fallocate(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                handle: <Self as FileSystem>::Handle,
+                mode: u32,
+                offset: u64,
+                length: u64,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+
+            async fn async_fsyncdir(
+                &self,
+                ctx: &Context,
+                inode: <Self as FileSystem>::Inode,
+                datasync: bool,
+                handle: <Self as FileSystem>::Handle,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+        }
+
         impl BackendFileSystem for FakeFileSystemTwo {
             fn mount(&self) -> Result<(Entry, u64)> {
                 Ok((
@@ -1623,15 +2055,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_mount_points() {
+        let vfs = Vfs::new(VfsOptions::default());
+        assert!(vfs.mount_points().is_empty());
+
+        let idx1 = vfs.mount(Box::new(FakeFileSystemOne {}), "/foo").unwrap();
+        let idx2 = vfs
+            .mount(Box::new(FakeFileSystemTwo {}), "/bar/baz")
+            .unwrap();
+        assert_eq!(
+            vfs.mount_points(),
+            vec![
+                (PathBuf::from("/bar/baz"), idx2),
+                (PathBuf::from("/foo"), idx1)
+            ]
+        );
+        for (path, _) in vfs.mount_points() {
+            assert!(vfs.get_rootfs(path.to_str().unwrap()).unwrap().is_some());
+        }
+        assert!(vfs.get_rootfs("/bar").unwrap().is_none());
+
+        vfs.umount("/foo").unwrap();
+        assert_eq!(vfs.mount_points(), vec![(PathBuf::from("/bar/baz"), idx2)]);
+    }
+
     #[test]
     #[should_panic]
     fn test_invalid_inode() {
-        let _ = VfsInode::new(1, VFS_MAX_INO + 1);
+        let layout = InodeLayout::new(VFS_DEFAULT_INDEX_BITS);
+        let _ = layout.inode(1, VFS_MAX_INO + 1);
     }
 
     #[test]
     fn test_inode() {
-        let inode = VfsInode::new(2, VFS_MAX_INO);
+        let layout = InodeLayout::new(VFS_DEFAULT_INDEX_BITS);
+        assert_eq!(layout.max_ino(), VFS_MAX_INO);
+        let inode = layout.decode(layout.inode(2, VFS_MAX_INO));
 
         assert_eq!(inode.fs_idx(), 2);
         assert_eq!(inode.ino(), VFS_MAX_INO);
@@ -1700,4 +2160,321 @@ mod tests {
             "File system can't be initialized: initialize".to_string()
         );
     }
+
+    // Record the inodes of syncfs requests, and fail them with `errno` if set.
+    struct FakeSyncFs {
+        synced: Arc<Mutex<Vec<u64>>>,
+        errno: Option<i32>,
+    }
+
+    impl FileSystem for FakeSyncFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn syncfs(&self, _: &Context, inode: Self::Inode) -> Result<()> {
+            self.synced.lock().unwrap().push(inode);
+            match self.errno {
+                Some(errno) => Err(Error::from_raw_os_error(errno)),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl BackendFileSystem for FakeSyncFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            Ok((
+                Entry {
+                    inode: 1,
+                    ..Default::default()
+                },
+                0,
+            ))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_vfs_syncfs() {
+        let vfs = Vfs::new(VfsOptions::default());
+        let ctx = Context::new();
+        let synced = Arc::new(Mutex::new(Vec::new()));
+        let fake_fs = |errno| FakeSyncFs {
+            synced: synced.clone(),
+            errno,
+        };
+
+        // Nothing to sync yet.
+        vfs.syncfs(&ctx, ROOT_ID.into()).unwrap();
+
+        vfs.mount(Box::new(fake_fs(None)), "/foo").unwrap();
+        vfs.mount(Box::new(fake_fs(Some(libc::ENOSYS))), "/bar")
+            .unwrap();
+        vfs.mount(Box::new(fake_fs(None)), "/x/y").unwrap();
+
+        // The pseudo root fans out to the root inodes of all mounted file systems, and ENOSYS of
+        // any of them is not reported.
+        vfs.syncfs(&ctx, ROOT_ID.into()).unwrap();
+        assert_eq!(*synced.lock().unwrap(), vec![1, 1, 1]);
+
+        // Other inodes go to their own file system.
+        synced.lock().unwrap().clear();
+        vfs.syncfs(&ctx, VfsInode(0x100_0000_0000_0005)).unwrap();
+        assert_eq!(*synced.lock().unwrap(), vec![5]);
+
+        vfs.mount(Box::new(fake_fs(Some(libc::EIO))), "/baz")
+            .unwrap();
+        synced.lock().unwrap().clear();
+        let err = vfs.syncfs(&ctx, ROOT_ID.into()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(synced.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    #[cfg(all(any(feature = "fusedev", feature = "virtiofs"), target_os = "linux"))]
+    fn test_vfs_remount() {
+        use crate::passthrough::{Config, PassthroughFs};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let new_fs = |file: &str| {
+            let source = TempDir::new().unwrap();
+            std::fs::write(source.as_path().join(file), b"data").unwrap();
+            let fs_cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                do_import: false,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+            fs.import().unwrap();
+            (Box::new(fs), source)
+        };
+        let vfs = Vfs::new(VfsOptions::default());
+        let ctx = Context::new();
+        vfs.init(FsOptions::ASYNC_READ).unwrap();
+        let (fs, _old_source) = new_fs("old");
+        let idx = vfs.mount(fs, "/m").unwrap();
+
+        let name = |name: &str| CString::new(name).unwrap();
+        let root = vfs.lookup(&ctx, ROOT_ID.into(), &name("m")).unwrap();
+        let old = vfs.lookup(&ctx, root.inode.into(), &name("old")).unwrap();
+        let (handle, _, _) = vfs
+            .open(&ctx, old.inode.into(), libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let handle = handle.unwrap();
+
+        let (new, _new_source) = new_fs("new");
+        let (inode, parent) = vfs.remount("/m", new).unwrap();
+        assert_eq!(Some(inode), vfs.root.path_walk("/m").unwrap());
+        assert_eq!(parent, ROOT_ID);
+        let rootfs = vfs.get_rootfs("/m").unwrap().unwrap();
+        assert!(rootfs.as_any().is::<PassthroughFs<()>>());
+
+        // The new backend gets an index of its own, so the old inodes and handles, the root
+        // included, don't resolve to it.
+        let new_root = vfs.lookup(&ctx, ROOT_ID.into(), &name("m")).unwrap();
+        let new_idx = vfs.layout.decode(new_root.inode.into()).fs_idx();
+        assert_ne!(new_idx, idx);
+        assert_eq!(vfs.mount_points(), vec![(PathBuf::from("/m"), new_idx)]);
+        let err = vfs
+            .fsync(&ctx, old.inode.into(), false, handle)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        let err = vfs
+            .lookup(&ctx, root.inode.into(), &name("new"))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        // Lookups from the new root hit the new backend, and the inodes found there aren't known
+        // by their old numbers.
+        let err = vfs
+            .lookup(&ctx, new_root.inode.into(), &name("old"))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        let new = vfs
+            .lookup(&ctx, new_root.inode.into(), &name("new"))
+            .unwrap();
+        assert_eq!(
+            vfs.layout.decode(old.inode.into()).ino(),
+            vfs.layout.decode(new.inode.into()).ino()
+        );
+        assert_ne!(new.inode, old.inode);
+        let err = vfs.getattr(&ctx, old.inode.into(), None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        // Only mountpoints can be remounted.
+        let (fs, _source) = new_fs("other");
+        match vfs.remount("/x", fs) {
+            Err(VfsError::NotFound(_e)) => {}
+            _ => panic!("expect VfsError::NotFound(/x)"),
+        }
+    }
+
+    // A backend file system with inodes up to a given number.
+    struct FakeMaxInoFs(u64);
+
+    impl FileSystem for FakeMaxInoFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _: &Context, _: Self::Inode, _: &CStr) -> Result<Entry> {
+            Ok(Entry {
+                inode: self.0,
+                ..Default::default()
+            })
+        }
+    }
+
+    impl BackendFileSystem for FakeMaxInoFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            Ok((
+                Entry {
+                    inode: 1,
+                    ..Default::default()
+                },
+                self.0,
+            ))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_fs_index_bits() {
+        let layout = InodeLayout::new(12);
+        assert_eq!(layout.nr_indexes(), 4096);
+        assert_eq!(layout.max_ino(), (1 << 52) - 1);
+        let inode = layout.decode(layout.inode(0xabc, 0xf_ffff_ffff_ffff));
+        assert_eq!(inode.fs_idx(), 0xabc);
+        assert_eq!(inode.ino(), 0xf_ffff_ffff_ffff);
+        assert_eq!(u64::from(inode), 0xabcf_ffff_ffff_ffff);
+        // Out of range values are clamped.
+        assert_eq!(InodeLayout::new(0).nr_indexes(), 2);
+        assert_eq!(InodeLayout::new(64).nr_indexes(), 65536);
+
+        let vfs = Vfs::new(VfsOptions {
+            fs_index_bits: 12,
+            ..Default::default()
+        });
+        let ctx = Context::new();
+        let name = CString::new("x").unwrap();
+        vfs.init(FsOptions::ASYNC_READ).unwrap();
+        assert_eq!(vfs.superblocks.load().len(), 4096);
+
+        // Indexes beyond 256 round-trip through the inodes.
+        {
+            let _guard = vfs.lock.lock().unwrap();
+            let mut superblocks = vfs.superblocks.load().deref().deref().clone();
+            for sb in superblocks.iter_mut().take(300).skip(1) {
+                *sb = Some(Arc::new(Box::new(FakeFileSystemOne {})));
+            }
+            vfs.superblocks.store(Arc::new(superblocks));
+            vfs.next_super.store(300, Ordering::SeqCst);
+        }
+        let idx = vfs.mount(Box::new(FakeMaxInoFs(0xff_ffff)), "/a").unwrap();
+        assert_eq!(idx, 300);
+        let root = vfs.lookup(&ctx, ROOT_ID.into(), &CString::new("a").unwrap());
+        let root = root.unwrap();
+        let entry = vfs.lookup(&ctx, root.inode.into(), &name).unwrap();
+        assert_eq!(entry.inode, (300 << 52) | 0xff_ffff);
+        let inode = vfs.layout.decode(entry.inode.into());
+        assert_eq!((inode.fs_idx(), inode.ino()), (idx, 0xff_ffff));
+        vfs.lookup(&ctx, entry.inode.into(), &name).unwrap();
+
+        // Backends with inodes beyond the range of the vfs are mounted, but their larger inodes
+        // are refused.
+        vfs.mount(Box::new(FakeMaxInoFs(VFS_MAX_INO)), "/b")
+            .unwrap();
+        let root = vfs.lookup(&ctx, ROOT_ID.into(), &CString::new("b").unwrap());
+        let err = vfs.lookup(&ctx, root.unwrap().inode.into(), &name);
+        assert!(err.is_err());
+        assert!(vfs.convert_inode(idx, layout.max_ino()).is_ok());
+        assert!(vfs.convert_inode(idx, layout.max_ino() + 1).is_err());
+
+        // Whatever the split, inodes can't go beyond VFS_MAX_INO.
+        match vfs.mount(Box::new(FakeMaxInoFs(VFS_MAX_INO + 1)), "/c") {
+            Err(VfsError::InodeIndex(_)) => {}
+            _ => panic!("expect VfsError::InodeIndex"),
+        }
+        let vfs = Vfs::new(VfsOptions::default());
+        assert!(vfs.convert_inode(1, VFS_MAX_INO + 1).is_err());
+        match vfs.mount(Box::new(FakeMaxInoFs(VFS_MAX_INO + 1)), "/c") {
+            Err(VfsError::InodeIndex(_)) => {}
+            _ => panic!("expect VfsError::InodeIndex"),
+        }
+    }
+
+    #[test]
+    fn test_vfs_notifier() {
+        let vfs = Vfs::new(VfsOptions::default());
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let sink = delivered.clone();
+        vfs.set_notify_callback(Box::new(move |n| {
+            sink.lock().unwrap().push(n);
+            Ok(())
+        }));
+        let name = CString::new("foo").unwrap();
+
+        let idx = vfs.mount(Box::new(FakeFileSystemOne {}), "/a").unwrap();
+        let notifier = vfs.notifier(idx).unwrap();
+        notifier.inval_entry(5, &name).unwrap();
+        notifier.inval_entry(ROOT_ID, &name).unwrap();
+        notifier.inval_inode(7, 0, 4096).unwrap();
+        let err = notifier.inval_inode(VFS_MAX_INO + 1, -1, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(
+            *delivered.lock().unwrap(),
+            vec![
+                VfsNotification::InvalEntry {
+                    parent: u64::from(vfs.layout.inode(idx, 5)),
+                    name: name.clone(),
+                },
+                VfsNotification::InvalEntry {
+                    parent: u64::from(vfs.layout.inode(idx, ROOT_ID)),
+                    name: name.clone(),
+                },
+                VfsNotification::InvalInode {
+                    inode: u64::from(vfs.layout.inode(idx, 7)),
+                    off: 0,
+                    len: 4096,
+                },
+            ]
+        );
+        delivered.lock().unwrap().clear();
+
+        // The root of a backend mounted on the root of the vfs is known as ROOT_ID.
+        let root_idx = vfs.mount(Box::new(FakeFileSystemOne {}), "/").unwrap();
+        vfs.notifier(root_idx)
+            .unwrap()
+            .inval_entry(ROOT_ID, &name)
+            .unwrap();
+        assert_eq!(
+            delivered.lock().unwrap().pop(),
+            Some(VfsNotification::InvalEntry {
+                parent: ROOT_ID,
+                name: name.clone(),
+            })
+        );
+
+        // Notifications of unmounted or replaced backends are dropped.
+        vfs.remount("/a", Box::new(FakeFileSystemOne {})).unwrap();
+        let err = notifier.inval_entry(5, &name).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert!(vfs.notifier(idx).is_err());
+        let idx = vfs
+            .mount_points()
+            .into_iter()
+            .find(|(path, _)| path == &PathBuf::from("/a"))
+            .unwrap()
+            .1;
+        let notifier = vfs.notifier(idx).unwrap();
+        vfs.umount("/a").unwrap();
+        let err = notifier.inval_inode(7, -1, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert!(delivered.lock().unwrap().is_empty());
+        assert!(vfs.notifier(idx).is_err());
+    }
 }
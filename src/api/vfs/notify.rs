@@ -0,0 +1,151 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Forward invalidations raised by backend file systems to the kernel.
+//!
+//! Backends only know their own inodes, so their notifications go through a `VfsNotifier`,
+//! which translates inodes into inodes of the vfs before handing them to the callback set with
+//! `Vfs::set_notify_callback()`, e.g. sending them with the notify methods of `Server`.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::{Arc, Weak};
+
+use arc_swap::ArcSwapOption;
+
+use super::{ArcSuperBlock, BackFileSystem, InodeLayout, Vfs, VfsError, VfsIndex, VfsResult};
+use crate::abi::fuse_abi::ROOT_ID;
+
+/// Invalidation of data cached by the kernel, with inodes of the vfs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VfsNotification {
+    /// Invalidate the dentry `name` in the directory `parent`.
+    InvalEntry {
+        /// Inode of the directory.
+        parent: u64,
+        /// Name of the entry.
+        name: CString,
+    },
+    /// Invalidate the attributes of `inode`, and its data in the range of `len` bytes at `off`,
+    /// see `Server::notify_inval_inode()`.
+    InvalInode {
+        /// Inode to invalidate.
+        inode: u64,
+        /// Offset of the data to invalidate, or negative to only invalidate the attributes.
+        off: i64,
+        /// Length of the data to invalidate, or `0` to invalidate up to the end of the file.
+        len: i64,
+    },
+}
+
+/// Callback delivering notifications to the kernel.
+pub type VfsNotifyCallback = Box<dyn Fn(VfsNotification) -> io::Result<()> + Send + Sync>;
+
+/// Raise notifications about the inodes of a mounted backend file system, see
+/// `Vfs::notifier()`.
+pub struct VfsNotifier {
+    fs_idx: VfsIndex,
+    layout: InodeLayout,
+    // Inode of the root of the backend if it is mounted on the root of the vfs, where the kernel
+    // knows it as ROOT_ID.
+    root_ino: Option<u64>,
+    fs: Weak<BackFileSystem>,
+    superblocks: Weak<ArcSuperBlock>,
+    callback: Arc<ArcSwapOption<VfsNotifyCallback>>,
+}
+
+impl VfsNotifier {
+    /// Invalidate the dentry `name` in the directory `parent` of the backend.
+    pub fn inval_entry(&self, parent: u64, name: &CStr) -> io::Result<()> {
+        self.notify(|notifier| {
+            Ok(VfsNotification::InvalEntry {
+                parent: notifier.vfs_inode(parent)?,
+                name: name.to_owned(),
+            })
+        })
+    }
+
+    /// Invalidate the attributes of `inode` of the backend, and its data in the range of `len`
+    /// bytes at `off`.
+    pub fn inval_inode(&self, inode: u64, off: i64, len: i64) -> io::Result<()> {
+        self.notify(|notifier| {
+            Ok(VfsNotification::InvalInode {
+                inode: notifier.vfs_inode(inode)?,
+                off,
+                len,
+            })
+        })
+    }
+
+    fn vfs_inode(&self, ino: u64) -> io::Result<u64> {
+        if Some(ino) == self.root_ino {
+            return Ok(ROOT_ID);
+        }
+        if ino > self.layout.max_ino() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(self.layout.inode(self.fs_idx, ino).0)
+    }
+
+    // Deliver the notification built by `f`. Notifications are dropped if no callback is set.
+    //
+    // The backend may have been unmounted or replaced since the notification was raised, its
+    // inodes are then unknown to the kernel, or belong to another backend, so fail with `ENOENT`
+    // as the kernel does for inodes it has forgotten.
+    fn notify<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&Self) -> io::Result<VfsNotification>,
+    {
+        let mounted = match (self.fs.upgrade(), self.superblocks.upgrade()) {
+            (Some(fs), Some(superblocks)) => superblocks.load()[self.fs_idx as usize]
+                .as_ref()
+                .map(|current| Arc::ptr_eq(current, &fs))
+                .unwrap_or(false),
+            _ => false,
+        };
+        if !mounted {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        let notification = f(self)?;
+        match self.callback.load().as_ref() {
+            Some(callback) => callback(notification),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Vfs {
+    /// Set the callback delivering the notifications of backends to the kernel, including the
+    /// ones of notifiers created before.
+    pub fn set_notify_callback(&self, callback: VfsNotifyCallback) {
+        self.notify_callback.store(Some(Arc::new(callback)));
+    }
+
+    /// Get a notifier for the backend file system mounted with index `fs_idx`, to hand over to
+    /// the backend. The notifier stops working once the backend is unmounted or replaced.
+    pub fn notifier(&self, fs_idx: VfsIndex) -> VfsResult<VfsNotifier> {
+        let fs = self
+            .superblocks
+            .load()
+            .get(fs_idx as usize)
+            .cloned()
+            .flatten()
+            .ok_or_else(|| VfsError::NotFound(format!("fs index {}", fs_idx)))?;
+        let root_ino = self
+            .mountpoints
+            .load()
+            .get(&ROOT_ID)
+            .filter(|mnt| mnt.fs_idx == fs_idx)
+            .map(|mnt| mnt.ino);
+
+        Ok(VfsNotifier {
+            fs_idx,
+            layout: self.layout,
+            root_ino,
+            fs: Arc::downgrade(&fs),
+            superblocks: Arc::downgrade(&self.superblocks),
+            callback: self.notify_callback.clone(),
+        })
+    }
+}
@@ -7,6 +7,7 @@ use super::*;
 use crate::abi::fuse_abi::{stat64, statvfs64};
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::abi::virtio_fs;
+use crate::api::filesystem::{FileLock, StatxResult};
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::transport::FsCacheReqHandler;
 
@@ -128,6 +129,27 @@ impl FileSystem for Vfs {
         }
     }
 
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: Option<VfsHandle>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<(StatxResult, Duration)> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.statx(ctx, idata.ino(), handle, flags, mask),
+            (Right(fs), idata) => {
+                fs.statx(ctx, idata.ino(), handle, flags, mask)
+                    .map(|(mut res, duration)| {
+                        res.st.st_ino = idata.into();
+                        self.remap_attr_id(true, &mut res.st);
+                        (res, duration)
+                    })
+            }
+        }
+    }
+
     fn setattr(
         &self,
         ctx: &Context,
@@ -333,6 +355,36 @@ impl FileSystem for Vfs {
         }
     }
 
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: VfsInode,
+        args: CreateIn,
+    ) -> Result<(Entry, Option<u64>, OpenOptions, Option<u32>)> {
+        // ENOSYS disables O_TMPFILE for the whole mount in the kernel, while other backend file
+        // systems may still support it.
+        let enosys_to_eopnotsupp = |e: Error| {
+            if e.raw_os_error() == Some(libc::ENOSYS) {
+                Error::from_raw_os_error(libc::EOPNOTSUPP)
+            } else {
+                e
+            }
+        };
+
+        match self.get_real_rootfs(parent)? {
+            (Left(fs), idata) => fs
+                .tmpfile(ctx, idata.ino(), args)
+                .map_err(enosys_to_eopnotsupp),
+            (Right(fs), idata) => fs
+                .tmpfile(ctx, idata.ino(), args)
+                .map_err(enosys_to_eopnotsupp)
+                .map(|(mut a, b, c, d)| {
+                    self.convert_entry(idata.fs_idx(), a.inode, &mut a)?;
+                    Ok((a, b, c, d))
+                })?,
+        }
+    }
+
     fn read(
         &self,
         ctx: &Context,
@@ -470,12 +522,13 @@ impl FileSystem for Vfs {
         name: &CStr,
         value: &[u8],
         flags: u32,
+        setxattr_flags: u32,
     ) -> Result<()> {
         validate_path_component(name)?;
 
         match self.get_real_rootfs(inode)? {
-            (Left(fs), idata) => fs.setxattr(ctx, idata.ino(), name, value, flags),
-            (Right(fs), idata) => fs.setxattr(ctx, idata.ino(), name, value, flags),
+            (Left(fs), idata) => fs.setxattr(ctx, idata.ino(), name, value, flags, setxattr_flags),
+            (Right(fs), idata) => fs.setxattr(ctx, idata.ino(), name, value, flags, setxattr_flags),
         }
     }
 
@@ -633,6 +686,26 @@ impl FileSystem for Vfs {
         }
     }
 
+    fn syncfs(&self, ctx: &Context, inode: VfsInode) -> Result<()> {
+        let inode = self.layout.decode(inode);
+        if !inode.is_pseudo_fs() {
+            let fs = self.get_fs_by_idx(inode.fs_idx())?;
+            return fs.syncfs(ctx, inode.ino());
+        }
+
+        // The pseudo fs holds no data, sync all mounted file systems instead. Don't report ENOSYS
+        // to the kernel, which would stop sending syncfs requests for all of them.
+        let mut res = Ok(());
+        for mnt in self.mountpoints.load().values() {
+            let fs = self.get_fs_by_idx(mnt.fs_idx)?;
+            match fs.syncfs(ctx, mnt.ino) {
+                Err(e) if e.raw_os_error() != Some(libc::ENOSYS) && res.is_ok() => res = Err(e),
+                _ => {}
+            }
+        }
+        res
+    }
+
     fn releasedir(&self, ctx: &Context, inode: VfsInode, flags: u32, handle: u64) -> Result<()> {
         match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.releasedir(ctx, idata.ino(), flags, handle),
@@ -647,6 +720,185 @@ impl FileSystem for Vfs {
         }
     }
 
+    fn getlk(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> Result<FileLock> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.getlk(ctx, idata.ino(), handle, owner, lock, flags),
+            (Right(fs), idata) => fs.getlk(ctx, idata.ino(), handle, owner, lock, flags),
+        }
+    }
+
+    fn setlk(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> Result<()> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.setlk(ctx, idata.ino(), handle, owner, lock, flags),
+            (Right(fs), idata) => fs.setlk(ctx, idata.ino(), handle, owner, lock, flags),
+        }
+    }
+
+    fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> Result<()> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.setlkw(ctx, idata.ino(), handle, owner, lock, flags),
+            (Right(fs), idata) => fs.setlkw(ctx, idata.ino(), handle, owner, lock, flags),
+        }
+    }
+
+    fn flock(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        owner: u64,
+        operation: i32,
+    ) -> Result<()> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.flock(ctx, idata.ino(), handle, owner, operation),
+            (Right(fs), idata) => fs.flock(ctx, idata.ino(), handle, owner, operation),
+        }
+    }
+
+    fn seal(&self, ctx: &Context, inode: VfsInode, handle: u64, seals: u32) -> Result<()> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.seal(ctx, idata.ino(), handle, seals),
+            (Right(fs), idata) => fs.seal(ctx, idata.ino(), handle, seals),
+        }
+    }
+
+    fn get_seals(&self, ctx: &Context, inode: VfsInode, handle: u64) -> Result<u32> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.get_seals(ctx, idata.ino(), handle),
+            (Right(fs), idata) => fs.get_seals(ctx, idata.ino(), handle),
+        }
+    }
+
+    fn ioctl<'a>(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData<'a>,
+        out_size: u32,
+    ) -> Result<IoctlData<'a>> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.ioctl(ctx, idata.ino(), handle, flags, cmd, data, out_size),
+            (Right(fs), idata) => fs.ioctl(ctx, idata.ino(), handle, flags, cmd, data, out_size),
+        }
+    }
+
+    fn copy_file_range(
+        &self,
+        ctx: &Context,
+        inode_in: VfsInode,
+        handle_in: u64,
+        offset_in: u64,
+        inode_out: VfsInode,
+        handle_out: u64,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> Result<usize> {
+        let (root, idata_in) = self.get_real_rootfs(inode_in)?;
+        let (_, idata_out) = self.get_real_rootfs(inode_out)?;
+
+        // Data can't be copied across backend file systems, let the kernel fall back to
+        // read/write.
+        if idata_in.fs_idx() != idata_out.fs_idx() {
+            return Err(Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        match root {
+            Left(fs) => fs.copy_file_range(
+                ctx,
+                idata_in.ino(),
+                handle_in,
+                offset_in,
+                idata_out.ino(),
+                handle_out,
+                offset_out,
+                len,
+                flags,
+            ),
+            Right(fs) => fs.copy_file_range(
+                ctx,
+                idata_in.ino(),
+                handle_in,
+                offset_in,
+                idata_out.ino(),
+                handle_out,
+                offset_out,
+                len,
+                flags,
+            ),
+        }
+    }
+
+    fn clone_range(
+        &self,
+        ctx: &Context,
+        inode_in: VfsInode,
+        handle_in: u64,
+        offset_in: u64,
+        inode_out: VfsInode,
+        handle_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<usize> {
+        let (root, idata_in) = self.get_real_rootfs(inode_in)?;
+        let (_, idata_out) = self.get_real_rootfs(inode_out)?;
+
+        // Data can't be shared across backend file systems.
+        if idata_in.fs_idx() != idata_out.fs_idx() {
+            return Err(Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        match root {
+            Left(fs) => fs.clone_range(
+                ctx,
+                idata_in.ino(),
+                handle_in,
+                offset_in,
+                idata_out.ino(),
+                handle_out,
+                offset_out,
+                len,
+            ),
+            Right(fs) => fs.clone_range(
+                ctx,
+                idata_in.ino(),
+                handle_in,
+                offset_in,
+                idata_out.ino(),
+                handle_out,
+                offset_out,
+                len,
+            ),
+        }
+    }
+
     #[inline]
     fn id_remap(&self, ctx: &mut Context) -> Result<()> {
         // If id_mapping is enabled, map the external ID to the internal ID.
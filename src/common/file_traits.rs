@@ -447,6 +447,136 @@ macro_rules! volatile_impl {
 
 volatile_impl!(File);
 
+/// A [`File`] wrapper doing positioned vectored IO with `preadv2(2)` and `pwritev2(2)`, passing the
+/// `RWF_*` flags given at creation, e.g. `RWF_HIPRI` to poll for the completion of direct IO.
+///
+/// Falls back to `preadv(2)` and `pwritev(2)`, without flags, if the kernel doesn't support them.
+/// Writes with `RWF_SYNC` or `RWF_DSYNC` are then followed by `fsync(2)` or `fdatasync(2)`.
+#[cfg(target_os = "linux")]
+pub struct RwfFile<'a> {
+    file: &'a mut File,
+    flags: c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> RwfFile<'a> {
+    /// Wrap `file` to pass `flags` to positioned vectored IO.
+    pub fn new(file: &'a mut File, flags: c_int) -> Self {
+        RwfFile { file, flags }
+    }
+
+    // Run `f` with the iovecs of `bufs`, falling back to `fallback` if `f` isn't supported.
+    fn rw_vectored(
+        &mut self,
+        bufs: &[FileVolatileSlice],
+        f: impl FnOnce(c_int, &[libc::iovec], c_int) -> isize,
+        fallback: impl FnOnce(&mut File) -> Result<usize>,
+    ) -> Result<usize> {
+        let iovecs: Vec<libc::iovec> = bufs
+            .iter()
+            .map(|s| libc::iovec {
+                iov_base: s.as_ptr() as *mut c_void,
+                iov_len: s.len() as size_t,
+            })
+            .collect();
+
+        if iovecs.is_empty() {
+            return Ok(0);
+        }
+
+        let ret = f(self.file.as_raw_fd(), &iovecs, self.flags);
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let e = Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => fallback(self.file),
+            _ => Err(e),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl FileReadWriteVolatile for RwfFile<'_> {
+    fn read_volatile(&mut self, slice: FileVolatileSlice) -> Result<usize> {
+        self.file.read_volatile(slice)
+    }
+
+    fn read_vectored_volatile(&mut self, bufs: &[FileVolatileSlice]) -> Result<usize> {
+        self.file.read_vectored_volatile(bufs)
+    }
+
+    fn write_volatile(&mut self, slice: FileVolatileSlice) -> Result<usize> {
+        self.file.write_volatile(slice)
+    }
+
+    fn write_vectored_volatile(&mut self, bufs: &[FileVolatileSlice]) -> Result<usize> {
+        self.file.write_vectored_volatile(bufs)
+    }
+
+    fn read_at_volatile(&mut self, slice: FileVolatileSlice, offset: u64) -> Result<usize> {
+        self.read_vectored_at_volatile(&[slice], offset)
+    }
+
+    fn read_vectored_at_volatile(
+        &mut self,
+        bufs: &[FileVolatileSlice],
+        offset: u64,
+    ) -> Result<usize> {
+        self.rw_vectored(
+            bufs,
+            // Safe because only bytes inside the buffers are accessed and the kernel is expected
+            // to handle arbitrary memory for I/O.
+            |fd, iovecs, flags| unsafe {
+                libc::preadv2(
+                    fd,
+                    iovecs.as_ptr(),
+                    iovecs.len() as c_int,
+                    offset as off64_t,
+                    flags,
+                )
+            },
+            |f| f.read_vectored_at_volatile(bufs, offset),
+        )
+    }
+
+    fn write_at_volatile(&mut self, slice: FileVolatileSlice, offset: u64) -> Result<usize> {
+        self.write_vectored_at_volatile(&[slice], offset)
+    }
+
+    fn write_vectored_at_volatile(
+        &mut self,
+        bufs: &[FileVolatileSlice],
+        offset: u64,
+    ) -> Result<usize> {
+        let flags = self.flags;
+        self.rw_vectored(
+            bufs,
+            // Safe because only bytes inside the buffers are accessed and the kernel is expected
+            // to handle arbitrary memory for I/O.
+            |fd, iovecs, flags| unsafe {
+                libc::pwritev2(
+                    fd,
+                    iovecs.as_ptr(),
+                    iovecs.len() as c_int,
+                    offset as off64_t,
+                    flags,
+                )
+            },
+            |f| {
+                let len = f.write_vectored_at_volatile(bufs, offset)?;
+                // Keep the durability asked for by the flags.
+                if flags & libc::RWF_SYNC != 0 {
+                    f.sync_all()?;
+                } else if flags & libc::RWF_DSYNC != 0 {
+                    f.sync_data()?;
+                }
+                Ok(len)
+            },
+        )
+    }
+}
+
 #[cfg(all(target_os = "linux", feature = "async-io"))]
 pub use async_io::AsyncFileReadWriteVolatile;
 
@@ -1323,4 +1453,40 @@ mod tests {
         file.read_exact_at_volatile(slice, 30).unwrap_err();
         file.read_exact_at_volatile(slice, 32).unwrap_err();
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_rwf_file_vectored() {
+        let mut file = TempFile::new().unwrap().into_file();
+        let data: Vec<u8> = (0..128 * 1024u32).map(|i| (i / 7) as u8).collect();
+
+        // Write 128KiB from four 32KiB buffers with a single pwritev2().
+        let mut src = data.clone();
+        let slices: Vec<_> = src
+            .chunks_mut(32 * 1024)
+            .map(|c| unsafe { FileVolatileSlice::from_raw_ptr(c.as_mut_ptr(), c.len()) })
+            .collect();
+        let mut f = RwfFile::new(&mut file, libc::RWF_HIPRI);
+        assert_eq!(
+            f.write_vectored_at_volatile(&slices, 0).unwrap(),
+            data.len()
+        );
+
+        // And read it back into four other buffers with preadv2().
+        let mut dst = vec![0u8; data.len()];
+        let slices: Vec<_> = dst
+            .chunks_mut(32 * 1024)
+            .map(|c| unsafe { FileVolatileSlice::from_raw_ptr(c.as_mut_ptr(), c.len()) })
+            .collect();
+        assert_eq!(slices.len(), 4);
+        assert_eq!(f.read_vectored_at_volatile(&slices, 0).unwrap(), data.len());
+        assert_eq!(dst, data);
+
+        assert_eq!(
+            f.read_vectored_at_volatile(&slices, data.len() as u64)
+                .unwrap(),
+            0
+        );
+        assert_eq!(f.read_vectored_at_volatile(&[], 0).unwrap(), 0);
+    }
 }
@@ -753,6 +753,7 @@ impl FileSystem for OverlayFs {
         name: &CStr,
         value: &[u8],
         flags: u32,
+        setxattr_flags: u32,
     ) -> Result<()> {
         trace!(
             "SETXATTR: inode: {}, name: {}, value: {:?}, flags: {}\n",
@@ -774,7 +775,7 @@ impl FileSystem for OverlayFs {
 
         let (layer, _, real_inode) = node.first_layer_inode();
 
-        layer.setxattr(ctx, real_inode, name, value, flags)
+        layer.setxattr(ctx, real_inode, name, value, flags, setxattr_flags)
 
         // TODO: recreate node since setxattr may made dir opaque. @weizhang555.zw
     }
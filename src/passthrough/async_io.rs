@@ -7,9 +7,11 @@
 
 use std::io;
 use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
 
 use async_trait::async_trait;
 
+use super::util::{copy_file_range_all, transfer_all};
 use super::*;
 use crate::abi::fuse_abi::{
     CreateIn, Opcode, OpenOptions, SetattrValid, FOPEN_IN_KILL_SUIDGID, WRITE_KILL_PRIV,
@@ -36,6 +38,20 @@ impl<'a> InodeData {
     }
 }
 
+// Read up to `size` bytes at `offset` of the file of a handle on a blocking thread of the runtime.
+async fn read_handle(data: Arc<HandleData>, size: usize, offset: u64) -> io::Result<Vec<u8>> {
+    let task = tokio::task::spawn_blocking(move || {
+        let mut buf = vec![0u8; size];
+        let n = transfer_all(size, offset, |count, off| {
+            data.file.read_at(&mut buf[size - count..], off)
+        })?;
+        buf.truncate(n);
+        Ok(buf)
+    });
+    task.await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
 impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     /*
     async fn async_open_file(
@@ -187,7 +203,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             let mut opts = OpenOptions::empty();
 
             self.handle_map.insert(handle, data);
-            match self.cfg.cache_policy {
+            match self.tunables.load().cache_policy {
                 // We only set the direct I/O option on files.
                 CachePolicy::Never => opts.set(
                     OpenOptions::DIRECT_IO,
@@ -245,7 +261,8 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             e
         })?;
 
-        Ok((st, self.cfg.attr_timeout))
+        let (_, attr_timeout) = self.timeouts(st.st_mode);
+        Ok((st, attr_timeout))
         */
     }
 
@@ -404,13 +421,14 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
             }
         };
 
+        let (entry_timeout, attr_timeout) = self.timeouts(st.get_stat().st_mode);
         Ok(Entry {
             inode,
-            generation: 0,
+            generation: self.inode_map.allocator.generation(inode),
             attr: st.get_stat(),
             attr_flags,
-            attr_timeout: self.cfg.attr_timeout,
-            entry_timeout: self.cfg.entry_timeout,
+            attr_timeout,
+            entry_timeout,
         })
         */
     }
@@ -610,7 +628,7 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         let dir_file = dir.async_get_file(&self.mount_fds).await?;
 
         let new_file = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let (_uid, _gid) = self.set_creds(ctx)?;
 
             let flags = self.get_writeback_open_flags(args.flags as i32);
             Self::create_file_excl(
@@ -637,7 +655,7 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
                     None
                 };
 
-                let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+                let (_uid, _gid) = self.set_creds(ctx)?;
                 self.async_open_inode(ctx, entry.inode, args.flags as i32)
                     .await?
             }
@@ -654,7 +672,7 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         };
 
         let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
+        match self.tunables.load().cache_policy {
             CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
             CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
             _ => {}
@@ -674,20 +692,50 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         size: u32,
         offset: u64,
         _lock_owner: Option<u64>,
-        _flags: u32,
+        flags: u32,
     ) -> io::Result<usize> {
-        unimplemented!()
-        /*
-        let data = self
-            .async_get_data(ctx, handle, inode, libc::O_RDONLY)
-            .await?;
-        let drive = ctx
-            .get_drive::<D>()
-            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+        let size = size as usize;
+        // Direct IO must reach the file.
+        if self.cfg.readahead == 0 || flags & libc::O_DIRECT as u32 != 0 {
+            let buf = read_handle(data, size, offset).await?;
+            w.write_all(&buf)?;
+            return Ok(buf.len());
+        }
 
-        w.async_write_from(drive, data.get_handle_raw_fd(), size as usize, offset)
-            .await
-         */
+        let (sequential, gen) = {
+            // Do not expect poisoned lock here, so safe to unwrap().
+            let mut readahead = data.readahead.lock().unwrap();
+            if let Some(buf) = readahead.get(offset, size) {
+                w.write_all(buf)?;
+                let len = buf.len();
+                readahead.next = Some(offset + len as u64);
+                return Ok(len);
+            }
+            (readahead.next == Some(offset), readahead.gen)
+        };
+
+        // Read ahead once reads are found sequential, random reads only read what they need.
+        let len = if sequential {
+            size.max(self.cfg.readahead)
+        } else {
+            size
+        };
+        let buf = read_handle(data.clone(), len, offset).await?;
+        let n = buf.len().min(size);
+        w.write_all(&buf[..n])?;
+
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut readahead = data.readahead.lock().unwrap();
+        if readahead.gen == gen {
+            if sequential {
+                readahead.offset = offset;
+                readahead.eof = buf.len() < len;
+                readahead.data = buf;
+            }
+            readahead.next = Some(offset + n as u64);
+        }
+        Ok(n)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -795,6 +843,42 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
          */
     }
 
+    #[allow(clippy::too_many_arguments)]
+    async fn async_copy_file_range(
+        &self,
+        ctx: &Context,
+        inode_in: <Self as FileSystem>::Inode,
+        handle_in: <Self as FileSystem>::Handle,
+        offset_in: u64,
+        inode_out: <Self as FileSystem>::Inode,
+        handle_out: <Self as FileSystem>::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        let (data_in, data_out, killpriv) = self
+            .prepare_copy_file_range(inode_in, handle_in, inode_out, handle_out, offset_out, len)?;
+
+        // Copying may take long for large ranges, run it on a blocking thread instead of stalling
+        // the other requests of the runtime. The handles are moved along so that the fds stay
+        // valid meanwhile.
+        let task = tokio::task::spawn_blocking(move || {
+            let _readahead = data_out.readahead_guard();
+            // Cap restored when _killpriv is dropped, by the thread which dropped it.
+            let _killpriv = if killpriv { drop_cap_fsetid()? } else { None };
+            copy_file_range_all(
+                &data_in.borrow_fd(),
+                offset_in,
+                &data_out.borrow_fd(),
+                offset_out,
+                len,
+                flags,
+            )
+        });
+        task.await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+    }
+
     async fn async_fsyncdir(
         &self,
         ctx: &Context,
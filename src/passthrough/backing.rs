@@ -0,0 +1,57 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Register open files as backing files of the FUSE device, for `Config::fuse_passthrough`.
+//!
+//! Handles opened with `OpenOptions::PASSTHROUGH` and the id of a backing file are read and
+//! written by the kernel through the backing file, without sending requests to the daemon.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use crate::abi::fuse_abi::BackingMap;
+
+// refer: include/uapi/linux/fuse.h of Linux 6.9
+// #define FUSE_DEV_IOC_BACKING_OPEN   _IOW(FUSE_DEV_IOC_MAGIC, 1, struct fuse_backing_map)
+// #define FUSE_DEV_IOC_BACKING_CLOSE  _IOW(FUSE_DEV_IOC_MAGIC, 2, uint32_t)
+nix::ioctl_write_ptr!(backing_open, 229, 1, BackingMap);
+nix::ioctl_write_ptr!(backing_close, 229, 2, u32);
+
+/// A file registered with the FUSE device, unregistered on drop.
+///
+/// Handles already opened by the kernel with the backing file keep using it after it has been
+/// unregistered.
+pub(super) struct BackingFile {
+    dev: Arc<File>,
+    id: u32,
+}
+
+impl BackingFile {
+    /// Register `file` with the FUSE device `dev`, which needs `CAP_SYS_ADMIN`.
+    pub fn new(dev: Arc<File>, file: &File) -> io::Result<Self> {
+        let map = BackingMap {
+            fd: file.as_raw_fd(),
+            ..Default::default()
+        };
+        // Safe because the kernel only reads `map`, and we check the return value.
+        let id = unsafe { backing_open(dev.as_raw_fd(), &map) }?;
+
+        Ok(BackingFile { dev, id: id as u32 })
+    }
+
+    /// Id of the backing file to reply to open requests with.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Drop for BackingFile {
+    fn drop(&mut self) {
+        // Safe because the kernel only reads the id, and we check the return value.
+        if let Err(e) = unsafe { backing_close(self.dev.as_raw_fd(), &self.id) } {
+            warn!("fuse: failed to close backing file {}, {}", self.id, e);
+        }
+    }
+}
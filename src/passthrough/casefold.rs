@@ -0,0 +1,131 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Resolve names of directory entries case-insensitively.
+//!
+//! Directories are scanned once and their entries indexed by their case-folded names. The index
+//! is dropped when the modification time of the directory changes.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::util::{read_dir_names, stat_fd};
+use super::Inode;
+
+// Entries of a directory indexed by their case-folded names.
+struct FoldedDir {
+    // Modification time of the directory when it was scanned.
+    mtime: (i64, i64),
+    // Whether the directory may have been changed within the same timestamp tick after it was
+    // scanned, in which case the index can't be trusted.
+    racy: bool,
+    names: HashMap<Vec<u8>, CString>,
+}
+
+/// Cache of case-folded directory listings, keyed by the inode of directories.
+#[derive(Default)]
+pub struct CaseFoldCache {
+    dirs: Mutex<HashMap<Inode, Arc<FoldedDir>>>,
+}
+
+impl CaseFoldCache {
+    /// Find the name of the entry in directory `dir` matching `name` case-insensitively.
+    ///
+    /// When several entries only differ by case, the smallest name in byte order wins, so the
+    /// result doesn't depend on the order of directory entries.
+    pub fn lookup(
+        &self,
+        inode: Inode,
+        dir: &impl AsRawFd,
+        name: &CStr,
+    ) -> io::Result<Option<CString>> {
+        let st = stat_fd(dir, None)?;
+        let mtime = (st.st_mtime, st.st_mtime_nsec);
+
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let cached = self.dirs.lock().unwrap().get(&inode).cloned();
+        let folded = match cached {
+            Some(folded) if folded.mtime == mtime && !folded.racy => folded,
+            _ => {
+                let folded = Arc::new(Self::scan(dir, mtime)?);
+                self.dirs.lock().unwrap().insert(inode, folded.clone());
+                folded
+            }
+        };
+
+        Ok(folded.names.get(&fold(name.to_bytes())).cloned())
+    }
+
+    /// Drop the cached listing of directory `inode`.
+    pub fn remove(&self, inode: Inode) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.dirs.lock().unwrap().remove(&inode);
+    }
+
+    fn scan(dir: &impl AsRawFd, mtime: (i64, i64)) -> io::Result<FoldedDir> {
+        let mut names: HashMap<Vec<u8>, CString> = HashMap::new();
+        for name in read_dir_names(dir)? {
+            let key = fold(name.to_bytes());
+            match names.get(&key) {
+                Some(other) if *other <= name => {}
+                _ => {
+                    names.insert(key, name);
+                }
+            }
+        }
+
+        // The modification time only has the granularity of a timer tick, so entries added within
+        // the tick after the scan go unnoticed. Rescan until the directory is at least a second
+        // old.
+        let racy = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64 <= mtime.0 + 1)
+            .unwrap_or(true);
+
+        Ok(FoldedDir { mtime, racy, names })
+    }
+}
+
+// Fold the case of a name, by Unicode rules for UTF-8 names and ASCII rules otherwise.
+fn fold(name: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(name) {
+        Ok(s) => s.to_lowercase().into_bytes(),
+        Err(_) => name.to_ascii_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_casefold_lookup() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.as_path().join("file.txt"), b"").unwrap();
+        std::fs::write(dir.as_path().join("Ünïcode"), b"").unwrap();
+        std::fs::write(dir.as_path().join("dup"), b"").unwrap();
+        std::fs::write(dir.as_path().join("DUP"), b"").unwrap();
+        let file = std::fs::File::open(dir.as_path()).unwrap();
+
+        let cache = CaseFoldCache::default();
+        let lookup = |name: &str| {
+            cache
+                .lookup(1, &file, &CString::new(name).unwrap())
+                .unwrap()
+                .map(|n| n.into_string().unwrap())
+        };
+        assert_eq!(lookup("FILE.TXT").as_deref(), Some("file.txt"));
+        assert_eq!(lookup("üNÏCODE").as_deref(), Some("Ünïcode"));
+        assert_eq!(lookup("Dup").as_deref(), Some("DUP"));
+        assert_eq!(lookup("missing"), None);
+
+        // The listing of a directory changed just now isn't trusted, so new entries show up.
+        std::fs::write(dir.as_path().join("new"), b"").unwrap();
+        assert_eq!(lookup("NEW").as_deref(), Some("new"));
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hand over the inodes and open handles of a passthrough file system to a new process, for hot
+//! upgrades of the daemon.
+//!
+//! Unlike `save_state()`, which needs file handles and reopens files by them, a checkpoint records
+//! the numbers of the fds held by the old process. The new process opens the same files again
+//! through `/proc/<pid>/fd/<fd>` of the old process, which must still be running, and can do so
+//! whatever the file system of the shared directory. Locks, poll handles and file offsets aren't
+//! handed over.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use vm_memory::bitmap::BitmapSlice;
+use vm_memory::ByteValued;
+
+use super::inode_store::InodeId;
+use super::statx::statx;
+use super::util::openat;
+use super::{Config, HandleData, InodeData, InodeHandle, PassthroughFs};
+use crate::abi::fuse_abi as fuse;
+
+// "PTFSCKPT" read as a little endian integer.
+const CHECKPOINT_MAGIC: u64 = 0x5450_4b43_5346_5450;
+const CHECKPOINT_VERSION: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct CheckpointHeader {
+    magic: u64,
+    version: u32,
+    // Process holding the fds.
+    pid: u32,
+    next_inode: u64,
+    next_handle: u64,
+    nr_inodes: u64,
+    nr_handles: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct InodeRecord {
+    inode: u64,
+    refcount: u64,
+    ino: u64,
+    dev: u64,
+    mode: u32,
+    fd: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct HandleRecord {
+    handle: u64,
+    inode: u64,
+    flags: u32,
+    fd: i32,
+}
+
+// Safe because these only contain integers, without implicit padding.
+unsafe impl ByteValued for CheckpointHeader {}
+unsafe impl ByteValued for InodeRecord {}
+unsafe impl ByteValued for HandleRecord {}
+
+fn read_record<T: ByteValued>(r: &mut dyn Read) -> io::Result<T> {
+    let mut record = T::default();
+    r.read_exact(record.as_mut_slice())?;
+    Ok(record)
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Write the inodes in use and the open handles to `w`, with the numbers of the fds holding
+    /// them, to hand them over to a new process with `restore()`.
+    ///
+    /// This process must keep running, and keep its fds open, until the new process has
+    /// restored the checkpoint. Inodes must be held by fds, so this fails with `EINVAL` if
+    /// `Config::inode_file_handles` or `Config::max_path_fds` is set. Requests shouldn't be
+    /// handled meanwhile.
+    pub fn checkpoint(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut inodes = Vec::new();
+        for shard in self.inode_map.shards.iter() {
+            // Do not expect poisoned lock here, so safe to unwrap().
+            for data in shard.read().unwrap().values() {
+                let fd = match &data.handle {
+                    InodeHandle::File(f) => f.as_raw_fd(),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("inode {} isn't held by an fd", data.inode),
+                        ))
+                    }
+                };
+                inodes.push(InodeRecord {
+                    inode: data.inode,
+                    refcount: data.refcount.load(Ordering::Relaxed),
+                    ino: data.id.ino,
+                    dev: data.id.dev,
+                    mode: data.mode,
+                    fd,
+                });
+            }
+        }
+        inodes.sort_by_key(|i| i.inode);
+
+        let mut handles = Vec::new();
+        for shard in self.handle_map.shards.iter() {
+            // Do not expect poisoned lock here, so safe to unwrap().
+            for (handle, data) in shard.read().unwrap().iter() {
+                handles.push(HandleRecord {
+                    handle: *handle,
+                    inode: data.inode,
+                    flags: data.get_flags(),
+                    fd: data.borrow_fd().as_raw_fd(),
+                });
+            }
+        }
+        handles.sort_by_key(|h| h.handle);
+
+        let header = CheckpointHeader {
+            magic: CHECKPOINT_MAGIC,
+            version: CHECKPOINT_VERSION,
+            pid: std::process::id(),
+            next_inode: self.inode_map.allocator.next(),
+            next_handle: self.next_handle.load(Ordering::Relaxed),
+            nr_inodes: inodes.len() as u64,
+            nr_handles: handles.len() as u64,
+        };
+        w.write_all(header.as_slice())?;
+        for record in inodes.iter() {
+            w.write_all(record.as_slice())?;
+        }
+        for record in handles.iter() {
+            w.write_all(record.as_slice())?;
+        }
+        w.flush()
+    }
+
+    /// Create a file system with `cfg`, import it, and restore the inodes and handles of the
+    /// checkpoint read from `r`, written by `checkpoint()` in another process sharing the same
+    /// directory.
+    ///
+    /// Files are opened again through the fds of the process which wrote the checkpoint, and
+    /// checked to still be the files with the saved inode numbers, failing with `ESTALE`
+    /// otherwise. `Config::pre_warm_depth` is ignored, the inode map is filled by the checkpoint
+    /// instead.
+    pub fn restore(mut cfg: Config, r: &mut dyn Read) -> io::Result<Self> {
+        cfg.pre_warm_depth = None;
+        let fs = Self::new(cfg)?;
+        fs.import()?;
+
+        let header: CheckpointHeader = read_record(r)?;
+        if header.magic != CHECKPOINT_MAGIC || header.version != CHECKPOINT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a passthrough checkpoint",
+            ));
+        }
+        let fd_dir = File::open(format!("/proc/{}/fd", header.pid))?;
+        let reopen = |fd: i32, flags: libc::c_int| {
+            // The fd number doesn't contain NUL bytes, so safe to unwrap().
+            let name = CString::new(fd.to_string()).unwrap();
+            openat(&fd_dir, &name, flags | libc::O_CLOEXEC, 0)
+        };
+
+        let root = fs.inode_map.get(fuse::ROOT_ID)?;
+        let mut inodes = Vec::new();
+        for _ in 0..header.nr_inodes {
+            let saved: InodeRecord = read_record(r)?;
+            if saved.inode == fuse::ROOT_ID {
+                if saved.ino != root.id.ino || saved.dev != root.id.dev {
+                    error!("passthroughfs: restore: the shared directory has changed");
+                    return Err(io::Error::from_raw_os_error(libc::ESTALE));
+                }
+                root.refcount.store(saved.refcount, Ordering::Relaxed);
+                continue;
+            }
+            let file = reopen(saved.fd, libc::O_PATH)?;
+            let st = statx(&file, None)?;
+            let id = InodeId::from_stat(&st);
+            if id.ino != saved.ino
+                || id.dev != saved.dev
+                || st.st.st_mode & libc::S_IFMT != saved.mode & libc::S_IFMT
+            {
+                error!(
+                    "passthroughfs: restore: inode {} refers to another file",
+                    saved.inode
+                );
+                return Err(io::Error::from_raw_os_error(libc::ESTALE));
+            }
+            inodes.push(Arc::new(InodeData::new(
+                saved.inode,
+                InodeHandle::File(file),
+                saved.refcount,
+                id,
+                st.st.st_mode,
+            )));
+        }
+
+        let mut handles = Vec::new();
+        for _ in 0..header.nr_handles {
+            let saved: HandleRecord = read_record(r)?;
+            if saved.inode != fuse::ROOT_ID && !inodes.iter().any(|i| i.inode == saved.inode) {
+                error!(
+                    "passthroughfs: restore: handle {} refers to unknown inode {}",
+                    saved.handle, saved.inode
+                );
+                return Err(io::Error::from_raw_os_error(libc::ESTALE));
+            }
+            // The file has been created or truncated when it was opened, don't do it again.
+            let flags = saved.flags as i32 & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+            let file = reopen(saved.fd, flags)?;
+            handles.push((
+                saved.handle,
+                HandleData::new(saved.inode, file, saved.flags),
+            ));
+        }
+
+        for data in inodes {
+            fs.inotify_watch(&data);
+            fs.inode_map.insert(data);
+        }
+        for (handle, data) in handles {
+            fs.handle_map.try_insert(handle, data)?;
+        }
+        fs.inode_map.allocator.reset(header.next_inode);
+        fs.next_handle.store(header.next_handle, Ordering::Relaxed);
+
+        Ok(fs)
+    }
+}
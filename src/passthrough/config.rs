@@ -1,9 +1,15 @@
 // Copyright (C) 2020-2022 Alibaba Cloud. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 
+use super::{UidGidMap, XattrMap, XattrPrefixMap, OVERFLOW_ID};
+
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
 /// protocol uses close-to-open consistency. This means that any cached contents of the file are
 /// invalidated the next time that file is opened.
@@ -49,6 +55,114 @@ impl FromStr for CachePolicy {
     }
 }
 
+/// What to do when opening a file would exceed `Config::max_handles`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum HandleLimitPolicy {
+    /// Fail the open with `EMFILE`.
+    #[default]
+    RejectNew,
+
+    /// Close the least recently used handle which isn't being used by a request, or fail with
+    /// `EMFILE` if there's none. Later requests on the closed handle fail with `EBADF`.
+    EvictOldest,
+}
+
+impl FromStr for HandleLimitPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(HandleLimitPolicy::RejectNew),
+            "evict" => Ok(HandleLimitPolicy::EvictOldest),
+            _ => Err("invalid handle limit policy"),
+        }
+    }
+}
+
+/// How `readdir` reports the inode numbers of directory entries.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum ReaddirInoPolicy {
+    /// Look each entry up to report its FUSE inode number, like `lookup` does. The
+    /// [Vfs](../api/vfs/struct.Vfs.html) layer relies on these numbers to find mount points.
+    #[default]
+    Exact,
+
+    /// Report the inode numbers of the host, as `getdents64(2)` returns them, without looking
+    /// entries up. Directories are `fstatat(2)`-ed to report the root of file systems mounted on
+    /// them. The numbers match the `st_ino` of attributes, unless inodes are remapped, e.g. by the
+    /// `Vfs` layer.
+    Fast,
+}
+
+impl FromStr for ReaddirInoPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(ReaddirInoPolicy::Exact),
+            "fast" => Ok(ReaddirInoPolicy::Fast),
+            _ => Err("invalid readdir inode policy"),
+        }
+    }
+}
+
+/// How inode numbers are allocated to files.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum InodeAllocPolicy {
+    /// Allocate increasing inode numbers, never reusing them. Forgotten inodes keep their inode
+    /// numbers, so that files get the same inode numbers when looked up again.
+    #[default]
+    Monotonic,
+
+    /// Reuse the inode numbers of inodes once the kernel has forgotten them, so that inode
+    /// numbers aren't exhausted by long running daemons looking up many files. Files looked up
+    /// again may get other inode numbers. The generation of an inode number is bumped on each
+    /// reuse, so that file handles of exported file systems stay unique.
+    FreeList,
+}
+
+impl FromStr for InodeAllocPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "monotonic" => Ok(InodeAllocPolicy::Monotonic),
+            "free_list" => Ok(InodeAllocPolicy::FreeList),
+            _ => Err("invalid inode allocation policy"),
+        }
+    }
+}
+
+/// Which callers to squash to `Config::anon_uid` and `Config::anon_gid`, like the options of NFS
+/// exports.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum SquashPolicy {
+    /// Run requests with the credentials of the caller.
+    #[default]
+    None,
+
+    /// Run requests of root with the anonymous ids, and chown files to the anonymous ids instead
+    /// of root.
+    RootSquash,
+
+    /// Run requests of all callers with the anonymous ids, and chown files to the anonymous ids
+    /// instead of root.
+    AllSquash,
+}
+
+impl FromStr for SquashPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" | "no_root_squash" => Ok(SquashPolicy::None),
+            "root_squash" => Ok(SquashPolicy::RootSquash),
+            "all_squash" => Ok(SquashPolicy::AllSquash),
+            _ => Err("invalid squash policy"),
+        }
+    }
+}
+
 /// Options that configure the behavior of the passthrough fuse file system.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Config {
@@ -75,6 +189,22 @@ pub struct Config {
     /// regular files.
     pub dir_entry_timeout: Option<Duration>,
 
+    /// Same as `attr_timeout`, override `attr_timeout` config, but only take effect on regular
+    /// files when specified.
+    pub file_attr_timeout: Option<Duration>,
+
+    /// Same as `entry_timeout`, override `entry_timeout` config, but only take effect on regular
+    /// files when specified.
+    pub file_entry_timeout: Option<Duration>,
+
+    /// Same as `attr_timeout`, override `attr_timeout` config, but only take effect on symlinks
+    /// when specified.
+    pub symlink_attr_timeout: Option<Duration>,
+
+    /// Same as `entry_timeout`, override `entry_timeout` config, but only take effect on symlinks
+    /// when specified.
+    pub symlink_entry_timeout: Option<Duration>,
+
     /// The caching policy the file system should use. See the documentation of `CachePolicy` for
     /// more details.
     pub cache_policy: CachePolicy,
@@ -99,6 +229,21 @@ pub struct Config {
     /// The default is `/`.
     pub root_dir: String,
 
+    /// An already opened fd of the root directory, used instead of opening `root_dir`, which is
+    /// then only used in messages. The fd may be an `O_PATH` fd, and is duplicated by
+    /// `PassthroughFs::new()`, so the caller may close it afterwards. See
+    /// `PassthroughFs::new_from_rootfd()` to hand over an open directory instead.
+    ///
+    /// The default value for this option is `None`.
+    pub root_fd: Option<RawFd>,
+
+    /// Whether the root is a single regular file rather than a directory. The file is used as the
+    /// root inode, which the kernel must mount with a regular file root mode, e.g.
+    /// `rootmode=100000`. Looking up, listing or creating entries in the root fails with `ENOTDIR`.
+    ///
+    /// The default value for this option is `false`.
+    pub root_is_file: bool,
+
     /// Whether the file system should support Extended Attributes (xattr). Enabling this feature may
     /// have a significant impact on performance, especially on write parallelism. This is the result
     /// of FUSE attempting to remove the special file privileges after each write request.
@@ -148,11 +293,16 @@ pub struct Config {
     /// share memory file to attack the host.
     pub seal_size: bool,
 
-    /// Whether count mount ID or not when comparing two inodes. By default we think two inodes
-    /// are same if their inode number and st_dev are the same. When `enable_mntid` is set as
-    /// 'true', inode's mount ID will be taken into account as well. For example, bindmount the
-    /// same file into virtiofs' source dir, the two bindmounted files will be identified as two
-    /// different inodes when this option is true, so the don't share pagecache.
+    /// Whether count mount ID or not when comparing two inodes in the async io path. By default
+    /// we think two inodes are same if their inode number and st_dev are the same. When
+    /// `enable_mntid` is set as 'true', inode's mount ID will be taken into account as well. For
+    /// example, bindmount the same file into virtiofs' source dir, the two bindmounted files will
+    /// be identified as two different inodes when this option is true, so the don't share
+    /// pagecache.
+    ///
+    /// The sync io path always takes the mount ID into account, as `statx(2)` returns it along
+    /// with the other attributes. On kernels before 5.8 it's taken from `name_to_handle_at(2)`,
+    /// or 0 if the file system doesn't support file handles.
     ///
     /// The default value for this option is `false`.
     pub enable_mntid: bool,
@@ -164,6 +314,16 @@ pub struct Config {
     /// to N Bytes.
     pub dax_file_size: Option<u64>,
 
+    /// Whether to let the `trusted.dax` extended attribute of a file choose DAX when it's opened.
+    ///
+    /// If enabled and `FUSE_PERFILE_DAX` is negotiated, files with `trusted.dax` set to `1` are
+    /// opened without `FOPEN_DIRECT_IO` and files with it set to `0` are opened with it, overriding
+    /// the cache policy. Files without the attribute follow the cache policy. Only takes effect
+    /// when `xattr` is enabled.
+    ///
+    /// The default value for this option is `false`.
+    pub perfile_dax_xattr: bool,
+
     /// Reduce memory consumption by directly use host inode when possible.
     ///
     /// When set to false, a virtual inode number will be allocated for each file managed by
@@ -181,6 +341,352 @@ pub struct Config {
     ///
     /// The default is `true`.
     pub allow_direct_io: bool,
+
+    /// Whether reads and writes of the sync file system interface are submitted through a per
+    /// thread io_uring instance, instead of blocking system calls. Only takes effect when built
+    /// with the `io-uring` feature, and falls back to blocking system calls if the kernel doesn't
+    /// support io_uring.
+    ///
+    /// The default value for this option is `false`.
+    pub use_io_uring: bool,
+
+    /// Whether files are opened with `O_NOATIME`, so that reads of the client don't update the
+    /// access times of files on the host. The kernel only allows it for files owned by the
+    /// daemon, or with `CAP_FOWNER`, other files are opened without it.
+    ///
+    /// The default value for this option is `false`.
+    pub noatime: bool,
+
+    /// Whether closing a file in the client makes its data durable on the host, as with a local
+    /// file system mounted with `sync`. Flushes and releases of handles opened for writing call
+    /// fdatasync(2) on the file, and fail with its error, which is returned by close(2) in the
+    /// client.
+    ///
+    /// The default value for this option is `false`.
+    pub sync_on_close: bool,
+
+    /// Whether to send a delete notification for the old name of entries renamed by the client,
+    /// through the notifier set with `PassthroughFs::set_delete_notifier()`, so that other
+    /// clients of the shared directory drop it from their caches.
+    ///
+    /// The default value for this option is `false`.
+    pub notify_on_rename: bool,
+
+    /// Whether `clone_range()` shares the extents of files with `ioctl(FICLONERANGE)` on file
+    /// systems supporting copy-on-write, such as btrfs and XFS. Data is copied with
+    /// `copy_file_range(2)` otherwise, or if the file system doesn't support clones.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_clone_range: bool,
+
+    /// Whether to let the kernel read and write regular files directly through the files opened
+    /// by the daemon, with `FUSE_PASSTHROUGH` on Linux 6.9 and later.
+    ///
+    /// Open files are registered as backing files with the FUSE device set by
+    /// `PassthroughFs::set_fuse_dev()`, which needs `CAP_SYS_ADMIN`. Files fail to be registered
+    /// are served by the daemon as usual. The kernel doesn't enable it along with the writeback
+    /// cache, so it's only requested without `writeback`.
+    ///
+    /// The default value for this option is `false`.
+    pub fuse_passthrough: bool,
+
+    /// ioctl request numbers which are passed through to the underlying files.
+    ///
+    /// ioctls are executed by the file system daemon on behalf of the client, so only well-formed
+    /// ioctls which are known to be safe should be listed here, e.g. `FS_IOC_GETFLAGS`. All other
+    /// ioctls fail with `ENOTTY`.
+    ///
+    /// If `None`, all ioctls are passed through when the `unsafe-ioctl` feature is enabled, and
+    /// no ioctl is passed through otherwise.
+    ///
+    /// The default value for this option is `None`.
+    pub ioctl_allowlist: Option<Vec<u32>>,
+
+    /// Use `statx(2)` instead of `fstatat(2)` to get the attributes of inodes in `getattr`.
+    /// `fstatat(2)` is used anyway if the kernel doesn't support `statx(2)`.
+    ///
+    /// The default value for this option is `true`.
+    pub use_statx: bool,
+
+    /// Mark mount points inside the shared directory as submounts in lookup replies, so that the
+    /// kernel creates a separate mount for each of them.
+    ///
+    /// Mount points are detected by `STATX_ATTR_MOUNT_ROOT`, or by having a different `st_dev`
+    /// than their parent directory. Only takes effect when the kernel supports `FUSE_SUBMOUNTS`.
+    ///
+    /// The default value for this option is `false`.
+    pub announce_submounts: bool,
+
+    /// Whether to resolve names with `openat2(2)`, restricted to the directory they're looked up
+    /// in.
+    ///
+    /// Names are resolved with `RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS`, so that symlinks and
+    /// magic links such as `/proc/self/root` can't lead out of the shared directory, failing with
+    /// `EXDEV` or `ELOOP` instead. Falls back to `openat(2)` with `O_NOFOLLOW` if the host kernel
+    /// doesn't support `openat2(2)`.
+    ///
+    /// The default value for this option is `true`.
+    pub resolve_beneath: bool,
+
+    /// Refuse to look up mount points inside the shared directory, failing with `EXDEV`.
+    ///
+    /// This additionally passes `RESOLVE_NO_XDEV` to `openat2(2)`, so it only takes effect with
+    /// `resolve_beneath` and if the host kernel supports `openat2(2)`. Leave it disabled for
+    /// submounts to be reachable.
+    ///
+    /// The default value for this option is `false`.
+    pub no_xdev: bool,
+
+    /// Mapping of user ids between the FUSE client and the host, for running the file system on
+    /// behalf of a container in another user namespace.
+    ///
+    /// User ids of the caller and of `setattr` requests are translated into host ids, and owners
+    /// of files are translated back in replies. Ids without a mapping in either direction are
+    /// squashed to `overflow_uid`.
+    ///
+    /// The default value for this option is `None`, ids are passed through as is.
+    pub uid_map: Option<UidGidMap>,
+
+    /// Mapping of group ids between the FUSE client and the host, see `uid_map`.
+    ///
+    /// The default value for this option is `None`, ids are passed through as is.
+    pub gid_map: Option<UidGidMap>,
+
+    /// User id that ids without a mapping in `uid_map` are squashed to.
+    ///
+    /// The default value for this option is `OVERFLOW_ID`.
+    pub overflow_uid: u32,
+
+    /// Group id that ids without a mapping in `gid_map` are squashed to.
+    ///
+    /// The default value for this option is `OVERFLOW_ID`.
+    pub overflow_gid: u32,
+
+    /// Whether to create files with the supplementary groups of the caller, so files can be
+    /// created in directories only writable by one of those groups.
+    ///
+    /// The groups are read from `/proc/<pid>/status` of the calling process, which only makes
+    /// sense if the pids of callers are meaningful to the file system daemon, i.e. with fusedev
+    /// in the same pid namespace. Kernels supporting `FUSE_CREATE_SUPP_GROUP` also send the group
+    /// a new file would belong to when the caller only has it as a supplementary group, which
+    /// works with virtiofs as well. It requires `CAP_SETGID`.
+    ///
+    /// The default value for this option is `false`.
+    pub supp_groups: bool,
+
+    /// Which callers to run requests as `anon_uid` and `anon_gid` for, so that root of an
+    /// untrusted client doesn't act as root on the host.
+    ///
+    /// Squashed callers get no supplementary groups. Owners of `setattr` requests which are root
+    /// are squashed as well, so squashed files can't be given back to root. Operations of the
+    /// file system daemon itself aren't affected.
+    ///
+    /// The default value for this option is `SquashPolicy::None`.
+    pub squash: SquashPolicy,
+
+    /// Host user id that squashed callers run as, see `squash`.
+    ///
+    /// The default value for this option is `OVERFLOW_ID`.
+    pub anon_uid: u32,
+
+    /// Host group id that squashed callers run as, see `squash`.
+    ///
+    /// The default value for this option is `OVERFLOW_ID`.
+    pub anon_gid: u32,
+
+    /// Prefix rules to allow, deny or remap extended attributes, in the syntax described in the
+    /// `xattrmap` module, e.g. `:bad:all:trusted.:trusted.: :ok:all:::`.
+    ///
+    /// Names of extended attributes from the client are translated or denied before calling
+    /// `{get,set,remove}xattr(2)` on the host, and names listed by `listxattr(2)` are translated
+    /// back or hidden from the client. Only takes effect when `xattr` is enabled.
+    ///
+    /// The default value for this option is `None`, all extended attributes are passed through.
+    pub xattr_permissions: Option<XattrMap>,
+
+    /// Prefixes of extended attribute names to hide from the client, e.g. `trusted.` and
+    /// `security.`.
+    ///
+    /// Matching names are removed from the list returned by `listxattr(2)`, and getting or removing
+    /// them fails with `ENODATA` as if they didn't exist, while setting them fails with `EPERM`.
+    /// This avoids listing attributes that an unprivileged user of the client can never read when
+    /// the daemon runs as root. Names are matched as seen by the client, i.e. after translation by
+    /// `xattr_permissions`. Only takes effect when `xattr` is enabled.
+    ///
+    /// The default value for this option is empty, no extended attribute is hidden.
+    pub hidden_xattr_prefixes: Vec<String>,
+
+    /// Prefixes of extended attribute names the client may use, and prefixes to replace in names
+    /// from the client, e.g. to store `user.` attributes of the client as `trusted.` attributes on
+    /// the host.
+    ///
+    /// Using names which aren't allowed fails with `EPERM`. Names listed by `listxattr(2)` are
+    /// translated back, and hidden from the client if they aren't allowed, or if the client can't
+    /// address them because their translated name would be remapped as well. The map applies to
+    /// names as seen by the client, before `xattr_permissions`. Only takes effect when `xattr` is
+    /// enabled.
+    ///
+    /// The default value for this option is `None`, names are neither filtered nor remapped.
+    pub xattr_prefix_map: Option<XattrPrefixMap>,
+
+    /// Whether to support POSIX ACLs.
+    ///
+    /// If enabled, `FUSE_POSIX_ACL` and `FUSE_DONT_MASK` are negotiated with the kernel. The
+    /// caller's umask is then applied by the host kernel when creating new files, which ignores it
+    /// if the parent directory has a default ACL, so that new files inherit the default ACL. ACLs
+    /// are stored in the `system.posix_acl_*` extended attributes, so `xattr` must be enabled too.
+    ///
+    /// The default value for this option is `false`.
+    pub posix_acl: bool,
+
+    /// Maximum number of inodes to keep track of.
+    ///
+    /// Inodes forgotten by the kernel are kept to reuse their inode numbers when the files are
+    /// looked up again, unless `use_host_ino` is enabled. Once the number of inodes exceeds the
+    /// limit, the least recently used forgotten inodes are dropped, and get new inode numbers
+    /// when looked up again. Inodes still referenced by the kernel are never dropped, so the limit
+    /// may be exceeded when the kernel references too many inodes.
+    ///
+    /// The default value for this option is `None`, forgotten inodes are kept forever.
+    pub max_inodes: Option<usize>,
+
+    /// How inode numbers are allocated to files, unless `use_host_ino` is enabled.
+    ///
+    /// The default value for this option is `InodeAllocPolicy::Monotonic`.
+    pub inode_alloc_policy: InodeAllocPolicy,
+
+    /// Number of shards of the inode map.
+    ///
+    /// Inodes are spread over shards by their inode numbers, each shard with its own lock, so
+    /// concurrent requests on different inodes seldom wait for each other.
+    ///
+    /// The default value for this option is `64`.
+    pub inode_map_shards: usize,
+
+    /// Whether to look up names case-insensitively.
+    ///
+    /// If enabled and no entry matches a name exactly, the parent directory is scanned for an
+    /// entry matching it when case is ignored. Directory listings are cached until the
+    /// modification time of the directory changes. When several entries only differ by case, the
+    /// smallest name in byte order is used. Only lookups are affected, new files are created with
+    /// the given name.
+    ///
+    /// The default value for this option is `false`.
+    pub case_insensitive: bool,
+
+    /// How long to remember that a name doesn't exist in a directory, so that probing the name
+    /// again fails with `ENOENT` without looking it up on the host.
+    ///
+    /// Names created through the file system are forgotten right away, but names created on the
+    /// host are only found once the entry expires.
+    ///
+    /// The default value for this option is `None`, missing names aren't remembered.
+    pub negative_cache_ttl: Option<Duration>,
+
+    /// How `readdir` reports the inode numbers of directory entries. `readdirplus` always looks
+    /// entries up.
+    ///
+    /// Looking every entry up opens and stats it, which is costly for large directories. See
+    /// `ReaddirInoPolicy` for when inode numbers of the host may be reported instead.
+    ///
+    /// The default value for this option is `ReaddirInoPolicy::Exact`.
+    pub readdir_ino: ReaddirInoPolicy,
+
+    /// Size of the largest buffer for the entries of `getdents64(2)` kept by each thread serving
+    /// requests, so that readdir requests don't each allocate one of the requested size.
+    ///
+    /// Buffers larger than this, for requests of a larger size, are freed once the reply has been
+    /// filled in. `0` frees every buffer.
+    ///
+    /// The default value for this option is `1 MiB`.
+    pub readdir_buf_cache_size: usize,
+
+    /// Number of bytes read at once by sequential reads of the async read path, the data beyond
+    /// the request being kept in a buffer of the handle to serve the next reads. Reads aren't
+    /// buffered if `0`.
+    ///
+    /// Buffers are dropped by writes and seeks through the same handle, but not by changes made
+    /// through other handles or on the host, so this is meant for files which don't change.
+    ///
+    /// The default value for this option is `0`.
+    pub readahead: usize,
+
+    /// Whether to watch the directories looked up with inotify(7), and invalidate the data cached
+    /// by the kernel for files closed after being written to on the host. Events are only
+    /// handled by `PassthroughFs::handle_inotify_events()`, and invalidations are sent by the
+    /// notifier set with `PassthroughFs::set_inval_notifier()`.
+    ///
+    /// Each directory watched counts towards `/proc/sys/fs/inotify/max_user_watches`, directories
+    /// beyond the limit aren't watched.
+    ///
+    /// The default value for this option is `false`.
+    pub inotify_invalidate: bool,
+
+    /// Maximum number of open file handles, each holding a file descriptor.
+    ///
+    /// Opening more files than the limit is handled according to `handle_limit_policy`, so that
+    /// the FUSE client can't exhaust the file descriptors of the process.
+    ///
+    /// The default value for this option is `None`, the number of handles isn't limited.
+    pub max_handles: Option<usize>,
+
+    /// What to do when opening a file would exceed `max_handles`.
+    ///
+    /// The default value for this option is `HandleLimitPolicy::RejectNew`.
+    pub handle_limit_policy: HandleLimitPolicy,
+
+    /// Number of shards of the file handle map.
+    ///
+    /// Handles are spread over shards by their handle numbers, each shard with its own lock, so
+    /// concurrent requests on different handles seldom wait for each other.
+    ///
+    /// The default value for this option is `64`.
+    pub handle_map_shards: usize,
+
+    /// Whether to export the shared directory read-only, whatever the client asks for.
+    ///
+    /// Requests modifying the shared directory fail with `EROFS` before reaching the host, as do
+    /// opens for writing or truncating and ioctls passing data to the host, and `statfs` reports a
+    /// read-only file system. Unlike a read-only mount in the client, this can't be bypassed by the
+    /// client.
+    ///
+    /// The default value for this option is `false`.
+    pub read_only: bool,
+
+    /// Configuration file to reload when the process receives `SIGUSR2`, see
+    /// `Config::reload_from_file()` for its format.
+    ///
+    /// The signal handler is installed by `init`, and the file is reloaded by the next call to
+    /// `PassthroughFs::reload_if_requested()` after the signal. The timeouts, `cache_policy` and
+    /// `xattr` of the reloaded configuration are then applied to the following requests, see
+    /// `PassthroughFs::apply_config_update()`.
+    ///
+    /// The default value for this option is `None`, the configuration isn't reloaded.
+    pub config_file: Option<PathBuf>,
+
+    /// Maximum number of `O_PATH` file descriptors held by inodes, unless `inode_file_handles` is
+    /// enabled.
+    ///
+    /// Once the limit is exceeded, the fds of the least recently used inodes are closed. The
+    /// inodes keep their numbers, and their files are reopened when used again, by their file
+    /// handles if the file system supports them, or else by their paths. An inode whose file has
+    /// been replaced or removed in the meantime fails with `ESTALE`. Open file handles are never
+    /// affected.
+    ///
+    /// The default value for this option is `None`, inodes always hold their fds.
+    pub max_path_fds: Option<usize>,
+
+    /// Populate the inode map with the inodes down to this depth below the shared directory at
+    /// import, see `PassthroughFs::pre_warm()`. Inodes of the shared directory itself are at depth
+    /// one.
+    ///
+    /// The default value for this option is `None`, no inode is known before being looked up.
+    pub pre_warm_depth: Option<usize>,
+
+    /// Maximum number of inodes populated at import with `pre_warm_depth`.
+    ///
+    /// The default value for this option is `None`, only the depth is limited.
+    pub pre_warm_max_inodes: Option<usize>,
 }
 
 impl Default for Config {
@@ -191,6 +697,8 @@ impl Default for Config {
             cache_policy: Default::default(),
             writeback: false,
             root_dir: String::from("/"),
+            root_fd: None,
+            root_is_file: false,
             xattr: false,
             do_import: true,
             no_open: false,
@@ -201,10 +709,117 @@ impl Default for Config {
             seal_size: false,
             enable_mntid: false,
             dax_file_size: None,
+            perfile_dax_xattr: false,
             dir_entry_timeout: None,
             dir_attr_timeout: None,
+            file_entry_timeout: None,
+            file_attr_timeout: None,
+            symlink_entry_timeout: None,
+            symlink_attr_timeout: None,
             use_host_ino: false,
             allow_direct_io: true,
+            use_io_uring: false,
+            noatime: false,
+            sync_on_close: false,
+            notify_on_rename: false,
+            allow_clone_range: false,
+            fuse_passthrough: false,
+            ioctl_allowlist: None,
+            use_statx: true,
+            announce_submounts: false,
+            resolve_beneath: true,
+            no_xdev: false,
+            uid_map: None,
+            gid_map: None,
+            overflow_uid: OVERFLOW_ID,
+            overflow_gid: OVERFLOW_ID,
+            supp_groups: false,
+            squash: SquashPolicy::None,
+            anon_uid: OVERFLOW_ID,
+            anon_gid: OVERFLOW_ID,
+            xattr_permissions: None,
+            hidden_xattr_prefixes: Vec::new(),
+            xattr_prefix_map: None,
+            posix_acl: false,
+            max_inodes: None,
+            inode_alloc_policy: InodeAllocPolicy::Monotonic,
+            inode_map_shards: 64,
+            case_insensitive: false,
+            negative_cache_ttl: None,
+            readdir_ino: ReaddirInoPolicy::Exact,
+            readdir_buf_cache_size: 1 << 20,
+            readahead: 0,
+            inotify_invalidate: false,
+            max_handles: None,
+            handle_limit_policy: HandleLimitPolicy::RejectNew,
+            handle_map_shards: 64,
+            read_only: false,
+            config_file: None,
+            max_path_fds: None,
+            pre_warm_depth: None,
+            pre_warm_max_inodes: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load a configuration from the file at `path`.
+    ///
+    /// The file holds one `key = value` option per line, empty lines and lines starting with `#`
+    /// are ignored. Options which aren't listed keep their default value. The supported keys are
+    /// `root_dir`, `do_import`, `cache_policy`, `xattr`, `entry_timeout`, `attr_timeout` and the
+    /// `dir_`, `file_` and `symlink_` variants of the timeouts, which are given in seconds.
+    pub fn reload_from_file(path: &Path) -> io::Result<Config> {
+        let invalid = |line: usize, msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), line, msg),
+            )
+        };
+        let duration = |value: &str| {
+            value
+                .parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or("invalid duration")
+        };
+        let boolean = |value: &str| value.parse::<bool>().map_err(|_| "invalid boolean");
+
+        let mut cfg = Config::default();
+        for (idx, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(idx + 1, "expected `key = value`"))?;
+            let value = value.trim();
+            let res = match key.trim() {
+                "root_dir" => {
+                    cfg.root_dir = value.to_string();
+                    Ok(())
+                }
+                "do_import" => boolean(value).map(|v| cfg.do_import = v),
+                "cache_policy" => value.parse().map(|v| cfg.cache_policy = v),
+                "xattr" => boolean(value).map(|v| cfg.xattr = v),
+                "entry_timeout" => duration(value).map(|v| cfg.entry_timeout = v),
+                "attr_timeout" => duration(value).map(|v| cfg.attr_timeout = v),
+                "dir_entry_timeout" => duration(value).map(|v| cfg.dir_entry_timeout = Some(v)),
+                "dir_attr_timeout" => duration(value).map(|v| cfg.dir_attr_timeout = Some(v)),
+                "file_entry_timeout" => duration(value).map(|v| cfg.file_entry_timeout = Some(v)),
+                "file_attr_timeout" => duration(value).map(|v| cfg.file_attr_timeout = Some(v)),
+                "symlink_entry_timeout" => {
+                    duration(value).map(|v| cfg.symlink_entry_timeout = Some(v))
+                }
+                "symlink_attr_timeout" => {
+                    duration(value).map(|v| cfg.symlink_attr_timeout = Some(v))
+                }
+                _ => Err("unknown option"),
+            };
+            res.map_err(|msg| invalid(idx + 1, msg))?;
         }
+
+        Ok(cfg)
     }
 }
@@ -0,0 +1,115 @@
+// Copyright (C) 2020-2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Query the extent mapping of files with the `FS_IOC_FIEMAP` ioctl.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_iowr_nr};
+
+/// Sync the file before mapping its extents.
+pub const FIEMAP_FLAG_SYNC: u32 = 0x1;
+/// Map the extended attribute tree instead of the file data.
+pub const FIEMAP_FLAG_XATTR: u32 = 0x2;
+
+/// Last extent in the file.
+pub const FIEMAP_EXTENT_LAST: u32 = 0x1;
+/// Data location is unknown.
+pub const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+/// Location is still pending, implies `FIEMAP_EXTENT_UNKNOWN`.
+pub const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+/// Space is allocated, but no data is written yet.
+pub const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+/// Space is shared with other files.
+pub const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+// Number of extents fetched by each `FS_IOC_FIEMAP` call.
+const FIEMAP_BATCH_SIZE: usize = 32;
+
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+ioctl_iowr_nr!(FS_IOC_FIEMAP, b'f' as u32, 11, Fiemap);
+
+/// A physical extent of a file, as reported by `FS_IOC_FIEMAP`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FiemapExtent {
+    /// Byte offset of the extent in the file.
+    pub fe_logical: u64,
+    /// Byte offset of the extent on disk.
+    pub fe_physical: u64,
+    /// Length of the extent in bytes.
+    pub fe_length: u64,
+    fe_reserved64: [u64; 2],
+    /// `FIEMAP_EXTENT_*` flags of the extent.
+    pub fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+// `struct fiemap` followed by its variable length extent array.
+#[repr(C)]
+struct FiemapBuf {
+    fiemap: Fiemap,
+    extents: [FiemapExtent; FIEMAP_BATCH_SIZE],
+}
+
+/// Get the extents of `file` within the byte range [`start`, `start` + `len`).
+///
+/// `flags` is a combination of `FIEMAP_FLAG_*`. Holes in the file have no extent, so they show up
+/// as gaps between the returned extents.
+pub fn fiemap(
+    file: &impl AsRawFd,
+    start: u64,
+    len: u64,
+    mut flags: u32,
+) -> io::Result<Vec<FiemapExtent>> {
+    let end = start.saturating_add(len);
+    let mut pos = start;
+    let mut extents = Vec::new();
+
+    while pos < end {
+        let mut buf = FiemapBuf {
+            fiemap: Fiemap {
+                fm_start: pos,
+                fm_length: end - pos,
+                fm_flags: flags,
+                fm_extent_count: FIEMAP_BATCH_SIZE as u32,
+                ..Default::default()
+            },
+            extents: [FiemapExtent::default(); FIEMAP_BATCH_SIZE],
+        };
+
+        // Safe because the kernel writes at most `fm_extent_count` extents following the header
+        // in `buf`, and we check the return value.
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP() as _, &mut buf) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mapped = &buf.extents[..buf.fiemap.fm_mapped_extents as usize];
+        let last = match mapped.last() {
+            Some(e) => *e,
+            None => break,
+        };
+        extents.extend_from_slice(mapped);
+        if last.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+            break;
+        }
+
+        pos = last.fe_logical + last.fe_length;
+        // The file has been synced by the first call already.
+        flags &= !FIEMAP_FLAG_SYNC;
+    }
+
+    Ok(extents)
+}
@@ -253,6 +253,44 @@ impl FileHandle {
         Self::from_name_at(fd, empty_path)
     }
 
+    /// Create a file handle from its type and opaque bytes, as returned by `handle_type()` and
+    /// `as_bytes()`, for the mount `mnt_id`.
+    pub fn from_raw(mnt_id: MountId, handle_type: libc::c_int, bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() > MAX_HANDLE_SIZE {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let mut c_fh = CFileHandle::new(bytes.len());
+        let fh = c_fh.wrapper.as_mut_fam_struct();
+        fh.handle_type = handle_type;
+        // Safe because the flexible array has been allocated with `bytes.len()` elements.
+        unsafe {
+            fh.f_handle
+                .as_mut_slice(bytes.len())
+                .copy_from_slice(&*(bytes as *const [u8] as *const [libc::c_char]));
+        }
+
+        Ok(FileHandle {
+            mnt_id,
+            handle: c_fh,
+        })
+    }
+
+    /// Get the type of the file handle, which is opaque to user space.
+    pub fn handle_type(&self) -> libc::c_int {
+        self.handle.wrapper.as_fam_struct_ref().handle_type
+    }
+
+    /// Get the opaque bytes identifying the file on its file system.
+    pub fn as_bytes(&self) -> &[u8] {
+        let fh = self.handle.wrapper.as_fam_struct_ref();
+        // Safe because the flexible array has `handle_bytes` elements, and `c_char` has the
+        // size and alignment of `u8`.
+        unsafe {
+            let handle = fh.f_handle.as_slice(fh.handle_bytes as usize);
+            &*(handle as *const [libc::c_char] as *const [u8])
+        }
+    }
+
     /// Return an openable copy of the file handle by ensuring that `mount_fd` contains a valid fd
     /// for the mount the file handle is for.
     ///
@@ -432,4 +470,16 @@ mod tests {
             0
         );
     }
+
+    #[test]
+    fn test_file_handle_from_raw() {
+        let dir = File::open(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let filename = CString::new("build.rs").unwrap();
+        let handle = FileHandle::from_name_at(&dir, &filename).unwrap().unwrap();
+
+        let copy =
+            FileHandle::from_raw(handle.mnt_id, handle.handle_type(), handle.as_bytes()).unwrap();
+        assert_eq!(copy, handle);
+        assert!(FileHandle::from_raw(0, 1, &[0; MAX_HANDLE_SIZE + 1]).is_err());
+    }
 }
@@ -0,0 +1,196 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Drop the capabilities a passthrough file system daemon doesn't need.
+//!
+//! The daemon usually starts as root, while [PassthroughFs](../struct.PassthroughFs.html) only
+//! needs a handful of capabilities, depending on its [Config](../struct.Config.html). Keeping only
+//! those limits the damage a compromised daemon can do.
+
+use std::fmt;
+use std::io;
+
+use caps::{CapSet, Capability, CapsHashSet};
+
+use super::Config;
+
+/// Capabilities kept and dropped by [drop_capabilities](fn.drop_capabilities.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapabilityReport {
+    /// Capabilities kept in the permitted and effective sets.
+    pub kept: CapsHashSet,
+    /// Capabilities which were permitted before, and have been dropped.
+    pub dropped: CapsHashSet,
+}
+
+impl fmt::Display for CapabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kept [{}], dropped [{}]",
+            cap_names(&self.kept),
+            cap_names(&self.dropped)
+        )
+    }
+}
+
+// List capabilities by their index, so the output is stable.
+fn cap_names(caps: &CapsHashSet) -> String {
+    let mut caps: Vec<_> = caps.iter().collect();
+    caps.sort_by_key(|c| c.index());
+    caps.iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Get the capabilities needed to serve a file system configured with `cfg`.
+///
+/// - `CAP_SETUID` and `CAP_SETGID` to run requests with the credentials of callers.
+/// - `CAP_DAC_OVERRIDE` to open files on behalf of callers.
+/// - `CAP_CHOWN`, `CAP_FOWNER` and `CAP_MKNOD` to set owners and attributes of files, and to
+///   create device nodes and whiteouts, unless `read_only` is set.
+/// - `CAP_FSETID` to keep the setuid and setgid bits on writes, with `killpriv_v2`.
+/// - `CAP_DAC_READ_SEARCH` to open files by their handles, with `inode_file_handles`.
+///
+/// Extended attributes in the `trusted.` and `security.` namespaces need `CAP_SYS_ADMIN`, which
+/// isn't included.
+pub fn required_capabilities(cfg: &Config) -> CapsHashSet {
+    let mut caps = CapsHashSet::new();
+    caps.insert(Capability::CAP_SETUID);
+    caps.insert(Capability::CAP_SETGID);
+    caps.insert(Capability::CAP_DAC_OVERRIDE);
+    if !cfg.read_only {
+        caps.insert(Capability::CAP_CHOWN);
+        caps.insert(Capability::CAP_FOWNER);
+        caps.insert(Capability::CAP_MKNOD);
+    }
+    if cfg.killpriv_v2 {
+        caps.insert(Capability::CAP_FSETID);
+    }
+    if cfg.inode_file_handles {
+        caps.insert(Capability::CAP_DAC_READ_SEARCH);
+    }
+    caps
+}
+
+/// Drop all capabilities except those needed by `cfg` and those in `extra`, and set
+/// `PR_SET_NO_NEW_PRIVS` so they can't be regained by executing programs.
+///
+/// Fail without changing anything if a needed capability isn't permitted, rather than serving
+/// requests which are bound to fail.
+///
+/// Capabilities belong to threads, so this must be called before spawning the threads serving
+/// requests, which inherit the capabilities of the calling thread.
+pub fn drop_capabilities(cfg: &Config, extra: &[Capability]) -> io::Result<CapabilityReport> {
+    let mut kept = required_capabilities(cfg);
+    kept.extend(extra.iter().copied());
+
+    let permitted = caps::read(None, CapSet::Permitted).map_err(caps_error)?;
+    let missing: CapsHashSet = kept.difference(&permitted).copied().collect();
+    if !missing.is_empty() {
+        error!(
+            "passthroughfs: missing capabilities [{}] required by the configuration",
+            cap_names(&missing)
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("missing capabilities [{}]", cap_names(&missing)),
+        ));
+    }
+
+    // The effective and inheritable sets must be subsets of the permitted set, so shrink them
+    // first. Lowering the inheritable set clears the ambient set as well.
+    caps::set(None, CapSet::Effective, &kept).map_err(caps_error)?;
+    caps::clear(None, CapSet::Inheritable).map_err(caps_error)?;
+    caps::set(None, CapSet::Permitted, &kept).map_err(caps_error)?;
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let dropped = permitted.difference(&kept).copied().collect();
+    Ok(CapabilityReport { kept, dropped })
+}
+
+fn caps_error(e: caps::errors::CapsError) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_capabilities() {
+        let caps = required_capabilities(&Config::default());
+        assert!(caps.contains(&Capability::CAP_SETUID));
+        assert!(caps.contains(&Capability::CAP_SETGID));
+        assert!(caps.contains(&Capability::CAP_DAC_OVERRIDE));
+        assert!(caps.contains(&Capability::CAP_CHOWN));
+        assert!(caps.contains(&Capability::CAP_FOWNER));
+        assert!(caps.contains(&Capability::CAP_MKNOD));
+        assert!(!caps.contains(&Capability::CAP_FSETID));
+        assert!(!caps.contains(&Capability::CAP_DAC_READ_SEARCH));
+        assert!(!caps.contains(&Capability::CAP_SYS_ADMIN));
+
+        let cfg = Config {
+            read_only: true,
+            ..Default::default()
+        };
+        let caps = required_capabilities(&cfg);
+        assert_eq!(caps.len(), 3);
+        assert!(!caps.contains(&Capability::CAP_CHOWN));
+        assert!(!caps.contains(&Capability::CAP_MKNOD));
+
+        let cfg = Config {
+            killpriv_v2: true,
+            inode_file_handles: true,
+            ..Default::default()
+        };
+        let caps = required_capabilities(&cfg);
+        assert!(caps.contains(&Capability::CAP_FSETID));
+        assert!(caps.contains(&Capability::CAP_DAC_READ_SEARCH));
+    }
+
+    #[test]
+    fn test_drop_capabilities() {
+        let all = caps::read(None, CapSet::Permitted).unwrap();
+        let cfg = Config {
+            read_only: true,
+            ..Default::default()
+        };
+        if !required_capabilities(&cfg).is_subset(&all) {
+            return;
+        }
+
+        // Capabilities belong to threads, so drop them in a thread of its own.
+        std::thread::spawn(move || {
+            let report = drop_capabilities(&cfg, &[Capability::CAP_FOWNER]).unwrap();
+            let mut kept = required_capabilities(&cfg);
+            kept.insert(Capability::CAP_FOWNER);
+            assert_eq!(report.kept, kept);
+            assert_eq!(report.dropped, all.difference(&kept).copied().collect());
+            assert_eq!(caps::read(None, CapSet::Permitted).unwrap(), kept);
+            assert_eq!(caps::read(None, CapSet::Effective).unwrap(), kept);
+            assert!(report
+                .to_string()
+                .starts_with("kept [CAP_DAC_OVERRIDE, CAP_FOWNER, "));
+            // Safe because this doesn't modify any memory.
+            assert_eq!(
+                unsafe { libc::prctl(libc::PR_GET_NO_NEW_PRIVS, 0, 0, 0, 0) },
+                1
+            );
+
+            // Dropped capabilities can't be kept anymore.
+            let cfg = Config::default();
+            let err = drop_capabilities(&cfg, &[]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(caps::read(None, CapSet::Permitted).unwrap(), kept);
+        })
+        .join()
+        .unwrap();
+    }
+}
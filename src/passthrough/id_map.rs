@@ -0,0 +1,134 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Translate user and group ids between the namespace of the FUSE client and the host.
+
+use std::str::FromStr;
+
+/// Default id that ids without a mapping are squashed to, the same as the kernel's default
+/// `overflowuid`.
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// A mapping of user or group ids between a container and the host.
+///
+/// The mapping consists of `(container_start, host_start, count)` ranges, mirroring the format of
+/// `/proc/<pid>/uid_map` and `/proc/<pid>/gid_map`: container ids
+/// `[container_start, container_start + count)` map to host ids
+/// `[host_start, host_start + count)`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct UidGidMap {
+    ranges: Vec<(u32, u32, u32)>,
+}
+
+impl UidGidMap {
+    /// Create a mapping from `(container_start, host_start, count)` ranges.
+    pub fn new(ranges: Vec<(u32, u32, u32)>) -> Self {
+        UidGidMap { ranges }
+    }
+
+    /// Get the ranges of the mapping.
+    pub fn ranges(&self) -> &[(u32, u32, u32)] {
+        &self.ranges
+    }
+
+    /// Translate an id of the container into an id of the host.
+    pub fn translate_in(&self, id: u32) -> Option<u32> {
+        self.ranges
+            .iter()
+            .find_map(|&(container, host, count)| Self::translate(id, container, host, count))
+    }
+
+    /// Translate an id of the host into an id of the container.
+    pub fn translate_out(&self, id: u32) -> Option<u32> {
+        self.ranges
+            .iter()
+            .find_map(|&(container, host, count)| Self::translate(id, host, container, count))
+    }
+
+    fn translate(id: u32, from: u32, to: u32, count: u32) -> Option<u32> {
+        let off = id.checked_sub(from)?;
+        if off < count {
+            to.checked_add(off)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for UidGidMap {
+    type Err = &'static str;
+
+    /// Parse a mapping in the format of `/proc/<pid>/uid_map`, one range per line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for line in s.lines().filter(|l| !l.trim().is_empty()) {
+            let fields = line
+                .split_whitespace()
+                .map(|f| f.parse::<u32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| "invalid id in id map")?;
+            match fields[..] {
+                [container, host, count] => ranges.push((container, host, count)),
+                _ => return Err("invalid range in id map"),
+            }
+        }
+
+        Ok(UidGidMap { ranges })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_map() {
+        let map = UidGidMap::new(vec![(0, 0, u32::MAX)]);
+        assert_eq!(map.translate_in(0), Some(0));
+        assert_eq!(map.translate_in(1000), Some(1000));
+        assert_eq!(map.translate_out(1000), Some(1000));
+        assert_eq!(map.translate_out(u32::MAX - 1), Some(u32::MAX - 1));
+    }
+
+    #[test]
+    fn test_shifted_map() {
+        let map = UidGidMap::new(vec![(0, 100000, 65536)]);
+        assert_eq!(map.translate_in(0), Some(100000));
+        assert_eq!(map.translate_in(1000), Some(101000));
+        assert_eq!(map.translate_in(65535), Some(165535));
+        assert_eq!(map.translate_in(65536), None);
+        assert_eq!(map.translate_out(100000), Some(0));
+        assert_eq!(map.translate_out(165535), Some(65535));
+        assert_eq!(map.translate_out(0), None);
+        assert_eq!(map.translate_out(165536), None);
+    }
+
+    #[test]
+    fn test_non_contiguous_map() {
+        let map = UidGidMap::new(vec![(0, 1000, 1), (1, 100000, 999), (1000, 1001, 10)]);
+        assert_eq!(map.translate_in(0), Some(1000));
+        assert_eq!(map.translate_in(1), Some(100000));
+        assert_eq!(map.translate_in(999), Some(100998));
+        assert_eq!(map.translate_in(1000), Some(1001));
+        assert_eq!(map.translate_in(1009), Some(1010));
+        assert_eq!(map.translate_in(1010), None);
+        assert_eq!(map.translate_out(1000), Some(0));
+        assert_eq!(map.translate_out(1005), Some(1004));
+        assert_eq!(map.translate_out(100998), Some(999));
+        assert_eq!(map.translate_out(999), None);
+
+        // Unmapped ids don't overflow at the end of the id space.
+        let map = UidGidMap::new(vec![(u32::MAX - 1, 10, 5)]);
+        assert_eq!(map.translate_out(14), None);
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let map: UidGidMap = "         0     100000      65536\n      1000       1000          1\n"
+            .parse()
+            .unwrap();
+        assert_eq!(map.ranges(), &[(0, 100000, 65536), (1000, 1000, 1)]);
+        assert!("0 100000".parse::<UidGidMap>().is_err());
+        assert!("0 x 1".parse::<UidGidMap>().is_err());
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Allocate the inode numbers of the file system, for `Config::inode_alloc_policy`.
+//!
+//! Inode numbers are handed back to the allocator once nothing refers to them anymore: the
+//! kernel has forgotten the inode, and the mapping of its file to its inode number has been
+//! dropped. Numbers handed back may be given to other files right away, the kernel can't mistake
+//! them for the old files. Each reuse of a number bumps its generation, so that file handles of
+//! the old files, e.g. of NFS exports, don't resolve to the new ones.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::config::InodeAllocPolicy;
+use super::Inode;
+use crate::abi::fuse_abi as fuse;
+
+/// Source of the inode numbers of new inodes.
+pub(super) trait InodeAllocator: Send + Sync {
+    /// Allocate an inode number which isn't in use.
+    fn allocate(&self) -> Inode;
+
+    /// Hand back `inode`, which isn't in use anymore.
+    fn release(&self, inode: Inode);
+
+    /// Get the generation of `inode`, which changes whenever the inode number is reused.
+    fn generation(&self, inode: Inode) -> u64;
+
+    /// Whether the inode numbers of forgotten inodes are kept for their files, so that files
+    /// looked up again get the same inode numbers. Otherwise their numbers are released as soon
+    /// as the kernel has forgotten them.
+    fn keep_forgotten(&self) -> bool;
+
+    /// The first inode number never allocated.
+    fn next(&self) -> Inode;
+
+    /// Allocate inode numbers from `next` on, forgetting the numbers released, e.g. when restoring
+    /// the inodes of another instance.
+    fn reset(&self, next: Inode);
+}
+
+// Allocate increasing inode numbers, never reusing them.
+struct MonotonicAllocator {
+    next: AtomicU64,
+}
+
+impl InodeAllocator for MonotonicAllocator {
+    fn allocate(&self) -> Inode {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn release(&self, _inode: Inode) {}
+
+    fn generation(&self, _inode: Inode) -> u64 {
+        0
+    }
+
+    fn keep_forgotten(&self) -> bool {
+        true
+    }
+
+    fn next(&self) -> Inode {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self, next: Inode) {
+        self.next.store(next, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct FreeList {
+    // Inode numbers released, oldest first.
+    free: VecDeque<Inode>,
+    // Generations of the inode numbers released at least once.
+    generations: HashMap<Inode, u64>,
+}
+
+// Reuse the inode numbers released, oldest first, before allocating new ones.
+struct FreeListAllocator {
+    next: AtomicU64,
+    list: Mutex<FreeList>,
+}
+
+impl InodeAllocator for FreeListAllocator {
+    fn allocate(&self) -> Inode {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        match self.list.lock().unwrap().free.pop_front() {
+            Some(inode) => inode,
+            None => self.next.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn release(&self, inode: Inode) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut list = self.list.lock().unwrap();
+        *list.generations.entry(inode).or_insert(0) += 1;
+        list.free.push_back(inode);
+    }
+
+    fn generation(&self, inode: Inode) -> u64 {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let list = self.list.lock().unwrap();
+        list.generations.get(&inode).copied().unwrap_or(0)
+    }
+
+    fn keep_forgotten(&self) -> bool {
+        false
+    }
+
+    fn next(&self) -> Inode {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self, next: Inode) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut list = self.list.lock().unwrap();
+        // Generations are kept, the kernel may still know files by earlier ones.
+        list.free.clear();
+        self.next.store(next, Ordering::Relaxed);
+    }
+}
+
+/// Create the allocator of `policy`, allocating the inode numbers after the root.
+pub(super) fn new_inode_allocator(policy: InodeAllocPolicy) -> Box<dyn InodeAllocator> {
+    let next = AtomicU64::new(fuse::ROOT_ID + 1);
+    match policy {
+        InodeAllocPolicy::Monotonic => Box::new(MonotonicAllocator { next }),
+        InodeAllocPolicy::FreeList => Box::new(FreeListAllocator {
+            next,
+            list: Mutex::new(FreeList::default()),
+        }),
+    }
+}
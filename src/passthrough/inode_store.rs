@@ -2,6 +2,7 @@
 // found in the LICENSE-BSD-3-Clause file.
 
 use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use super::file_handle::FileHandle;
@@ -27,64 +28,132 @@ impl InodeId {
     }
 }
 
+// Keys of a forgotten inode, kept to reuse its inode number.
+struct ForgottenInode {
+    id: InodeId,
+    handle: Option<Arc<FileHandle>>,
+}
+
+/// Keys of inodes, mapping the IDs and file handles of files to their inode numbers.
 #[derive(Default)]
 pub struct InodeStore {
-    data: BTreeMap<Inode, Arc<InodeData>>,
     by_id: BTreeMap<InodeId, Inode>,
     by_handle: BTreeMap<Arc<FileHandle>, Inode>,
+    // Maximum number of inodes, including forgotten ones, see `Config::max_inodes`.
+    max_inodes: Option<usize>,
+    // Mappings of forgotten inodes, ordered by their last use.
+    forgotten: BTreeMap<(u64, Inode), ForgottenInode>,
+    // Last use of forgotten inodes, to find them in `forgotten`.
+    forgotten_time: BTreeMap<Inode, u64>,
 }
 
 impl InodeStore {
-    /// Insert an inode into the manager
+    /// Create an inode key manager keeping at most `max_inodes` inodes, if possible.
+    pub fn new(max_inodes: Option<usize>) -> Self {
+        InodeStore {
+            max_inodes,
+            ..Default::default()
+        }
+    }
+
+    /// Insert the keys of an inode into the manager
     ///
     /// The caller needs to ensure that no inode with the same key exists, otherwise the old inode
     /// will get lost.
-    pub fn insert(&mut self, data: Arc<InodeData>) {
+    pub fn insert(&mut self, data: &InodeData) {
+        // The inode number of a forgotten inode is being reused.
+        if let Some(time) = self.forgotten_time.remove(&data.inode) {
+            self.forgotten.remove(&(time, data.inode));
+        }
+
         self.by_id.insert(data.id, data.inode);
         if let InodeHandle::Handle(handle) = &data.handle {
             self.by_handle
                 .insert(handle.file_handle().clone(), data.inode);
         }
-        self.data.insert(data.inode, data);
     }
 
-    /// Remove an inode from the manager, keeping the (key, ino) mapping if `remove_data_only` is true.
-    pub fn remove(&mut self, inode: &Inode, remove_data_only: bool) -> Option<Arc<InodeData>> {
-        let data = self.data.remove(inode);
-        if remove_data_only {
+    /// Remove the keys of an inode which isn't used anymore, keeping the (key, ino) mapping if
+    /// `keep_mapping` is true.
+    pub fn remove(&mut self, data: &InodeData, keep_mapping: bool) {
+        if keep_mapping {
             // Don't remove by_id and by_handle, we need use it to store inode
             // record the mapping of inodes using these two structures to ensure
             // that the same files always use the same inode
-            return data;
+            if self.max_inodes.is_some() {
+                let time = data.last_used.load(Ordering::Relaxed);
+                let handle = match &data.handle {
+                    InodeHandle::Handle(h) => Some(h.file_handle().clone()),
+                    InodeHandle::File(_) | InodeHandle::PathFd(_) => None,
+                };
+                let forgotten = ForgottenInode {
+                    id: data.id,
+                    handle,
+                };
+                self.forgotten.insert((time, data.inode), forgotten);
+                // The inode may have been forgotten twice by racing threads.
+                if let Some(old) = self.forgotten_time.insert(data.inode, time) {
+                    if old != time {
+                        self.forgotten.remove(&(old, data.inode));
+                    }
+                }
+            }
+            return;
         }
 
-        if let Some(data) = data.as_ref() {
-            if let InodeHandle::Handle(handle) = &data.handle {
+        if let InodeHandle::Handle(handle) = &data.handle {
+            if self.by_handle.get(handle.file_handle()) == Some(&data.inode) {
                 self.by_handle.remove(handle.file_handle());
             }
+        }
+        if self.by_id.get(&data.id) == Some(&data.inode) {
             self.by_id.remove(&data.id);
         }
-        data
     }
 
     pub fn clear(&mut self) {
-        self.data.clear();
         self.by_handle.clear();
         self.by_id.clear();
+        self.forgotten.clear();
+        self.forgotten_time.clear();
     }
 
-    pub fn get(&self, inode: &Inode) -> Option<&Arc<InodeData>> {
-        self.data.get(inode)
+    /// Get the number of forgotten inodes whose mappings are kept.
+    #[cfg(test)]
+    pub fn forgotten(&self) -> usize {
+        self.forgotten.len()
     }
 
-    pub fn get_by_id(&self, id: &InodeId) -> Option<&Arc<InodeData>> {
-        let inode = self.inode_by_id(id)?;
-        self.get(inode)
-    }
+    /// Drop mappings of the least recently used forgotten inodes until there are at most
+    /// `max_inodes` inodes along with the `live` inodes in use, or only inodes in use are left,
+    /// and return the inode numbers dropped.
+    pub fn evict(&mut self, live: usize) -> Vec<Inode> {
+        let mut evicted = Vec::new();
+        let max_inodes = match self.max_inodes {
+            Some(max) => max,
+            None => return evicted,
+        };
+
+        while live + self.forgotten.len() > max_inodes {
+            let (time, inode) = match self.forgotten.keys().next() {
+                Some(key) => *key,
+                None => break,
+            };
+            let ForgottenInode { id, handle } = self.forgotten.remove(&(time, inode)).unwrap();
+            self.forgotten_time.remove(&inode);
 
-    pub fn get_by_handle(&self, handle: &FileHandle) -> Option<&Arc<InodeData>> {
-        let inode = self.inode_by_handle(handle)?;
-        self.get(inode)
+            // Another inode may have taken over the key since, leave its mapping alone.
+            if self.by_id.get(&id) == Some(&inode) {
+                self.by_id.remove(&id);
+            }
+            if let Some(handle) = handle {
+                if self.by_handle.get(&handle) == Some(&inode) {
+                    self.by_handle.remove(&handle);
+                }
+            }
+            evicted.push(inode);
+        }
+        evicted
     }
 
     pub fn inode_by_id(&self, id: &InodeId) -> Option<&Inode> {
@@ -159,10 +228,14 @@ mod test {
         let inode_stat1 = StatExt {
             st: stat_fd(tmpfile1.as_file()).unwrap(),
             mnt_id: 0,
+            btime: None,
+            attributes: 0,
         };
         let inode_stat2 = StatExt {
             st: stat_fd(tmpfile2.as_file()).unwrap(),
             mnt_id: 0,
+            btime: None,
+            attributes: 0,
         };
         let id1 = InodeId::from_stat(&inode_stat1);
         let id2 = InodeId::from_stat(&inode_stat2);
@@ -170,48 +243,102 @@ mod test {
         let file_or_handle2 = InodeHandle::File(tmpfile2.into_file());
         let data1 = InodeData::new(inode1, file_or_handle1, 2, id1, inode_stat1.st.st_mode);
         let data2 = InodeData::new(inode2, file_or_handle2, 2, id2, inode_stat2.st.st_mode);
-        let data1 = Arc::new(data1);
-        let data2 = Arc::new(data2);
 
-        m.insert(data1.clone());
+        m.insert(&data1);
 
-        // get not present key, expect none
-        assert!(m.get(&1).is_none());
-
-        // get just inserted value by key, by id, by handle
-        assert!(m.get_by_id(&InodeId::default()).is_none());
-        assert!(m.get_by_handle(&FileHandle::default()).is_none());
-        assert_eq!(m.get(&inode1).unwrap(), &data1);
-        assert_eq!(m.get_by_id(&id1).unwrap(), &data1);
+        // get just inserted value by id, by handle
+        assert!(m.inode_by_id(&InodeId::default()).is_none());
+        assert!(m.inode_by_handle(&FileHandle::default()).is_none());
+        assert_eq!(m.inode_by_id(&id1), Some(&inode1));
 
         // insert another value, and check again
-        m.insert(data2.clone());
-        assert!(m.get(&1).is_none());
-        assert!(m.get_by_id(&InodeId::default()).is_none());
-        assert!(m.get_by_handle(&FileHandle::default()).is_none());
-        assert_eq!(m.get(&inode1).unwrap(), &data1);
-        assert_eq!(m.get_by_id(&id1).unwrap(), &data1);
-        assert_eq!(m.get(&inode2).unwrap(), &data2);
-        assert_eq!(m.get_by_id(&id2).unwrap(), &data2);
-
-        // remove non-present key
-        assert!(m.remove(&1, false).is_none());
-
-        // remove present key, return its value
-        assert_eq!(m.remove(&inode1, false).unwrap(), data1.clone());
-        assert!(m.get(&inode1).is_none());
-        assert!(m.get_by_id(&id1).is_none());
-        assert_eq!(m.get(&inode2).unwrap(), &data2);
-        assert_eq!(m.get_by_id(&id2).unwrap(), &data2);
+        m.insert(&data2);
+        assert!(m.inode_by_id(&InodeId::default()).is_none());
+        assert!(m.inode_by_handle(&FileHandle::default()).is_none());
+        assert_eq!(m.inode_by_id(&id1), Some(&inode1));
+        assert_eq!(m.inode_by_id(&id2), Some(&inode2));
+
+        // keep the mapping of a removed inode, without a limit nothing is forgotten
+        m.remove(&data1, true);
+        assert_eq!(m.inode_by_id(&id1), Some(&inode1));
+        assert_eq!(m.forgotten(), 0);
+
+        // remove present key
+        m.remove(&data1, false);
+        assert!(m.inode_by_id(&id1).is_none());
+        assert_eq!(m.inode_by_id(&id2), Some(&inode2));
+
+        // the key of a removed inode taken over by another inode is left alone
+        let data3 = InodeData::new(
+            5,
+            InodeHandle::File(TempFile::new().unwrap().into_file()),
+            1,
+            id2,
+            0,
+        );
+        m.insert(&data3);
+        m.remove(&data2, false);
+        assert_eq!(m.inode_by_id(&id2), Some(&5));
 
         // clear the map
         m.clear();
-        assert!(m.get(&1).is_none());
-        assert!(m.get_by_id(&InodeId::default()).is_none());
-        assert!(m.get_by_handle(&FileHandle::default()).is_none());
-        assert!(m.get(&inode1).is_none());
-        assert!(m.get_by_id(&id1).is_none());
-        assert!(m.get(&inode2).is_none());
-        assert!(m.get_by_id(&id2).is_none());
+        assert!(m.inode_by_id(&InodeId::default()).is_none());
+        assert!(m.inode_by_handle(&FileHandle::default()).is_none());
+        assert!(m.inode_by_id(&id1).is_none());
+        assert!(m.inode_by_id(&id2).is_none());
+    }
+
+    #[test]
+    fn test_inode_store_max_inodes() {
+        let mut m = InodeStore::new(Some(3));
+        let new_data = |inode: Inode| {
+            let file = TempFile::new().unwrap().into_file();
+            let st = StatExt {
+                st: stat_fd(&file).unwrap(),
+                mnt_id: 0,
+                btime: None,
+                attributes: 0,
+            };
+            let id = InodeId::from_stat(&st);
+            let data = InodeData::new(inode, InodeHandle::File(file), 1, id, st.st.st_mode);
+            data.last_used.store(inode, Ordering::Relaxed);
+            data
+        };
+        let data: Vec<_> = (1..=5).map(new_data).collect();
+
+        // Inodes in use are never dropped.
+        for d in data.iter().take(4) {
+            m.insert(d);
+        }
+        m.evict(4);
+        assert_eq!(m.inode_by_id(&data[3].id), Some(&4));
+        m.remove(&data[3], false);
+
+        // The mappings of forgotten inodes are dropped beyond the limit, least recently used
+        // first.
+        data[0].last_used.store(10, Ordering::Relaxed);
+        m.remove(&data[0], true);
+        m.remove(&data[1], true);
+        m.evict(1);
+        assert_eq!(m.forgotten(), 2);
+        m.insert(&data[3]);
+        assert_eq!(m.evict(2), [2]);
+        assert_eq!(m.forgotten(), 1);
+        assert_eq!(m.inode_by_id(&data[0].id), Some(&1));
+        assert!(m.inode_by_id(&data[1].id).is_none());
+
+        m.insert(&data[4]);
+        m.evict(3);
+        assert_eq!(m.forgotten(), 0);
+        assert!(m.inode_by_id(&data[0].id).is_none());
+
+        // Reusing a forgotten inode number brings the inode back into use.
+        m.remove(&data[2], true);
+        m.evict(2);
+        assert_eq!(m.inode_by_id(&data[2].id), Some(&3));
+        m.insert(&data[2]);
+        m.evict(3);
+        assert_eq!(m.forgotten(), 0);
+        assert_eq!(m.inode_by_id(&data[2].id), Some(&3));
     }
 }
@@ -0,0 +1,269 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Invalidate the data cached by the kernel for files changed out-of-band, for
+//! `Config::inotify_invalidate`.
+//!
+//! Directories are watched with inotify(7) once looked up, and files closed after being written
+//! to in a watched directory are invalidated through the notifier set with
+//! `PassthroughFs::set_inval_notifier()`. Only files already known to the kernel are invalidated,
+//! other files have nothing cached.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use vm_memory::bitmap::BitmapSlice;
+
+use super::inode_store::InodeId;
+use super::{Inode, InodeData, PassthroughFs};
+
+/// Callback sending `FUSE_NOTIFY_INVAL_INODE` to the kernel, for an inode of the file system and
+/// the range of `len` bytes at `offset` to invalidate, e.g. built around
+/// `transport::notify_inval_inode()` or `VfsNotifier::inval_inode()`.
+pub type InvalNotifier = Box<dyn Fn(Inode, i64, i64) -> io::Result<()> + Send + Sync>;
+
+// Room for a batch of events with long names.
+const INOTIFY_BUF_SIZE: usize = 16 * 1024;
+
+// The directories watched by an inotify instance.
+#[derive(Default)]
+struct Watches {
+    // Inodes of the watched directories, keyed by watch descriptor.
+    dirs: HashMap<i32, Inode>,
+    // Watch descriptors of the watched directories, keyed by inode.
+    wds: HashMap<Inode, i32>,
+}
+
+// An inotify instance watching the directories known to the kernel.
+pub(super) struct InotifyWatcher {
+    file: File,
+    watches: Mutex<Watches>,
+}
+
+impl InotifyWatcher {
+    pub fn new() -> io::Result<Self> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(InotifyWatcher {
+            // Safe because we just opened this fd.
+            file: unsafe { File::from_raw_fd(fd) },
+            watches: Mutex::new(Watches::default()),
+        })
+    }
+
+    // Watch the directory `inode` opened as `dir`.
+    fn watch(&self, inode: Inode, dir: &impl AsRawFd) -> io::Result<()> {
+        // inotify(7) only takes paths, go through the fd of the directory.
+        // The path doesn't contain NUL bytes, so safe to unwrap().
+        let path = CString::new(format!("/proc/self/fd/{}", dir.as_raw_fd())).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                self.file.as_raw_fd(),
+                path.as_ptr(),
+                libc::IN_CLOSE_WRITE | libc::IN_ONLYDIR,
+            )
+        };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut watches = self.watches.lock().unwrap();
+        if let Some(old) = watches.dirs.insert(wd, inode) {
+            if old != inode {
+                watches.wds.remove(&old);
+            }
+        }
+        watches.wds.insert(inode, wd);
+        Ok(())
+    }
+
+    // Stop watching the directory `inode`, if watched.
+    fn unwatch(&self, inode: Inode) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut watches = self.watches.lock().unwrap();
+        let wd = match watches.wds.remove(&inode) {
+            Some(wd) => wd,
+            None => return,
+        };
+        watches.dirs.remove(&wd);
+        // The watch is already gone if the directory has been removed meanwhile.
+        // Safe because this doesn't modify any memory.
+        unsafe { libc::inotify_rm_watch(self.file.as_raw_fd(), wd) };
+    }
+
+    // Get the number of directories watched.
+    #[cfg(test)]
+    pub fn watched(&self) -> usize {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.watches.lock().unwrap().dirs.len()
+    }
+
+    // Wait up to `timeout` milliseconds for files to be closed after being written to, and return
+    // the inodes of their directories with their names. A negative `timeout` waits forever.
+    fn wait(&self, timeout: i32) -> io::Result<Vec<(Inode, CString)>> {
+        let mut pfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because the kernel only writes to `pfd`, and we check the return value.
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(e);
+        }
+
+        let mut buf = vec![0u8; INOTIFY_BUF_SIZE];
+        let len = match (&self.file).read(&mut buf) {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut watches = self.watches.lock().unwrap();
+        let mut changed = Vec::new();
+        let mut pos = 0;
+        while pos + size_of::<libc::inotify_event>() <= len {
+            // Safe because the kernel wrote a whole event at `pos`, which may not be aligned.
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buf[pos..].as_ptr() as *const _) };
+            let name_start = pos + size_of::<libc::inotify_event>();
+            pos = name_start + event.len as usize;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                warn!("passthroughfs: inotify: event queue overflowed, changes were lost");
+                continue;
+            }
+            // The directory has been removed, or its file system unmounted.
+            if event.mask & libc::IN_IGNORED != 0 {
+                if let Some(dir) = watches.dirs.remove(&event.wd) {
+                    watches.wds.remove(&dir);
+                }
+                continue;
+            }
+            let dir = match watches.dirs.get(&event.wd) {
+                Some(dir) => *dir,
+                None => continue,
+            };
+            // The name is padded with NUL bytes.
+            if let Ok(name) = CStr::from_bytes_until_nul(&buf[name_start..pos]) {
+                if !name.to_bytes().is_empty() {
+                    changed.push((dir, name.to_owned()));
+                }
+            }
+        }
+        Ok(changed)
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Set the callback sending invalidations of inodes to the kernel, used by
+    /// `invalidate_inode()`.
+    pub fn set_inval_notifier(&self, notifier: InvalNotifier) {
+        self.inval_notifier.store(Some(Arc::new(notifier)));
+    }
+
+    /// Invalidate the cached attributes of `inode` and its cached data in the range of `len`
+    /// bytes at `offset`, e.g. after the file has been changed out-of-band, see
+    /// `Server::notify_inval_inode()` for the meaning of `offset` and `len`.
+    ///
+    /// Fail with `ENOTCONN` if no notifier has been set with `set_inval_notifier()`.
+    pub fn invalidate_inode(&self, inode: Inode, offset: i64, len: i64) -> io::Result<()> {
+        match self.inval_notifier.load().as_ref() {
+            Some(notifier) => notifier(inode, offset, len),
+            None => Err(io::Error::from_raw_os_error(libc::ENOTCONN)),
+        }
+    }
+
+    /// Wait for files to be closed after being written to in directories looked up, with
+    /// `Config::inotify_invalidate`, and invalidate the ones known to the kernel with
+    /// `invalidate_inode()`.
+    ///
+    /// Return how many inodes have been invalidated. The caller is expected to call this in a
+    /// loop, usually from a dedicated thread. Wait forever if `timeout` is `None`.
+    pub fn handle_inotify_events(&self, timeout: Option<Duration>) -> io::Result<usize> {
+        let watcher = self.inotify.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "inotify_invalidate isn't enabled",
+            )
+        })?;
+        let timeout = timeout
+            .map(|t| t.as_millis().min(i32::MAX as u128) as i32)
+            .unwrap_or(-1);
+
+        let mut invalidated = 0;
+        for (parent, name) in watcher.wait(timeout)? {
+            let dir = match self.inode_map.get(parent) {
+                Ok(dir) => dir,
+                Err(_) => continue,
+            };
+            // The file may have been removed meanwhile.
+            let (_path_fd, handle_opt, st) = match dir
+                .get_file()
+                .and_then(|dir_file| self.open_file_and_handle(&dir_file, &name))
+            {
+                Ok(res) => res,
+                Err(_) => continue,
+            };
+            let data = match self
+                .inode_map
+                .get_alt(&InodeId::from_stat(&st), handle_opt.as_ref())
+            {
+                Some(data) => data,
+                None => continue,
+            };
+            match self.invalidate_inode(data.inode, 0, 0) {
+                Ok(()) => invalidated += 1,
+                // The kernel has already forgotten the inode.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(invalidated)
+    }
+
+    // Watch the directory `data` for `Config::inotify_invalidate`.
+    pub(super) fn inotify_watch(&self, data: &InodeData) {
+        let watcher = match self.inotify.as_ref() {
+            Some(watcher) => watcher,
+            None => return,
+        };
+        if data.mode & libc::S_IFMT != libc::S_IFDIR {
+            return;
+        }
+        if let Err(e) = data
+            .get_file()
+            .and_then(|file| watcher.watch(data.inode, &file))
+        {
+            debug!(
+                "passthroughfs: failed to watch inode {} with inotify, {}",
+                data.inode, e
+            );
+        }
+    }
+
+    // Stop watching the directory `data`, once forgotten by the kernel.
+    pub(super) fn inotify_unwatch(&self, data: &InodeData) {
+        if let Some(watcher) = self.inotify.as_ref() {
+            if data.mode & libc::S_IFMT == libc::S_IFDIR {
+                watcher.unwatch(data.inode);
+            }
+        }
+    }
+}
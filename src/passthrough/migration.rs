@@ -0,0 +1,234 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Save and restore the inodes and handles of a passthrough file system, for live migration.
+//!
+//! Inodes are saved as file handles from `name_to_handle_at(2)`, so they can be opened again by
+//! another process, possibly on another host sharing the same file system, with
+//! `open_by_handle_at(2)`. Open handles are saved with their inode and flags, and reopened from
+//! their inode on restore. Locks, poll handles and file offsets aren't saved.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+#[cfg(feature = "persist")]
+use versionize::{VersionMap, Versionize, VersionizeResult};
+#[cfg(feature = "persist")]
+use versionize_derive::Versionize;
+use vm_memory::bitmap::BitmapSlice;
+
+use super::file_handle::FileHandle;
+use super::inode_store::InodeId;
+use super::statx::statx;
+use super::{Handle, HandleData, Inode, InodeData, InodeHandle, PassthroughFs};
+use crate::abi::fuse_abi as fuse;
+
+/// State of an inode in use by the kernel.
+#[cfg_attr(feature = "persist", derive(Versionize))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InodeState {
+    /// Inode number exposed to the kernel.
+    pub inode: Inode,
+    /// Number of lookups not forgotten by the kernel yet.
+    pub refcount: u64,
+    /// File type and mode.
+    pub mode: u32,
+    /// Inode number on the host, to validate the file handle on restore.
+    pub ino: u64,
+    /// Mount ID of the file handle.
+    pub mnt_id: u64,
+    /// Type of the file handle.
+    pub handle_type: i32,
+    /// Opaque bytes of the file handle.
+    pub handle: Vec<u8>,
+}
+
+/// State of a handle opened by the kernel.
+#[cfg_attr(feature = "persist", derive(Versionize))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HandleState {
+    /// Handle number exposed to the kernel.
+    pub handle: Handle,
+    /// Inode the handle has been opened for.
+    pub inode: Inode,
+    /// Flags the handle has been opened with.
+    pub flags: u32,
+}
+
+/// State of a `PassthroughFs`, see `PassthroughFs::save_state()`.
+#[cfg_attr(feature = "persist", derive(Versionize))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PassthroughFsState {
+    /// Next inode number to allocate.
+    pub next_inode: u64,
+    /// Next handle number to allocate.
+    pub next_handle: u64,
+    /// Inodes in use, including the root inode.
+    pub inodes: Vec<InodeState>,
+    /// Open handles.
+    pub handles: Vec<HandleState>,
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Save the inodes in use and the open handles, to restore them with `restore_state()`.
+    ///
+    /// Inodes are saved by their file handles, so this fails with `EINVAL` unless
+    /// `Config::inode_file_handles` is set. Requests shouldn't be handled while saving.
+    pub fn save_state(&self) -> io::Result<PassthroughFsState> {
+        if !self.cfg.inode_file_handles {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "saving the state needs inode_file_handles",
+            ));
+        }
+
+        let mut inodes = Vec::new();
+        for shard in self.inode_map.shards.iter() {
+            // Do not expect poisoned lock here, so safe to unwrap().
+            for data in shard.read().unwrap().values() {
+                let handle = match data.handle.file_handle() {
+                    Some(handle) => handle,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            format!("no file handle for inode {}", data.inode),
+                        ))
+                    }
+                };
+                inodes.push(InodeState {
+                    inode: data.inode,
+                    refcount: data.refcount.load(Ordering::Relaxed),
+                    mode: data.mode,
+                    ino: data.id.ino,
+                    mnt_id: handle.mnt_id,
+                    handle_type: handle.handle_type(),
+                    handle: handle.as_bytes().to_vec(),
+                });
+            }
+        }
+        inodes.sort_by_key(|i| i.inode);
+
+        let mut handles = Vec::new();
+        for shard in self.handle_map.shards.iter() {
+            // Do not expect poisoned lock here, so safe to unwrap().
+            for (handle, data) in shard.read().unwrap().iter() {
+                handles.push(HandleState {
+                    handle: *handle,
+                    inode: data.inode,
+                    flags: data.get_flags(),
+                });
+            }
+        }
+        handles.sort_by_key(|h| h.handle);
+
+        Ok(PassthroughFsState {
+            next_inode: self.inode_map.allocator.next(),
+            next_handle: self.next_handle.load(Ordering::Relaxed),
+            inodes,
+            handles,
+        })
+    }
+
+    /// Restore the inodes and handles saved by `save_state()`, after `import()` and before
+    /// handling any request.
+    ///
+    /// File handles of the mount of the root directory are opened on the mount of the current
+    /// root directory, whose mount ID may have changed. Each file handle is checked to still refer
+    /// to a file with the saved inode number and type, failing with `ESTALE` otherwise. Nothing
+    /// is restored if any inode or handle fails to be restored.
+    pub fn restore_state(&self, state: &PassthroughFsState) -> io::Result<()> {
+        if !self.cfg.inode_file_handles {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "restoring the state needs inode_file_handles",
+            ));
+        }
+        if self.inode_map.live.load(Ordering::Relaxed) != 1
+            || self.handle_map.len.load(Ordering::Relaxed) != 0
+        {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+
+        let root = self.inode_map.get(fuse::ROOT_ID)?;
+        let root_mnt_id = root
+            .handle
+            .file_handle()
+            .map(|h| h.mnt_id)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let saved_root_mnt_id = state
+            .inodes
+            .iter()
+            .find(|i| i.inode == fuse::ROOT_ID)
+            .map(|i| i.mnt_id)
+            .unwrap_or(root_mnt_id);
+
+        let mut inodes = Vec::with_capacity(state.inodes.len());
+        for saved in state.inodes.iter() {
+            if saved.inode == fuse::ROOT_ID {
+                continue;
+            }
+            let mnt_id = if saved.mnt_id == saved_root_mnt_id {
+                root_mnt_id
+            } else {
+                saved.mnt_id
+            };
+            let handle = FileHandle::from_raw(mnt_id, saved.handle_type, &saved.handle)?;
+            let handle = self.to_openable_handle(handle)?;
+            let file = handle.open(libc::O_PATH)?;
+            let st = statx(&file, None)?;
+            if st.st.st_ino != saved.ino
+                || st.st.st_mode & libc::S_IFMT != saved.mode & libc::S_IFMT
+            {
+                error!(
+                    "passthroughfs: restore: inode {} refers to another file",
+                    saved.inode
+                );
+                return Err(io::Error::from_raw_os_error(libc::ESTALE));
+            }
+            inodes.push(Arc::new(InodeData::new(
+                saved.inode,
+                InodeHandle::Handle(handle),
+                saved.refcount,
+                InodeId::from_stat(&st),
+                st.st.st_mode,
+            )));
+        }
+
+        let mut handles = Vec::with_capacity(state.handles.len());
+        for saved in state.handles.iter() {
+            let data = match inodes.iter().find(|data| data.inode == saved.inode) {
+                Some(data) => data,
+                None if saved.inode == fuse::ROOT_ID => &root,
+                None => {
+                    error!(
+                        "passthroughfs: restore: handle {} refers to unknown inode {}",
+                        saved.handle, saved.inode
+                    );
+                    return Err(io::Error::from_raw_os_error(libc::ESTALE));
+                }
+            };
+            // The file has been created or truncated when it was opened, don't do it again.
+            let flags = saved.flags as i32 & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+            let file = data.open_file(flags | libc::O_CLOEXEC, &self.proc_self_fd)?;
+            handles.push((
+                saved.handle,
+                HandleData::new(saved.inode, file, saved.flags),
+            ));
+        }
+
+        if let Some(saved) = state.inodes.iter().find(|i| i.inode == fuse::ROOT_ID) {
+            root.refcount.store(saved.refcount, Ordering::Relaxed);
+        }
+        for data in inodes {
+            self.inode_map.insert(data);
+        }
+        for (handle, data) in handles {
+            self.handle_map.try_insert(handle, data)?;
+        }
+        self.inode_map.allocator.reset(state.next_inode);
+        self.next_handle.store(state.next_handle, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
@@ -12,7 +12,8 @@
 //! with heavy modification/enhancements from Alibaba Cloud OS team.
 
 use std::any::Any;
-use std::collections::{btree_map, BTreeMap};
+use std::cell::Cell;
+use std::collections::{btree_map, BTreeMap, HashMap};
 use std::ffi::{CStr, CString, OsString};
 use std::fs::File;
 use std::io;
@@ -22,24 +23,53 @@ use std::os::fd::{AsFd, BorrowedFd};
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
 use std::time::Duration;
 
+use arc_swap::{ArcSwap, ArcSwapOption};
 use vm_memory::{bitmap::BitmapSlice, ByteValued};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 
-pub use self::config::{CachePolicy, Config};
+use self::backing::BackingFile;
+use self::casefold::CaseFoldCache;
+pub use self::config::{
+    CachePolicy, Config, HandleLimitPolicy, InodeAllocPolicy, ReaddirInoPolicy, SquashPolicy,
+};
+pub use self::fiemap::{
+    FiemapExtent, FIEMAP_EXTENT_DELALLOC, FIEMAP_EXTENT_LAST, FIEMAP_EXTENT_SHARED,
+    FIEMAP_EXTENT_UNKNOWN, FIEMAP_EXTENT_UNWRITTEN, FIEMAP_FLAG_SYNC, FIEMAP_FLAG_XATTR,
+};
 use self::file_handle::{FileHandle, OpenableFileHandle};
+pub use self::hardening::{drop_capabilities, required_capabilities, CapabilityReport};
+pub use self::id_map::{UidGidMap, OVERFLOW_ID};
+use self::inode_alloc::{new_inode_allocator, InodeAllocator};
 use self::inode_store::{InodeId, InodeStore};
+use self::inotify::InotifyWatcher;
+pub use self::inotify::InvalNotifier;
+pub use self::migration::{HandleState, InodeState, PassthroughFsState};
 use self::mount_fd::MountFds;
+use self::negative_cache::NegativeCache;
+use self::os_compat::{
+    RESOLVE_BENEATH, RESOLVE_NO_MAGICLINKS, RESOLVE_NO_XDEV, STATX_ATTR_MOUNT_ROOT,
+};
+pub use self::path_fds::PathFdStats;
+use self::path_fds::{PathFd, PathFdCache};
+use self::reload::Tunables;
 use self::statx::{statx, StatExt};
+#[cfg(feature = "io-uring")]
+pub use self::uring::IoUringEngine;
+#[cfg(feature = "io-uring")]
+use self::uring::UringFile;
 use self::util::{
-    ebadf, einval, enosys, eperm, is_dir, is_safe_inode, openat, reopen_fd_through_proc, stat_fd,
-    UniqueInodeGenerator,
+    ebadf, einval, enosys, eperm, is_safe_inode, openat, reopen_fd_through_proc, safe_openat2,
+    stat_fd, ProcFdPath, UniqueInodeGenerator,
 };
+pub use self::xattr_prefix::XattrPrefixMap;
+pub use self::xattrmap::XattrMap;
 use crate::abi::fuse_abi as fuse;
 use crate::abi::fuse_abi::Opcode;
-use crate::api::filesystem::Entry;
+use crate::api::filesystem::{Context, Entry};
 use crate::api::{
     validate_path_component, BackendFileSystem, CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR,
     PROC_SELF_FD_CSTR, SLASH_ASCII, VFS_MAX_INO,
@@ -47,15 +77,34 @@ use crate::api::{
 
 #[cfg(feature = "async-io")]
 mod async_io;
+mod backing;
+mod casefold;
+mod checkpoint;
 mod config;
+mod fiemap;
 mod file_handle;
+mod hardening;
+mod id_map;
+mod inode_alloc;
 mod inode_store;
+mod inotify;
+mod migration;
 mod mount_fd;
+mod negative_cache;
 mod os_compat;
 mod overlay;
+mod path_fds;
+mod prewarm;
+mod reload;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod seccomp;
 mod statx;
 mod sync_io;
+#[cfg(feature = "io-uring")]
+mod uring;
 mod util;
+mod xattr_prefix;
+mod xattrmap;
 
 type Inode = u64;
 type Handle = u64;
@@ -74,6 +123,7 @@ const MAX_HOST_INO: u64 = 0x7fff_ffff_ffff;
 enum InodeFile<'a> {
     Owned(File),
     Ref(&'a File),
+    Shared(Arc<File>),
 }
 
 impl AsRawFd for InodeFile<'_> {
@@ -83,6 +133,7 @@ impl AsRawFd for InodeFile<'_> {
         match self {
             Self::Owned(file) => file.as_raw_fd(),
             Self::Ref(file_ref) => file_ref.as_raw_fd(),
+            Self::Shared(file) => file.as_raw_fd(),
         }
     }
 }
@@ -92,6 +143,7 @@ impl AsFd for InodeFile<'_> {
         match self {
             Self::Owned(file) => file.as_fd(),
             Self::Ref(file_ref) => file_ref.as_fd(),
+            Self::Shared(file) => file.as_fd(),
         }
     }
 }
@@ -100,12 +152,14 @@ impl AsFd for InodeFile<'_> {
 enum InodeHandle {
     File(File),
     Handle(Arc<OpenableFileHandle>),
+    // An fd which may be closed and reopened, see `Config::max_path_fds`.
+    PathFd(Arc<PathFd>),
 }
 
 impl InodeHandle {
     fn file_handle(&self) -> Option<&FileHandle> {
         match self {
-            InodeHandle::File(_) => None,
+            InodeHandle::File(_) | InodeHandle::PathFd(_) => None,
             InodeHandle::Handle(h) => Some(h.file_handle().deref()),
         }
     }
@@ -117,6 +171,7 @@ impl InodeHandle {
                 let f = h.open(libc::O_PATH)?;
                 Ok(InodeFile::Owned(f))
             }
+            InodeHandle::PathFd(p) => Ok(InodeFile::Shared(p.get()?)),
         }
     }
 
@@ -124,15 +179,17 @@ impl InodeHandle {
         match self {
             InodeHandle::File(f) => reopen_fd_through_proc(f, flags, proc_self_fd),
             InodeHandle::Handle(h) => h.open(flags),
+            InodeHandle::PathFd(p) => reopen_fd_through_proc(&*p.get()?, flags, proc_self_fd),
         }
     }
 
-    fn stat(&self) -> io::Result<libc::stat64> {
+    fn stat(&self) -> io::Result<StatExt> {
         match self {
-            InodeHandle::File(f) => stat_fd(f, None),
+            InodeHandle::File(f) => statx(f, None),
+            InodeHandle::PathFd(p) => statx(&*p.get()?, None),
             InodeHandle::Handle(_h) => {
                 let file = self.get_file()?;
-                stat_fd(&file, None)
+                statx(&file, None)
             }
         }
     }
@@ -148,6 +205,11 @@ pub struct InodeData {
     refcount: AtomicU64,
     // File type and mode
     mode: u32,
+    // Time of the last access, see `ShardedInodeMap::touch()`.
+    last_used: AtomicU64,
+    // Bumped whenever entries of the directory are changed through the file system, see
+    // `HandleData::dirplus_get()`.
+    dir_gen: AtomicU64,
 }
 
 impl InodeData {
@@ -158,9 +220,16 @@ impl InodeData {
             id,
             refcount: AtomicU64::new(refcount),
             mode,
+            last_used: AtomicU64::new(0),
+            dir_gen: AtomicU64::new(0),
         }
     }
 
+    // Note that entries of the directory have been changed, after changing them.
+    fn dir_changed(&self) {
+        self.dir_gen.fetch_add(1, Ordering::Release);
+    }
+
     fn get_file(&self) -> io::Result<InodeFile<'_>> {
         self.handle.get_file()
     }
@@ -171,30 +240,79 @@ impl InodeData {
 }
 
 /// Data structures to manage accessed inodes.
-struct InodeMap {
-    inodes: RwLock<InodeStore>,
+///
+/// Inodes are spread over shards by their inode numbers, so that requests on different inodes
+/// seldom contend on the same lock. Looking up inodes by their keys, e.g. to create new inodes,
+/// goes through the keys of all inodes under a single lock.
+struct ShardedInodeMap {
+    shards: Vec<RwLock<HashMap<Inode, Arc<InodeData>>>>,
+    keys: RwLock<InodeStore>,
+    // Number of inodes in use, see `Config::max_inodes`.
+    live: AtomicUsize,
+    max_inodes: Option<usize>,
+    // Source of the `last_used` time of inodes, only ticking when `max_inodes` is set.
+    clock: AtomicU64,
+    // Allocates inode numbers, and gets them back once their mappings are dropped.
+    allocator: Box<dyn InodeAllocator>,
 }
 
-impl InodeMap {
-    fn new() -> Self {
-        InodeMap {
-            inodes: RwLock::new(Default::default()),
+impl ShardedInodeMap {
+    fn new(shards: usize, max_inodes: Option<usize>, allocator: Box<dyn InodeAllocator>) -> Self {
+        ShardedInodeMap {
+            shards: (0..shards).map(|_| RwLock::new(HashMap::new())).collect(),
+            keys: RwLock::new(InodeStore::new(max_inodes)),
+            live: AtomicUsize::new(0),
+            max_inodes,
+            clock: AtomicU64::new(0),
+            allocator,
         }
     }
 
+    fn shard_index(&self, inode: Inode) -> usize {
+        (inode % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, inode: Inode) -> &RwLock<HashMap<Inode, Arc<InodeData>>> {
+        &self.shards[self.shard_index(inode)]
+    }
+
     fn clear(&self) {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.inodes.write().unwrap().clear();
+        let mut keys = self.keys.write().unwrap();
+        keys.clear();
+        for shard in self.shards.iter() {
+            shard.write().unwrap().clear();
+        }
+        self.live.store(0, Ordering::Relaxed);
     }
 
     fn get(&self, inode: Inode) -> io::Result<Arc<InodeData>> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.inodes
+        let data = self
+            .shard(inode)
             .read()
             .unwrap()
             .get(&inode)
             .cloned()
-            .ok_or_else(ebadf)
+            .ok_or_else(ebadf)?;
+        self.touch(&data);
+
+        Ok(data)
+    }
+
+    /// Get the number of inodes, including forgotten inodes whose mappings are kept.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.live.load(Ordering::Relaxed) + self.keys.read().unwrap().forgotten()
+    }
+
+    // Record an access to `data`, to keep its mapping longer once forgotten.
+    fn touch(&self, data: &InodeData) {
+        if self.max_inodes.is_some() {
+            let now = self.clock.fetch_add(1, Ordering::Relaxed);
+            data.last_used.store(now, Ordering::Relaxed);
+        }
     }
 
     fn get_inode_locked(
@@ -210,20 +328,25 @@ impl InodeMap {
 
     fn get_alt(&self, id: &InodeId, handle: Option<&FileHandle>) -> Option<Arc<InodeData>> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        let inodes = self.inodes.read().unwrap();
+        let inodes = self.keys.read().unwrap();
+        let data = self.get_alt_locked(inodes.deref(), id, handle)?;
+        self.touch(&data);
 
-        Self::get_alt_locked(inodes.deref(), id, handle)
+        Some(data)
     }
 
     fn get_alt_locked(
+        &self,
         inodes: &InodeStore,
         id: &InodeId,
         handle: Option<&FileHandle>,
     ) -> Option<Arc<InodeData>> {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let get = |inode: &Inode| self.shard(*inode).read().unwrap().get(inode).cloned();
         handle
-            .and_then(|h| inodes.get_by_handle(h))
+            .and_then(|h| inodes.inode_by_handle(h).and_then(get))
             .or_else(|| {
-                inodes.get_by_id(id).filter(|data| {
+                inodes.inode_by_id(id).and_then(get).filter(|data| {
                     // When we have to fall back to looking up an inode by its IDs, ensure that
                     // we hit an entry that does not have a file handle.  Entries with file
                     // handles must also have a handle alt key, so if we have not found it by
@@ -235,22 +358,197 @@ impl InodeMap {
                     handle.is_none() || data.handle.file_handle().is_none()
                 })
             })
-            .cloned()
     }
 
+    // Lock the keys of inodes, to look up and insert inodes without races.
     fn get_map_mut(&self) -> RwLockWriteGuard<InodeStore> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.inodes.write().unwrap()
+        self.keys.write().unwrap()
     }
 
     fn insert(&self, data: Arc<InodeData>) {
         let mut inodes = self.get_map_mut();
 
-        Self::insert_locked(inodes.deref_mut(), data)
+        self.insert_locked(inodes.deref_mut(), data)
+    }
+
+    fn insert_locked(&self, inodes: &mut InodeStore, data: Arc<InodeData>) {
+        inodes.insert(&data);
+        self.touch(&data);
+        // Do not expect poisoned lock here, so safe to unwrap().
+        if self
+            .shard(data.inode)
+            .write()
+            .unwrap()
+            .insert(data.inode, data)
+            .is_none()
+        {
+            self.live.fetch_add(1, Ordering::Relaxed);
+        }
+        self.evict_locked(inodes);
+    }
+
+    // Drop the mappings of forgotten inodes beyond `max_inodes`, releasing their inode numbers.
+    fn evict_locked(&self, inodes: &mut InodeStore) {
+        for inode in inodes.evict(self.live.load(Ordering::Relaxed)) {
+            self.allocator.release(inode);
+        }
+    }
+
+    // Drop `count` references to each `inode` of `requests`, and the inodes themselves once there
+    // are none left, keeping the mappings of their keys to their inode numbers if `keep_mapping`
+    // returns true for them. `removed_fn` is called for each inode dropped, before its inode number
+    // may be reused.
+    //
+    // Requests are grouped by shard, so each shard is locked once. The keys of inodes are only
+    // locked if some of them need to be updated.
+    fn forget(
+        &self,
+        requests: &[(Inode, u64)],
+        keep_mapping: impl Fn(&InodeData) -> bool,
+        removed_fn: impl Fn(&InodeData),
+    ) {
+        let mut requests = requests.to_vec();
+        requests.sort_by_key(|(inode, _)| self.shard_index(*inode));
+
+        let mut removed = Vec::new();
+        let mut rest = requests.as_slice();
+        while let Some((first, _)) = rest.first() {
+            let index = self.shard_index(*first);
+            let len = rest
+                .iter()
+                .position(|(inode, _)| self.shard_index(*inode) != index)
+                .unwrap_or(rest.len());
+            let (batch, tail) = rest.split_at(len);
+            rest = tail;
+
+            // Do not expect poisoned lock here, so safe to unwrap().
+            let mut shard = self.shards[index].write().unwrap();
+            for (inode, count) in batch {
+                if let Some(data) = Self::forget_locked(&mut shard, *inode, *count) {
+                    self.live.fetch_sub(1, Ordering::Relaxed);
+                    removed_fn(&data);
+                    removed.push(data);
+                }
+            }
+        }
+
+        let removed: Vec<_> = removed
+            .into_iter()
+            .map(|data| (keep_mapping(&data), data))
+            .filter(|(keep, _)| !keep || self.max_inodes.is_some())
+            .collect();
+        if removed.is_empty() {
+            return;
+        }
+        let mut inodes = self.get_map_mut();
+        for (keep, data) in removed {
+            // The inode may have been looked up again with the same inode number, in which case
+            // its keys are in use again.
+            // Do not expect poisoned lock here, so safe to unwrap().
+            if !self
+                .shard(data.inode)
+                .read()
+                .unwrap()
+                .contains_key(&data.inode)
+            {
+                inodes.remove(&data, keep);
+                // Nothing refers to the inode number anymore.
+                if !keep {
+                    self.allocator.release(data.inode);
+                }
+            }
+        }
+        self.evict_locked(inodes.deref_mut());
+    }
+
+    // Drop `count` references to `inode` in its locked `shard`, and return the inode if there are
+    // none left.
+    fn forget_locked(
+        shard: &mut HashMap<Inode, Arc<InodeData>>,
+        inode: Inode,
+        count: u64,
+    ) -> Option<Arc<InodeData>> {
+        let data = shard.get(&inode)?.clone();
+
+        // Acquiring the write lock on the shard prevents new lookups from incrementing the
+        // refcount but there is the possibility that a previous lookup already acquired a
+        // reference to the inode data and is in the process of updating the refcount so we
+        // need to loop here until we can decrement successfully.
+        loop {
+            let curr = data.refcount.load(Ordering::Acquire);
+
+            // Saturating sub because it doesn't make sense for a refcount to go below zero and
+            // we don't want misbehaving clients to cause integer overflow.
+            let new = curr.saturating_sub(count);
+
+            // Synchronizes with the acquire load in `do_lookup`.
+            if data
+                .refcount
+                .compare_exchange(curr, new, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                if new != 0 {
+                    return None;
+                }
+                break;
+            }
+        }
+
+        // We just removed the last refcount for this inode.
+        shard.remove(&inode);
+        Some(data)
+    }
+}
+
+// Maximum number of entries remembered by readdirplus for a directory handle.
+const MAX_DIRPLUS_ENTRIES: usize = 16 * 1024;
+
+// State of a directory, entries looked up in a state are only valid in the same state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct DirState {
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    gen: u64,
+}
+
+// Inodes of the entries of a directory looked up by readdirplus, with their attribute flags.
+#[derive(Default)]
+struct DirplusCache {
+    state: Option<DirState>,
+    entries: HashMap<CString, (Inode, u32)>,
+}
+
+// Data read ahead of sequential reads on a handle, see `Config::readahead`.
+#[derive(Default)]
+struct ReadaheadBuf {
+    // Offset of `data` in the file.
+    offset: u64,
+    data: Vec<u8>,
+    // Whether `data` reaches the end of the file.
+    eof: bool,
+    // Offset following the last read, where the next read starts if reads are sequential.
+    next: Option<u64>,
+    // Incremented when the buffer is invalidated, so that data read meanwhile isn't kept.
+    gen: u64,
+}
+
+impl ReadaheadBuf {
+    // Get the buffered data of the read of `size` bytes at `offset`, if all of it is buffered.
+    #[cfg_attr(not(feature = "async-io"), allow(dead_code))]
+    fn get(&self, offset: u64, size: usize) -> Option<&[u8]> {
+        let start = offset.checked_sub(self.offset)? as usize;
+        if start > self.data.len() || (start + size > self.data.len() && !self.eof) {
+            return None;
+        }
+        let end = (start + size).min(self.data.len());
+        Some(&self.data[start..end])
     }
 
-    fn insert_locked(inodes: &mut InodeStore, data: Arc<InodeData>) {
-        inodes.insert(data);
+    fn invalidate(&mut self) {
+        self.data = Vec::new();
+        self.next = None;
+        self.gen += 1;
     }
 }
 
@@ -259,6 +557,27 @@ struct HandleData {
     file: File,
     lock: Mutex<()>,
     open_flags: AtomicU32,
+    // Files used to emulate flock(2) locks, one for each lock owner.
+    flock_files: Mutex<HashMap<u64, Arc<File>>>,
+    // Files used to take POSIX record locks as open file description locks, one for each lock
+    // owner.
+    posix_lock_files: Mutex<HashMap<u64, Arc<File>>>,
+    // Time of the last access, see `HandleMap::touch()`.
+    last_used: AtomicU64,
+    // Backing file of the handle with `Config::fuse_passthrough`.
+    backing: Option<BackingFile>,
+    // Entries looked up by readdirplus on a directory handle.
+    dirplus: Mutex<DirplusCache>,
+    // Data read ahead by the async read path.
+    readahead: Mutex<ReadaheadBuf>,
+}
+
+struct ReadaheadGuard<'a>(&'a HandleData);
+
+impl Drop for ReadaheadGuard<'_> {
+    fn drop(&mut self) {
+        self.0.invalidate_readahead();
+    }
 }
 
 impl HandleData {
@@ -268,7 +587,92 @@ impl HandleData {
             file,
             lock: Mutex::new(()),
             open_flags: AtomicU32::new(flags),
+            flock_files: Mutex::new(HashMap::new()),
+            posix_lock_files: Mutex::new(HashMap::new()),
+            last_used: AtomicU64::new(0),
+            backing: None,
+            dirplus: Mutex::new(DirplusCache::default()),
+            readahead: Mutex::new(ReadaheadBuf::default()),
+        }
+    }
+
+    // Drop the data read ahead, after changing the file or the offset through the handle.
+    fn invalidate_readahead(&self) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.readahead.lock().unwrap().invalidate();
+    }
+
+    // Get a guard dropping the data read ahead when dropped, once the file has been changed.
+    // Reads racing with the change don't keep the data they read ahead.
+    fn readahead_guard(&self) -> ReadaheadGuard<'_> {
+        ReadaheadGuard(self)
+    }
+
+    // Get the inode and attribute flags of the entry `name` looked up by readdirplus, if the
+    // directory is still in `state`. Entries of other states are dropped.
+    fn dirplus_get(&self, state: &DirState, name: &CStr) -> Option<(Inode, u32)> {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut cache = self.dirplus.lock().unwrap();
+        if cache.state.as_ref() != Some(state) {
+            cache.state = Some(*state);
+            cache.entries.clear();
+            return None;
+        }
+        cache.entries.get(name).copied()
+    }
+
+    // Remember the inode and attribute flags of the entry `name` looked up by readdirplus in
+    // `state`.
+    fn dirplus_insert(&self, state: &DirState, name: &CStr, inode: Inode, attr_flags: u32) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut cache = self.dirplus.lock().unwrap();
+        if cache.state.as_ref() == Some(state) && cache.entries.len() < MAX_DIRPLUS_ENTRIES {
+            cache.entries.insert(name.to_owned(), (inode, attr_flags));
+        }
+    }
+
+    fn get_flock_file(&self, owner: u64, proc_self_fd: &impl AsRawFd) -> io::Result<Arc<File>> {
+        self.get_owner_file(&self.flock_files, owner, proc_self_fd)
+    }
+
+    fn release_flock_file(&self, owner: u64) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.flock_files.lock().unwrap().remove(&owner);
+    }
+
+    fn get_posix_lock_file(
+        &self,
+        owner: u64,
+        proc_self_fd: &impl AsRawFd,
+    ) -> io::Result<Arc<File>> {
+        self.get_owner_file(&self.posix_lock_files, owner, proc_self_fd)
+    }
+
+    fn release_posix_lock_file(&self, owner: u64) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.posix_lock_files.lock().unwrap().remove(&owner);
+    }
+
+    fn get_owner_file(
+        &self,
+        files: &Mutex<HashMap<u64, Arc<File>>>,
+        owner: u64,
+        proc_self_fd: &impl AsRawFd,
+    ) -> io::Result<Arc<File>> {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut files = files.lock().unwrap();
+        if let Some(file) = files.get(&owner) {
+            return Ok(file.clone());
         }
+
+        // flock(2) and OFD locks belong to the open file description, which a dup() of
+        // `self.file` would share with all other owners. So reopen the file to get a private one
+        // for each owner.
+        let flags = (self.get_flags() as i32 & libc::O_ACCMODE) | libc::O_CLOEXEC;
+        let file = Arc::new(reopen_fd_through_proc(&self.file, flags, proc_self_fd)?);
+        files.insert(owner, file.clone());
+
+        Ok(file)
     }
 
     fn get_file(&self) -> &File {
@@ -290,38 +694,144 @@ impl HandleData {
     fn set_flags(&self, flags: u32) {
         self.open_flags.store(flags, Ordering::Relaxed);
     }
+
+    // Write the data of the file to stable storage if the handle was opened for writing, for
+    // `Config::sync_on_close`.
+    fn sync_on_close(&self) -> io::Result<()> {
+        if self.get_flags() & libc::O_ACCMODE as u32 == libc::O_RDONLY as u32 {
+            return Ok(());
+        }
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::fdatasync(self.borrow_fd().as_raw_fd()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
 }
 
 struct HandleMap {
-    handles: RwLock<BTreeMap<Handle, Arc<HandleData>>>,
+    shards: Vec<RwLock<BTreeMap<Handle, Arc<HandleData>>>>,
+    // Number of handles in the map, or being inserted.
+    len: AtomicUsize,
+    // See `Config::max_handles` and `Config::handle_limit_policy`.
+    max_handles: Option<usize>,
+    policy: HandleLimitPolicy,
+    // Source of the `last_used` time of handles.
+    clock: AtomicU64,
 }
 
 impl HandleMap {
-    fn new() -> Self {
+    fn new(shards: usize, max_handles: Option<usize>, policy: HandleLimitPolicy) -> Self {
         HandleMap {
-            handles: RwLock::new(BTreeMap::new()),
+            shards: (0..shards).map(|_| RwLock::new(BTreeMap::new())).collect(),
+            len: AtomicUsize::new(0),
+            max_handles,
+            policy,
+            clock: AtomicU64::new(0),
         }
     }
 
+    fn shard(&self, handle: Handle) -> &RwLock<BTreeMap<Handle, Arc<HandleData>>> {
+        &self.shards[(handle % self.shards.len() as u64) as usize]
+    }
+
     fn clear(&self) {
-        // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles.write().unwrap().clear();
+        for shard in self.shards.iter() {
+            // Do not expect poisoned lock here, so safe to unwrap().
+            let mut handles = shard.write().unwrap();
+            self.len.fetch_sub(handles.len(), Ordering::Relaxed);
+            handles.clear();
+        }
     }
 
-    fn insert(&self, handle: Handle, data: HandleData) {
+    // Insert a handle unless there are `max_handles` handles already and none can be evicted,
+    // in which case fail with `EMFILE`. A slot is reserved before the insertion, so concurrent
+    // opens can't exceed the limit.
+    fn try_insert(&self, handle: Handle, data: HandleData) -> io::Result<()> {
+        match self.max_handles {
+            Some(max_handles) => {
+                let reserved = self
+                    .len
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                        (len < max_handles).then_some(len + 1)
+                    })
+                    .is_ok();
+                if !reserved {
+                    if self.policy == HandleLimitPolicy::RejectNew {
+                        return Err(io::Error::from_raw_os_error(libc::EMFILE));
+                    }
+                    // Take over the slot of the evicted handle.
+                    self.evict_oldest()?;
+                }
+            }
+            None => {
+                self.len.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.touch(&data);
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        self.shard(handle)
+            .write()
+            .unwrap()
+            .insert(handle, Arc::new(data));
+        Ok(())
+    }
+
+    // Remove the least recently used handle, keeping its slot in `len` reserved.
+    fn evict_oldest(&self) -> io::Result<()> {
+        // The oldest handle may get used while looking for it in other shards, try again then.
+        for _ in 0..3 {
+            // Requests using a handle hold a reference to its data, leave those alone.
+            let oldest = self
+                .shards
+                .iter()
+                .filter_map(|shard| {
+                    // Do not expect poisoned lock here, so safe to unwrap().
+                    shard
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .filter(|(_, data)| Arc::strong_count(data) == 1)
+                        .map(|(handle, data)| (*handle, data.last_used.load(Ordering::Relaxed)))
+                        .min_by_key(|(_, last_used)| *last_used)
+                })
+                .min_by_key(|(_, last_used)| *last_used);
+            let oldest = match oldest {
+                Some((handle, _)) => handle,
+                None => break,
+            };
+
+            // Do not expect poisoned lock here, so safe to unwrap().
+            let mut handles = self.shard(oldest).write().unwrap();
+            if let btree_map::Entry::Occupied(e) = handles.entry(oldest) {
+                if Arc::strong_count(e.get()) == 1 {
+                    e.remove();
+                    return Ok(());
+                }
+            }
+        }
+
+        Err(io::Error::from_raw_os_error(libc::EMFILE))
+    }
+
+    fn touch(&self, data: &HandleData) {
+        if self.max_handles.is_some() {
+            let now = self.clock.fetch_add(1, Ordering::Relaxed);
+            data.last_used.store(now, Ordering::Relaxed);
+        }
     }
 
     fn release(&self, handle: Handle, inode: Inode) -> io::Result<()> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        let mut handles = self.handles.write().unwrap();
+        let mut handles = self.shard(handle).write().unwrap();
 
         if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
             if e.get().inode == inode {
                 // We don't need to close the file here because that will happen automatically when
                 // the last `Arc` is dropped.
                 e.remove();
+                self.len.fetch_sub(1, Ordering::Relaxed);
                 return Ok(());
             }
         }
@@ -331,14 +841,149 @@ impl HandleMap {
 
     fn get(&self, handle: Handle, inode: Inode) -> io::Result<Arc<HandleData>> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles
+        let data = self
+            .shard(handle)
             .read()
             .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
             .cloned()
-            .ok_or_else(ebadf)
+            .ok_or_else(ebadf)?;
+        self.touch(&data);
+
+        Ok(data)
+    }
+}
+
+// Number of epoll events fetched by each `PollHandleMap::wait()` call.
+const POLL_EVENTS_BATCH: usize = 32;
+
+// A kernel poll handle waiting to be notified when a file becomes ready.
+struct PollHandleData {
+    inode: Inode,
+    handle: Handle,
+    // A duplicate of the handle's fd, registered to the epoll instance.
+    file: File,
+}
+
+// Kernel poll handles registered by `poll()` with `POLL_SCHEDULE_NOTIFY`, keyed by the kernel
+// poll handle. The files are watched by an epoll instance until they become ready.
+struct PollHandleMap {
+    epoll: Epoll,
+    handles: Mutex<HashMap<u64, PollHandleData>>,
+}
+
+impl PollHandleMap {
+    fn new() -> io::Result<Self> {
+        Ok(PollHandleMap {
+            epoll: Epoll::new()?,
+            handles: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn unwatch(&self, data: &PollHandleData) {
+        // The file may have been removed from the epoll instance already, ignore the error.
+        let _ = self.epoll.ctl(
+            ControlOperation::Delete,
+            data.file.as_raw_fd(),
+            EpollEvent::default(),
+        );
+    }
+
+    fn clear(&self) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut handles = self.handles.lock().unwrap();
+        for (_, data) in handles.drain() {
+            self.unwatch(&data);
+        }
+    }
+
+    // Watch `fd` for `events` on behalf of the kernel poll handle `kh`.
+    fn insert(
+        &self,
+        kh: u64,
+        inode: Inode,
+        handle: Handle,
+        fd: BorrowedFd,
+        events: u32,
+    ) -> io::Result<()> {
+        // Watch a duplicate of the fd, so the same file may be registered by several kernel poll
+        // handles.
+        let file = File::from(fd.try_clone_to_owned()?);
+        // poll(2) and epoll(7) share the same event bits.
+        let event = EpollEvent::new(
+            EventSet::from_bits_truncate(events) | EventSet::ONE_SHOT,
+            kh,
+        );
+
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut handles = self.handles.lock().unwrap();
+        self.epoll
+            .ctl(ControlOperation::Add, file.as_raw_fd(), event)?;
+        if let Some(old) = handles.insert(
+            kh,
+            PollHandleData {
+                inode,
+                handle,
+                file,
+            },
+        ) {
+            self.unwatch(&old);
+        }
+        Ok(())
     }
+
+    // Wait up to `timeout` milliseconds for watched files to become ready, and return the kernel
+    // poll handles of the ready files. A negative `timeout` waits forever.
+    fn wait(&self, timeout: i32) -> io::Result<Vec<u64>> {
+        let mut events = [EpollEvent::default(); POLL_EVENTS_BATCH];
+        let count = match self.epoll.wait(timeout, &mut events) {
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut handles = self.handles.lock().unwrap();
+        let khs = events[..count]
+            .iter()
+            .filter_map(|event| {
+                let kh = event.data();
+                // The handle may have been released in the meantime.
+                let data = handles.remove(&kh)?;
+                self.unwatch(&data);
+                Some(kh)
+            })
+            .collect();
+        Ok(khs)
+    }
+
+    // Forget all kernel poll handles waiting on `handle`, which is going to be released.
+    fn release(&self, handle: Handle, inode: Inode) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.handles.lock().unwrap().retain(|_, data| {
+            let keep = data.handle != handle || data.inode != inode;
+            if !keep {
+                self.unwatch(data);
+            }
+            keep
+        });
+    }
+}
+
+/// Callback sending `FUSE_NOTIFY_DELETE` to the kernel, for the entry `name` of an inode in a
+/// directory, e.g. built around `transport::notify_delete()`, see
+/// [PassthroughFs::set_delete_notifier](struct.PassthroughFs.html#method.set_delete_notifier).
+pub type DeleteNotifier = Box<dyn Fn(Inode, Inode, &CStr) -> io::Result<()> + Send + Sync>;
+
+/// Hits and misses of the entries remembered by readdirplus, see
+/// [PassthroughFs::readdirplus_cache_stats](struct.PassthroughFs.html#method.readdirplus_cache_stats).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReaddirplusCacheStats {
+    /// Entries whose inode was remembered, so they haven't been looked up by name again.
+    pub hits: u64,
+    /// Entries which had to be looked up by name.
+    pub misses: u64,
 }
 
 /// A file system that simply "passes through" all requests it receives to the underlying file
@@ -353,19 +998,24 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // the `O_PATH` option so they cannot be used for reading or writing any data. See the
     // documentation of the `O_PATH` flag in `open(2)` for more details on what one can and cannot
     // do with an fd opened with this flag.
-    inode_map: InodeMap,
-    next_inode: AtomicU64,
+    inode_map: ShardedInodeMap,
 
     // File descriptors for open files and directories. Unlike the fds in `inodes`, these _can_ be
     // used for reading and writing data.
     handle_map: HandleMap,
     next_handle: AtomicU64,
 
+    // Kernel poll handles to notify when the files they are polling become ready.
+    poll_handle_map: PollHandleMap,
+
     // Use to generate unique inode
     ino_allocator: UniqueInodeGenerator,
     // Maps mount IDs to an open FD on the respective ID for the purpose of open_by_handle_at().
     mount_fds: MountFds,
 
+    // The root directory, if opened by the caller, see `Config::root_fd`.
+    root_file: Option<File>,
+
     // File descriptor pointing to the `/proc/self/fd` directory. This is used to convert an fd from
     // `inodes` into one that can go into `handles`. This is accomplished by reading the
     // `/proc/self/fd/{}` symlink. We keep an open fd here in case the file system tree that we are meant
@@ -395,8 +1045,53 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // Init from guest kernel Init cmd of fuse fs.
     perfile_dax: AtomicBool,
 
-    dir_entry_timeout: Duration,
-    dir_attr_timeout: Duration,
+    // Whether the kernel supports submounts.
+    // Init from guest kernel Init cmd of fuse fs.
+    submounts: AtomicBool,
+
+    // Whether the shared directory supports O_TMPFILE, probed on init.
+    tmpfile: AtomicBool,
+
+    // Whether the host kernel supports openat2(2), cleared on the first failure.
+    has_openat2: AtomicBool,
+
+    // Whether the host kernel supports faccessat2(2), cleared on the first failure.
+    has_faccessat2: AtomicBool,
+
+    // Whether POSIX ACLs are enabled, in which case the host kernel applies the umask.
+    posix_acl: AtomicBool,
+
+    // Whether the kernel reads and writes files through backing files, for
+    // `Config::fuse_passthrough`.
+    fuse_passthrough: AtomicBool,
+
+    // FUSE device to register backing files with, see `set_fuse_dev()`.
+    fuse_dev: ArcSwapOption<File>,
+
+    // Sends invalidations to the kernel, see `set_inval_notifier()`.
+    inval_notifier: ArcSwapOption<InvalNotifier>,
+    // Sends removals of entries to the kernel, for `Config::notify_on_rename`.
+    delete_notifier: ArcSwapOption<DeleteNotifier>,
+    // Watches the directories looked up, for `Config::inotify_invalidate`.
+    inotify: Option<InotifyWatcher>,
+
+    // Case-folded directory listings for `Config::case_insensitive`.
+    case_fold_cache: CaseFoldCache,
+
+    // Names known not to exist, for `Config::negative_cache_ttl`.
+    negative_cache: Option<NegativeCache>,
+
+    // Hits and misses of the entries remembered by readdirplus.
+    dirplus_hits: AtomicU64,
+    dirplus_misses: AtomicU64,
+
+    // Evictable fds of inodes, for `Config::max_path_fds`.
+    path_fds: Option<Arc<PathFdCache>>,
+
+    // Options which may be changed at runtime, see `apply_config_update()`.
+    tunables: ArcSwap<Tunables>,
+    // Number of `SIGUSR2` handled when `Config::config_file` was last reloaded.
+    reloads: AtomicU64,
 
     cfg: Config,
 
@@ -405,7 +1100,27 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
 
 impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     /// Create a Passthrough file system instance.
-    pub fn new(mut cfg: Config) -> io::Result<PassthroughFs<S>> {
+    pub fn new(cfg: Config) -> io::Result<PassthroughFs<S>> {
+        let root_file = match cfg.root_fd {
+            // Safe because the fd is only borrowed to be duplicated, and the caller guarantees it
+            // is open.
+            Some(fd) => Some(File::from(
+                unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?,
+            )),
+            None => None,
+        };
+        Self::with_root_file(cfg, root_file)
+    }
+
+    /// Create a Passthrough file system instance sharing the directory `root`, e.g. opened with
+    /// `O_PATH` before entering a sandbox or received from another process. `Config::root_dir`
+    /// is only used in messages, the shared directory is never opened by path.
+    pub fn new_from_rootfd(mut cfg: Config, root: File) -> io::Result<PassthroughFs<S>> {
+        cfg.root_fd = Some(root.as_raw_fd());
+        Self::with_root_file(cfg, Some(root))
+    }
+
+    fn with_root_file(mut cfg: Config, root_file: Option<File>) -> io::Result<PassthroughFs<S>> {
         if cfg.no_open && cfg.cache_policy != CachePolicy::Always {
             warn!("passthroughfs: no_open only work with cache=always, reset to open mode");
             cfg.no_open = false;
@@ -416,6 +1131,14 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             );
             cfg.writeback = false;
         }
+        if cfg.inode_map_shards == 0 {
+            warn!("passthroughfs: inode map needs at least one shard, reset to 1");
+            cfg.inode_map_shards = 1;
+        }
+        if cfg.handle_map_shards == 0 {
+            warn!("passthroughfs: handle map needs at least one shard, reset to 1");
+            cfg.handle_map_shards = 1;
+        }
 
         // Safe because this is a constant value and a valid C string.
         let proc_self_fd_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_SELF_FD_CSTR) };
@@ -426,25 +1149,43 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             0,
         )?;
 
-        let (dir_entry_timeout, dir_attr_timeout) =
-            match (cfg.dir_entry_timeout, cfg.dir_attr_timeout) {
-                (Some(e), Some(a)) => (e, a),
-                (Some(e), None) => (e, cfg.attr_timeout),
-                (None, Some(a)) => (cfg.entry_timeout, a),
-                (None, None) => (cfg.entry_timeout, cfg.attr_timeout),
-            };
-
         let mount_fds = MountFds::new(None)?;
+        let path_fds = match cfg.max_path_fds {
+            Some(max_fds) if !cfg.inode_file_handles => Some(Arc::new(PathFdCache::new(
+                max_fds,
+                proc_self_fd.try_clone()?,
+            ))),
+            _ => None,
+        };
+        let inotify = if cfg.inotify_invalidate {
+            Some(InotifyWatcher::new()?)
+        } else {
+            None
+        };
 
         Ok(PassthroughFs {
-            inode_map: InodeMap::new(),
-            next_inode: AtomicU64::new(fuse::ROOT_ID + 1),
+            inode_map: ShardedInodeMap::new(
+                cfg.inode_map_shards,
+                cfg.max_inodes,
+                // Inode numbers derived from the host aren't allocated.
+                new_inode_allocator(if cfg.use_host_ino {
+                    InodeAllocPolicy::Monotonic
+                } else {
+                    cfg.inode_alloc_policy
+                }),
+            ),
             ino_allocator: UniqueInodeGenerator::new(),
 
-            handle_map: HandleMap::new(),
+            handle_map: HandleMap::new(
+                cfg.handle_map_shards,
+                cfg.max_handles,
+                cfg.handle_limit_policy,
+            ),
             next_handle: AtomicU64::new(1),
+            poll_handle_map: PollHandleMap::new()?,
 
             mount_fds,
+            root_file,
             proc_self_fd,
 
             writeback: AtomicBool::new(false),
@@ -454,8 +1195,23 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             no_readdir: AtomicBool::new(cfg.no_readdir),
             seal_size: AtomicBool::new(cfg.seal_size),
             perfile_dax: AtomicBool::new(false),
-            dir_entry_timeout,
-            dir_attr_timeout,
+            submounts: AtomicBool::new(false),
+            tmpfile: AtomicBool::new(false),
+            has_openat2: AtomicBool::new(true),
+            has_faccessat2: AtomicBool::new(true),
+            posix_acl: AtomicBool::new(false),
+            fuse_passthrough: AtomicBool::new(false),
+            fuse_dev: ArcSwapOption::empty(),
+            inval_notifier: ArcSwapOption::empty(),
+            delete_notifier: ArcSwapOption::empty(),
+            inotify,
+            case_fold_cache: CaseFoldCache::default(),
+            negative_cache: cfg.negative_cache_ttl.map(NegativeCache::new),
+            dirplus_hits: AtomicU64::new(0),
+            dirplus_misses: AtomicU64::new(0),
+            path_fds,
+            tunables: ArcSwap::new(Arc::new(Tunables::new(&cfg))),
+            reloads: AtomicU64::new(0),
             cfg,
 
             phantom: PhantomData,
@@ -464,15 +1220,42 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
 
     /// Initialize the Passthrough file system.
     pub fn import(&self) -> io::Result<()> {
-        let root = CString::new(self.cfg.root_dir.as_str()).expect("CString::new failed");
-
-        let (path_fd, handle_opt, st) = Self::open_file_and_handle(self, &libc::AT_FDCWD, &root)
-            .map_err(|e| {
-                error!("fuse: import: failed to get file or handle: {:?}", e);
-                e
-            })?;
+        let (path_fd, handle_opt, st) = match self.root_file.as_ref() {
+            Some(root) => self.open_root_file_and_handle(root),
+            None => {
+                let root = CString::new(self.cfg.root_dir.as_str()).expect("CString::new failed");
+                Self::open_file_and_handle(self, &libc::AT_FDCWD, &root)
+            }
+        }
+        .map_err(|e| {
+            error!("fuse: import: failed to get file or handle: {:?}", e);
+            e
+        })?;
+        let errno = match (self.cfg.root_is_file, st.st.st_mode & libc::S_IFMT) {
+            (false, libc::S_IFDIR) | (true, libc::S_IFREG) => 0,
+            (false, _) => libc::ENOTDIR,
+            (true, libc::S_IFDIR) => libc::EISDIR,
+            (true, _) => libc::EINVAL,
+        };
+        if errno != 0 {
+            error!(
+                "fuse: import: unexpected type of root {}",
+                self.cfg.root_dir
+            );
+            return Err(io::Error::from_raw_os_error(errno));
+        }
         let id = InodeId::from_stat(&st);
         let handle = if let Some(h) = handle_opt {
+            // The mount point of a root opened by the caller may not be reachable by path, open
+            // the file handles of its mount through the root itself.
+            let _mount_fd = match self.root_file.as_ref() {
+                Some(_) => {
+                    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+                    let file = reopen_fd_through_proc(&path_fd, flags, &self.proc_self_fd)?;
+                    Some(self.mount_fds.insert(h.mnt_id, file))
+                }
+                None => None,
+            };
             InodeHandle::Handle(self.to_openable_handle(h)?)
         } else {
             InodeHandle::File(path_fd)
@@ -484,20 +1267,41 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         unsafe { libc::umask(0o000) };
 
         // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
-        self.inode_map.insert(Arc::new(InodeData::new(
-            fuse::ROOT_ID,
-            handle,
-            2,
-            id,
-            st.st.st_mode,
-        )));
+        let root = Arc::new(InodeData::new(fuse::ROOT_ID, handle, 2, id, st.st.st_mode));
+        self.inotify_watch(&root);
+        self.inode_map.insert(root);
+
+        if let Some(depth) = self.cfg.pre_warm_depth {
+            let max_inodes = self.cfg.pre_warm_max_inodes.unwrap_or(usize::MAX);
+            match self.pre_warm(depth, max_inodes) {
+                Ok(n) => info!("passthroughfs: pre-warmed {} inodes", n),
+                Err(e) => warn!("passthroughfs: failed to pre-warm inodes: {}", e),
+            }
+        }
 
         Ok(())
     }
 
     /// Get the list of file descriptors which should be reserved across live upgrade.
     pub fn keep_fds(&self) -> Vec<RawFd> {
-        vec![self.proc_self_fd.as_raw_fd()]
+        let mut fds = vec![self.proc_self_fd.as_raw_fd()];
+        fds.extend(self.root_file.as_ref().map(|f| f.as_raw_fd()));
+        fds
+    }
+
+    /// Set the FUSE device, e.g. a clone of the file of the `FuseSession`, to register backing
+    /// files with for `Config::fuse_passthrough`.
+    pub fn set_fuse_dev(&self, dev: File) {
+        self.fuse_dev.store(Some(Arc::new(dev)));
+    }
+
+    /// Set the callback sending removals of entries to the kernel, for `Config::notify_on_rename`.
+    ///
+    /// The callback is called while handling the rename, before the reply. The kernel holds the
+    /// directories locked until then, and needs the lock of the directory to drop the entry, so
+    /// the callback must hand the notification over to another thread to send it.
+    pub fn set_delete_notifier(&self, notifier: DeleteNotifier) {
+        self.delete_notifier.store(Some(Arc::new(notifier)));
     }
 
     fn readlinkat(dfd: i32, pathname: &CStr) -> io::Result<PathBuf> {
@@ -532,10 +1336,9 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     pub fn readlinkat_proc_file(&self, inode: Inode) -> io::Result<PathBuf> {
         let data = self.inode_map.get(inode)?;
         let file = data.get_file()?;
-        let pathname = CString::new(format!("{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let pathname = ProcFdPath::new(file.as_raw_fd());
 
-        Self::readlinkat(self.proc_self_fd.as_raw_fd(), &pathname)
+        Self::readlinkat(self.proc_self_fd.as_raw_fd(), pathname.as_name())
     }
 
     fn create_file_excl(
@@ -568,16 +1371,32 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         dir: &impl AsRawFd,
         pathname: &CStr,
         flags: i32,
-        mode: u32,
     ) -> io::Result<File> {
         let flags = libc::O_NOFOLLOW | libc::O_CLOEXEC | flags;
 
-        // TODO
-        //if self.os_facts.has_openat2 {
-        //    oslib::do_open_relative_to(dir, pathname, flags, mode)
-        //} else {
-        openat(dir, pathname, flags, mode)
-        //}
+        // Keep the resolution of `pathname` inside `dir`, failing with EXDEV or ELOOP rather than
+        // escaping. That doesn't hold for "..", which only leaves `dir` if `dir` isn't the root,
+        // so it's safe to resolve as is. Nor for the root directory itself, which `import()`
+        // opens relative to the working directory.
+        if self.cfg.resolve_beneath
+            && self.has_openat2.load(Ordering::Relaxed)
+            && dir.as_raw_fd() != libc::AT_FDCWD
+            && !pathname.to_bytes_with_nul().starts_with(PARENT_DIR_CSTR)
+        {
+            let mut resolve = RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS;
+            if self.cfg.no_xdev {
+                resolve |= RESOLVE_NO_XDEV;
+            }
+            match safe_openat2(dir, pathname, flags, resolve) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                    warn!("fuse: openat2(2) is not supported by the host kernel, fall back to openat(2)");
+                    self.has_openat2.store(false, Ordering::Relaxed);
+                }
+                res => return res,
+            }
+        }
+
+        openat(dir, pathname, flags, 0)
     }
 
     /// Create a File or File Handle for `name` under directory `dir_fd` to support `lookup()`.
@@ -586,7 +1405,23 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         dir: &impl AsRawFd,
         name: &CStr,
     ) -> io::Result<(File, Option<FileHandle>, StatExt)> {
-        let path_file = self.open_file_restricted(dir, name, libc::O_PATH, 0)?;
+        let path_file = self.open_file_restricted(dir, name, libc::O_PATH)?;
+        let st = statx(&path_file, None)?;
+        let handle = if self.cfg.inode_file_handles {
+            FileHandle::from_fd(&path_file)?
+        } else {
+            None
+        };
+
+        Ok((path_file, handle, st))
+    }
+
+    // Duplicate the root directory opened by the caller.
+    fn open_root_file_and_handle(
+        &self,
+        root: &File,
+    ) -> io::Result<(File, Option<FileHandle>, StatExt)> {
+        let path_file = root.try_clone()?;
         let st = statx(&path_file, None)?;
         let handle = if self.cfg.inode_file_handles {
             FileHandle::from_fd(&path_file)?
@@ -619,12 +1454,12 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         if !self.cfg.use_host_ino {
             // If the inode has already been assigned before, the new inode is not reassigned,
             // ensuring that the same file is always the same inode
-            Ok(InodeMap::get_inode_locked(inodes, id, handle_opt)
-                .unwrap_or_else(|| self.next_inode.fetch_add(1, Ordering::Relaxed)))
+            Ok(ShardedInodeMap::get_inode_locked(inodes, id, handle_opt)
+                .unwrap_or_else(|| self.inode_map.allocator.allocate()))
         } else {
             let inode = if id.ino > MAX_HOST_INO {
                 // Prefer looking for previous mappings from memory
-                match InodeMap::get_inode_locked(inodes, id, handle_opt) {
+                match ShardedInodeMap::get_inode_locked(inodes, id, handle_opt) {
                     Some(ino) => ino,
                     None => self.ino_allocator.get_unique_inode(id)?,
                 }
@@ -637,6 +1472,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     }
 
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        self.check_dir_inode(parent)?;
         let name =
             if parent == fuse::ROOT_ID && name.to_bytes_with_nul().starts_with(PARENT_DIR_CSTR) {
                 // Safe as this is a constant value and a valid C string.
@@ -647,7 +1483,244 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
 
         let dir = self.inode_map.get(parent)?;
         let dir_file = dir.get_file()?;
-        let (path_fd, handle_opt, st) = Self::open_file_and_handle(self, &dir_file, name)?;
+        let (path_fd, handle_opt, st) = match Self::open_file_and_handle(self, &dir_file, name) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) && self.cfg.case_insensitive => {
+                let name = self
+                    .case_fold_cache
+                    .lookup(parent, &dir_file, name)?
+                    .ok_or(e)?;
+                Self::open_file_and_handle(self, &dir_file, &name)?
+            }
+            res => res?,
+        };
+        let submount = self.is_submount(&dir_file, name, &st)?;
+
+        let mut entry = self.do_lookup_file(path_fd, handle_opt, st)?;
+        if submount {
+            entry.attr_flags |= fuse::ATTR_SUBMOUNT;
+        }
+        // New entries are looked up once created, so that's where they stop being missing.
+        if let Some(cache) = self.negative_cache.as_ref() {
+            cache.remove(parent, name);
+        }
+        Ok(entry)
+    }
+
+    // Whether the entry `name` in `dir` with attributes `st` is a mount point to report as a
+    // submount. The root of the shared directory and its parent are never reported.
+    fn is_submount(&self, dir: &impl AsRawFd, name: &CStr, st: &StatExt) -> io::Result<bool> {
+        if !self.cfg.announce_submounts
+            || !self.submounts.load(Ordering::Relaxed)
+            || name.to_bytes_with_nul() == CURRENT_DIR_CSTR
+            || name.to_bytes_with_nul() == PARENT_DIR_CSTR
+        {
+            return Ok(false);
+        }
+        if st.attributes & STATX_ATTR_MOUNT_ROOT != 0 {
+            return Ok(true);
+        }
+        // Bind mounts of the same filesystem can only be told apart by statx(2).
+        let parent = stat_fd(dir, None)?;
+        Ok(parent.st_dev != st.st.st_dev)
+    }
+
+    /// Create an unnamed temporary file in `dir` with `O_TMPFILE`, and register an inode for it.
+    ///
+    /// The returned `Entry` refers to an inode without any name, which can be linked into the
+    /// tree later by `link()`.
+    fn create_tmpfile(
+        &self,
+        dir: &impl AsRawFd,
+        flags: i32,
+        mode: u32,
+    ) -> io::Result<(Entry, File)> {
+        // Safe as this is a constant value and a valid C string.
+        let cur = CStr::from_bytes_with_nul(CURRENT_DIR_CSTR).unwrap();
+        let file = openat(dir, cur, flags | libc::O_CLOEXEC, mode)?;
+        let path_fd = reopen_fd_through_proc(&file, libc::O_PATH, &self.proc_self_fd)?;
+        let st = statx(&path_fd, None)?;
+
+        // Don't use file handles for the anonymous inode, some filesystems refuse to open
+        // handles of unlinked inodes.
+        let entry = self.do_lookup_file(path_fd, None, st)?;
+
+        Ok((entry, file))
+    }
+
+    // Whether the caller runs as the anonymous ids, see `Config::squash`.
+    fn is_squashed(&self, ctx: &Context) -> bool {
+        match self.cfg.squash {
+            SquashPolicy::None => false,
+            SquashPolicy::RootSquash => ctx.uid == 0,
+            SquashPolicy::AllSquash => true,
+        }
+    }
+
+    // Translate the credentials of the caller into host ids.
+    fn host_creds(&self, ctx: &Context) -> io::Result<(libc::uid_t, libc::gid_t)> {
+        if self.is_squashed(ctx) {
+            return Ok((self.cfg.anon_uid, self.cfg.anon_gid));
+        }
+        Ok((self.uid_in(ctx.uid), self.gid_in(ctx.gid)))
+    }
+
+    // Switch to the host credentials of the caller, see `set_creds()`.
+    fn set_creds(&self, ctx: &Context) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
+        let (uid, gid) = self.host_creds(ctx)?;
+        set_creds(uid, gid)
+    }
+
+    // Switch to the supplementary groups of the caller for `Config::supp_groups`. Must be called
+    // before `set_creds()`, which drops the capability to change groups, and the guard must be
+    // dropped after the one of `set_creds()`.
+    fn set_supp_groups(&self, ctx: &Context) -> io::Result<Option<ScopedSuppGroups>> {
+        if !self.cfg.supp_groups || self.is_squashed(ctx) {
+            return Ok(None);
+        }
+        // Root isn't restricted by groups anyway.
+        let (uid, _) = self.host_creds(ctx)?;
+        if uid == 0 {
+            return Ok(None);
+        }
+
+        // Groups sent by the kernel are ids of the client.
+        let mut groups = proc_supp_groups(ctx.pid, uid).unwrap_or_default();
+        for gid in ctx.supp_groups.iter().map(|gid| self.gid_in(*gid)) {
+            if !groups.contains(&gid) {
+                groups.push(gid);
+            }
+        }
+        if groups.is_empty() {
+            return Ok(None);
+        }
+        ScopedSuppGroups::new(&groups).map(Some)
+    }
+
+    // Get the mode to create a new file with, applying the caller's umask. With POSIX ACLs the
+    // umask must be ignored if the parent directory has a default ACL, so leave that to the host
+    // kernel by switching to the caller's umask until the returned guard is dropped.
+    fn create_mode(&self, mode: u32, umask: u32) -> io::Result<(u32, Option<ScopedUmask>)> {
+        if self.posix_acl.load(Ordering::Relaxed) {
+            Ok((mode, Some(ScopedUmask::new(umask)?)))
+        } else {
+            Ok((mode & !(umask & 0o777), None))
+        }
+    }
+
+    // Fail requests on entries of `inode` if it is the root with `Config::root_is_file`.
+    fn check_dir_inode(&self, inode: Inode) -> io::Result<()> {
+        if self.cfg.root_is_file && inode == fuse::ROOT_ID {
+            Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Fail requests modifying the shared directory with `Config::read_only`.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.cfg.read_only {
+            Err(io::Error::from_raw_os_error(libc::EROFS))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Get the entry and attr timeouts of an inode by its file type.
+    fn timeouts(&self, mode: u32) -> (Duration, Duration) {
+        let tunables = self.tunables.load();
+        let (entry, attr) = match mode & libc::S_IFMT {
+            libc::S_IFDIR => (tunables.dir_entry_timeout, tunables.dir_attr_timeout),
+            libc::S_IFREG => (tunables.file_entry_timeout, tunables.file_attr_timeout),
+            libc::S_IFLNK => (
+                tunables.symlink_entry_timeout,
+                tunables.symlink_attr_timeout,
+            ),
+            _ => (None, None),
+        };
+
+        (
+            entry.unwrap_or(tunables.entry_timeout),
+            attr.unwrap_or(tunables.attr_timeout),
+        )
+    }
+
+    // Translate a user id of the FUSE client into a host id.
+    fn uid_in(&self, uid: u32) -> u32 {
+        match self.cfg.uid_map.as_ref() {
+            Some(map) => map.translate_in(uid).unwrap_or(self.cfg.overflow_uid),
+            None => uid,
+        }
+    }
+
+    // Translate a group id of the FUSE client into a host id.
+    fn gid_in(&self, gid: u32) -> u32 {
+        match self.cfg.gid_map.as_ref() {
+            Some(map) => map.translate_in(gid).unwrap_or(self.cfg.overflow_gid),
+            None => gid,
+        }
+    }
+
+    // Translate the new owner of a file into a host id, squashing root to `Config::anon_uid`.
+    fn owner_uid_in(&self, uid: u32) -> u32 {
+        if uid == 0 && self.cfg.squash != SquashPolicy::None {
+            self.cfg.anon_uid
+        } else {
+            self.uid_in(uid)
+        }
+    }
+
+    // Translate the new group of a file into a host id, squashing root to `Config::anon_gid`.
+    fn owner_gid_in(&self, gid: u32) -> u32 {
+        if gid == 0 && self.cfg.squash != SquashPolicy::None {
+            self.cfg.anon_gid
+        } else {
+            self.gid_in(gid)
+        }
+    }
+
+    // Translate the owner of a file into ids of the FUSE client.
+    fn map_stat_out(&self, mut st: libc::stat64) -> libc::stat64 {
+        if let Some(map) = self.cfg.uid_map.as_ref() {
+            st.st_uid = map
+                .translate_out(st.st_uid)
+                .unwrap_or(self.cfg.overflow_uid);
+        }
+        if let Some(map) = self.cfg.gid_map.as_ref() {
+            st.st_gid = map
+                .translate_out(st.st_gid)
+                .unwrap_or(self.cfg.overflow_gid);
+        }
+        st
+    }
+
+    // Check whether the filesystem of the shared directory supports O_TMPFILE.
+    fn probe_tmpfile(&self) -> bool {
+        let root = match self.inode_map.get(fuse::ROOT_ID) {
+            Ok(root) => root,
+            Err(_) => return false,
+        };
+        let root_file = match root.get_file() {
+            Ok(f) => f,
+            Err(_) => return false,
+        };
+
+        // Safe as this is a constant value and a valid C string.
+        let cur = CStr::from_bytes_with_nul(CURRENT_DIR_CSTR).unwrap();
+        openat(
+            &root_file,
+            cur,
+            libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC,
+            0o600,
+        )
+        .is_ok()
+    }
+
+    fn do_lookup_file(
+        &self,
+        path_fd: File,
+        handle_opt: Option<FileHandle>,
+        st: StatExt,
+    ) -> io::Result<Entry> {
         let id = InodeId::from_stat(&st);
 
         let mut found = None;
@@ -683,6 +1756,13 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         } else {
             let handle = if let Some(h) = handle_opt.clone() {
                 InodeHandle::Handle(self.to_openable_handle(h)?)
+            } else if let Some(path_fds) = self.path_fds.as_ref() {
+                // Keep a file handle to reopen the file once its fd is closed, if possible.
+                let fh = FileHandle::from_fd(&path_fd)
+                    .ok()
+                    .flatten()
+                    .and_then(|h| self.to_openable_handle(h).ok());
+                InodeHandle::PathFd(path_fds.insert(path_fd, &id, fh))
             } else {
                 InodeHandle::File(path_fd)
             };
@@ -694,11 +1774,27 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             // racing thread already added an inode with the same id while we're not holding
             // the lock. If so just use the newly added inode, otherwise the inode will be replaced
             // and results in EBADF.
-            match InodeMap::get_alt_locked(inodes.deref(), &id, handle_opt.as_ref()) {
+            //
+            // Inodes are forgotten without holding the lock, so skip an inode whose last
+            // reference has just been dropped, its inode number is reused below.
+            let found = self
+                .inode_map
+                .get_alt_locked(inodes.deref(), &id, handle_opt.as_ref())
+                .filter(|data| {
+                    data.refcount
+                        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |curr| {
+                            if curr == 0 {
+                                None
+                            } else {
+                                Some(curr.saturating_add(1))
+                            }
+                        })
+                        .is_ok()
+                });
+            match found {
                 Some(data) => {
                     // An inode was added concurrently while we did not hold a lock on
                     // `self.inodes_map`, so we use that instead. `handle` will be dropped.
-                    data.refcount.fetch_add(1, Ordering::Relaxed);
                     data.inode
                 }
                 None => {
@@ -712,20 +1808,20 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         ));
                     }
 
-                    InodeMap::insert_locked(
-                        inodes.deref_mut(),
-                        Arc::new(InodeData::new(inode, handle, 1, id, st.st.st_mode)),
-                    );
+                    let data = Arc::new(InodeData::new(inode, handle, 1, id, st.st.st_mode));
+                    self.inotify_watch(&data);
+                    self.inode_map.insert_locked(inodes.deref_mut(), data);
                     inode
                 }
             }
         };
 
-        let (entry_timeout, attr_timeout) = if is_dir(st.st.st_mode) {
-            (self.dir_entry_timeout, self.dir_attr_timeout)
-        } else {
-            (self.cfg.entry_timeout, self.cfg.attr_timeout)
-        };
+        Ok(self.to_entry(inode, &st))
+    }
+
+    // Build the entry of `inode` with the attributes `st`.
+    fn to_entry(&self, inode: Inode, st: &StatExt) -> Entry {
+        let (entry_timeout, attr_timeout) = self.timeouts(st.st.st_mode);
 
         // Whether to enable file DAX according to the value of dax_file_size
         let mut attr_flags: u32 = 0;
@@ -739,55 +1835,123 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             }
         }
 
-        Ok(Entry {
+        Entry {
             inode,
-            generation: 0,
-            attr: st.st,
+            generation: self.inode_map.allocator.generation(inode),
+            attr: self.map_stat_out(st.st),
             attr_flags,
             attr_timeout,
             entry_timeout,
-        })
+        }
     }
 
-    fn forget_one(&self, inodes: &mut InodeStore, inode: Inode, count: u64) {
-        // ROOT_ID should not be forgotten, or we're not able to access to files any more.
-        if inode == fuse::ROOT_ID {
-            return;
+    // Take a reference on `inode` and get its entry, if it's still in use, without looking it up
+    // by name. The entry gets the extra `attr_flags` remembered from the lookup by name.
+    fn relookup(&self, inode: Inode, attr_flags: u32) -> Option<Entry> {
+        let data = self.inode_map.get(inode).ok()?;
+        data.refcount
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |curr| {
+                if curr == 0 {
+                    None
+                } else {
+                    Some(curr.saturating_add(1))
+                }
+            })
+            .ok()?;
+        match data.get_file().and_then(|f| statx(&f, None)) {
+            Ok(st) if InodeId::from_stat(&st) == data.id => {
+                let mut entry = self.to_entry(inode, &st);
+                entry.attr_flags |= attr_flags;
+                Some(entry)
+            }
+            _ => {
+                self.forget_one(inode, 1);
+                None
+            }
         }
+    }
 
-        if let Some(data) = inodes.get(&inode) {
-            // Acquiring the write lock on the inode map prevents new lookups from incrementing the
-            // refcount but there is the possibility that a previous lookup already acquired a
-            // reference to the inode data and is in the process of updating the refcount so we need
-            // to loop here until we can decrement successfully.
-            loop {
-                let curr = data.refcount.load(Ordering::Acquire);
-
-                // Saturating sub because it doesn't make sense for a refcount to go below zero and
-                // we don't want misbehaving clients to cause integer overflow.
-                let new = curr.saturating_sub(count);
-
-                // Synchronizes with the acquire load in `do_lookup`.
-                if data
-                    .refcount
-                    .compare_exchange(curr, new, Ordering::AcqRel, Ordering::Acquire)
-                    .is_ok()
-                {
-                    if new == 0 {
-                        // We just removed the last refcount for this inode.
-                        // The allocated inode number should be kept in the map when use_host_ino
-                        // is false or host inode(don't use the virtual 56bit inode) is bigger than MAX_HOST_INO.
-                        let keep_mapping = !self.cfg.use_host_ino || data.id.ino > MAX_HOST_INO;
-                        inodes.remove(&inode, keep_mapping);
-                    }
-                    break;
-                }
+    /// Get the hits and misses of the entries remembered by readdirplus for directory handles.
+    ///
+    /// Entries are remembered while the directory isn't changed, so that listing it again doesn't
+    /// look them up by name.
+    pub fn readdirplus_cache_stats(&self) -> ReaddirplusCacheStats {
+        ReaddirplusCacheStats {
+            hits: self.dirplus_hits.load(Ordering::Relaxed),
+            misses: self.dirplus_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Get the number of `O_PATH` fds held by inodes, and how many have been closed and reopened
+    /// to stay within `Config::max_path_fds`.
+    pub fn path_fd_stats(&self) -> PathFdStats {
+        self.path_fds
+            .as_ref()
+            .map(|path_fds| path_fds.stats())
+            .unwrap_or_default()
+    }
+
+    // Register `file` opened with `flags` as a backing file with `Config::fuse_passthrough`, or
+    // return `None` to serve it as usual.
+    fn open_backing(&self, file: &File, flags: u32) -> Option<BackingFile> {
+        if !self.fuse_passthrough.load(Ordering::Relaxed) || flags & libc::O_DIRECTORY as u32 != 0 {
+            return None;
+        }
+        let dev = self.fuse_dev.load_full()?;
+        match BackingFile::new(dev, file) {
+            Ok(backing) => Some(backing),
+            Err(e) => {
+                debug!("fuse: failed to register backing file, {}", e);
+                None
             }
         }
     }
 
+    fn forget_one(&self, inode: Inode, count: u64) {
+        self.forget_many(&[(inode, count)])
+    }
+
+    fn forget_many(&self, requests: &[(Inode, u64)]) {
+        // ROOT_ID should not be forgotten, or we're not able to access to files any more.
+        let requests: Vec<_> = requests
+            .iter()
+            .filter(|(inode, _)| *inode != fuse::ROOT_ID)
+            .copied()
+            .collect();
+
+        self.inode_map.forget(
+            &requests,
+            |data| {
+                // The allocated inode number should be kept in the map when use_host_ino
+                // is false or host inode(don't use the virtual 56bit inode) is bigger than MAX_HOST_INO,
+                // unless the allocator reuses the inode numbers of forgotten inodes.
+                if self.cfg.use_host_ino {
+                    data.id.ino > MAX_HOST_INO
+                } else {
+                    self.inode_map.allocator.keep_forgotten()
+                }
+            },
+            |data| {
+                // Drop what is known about the inode before its number may be reused.
+                self.inotify_unwatch(data);
+                self.case_fold_cache.remove(data.inode);
+                if let Some(cache) = self.negative_cache.as_ref() {
+                    cache.remove_dir(data.inode);
+                }
+            },
+        );
+    }
+
     fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
-        self.handle_map.release(handle, inode)
+        // The handle is released even if the sync fails, the kernel doesn't release it again.
+        let synced = if self.cfg.sync_on_close {
+            self.handle_map.get(handle, inode)?.sync_on_close()
+        } else {
+            Ok(())
+        };
+        self.poll_handle_map.release(handle, inode);
+        self.handle_map.release(handle, inode)?;
+        synced
     }
 
     // Validate a path component, same as the one in vfs layer, but only do the validation if this
@@ -819,8 +1983,8 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         }
 
         match opcode {
-            // write should not exceed the file size.
-            Opcode::Write => {
+            // write and copy_file_range should not exceed the file size.
+            Opcode::Write | Opcode::CopyFileRange => {
                 if size + offset > file_size {
                     return Err(eperm());
                 }
@@ -829,16 +1993,21 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             Opcode::Fallocate => {
                 let op = mode & !(libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_UNSHARE_RANGE);
                 match op {
-                    // Allocate, punch and zero, must not change file size.
-                    0 | libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE => {
-                        if size + offset > file_size {
-                            return Err(eperm());
-                        }
-                    }
                     // collapse and insert will change file size, forbid.
                     libc::FALLOC_FL_COLLAPSE_RANGE | libc::FALLOC_FL_INSERT_RANGE => {
                         return Err(eperm());
                     }
+                    // Allocate, punch and zero keep the file size with FALLOC_FL_KEEP_SIZE.
+                    0 | libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE
+                        if mode & libc::FALLOC_FL_KEEP_SIZE != 0 => {}
+                    // Allocate and zero, must not change file size.
+                    0 | libc::FALLOC_FL_ZERO_RANGE => {
+                        if size + offset > file_size {
+                            return Err(eperm());
+                        }
+                    }
+                    // Punching holes without FALLOC_FL_KEEP_SIZE isn't supported anyway.
+                    libc::FALLOC_FL_PUNCH_HOLE => return Err(eperm()),
                     // Invalid operation
                     _ => return Err(einval()),
                 }
@@ -951,6 +2120,124 @@ fn set_creds(
     ScopedGid::new(gid).and_then(|gid| Ok((ScopedUid::new(uid)?, gid)))
 }
 
+// Get the supplementary groups of process `pid` from `/proc/<pid>/status`, if the process still
+// runs as `uid`. The check guards against pids reused by another process.
+fn proc_supp_groups(pid: libc::pid_t, uid: libc::uid_t) -> Option<Vec<libc::gid_t>> {
+    if pid <= 0 {
+        return None;
+    }
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(e) => {
+            debug!("fuse: failed to get groups of process {}, {}", pid, e);
+            return None;
+        }
+    };
+
+    let mut fsuid = None;
+    let mut groups = None;
+    for line in status.lines() {
+        if let Some(ids) = line.strip_prefix("Uid:") {
+            // Real, effective, saved set and filesystem uid.
+            fsuid = ids.split_whitespace().nth(3).and_then(|id| id.parse().ok());
+        } else if let Some(ids) = line.strip_prefix("Groups:") {
+            groups = ids
+                .split_whitespace()
+                .map(|id| id.parse().ok())
+                .collect::<Option<Vec<_>>>();
+        }
+    }
+
+    if fsuid == Some(uid) {
+        groups
+    } else {
+        None
+    }
+}
+
+// Set the supplementary groups of the current thread, restored when dropped.
+//
+// Like `ScopedUid` and `ScopedGid`, this invokes the syscall directly to only change the
+// credentials of the current thread.
+struct ScopedSuppGroups {
+    old: Vec<libc::gid_t>,
+}
+
+impl ScopedSuppGroups {
+    fn new(groups: &[libc::gid_t]) -> io::Result<Self> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut old = vec![0; res as usize];
+        // Safe because the kernel only writes `old.len()` entries to `old` and we check the return
+        // value.
+        let res = unsafe { libc::getgroups(old.len() as libc::c_int, old.as_mut_ptr()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        old.truncate(res as usize);
+
+        Self::set(groups)?;
+        Ok(ScopedSuppGroups { old })
+    }
+
+    fn set(groups: &[libc::gid_t]) -> io::Result<()> {
+        // Safe because the kernel only reads `groups` and we check the return value.
+        let res = unsafe { libc::syscall(libc::SYS_setgroups, groups.len(), groups.as_ptr()) };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+impl Drop for ScopedSuppGroups {
+    fn drop(&mut self) {
+        if let Err(e) = Self::set(&self.old) {
+            error!("fuse: failed to restore supplementary groups: {}", e);
+        }
+    }
+}
+
+thread_local! {
+    // Whether the current thread has its own umask, see `ScopedUmask`.
+    static FS_UNSHARED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Set the umask of the current thread, restored when dropped.
+//
+// The umask is shared by all threads of a process, so each thread calls `unshare(CLONE_FS)` once
+// to get a private copy before changing it.
+struct ScopedUmask {
+    old: libc::mode_t,
+}
+
+impl ScopedUmask {
+    fn new(umask: u32) -> io::Result<Self> {
+        if !FS_UNSHARED.with(|u| u.get()) {
+            // Safe because this doesn't modify any memory and we check the return value.
+            if unsafe { libc::unshare(libc::CLONE_FS) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            FS_UNSHARED.with(|u| u.set(true));
+        }
+
+        // Safe because this doesn't modify any memory and umask(2) always succeeds.
+        let old = unsafe { libc::umask((umask & 0o777) as libc::mode_t) };
+        Ok(ScopedUmask { old })
+    }
+}
+
+impl Drop for ScopedUmask {
+    fn drop(&mut self) {
+        // Safe because this doesn't modify any memory and umask(2) always succeeds.
+        unsafe { libc::umask(self.old) };
+    }
+}
+
 struct CapFsetid {}
 
 impl Drop for CapFsetid {
@@ -976,6 +2263,34 @@ fn drop_cap_fsetid() -> io::Result<Option<CapFsetid>> {
     Ok(Some(CapFsetid {}))
 }
 
+struct CapMknod {}
+
+impl Drop for CapMknod {
+    fn drop(&mut self) {
+        if let Err(e) = caps::drop(None, caps::CapSet::Effective, caps::Capability::CAP_MKNOD) {
+            error!("fail to drop thread cap_mknod: {}", e);
+        };
+    }
+}
+
+// Changing the effective uid away from root clears the effective capability set, so CAP_MKNOD
+// needs to be raised again after `set_creds()` for operations creating device nodes on behalf of
+// the caller, such as the whiteout of `RENAME_WHITEOUT`.
+fn raise_cap_mknod() -> io::Result<Option<CapMknod>> {
+    if caps::has_cap(None, caps::CapSet::Effective, caps::Capability::CAP_MKNOD)
+        .map_err(|_e| io::Error::new(io::ErrorKind::PermissionDenied, "no CAP_MKNOD capability"))?
+    {
+        return Ok(None);
+    }
+    caps::raise(None, caps::CapSet::Effective, caps::Capability::CAP_MKNOD).map_err(|_e| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "failed to raise CAP_MKNOD capability",
+        )
+    })?;
+    Ok(Some(CapMknod {}))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1318,6 +2633,48 @@ mod tests {
         fs.destroy();
     }
 
+    #[test]
+    fn test_passthroughfs_file_type_timeout() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+        std::os::unix::fs::symlink("file", source.as_path().join("link")).unwrap();
+
+        let secs = Duration::from_secs;
+        let fs_cfg = Config {
+            do_import: true,
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            entry_timeout: secs(0),
+            attr_timeout: secs(0),
+            dir_entry_timeout: Some(secs(1)),
+            dir_attr_timeout: Some(secs(2)),
+            file_entry_timeout: Some(secs(3)),
+            file_attr_timeout: Some(secs(4)),
+            symlink_entry_timeout: Some(secs(5)),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+        fs.import().unwrap();
+        let ctx = Context::default();
+
+        for (name, entry_timeout, attr_timeout) in [
+            ("dir", secs(1), secs(2)),
+            ("file", secs(3), secs(4)),
+            // Falls back to `attr_timeout` without `symlink_attr_timeout`.
+            ("link", secs(5), secs(0)),
+        ] {
+            let entry = fs
+                .lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+                .unwrap();
+            assert_eq!(entry.entry_timeout, entry_timeout);
+            assert_eq!(entry.attr_timeout, attr_timeout);
+            let (_, timeout) = fs.getattr(&ctx, entry.inode, None).unwrap();
+            assert_eq!(timeout, attr_timeout);
+        }
+
+        fs.destroy();
+    }
+
     #[test]
     fn test_stable_inode() {
         use std::os::unix::fs::MetadataExt;
@@ -1373,15 +2730,15 @@ mod tests {
             fs.import().unwrap();
             let entry = fs.lookup(&ctx, ROOT_ID, &child).unwrap();
             assert_eq!(entry.inode & MAX_HOST_INO, meta.ino());
+            let inode_data = fs.inode_map.get(entry.inode).unwrap();
             let inode_store = fs.inode_map.get_map_mut();
-            let inode_data = inode_store.get(&entry.inode).unwrap();
             assert!(inode_store.inode_by_id(&inode_data.id).is_some());
             let id = inode_data.id.clone();
             drop(inode_store);
 
             fs.forget(&ctx, entry.inode, 1);
+            assert!(fs.inode_map.get(entry.inode).is_err());
             let inode_store = fs.inode_map.get_map_mut();
-            assert!(inode_store.get(&entry.inode).is_none());
             assert!(inode_store.inode_by_id(&id).is_none());
             drop(inode_store);
 
@@ -1436,12 +2793,226 @@ mod tests {
             let mode = file.as_file().metadata().unwrap().mode();
             let inode_data =
                 InodeData::new(inode, InodeHandle::File(file.into_file()), 1, id, mode);
-            m.insert(Arc::new(inode_data));
+            m.insert(&inode_data);
             let inode = fs.allocate_inode(&m, &id, None).unwrap();
             assert_eq!(inode & MAX_HOST_INO, 2);
         }
     }
 
+    fn prepare_sharded_passthroughfs(shards: usize, files: usize) -> (PassthroughFs, TempDir) {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for i in 0..files {
+            std::fs::write(source.as_path().join(format!("f{}", i)), b"").unwrap();
+        }
+        let fs_cfg = Config {
+            do_import: true,
+            inode_file_handles: false,
+            inode_map_shards: shards,
+            root_dir: source
+                .as_path()
+                .to_str()
+                .expect("source path to string")
+                .to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+        fs.import().unwrap();
+
+        (fs, source)
+    }
+
+    #[test]
+    fn test_inode_map_shards() {
+        // At least one shard is needed.
+        let (fs, _source) = prepare_sharded_passthroughfs(0, 0);
+        assert_eq!(fs.cfg.inode_map_shards, 1);
+        assert_eq!(fs.inode_map.shards.len(), 1);
+
+        let (fs, _source) = prepare_sharded_passthroughfs(4, 16);
+        let fs = Arc::new(fs);
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let fs = fs.clone();
+                std::thread::spawn(move || {
+                    let ctx = Context::default();
+                    for _ in 0..50 {
+                        for i in 0..16 {
+                            let name = CString::new(format!("f{}", (i + t * 4) % 16)).unwrap();
+                            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+                            assert!(fs.inode_map.get(entry.inode).is_ok());
+                            fs.forget(&ctx, entry.inode, 1);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // Only the root inode is left, and files keep their inode numbers.
+        assert_eq!(fs.inode_map.len(), 1);
+        let ctx = Context::default();
+        let name = CString::new("f0").unwrap();
+        let inode = fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode;
+        assert!(fs
+            .inode_map
+            .shard(inode)
+            .read()
+            .unwrap()
+            .contains_key(&inode));
+        fs.forget(&ctx, inode, 1);
+        assert_eq!(fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode, inode);
+    }
+
+    #[test]
+    fn test_inode_map_refcounts() {
+        const FILES: usize = 16;
+        const ROUNDS: usize = 200;
+        const THREADS: usize = 4;
+
+        let (fs, _source) = prepare_sharded_passthroughfs(4, FILES);
+        let fs = Arc::new(fs);
+        let threads: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let fs = fs.clone();
+                std::thread::spawn(move || {
+                    let ctx = Context::default();
+                    let mut forgets = Vec::new();
+                    for r in 0..ROUNDS {
+                        let name = CString::new(format!("f{}", (r + t) % FILES)).unwrap();
+                        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+                        // Forget every other reference one by one, the others in batches.
+                        if r % 2 == 0 {
+                            fs.forget(&ctx, entry.inode, 1);
+                        } else {
+                            forgets.push((entry.inode, 1));
+                        }
+                        if forgets.len() == 8 {
+                            fs.batch_forget(&ctx, std::mem::take(&mut forgets));
+                        }
+                    }
+                    fs.batch_forget(&ctx, forgets);
+
+                    // Keep a reference to every file.
+                    for i in 0..FILES {
+                        let name = CString::new(format!("f{}", i)).unwrap();
+                        fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // Every file is referenced once per thread, by a single inode.
+        assert_eq!(fs.inode_map.len(), FILES + 1);
+        let ctx = Context::default();
+        let mut forgets = Vec::new();
+        for i in 0..FILES {
+            let name = CString::new(format!("f{}", i)).unwrap();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let data = fs.inode_map.get(entry.inode).unwrap();
+            assert_eq!(data.refcount.load(Ordering::Relaxed), THREADS as u64 + 1);
+            forgets.push((entry.inode, THREADS as u64 + 1));
+        }
+        fs.batch_forget(&ctx, forgets);
+        assert_eq!(fs.inode_map.len(), 1);
+    }
+
+    // Measure the throughput of concurrent lookups and forgets on a single and on the default
+    // number of shards, with `cargo test -- --ignored --nocapture bench_inode_map_shards`.
+    #[test]
+    #[ignore]
+    fn bench_inode_map_shards() {
+        const FILES: usize = 64;
+        const ROUNDS: usize = 20_000;
+
+        for shards in [1, Config::default().inode_map_shards] {
+            for nr_threads in [1, 2, 4, 8, 16] {
+                let (fs, _source) = prepare_sharded_passthroughfs(shards, FILES);
+                let fs = Arc::new(fs);
+                let ctx = Context::default();
+                // Keep the files referenced, so lookups only hit the inode map.
+                let inodes: Vec<_> = (0..FILES)
+                    .map(|i| {
+                        let name = CString::new(format!("f{}", i)).unwrap();
+                        fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode
+                    })
+                    .collect();
+
+                let start = std::time::Instant::now();
+                let threads: Vec<_> = (0..nr_threads)
+                    .map(|t| {
+                        let fs = fs.clone();
+                        let inodes = inodes.clone();
+                        std::thread::spawn(move || {
+                            for r in 0..ROUNDS {
+                                let inode = inodes[(r + t * 7) % FILES];
+                                let data = fs.inode_map.get(inode).unwrap();
+                                data.refcount.fetch_add(1, Ordering::Relaxed);
+                                fs.forget_one(inode, 1);
+                            }
+                        })
+                    })
+                    .collect();
+                for t in threads {
+                    t.join().unwrap();
+                }
+                let elapsed = start.elapsed();
+                println!(
+                    "shards {:3} threads {:2}: {:10.0} ops/s",
+                    shards,
+                    nr_threads,
+                    (nr_threads * ROUNDS) as f64 / elapsed.as_secs_f64()
+                );
+            }
+        }
+    }
+
+    // Measure the throughput of concurrent handle lookups on a single and on the default number
+    // of shards, with `cargo test -- --ignored --nocapture bench_handle_map_shards`.
+    #[test]
+    #[ignore]
+    fn bench_handle_map_shards() {
+        const HANDLES: u64 = 64;
+        const ROUNDS: u64 = 200_000;
+
+        let file = TempFile::new().unwrap().into_file();
+        for shards in [1, Config::default().handle_map_shards] {
+            for nr_threads in [1, 2, 4, 8, 16] {
+                let map = Arc::new(HandleMap::new(shards, None, HandleLimitPolicy::RejectNew));
+                for handle in 0..HANDLES {
+                    let data = HandleData::new(ROOT_ID, file.try_clone().unwrap(), 0);
+                    map.try_insert(handle, data).unwrap();
+                }
+
+                let start = std::time::Instant::now();
+                let threads: Vec<_> = (0..nr_threads)
+                    .map(|t| {
+                        let map = map.clone();
+                        std::thread::spawn(move || {
+                            for r in 0..ROUNDS {
+                                map.get((r + t * 7) % HANDLES, ROOT_ID).unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for t in threads {
+                    t.join().unwrap();
+                }
+                let elapsed = start.elapsed();
+                println!(
+                    "shards {:3} threads {:2}: {:10.0} gets/s",
+                    shards,
+                    nr_threads,
+                    (nr_threads * ROUNDS) as f64 / elapsed.as_secs_f64()
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_validate_virtiofs_config() {
         // cache=none + writeback, writeback should be disabled
@@ -1578,4 +3149,128 @@ mod tests {
         read_buffer_file.read_to_end(&mut newbuf).unwrap();
         assert_eq!(newbuf, data);
     }
+
+    #[test]
+    fn test_new_from_rootfd() {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let has_cap = caps::has_cap(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH);
+        for handles in [false, true] {
+            if handles && !matches!(has_cap, Ok(true)) {
+                println!("invoking open_by_handle_at needs CAP_DAC_READ_SEARCH");
+                continue;
+            }
+            for by_fd in [false, true] {
+                let source = TempDir::new().expect("Cannot create temporary directory.");
+                std::fs::write(source.as_path().join("file"), b"data").unwrap();
+                let root = std::fs::OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+                    .open(source.as_path())
+                    .unwrap();
+                let cfg = Config {
+                    root_dir: "/nonexistent".to_string(),
+                    inode_file_handles: handles,
+                    ..Default::default()
+                };
+                // The fd in the configuration is duplicated, the caller may close it.
+                let fs = if by_fd {
+                    let cfg = Config {
+                        root_fd: Some(root.as_raw_fd()),
+                        ..cfg
+                    };
+                    PassthroughFs::<()>::new(cfg).unwrap()
+                } else {
+                    PassthroughFs::<()>::new_from_rootfd(cfg, root).unwrap()
+                };
+                assert_eq!(fs.keep_fds().len(), 2);
+
+                // The shared directory is no longer reachable by its original path.
+                let moved = TempDir::new().expect("Cannot create temporary directory.");
+                let moved = moved.as_path().join("moved");
+                std::fs::rename(source.as_path(), &moved).unwrap();
+                fs.import().unwrap();
+
+                let ctx = Context::default();
+                let name = CString::new("file").unwrap();
+                let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+                assert_eq!(entry.attr.st_size, 4);
+                let (handle, _, _) = fs
+                    .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+                    .unwrap();
+                let mut out_file = TempFile::new().unwrap().into_file();
+                let read = fs
+                    .read(
+                        &ctx,
+                        entry.inode,
+                        handle.unwrap(),
+                        &mut out_file,
+                        4,
+                        0,
+                        None,
+                        0,
+                    )
+                    .unwrap();
+                assert_eq!(read, 4);
+                std::fs::remove_dir_all(&moved).unwrap();
+            }
+        }
+
+        // The root must be a directory.
+        let file = TempFile::new().unwrap().into_file();
+        let fs = PassthroughFs::<()>::new_from_rootfd(Config::default(), file).unwrap();
+        let err = fs.import().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+    }
+
+    #[test]
+    fn test_pre_warm() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for d in 0..10 {
+            let dir = source.as_path().join(format!("dir{d}"));
+            std::fs::create_dir(&dir).unwrap();
+            for f in 0..10 {
+                std::fs::write(dir.join(format!("file{f}")), b"data").unwrap();
+            }
+        }
+        let new_fs = |pre_warm_depth| {
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                pre_warm_depth,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            fs
+        };
+
+        let fs = new_fs(Some(2));
+        let next_inode = fs.inode_map.allocator.next();
+        assert_eq!(next_inode, ROOT_ID + 1 + 110);
+        // Lookups find the inodes already known, and hold them once more.
+        let ctx = Context::default();
+        for d in 0..10 {
+            let name = CString::new(format!("dir{d}")).unwrap();
+            let dir = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            for f in 0..10 {
+                let name = CString::new(format!("file{f}")).unwrap();
+                let entry = fs.lookup(&ctx, dir.inode, &name).unwrap();
+                assert_eq!(entry.attr.st_size, 4);
+                let data = fs.inode_map.get(entry.inode).unwrap();
+                assert_eq!(data.refcount.load(Ordering::Relaxed), 2);
+                fs.forget(&ctx, entry.inode, 1);
+                assert!(fs.inode_map.get(entry.inode).is_ok());
+            }
+        }
+        assert_eq!(fs.inode_map.allocator.next(), next_inode);
+        // Known inodes are skipped.
+        assert_eq!(fs.pre_warm(2, usize::MAX).unwrap(), 0);
+
+        // Depth and number of inodes are limited.
+        let fs = new_fs(None);
+        assert_eq!(fs.pre_warm(0, usize::MAX).unwrap(), 0);
+        assert_eq!(fs.pre_warm(1, usize::MAX).unwrap(), 10);
+        assert_eq!(fs.pre_warm(2, 15).unwrap(), 15);
+        assert_eq!(fs.pre_warm(2, usize::MAX).unwrap(), 85);
+    }
 }
@@ -159,35 +159,43 @@ impl MountFds {
                 ))
             })?;
 
-            let mut mount_fds_locked = self.map.write().unwrap();
-
-            // As above: by calling `and_then(Weak::upgrade)`, we treat a failed upgrade just like a
-            // non-existent key.  If the key exists but upgrade fails, then `HashMap::insert()`
-            // below will update the value.  `MountFd::drop()` takes care to only remove a `MountFd`
-            // without strong references from the map, and hence will not touch the updated one.
-            if let Some(mount_fd) = mount_fds_locked.get(&mount_id).and_then(Weak::upgrade) {
-                // A mount FD was added concurrently while we did not hold a lock on
-                // `mount_fds.map` -- use that entry (`file` will be dropped).
-                mount_fd
-            } else {
-                debug!(
-                    "Creating MountFd: mount_id={}, mount_fd={}",
-                    mount_id,
-                    file.as_raw_fd(),
-                );
-                let mount_fd = Arc::new(MountFd {
-                    file,
-                    mount_id,
-                    map: Arc::downgrade(&self.map),
-                });
-                mount_fds_locked.insert(mount_id, Arc::downgrade(&mount_fd));
-                mount_fd
-            }
+            self.insert(mount_id, file)
         };
 
         Ok(mount_fd)
     }
 
+    /// Use `file`, a non-`O_PATH` fd on the mount `mount_id`, to open file handles of that mount
+    /// while the returned `MountFd` is alive, unless the mount already has an fd in the map.
+    ///
+    /// This avoids looking the mount point up in mountinfo, which may not be reachable by path.
+    pub fn insert(&self, mount_id: MountId, file: File) -> Arc<MountFd> {
+        let mut mount_fds_locked = self.map.write().unwrap();
+
+        // As in `get()`: by calling `and_then(Weak::upgrade)`, we treat a failed upgrade just like
+        // a non-existent key.  If the key exists but upgrade fails, then `HashMap::insert()`
+        // below will update the value.  `MountFd::drop()` takes care to only remove a `MountFd`
+        // without strong references from the map, and hence will not touch the updated one.
+        if let Some(mount_fd) = mount_fds_locked.get(&mount_id).and_then(Weak::upgrade) {
+            // A mount FD was added concurrently while we did not hold a lock on
+            // `mount_fds.map` -- use that entry (`file` will be dropped).
+            mount_fd
+        } else {
+            debug!(
+                "Creating MountFd: mount_id={}, mount_fd={}",
+                mount_id,
+                file.as_raw_fd(),
+            );
+            let mount_fd = Arc::new(MountFd {
+                file,
+                mount_id,
+                map: Arc::downgrade(&self.map),
+            });
+            mount_fds_locked.insert(mount_id, Arc::downgrade(&mount_fd));
+            mount_fd
+        }
+    }
+
     // Ensure that `mount_point_path` refers to an inode with the mount ID we need
     fn validate_mount_id(
         &self,
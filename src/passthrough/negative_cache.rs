@@ -0,0 +1,144 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Remember names which don't exist, so probing them again doesn't go to the host.
+//!
+//! Names are remembered for a fixed time, as they may be created on the host behind our back.
+//! Names created through the file system are forgotten right away.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Inode;
+
+// Maximum number of names to remember, to bound the memory used by the cache.
+const MAX_NEGATIVE_ENTRIES: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Entries {
+    // Expiry time of missing names, keyed by the inode of their directory.
+    dirs: HashMap<Inode, HashMap<CString, Instant>>,
+    len: usize,
+}
+
+impl Entries {
+    fn prune(&mut self, now: Instant) {
+        let mut len = 0;
+        self.dirs.retain(|_, names| {
+            names.retain(|_, expiry| *expiry > now);
+            len += names.len();
+            !names.is_empty()
+        });
+        self.len = len;
+    }
+}
+
+/// Cache of names known not to exist in directories.
+pub struct NegativeCache {
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl NegativeCache {
+    /// Create a cache remembering missing names for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        NegativeCache {
+            ttl,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Whether `name` in directory `parent` is known not to exist.
+    pub fn contains(&self, parent: Inode, name: &CStr) -> bool {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let entries = self.entries.lock().unwrap();
+        entries
+            .dirs
+            .get(&parent)
+            .and_then(|names| names.get(name))
+            .is_some_and(|expiry| *expiry > Instant::now())
+    }
+
+    /// Remember that `name` in directory `parent` doesn't exist.
+    pub fn insert(&self, parent: Inode, name: &CStr) {
+        let now = Instant::now();
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len >= MAX_NEGATIVE_ENTRIES {
+            entries.prune(now);
+            if entries.len >= MAX_NEGATIVE_ENTRIES {
+                *entries = Entries::default();
+            }
+        }
+        let names = entries.dirs.entry(parent).or_default();
+        if names.insert(name.to_owned(), now + self.ttl).is_none() {
+            entries.len += 1;
+        }
+    }
+
+    /// Forget `name` in directory `parent`, once it has been created.
+    pub fn remove(&self, parent: Inode, name: &CStr) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut entries = self.entries.lock().unwrap();
+        let removed = match entries.dirs.get_mut(&parent) {
+            Some(names) => {
+                let removed = names.remove(name).is_some();
+                if names.is_empty() {
+                    entries.dirs.remove(&parent);
+                }
+                removed
+            }
+            None => false,
+        };
+        if removed {
+            entries.len -= 1;
+        }
+    }
+
+    /// Forget all names in directory `parent`.
+    pub fn remove_dir(&self, parent: Inode) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(names) = entries.dirs.remove(&parent) {
+            entries.len -= names.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negative_cache() {
+        let cache = NegativeCache::new(Duration::from_secs(60));
+        let name = CString::new("missing").unwrap();
+        let other = CString::new("other").unwrap();
+
+        assert!(!cache.contains(1, &name));
+        cache.insert(1, &name);
+        cache.insert(1, &name);
+        cache.insert(1, &other);
+        cache.insert(2, &name);
+        assert!(cache.contains(1, &name));
+        assert!(cache.contains(2, &name));
+        assert!(!cache.contains(3, &name));
+        assert_eq!(cache.entries.lock().unwrap().len, 3);
+
+        cache.remove(1, &name);
+        assert!(!cache.contains(1, &name));
+        assert!(cache.contains(1, &other));
+        cache.remove_dir(1);
+        assert!(!cache.contains(1, &other));
+        assert_eq!(cache.entries.lock().unwrap().len, 1);
+
+        // Names are forgotten once expired.
+        let cache = NegativeCache::new(Duration::from_millis(0));
+        cache.insert(1, &name);
+        assert!(!cache.contains(1, &name));
+        cache.entries.lock().unwrap().prune(Instant::now());
+        assert_eq!(cache.entries.lock().unwrap().len, 0);
+    }
+}
@@ -19,7 +19,7 @@ unsafe impl ByteValued for LinuxDirent64 {}
 pub use libc::statx as statx_st;
 
 #[cfg(target_env = "gnu")]
-pub use libc::{STATX_BASIC_STATS, STATX_MNT_ID};
+pub use libc::{STATX_BASIC_STATS, STATX_BTIME, STATX_MNT_ID};
 
 // musl provides the 'struct statx', but without stx_mnt_id.
 // However, the libc crate does not provide libc::statx
@@ -64,5 +64,25 @@ pub struct statx_st {
 #[cfg(not(target_env = "gnu"))]
 pub const STATX_BASIC_STATS: libc::c_uint = 0x07ff;
 
+#[cfg(not(target_env = "gnu"))]
+pub const STATX_BTIME: libc::c_uint = 0x0800;
+
 #[cfg(not(target_env = "gnu"))]
 pub const STATX_MNT_ID: libc::c_uint = 0x1000;
+
+// The inode is the root of a mount, not provided by all libc versions.
+pub const STATX_ATTR_MOUNT_ROOT: u64 = 0x2000;
+
+// The argument of openat2(2), not provided by all libc versions.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenHow {
+    pub flags: u64,
+    pub mode: u64,
+    pub resolve: u64,
+}
+
+// Resolution flags of openat2(2), not provided by all libc versions.
+pub const RESOLVE_NO_XDEV: u64 = 0x01;
+pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+pub const RESOLVE_BENEATH: u64 = 0x08;
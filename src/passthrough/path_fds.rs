@@ -0,0 +1,247 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keep the `O_PATH` fds held by inodes within `Config::max_path_fds`.
+//!
+//! Inodes with an evictable fd are kept in a clock: when there are too many fds open, the clock
+//! hand closes the fds of inodes which haven't been used since it last went by them. An inode
+//! whose fd has been closed reopens its file when used again, by its file handle or by the path
+//! its fd had when closed, and checks that it is still the same file.
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use super::file_handle::OpenableFileHandle;
+use super::inode_store::InodeId;
+use super::statx::statx;
+use super::util::openat;
+
+/// Number of `O_PATH` fds of inodes, see
+/// [PassthroughFs::path_fd_stats](struct.PassthroughFs.html#method.path_fd_stats).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PathFdStats {
+    /// Fds currently open.
+    pub open: usize,
+    /// Fds closed to stay within `Config::max_path_fds`.
+    pub evictions: u64,
+    /// Fds reopened after having been closed.
+    pub reopens: u64,
+}
+
+/// Clock of the inodes holding an evictable `O_PATH` fd.
+pub(super) struct PathFdCache {
+    max_fds: usize,
+    open: AtomicUsize,
+    evictions: AtomicU64,
+    reopens: AtomicU64,
+    // Inodes in the order the clock hand goes by them. Entries of dropped inodes are skipped.
+    clock: Mutex<VecDeque<Weak<PathFd>>>,
+    // Used to get the paths of fds being closed.
+    proc_self_fd: File,
+}
+
+impl PathFdCache {
+    pub fn new(max_fds: usize, proc_self_fd: File) -> Self {
+        PathFdCache {
+            max_fds,
+            open: AtomicUsize::new(0),
+            evictions: AtomicU64::new(0),
+            reopens: AtomicU64::new(0),
+            clock: Mutex::new(VecDeque::new()),
+            proc_self_fd,
+        }
+    }
+
+    /// Keep track of the `O_PATH` fd `file` of the inode `id`, which may be reopened through
+    /// `handle`.
+    pub fn insert(
+        self: &Arc<Self>,
+        file: File,
+        id: &InodeId,
+        handle: Option<Arc<OpenableFileHandle>>,
+    ) -> Arc<PathFd> {
+        let path_fd = Arc::new(PathFd {
+            file: RwLock::new(Some(Arc::new(file))),
+            handle,
+            path: Mutex::new(None),
+            ino: id.ino,
+            dev: id.dev,
+            referenced: AtomicBool::new(true),
+            cache: self.clone(),
+        });
+        self.open.fetch_add(1, Ordering::Relaxed);
+        self.opened(&path_fd);
+
+        path_fd
+    }
+
+    pub fn stats(&self) -> PathFdStats {
+        PathFdStats {
+            open: self.open.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            reopens: self.reopens.load(Ordering::Relaxed),
+        }
+    }
+
+    // Put the inode whose fd has just been opened in the clock, and close the fds of other
+    // inodes if there are too many.
+    fn opened(&self, path_fd: &Arc<PathFd>) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let mut clock = self.clock.lock().unwrap();
+        clock.push_back(Arc::downgrade(path_fd));
+        // Entries of dropped inodes are only skipped when the hand goes by, drop them once they
+        // outnumber the fds.
+        if clock.len() > self.max_fds * 2 + 64 {
+            clock.retain(|entry| entry.strong_count() > 0);
+        }
+
+        // Go around the clock at most twice, clearing the referenced bits on the first round.
+        let mut budget = clock.len() * 2;
+        while self.open.load(Ordering::Relaxed) > self.max_fds && budget > 0 {
+            budget -= 1;
+            let entry = match clock.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            let candidate = match entry.upgrade() {
+                Some(candidate) => candidate,
+                None => continue,
+            };
+            if candidate.referenced.swap(false, Ordering::Relaxed) || !candidate.evict() {
+                clock.push_back(entry);
+            }
+        }
+    }
+}
+
+/// An `O_PATH` fd of an inode, which may be closed and reopened later.
+pub(super) struct PathFd {
+    file: RwLock<Option<Arc<File>>>,
+    handle: Option<Arc<OpenableFileHandle>>,
+    // Path of the fd when it was closed, used if the file can't be reopened by its handle.
+    path: Mutex<Option<CString>>,
+    ino: u64,
+    dev: u64,
+    // Whether the fd has been used since the clock hand last went by.
+    referenced: AtomicBool,
+    cache: Arc<PathFdCache>,
+}
+
+impl fmt::Debug for PathFd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Path fd: ino {}, dev {}", self.ino, self.dev)
+    }
+}
+
+impl PathFd {
+    /// Get the fd, reopening the file if the fd has been closed.
+    ///
+    /// The fd stays open while the returned file is alive, even if it gets evicted meanwhile.
+    pub fn get(self: &Arc<Self>) -> io::Result<Arc<File>> {
+        self.referenced.store(true, Ordering::Relaxed);
+        // Do not expect poisoned lock here, so safe to unwrap().
+        if let Some(file) = self.file.read().unwrap().as_ref() {
+            return Ok(file.clone());
+        }
+
+        let file = {
+            // Do not expect poisoned lock here, so safe to unwrap().
+            let mut guard = self.file.write().unwrap();
+            // The file may have been reopened by a concurrent request.
+            if let Some(file) = guard.as_ref() {
+                return Ok(file.clone());
+            }
+            let file = Arc::new(self.reopen()?);
+            *guard = Some(file.clone());
+            file
+        };
+        self.cache.open.fetch_add(1, Ordering::Relaxed);
+        self.cache.reopens.fetch_add(1, Ordering::Relaxed);
+        // Evict other fds without holding the lock of this one, the clock locks them in turn.
+        self.cache.opened(self);
+
+        Ok(file)
+    }
+
+    fn reopen(&self) -> io::Result<File> {
+        let file = match self.handle.as_ref().map(|h| h.open(libc::O_PATH)) {
+            Some(Ok(file)) => file,
+            // Handles of removed files are stale, while paths may have been reused.
+            Some(Err(e)) if e.raw_os_error() != Some(libc::EPERM) => return Err(e),
+            // Opening by handle needs CAP_DAC_READ_SEARCH, fall back to the path.
+            Some(Err(_)) | None => {
+                // Do not expect poisoned lock here, so safe to unwrap().
+                let path = self.path.lock().unwrap().clone();
+                let path = path.ok_or_else(|| io::Error::from_raw_os_error(libc::ESTALE))?;
+                openat(
+                    &libc::AT_FDCWD,
+                    &path,
+                    libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                    0,
+                )
+                .map_err(|e| match e.raw_os_error() {
+                    Some(libc::ENOENT) => io::Error::from_raw_os_error(libc::ESTALE),
+                    _ => e,
+                })?
+            }
+        };
+
+        // The path may now be another file. Its inode number may be reused too, which isn't
+        // detected without a file handle.
+        let st = statx(&file, None)?;
+        if st.st.st_ino != self.ino || st.st.st_dev != self.dev {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE));
+        }
+
+        Ok(file)
+    }
+
+    // Close the fd unless it is being reopened, or it can't be reopened later. Return whether the
+    // fd has been closed.
+    fn evict(&self) -> bool {
+        let mut guard = match self.file.try_write() {
+            Ok(guard) => guard,
+            Err(_) => return false,
+        };
+        let file = match guard.as_ref() {
+            Some(file) => file,
+            None => return false,
+        };
+        // Remember the path even with a handle, in case opening by handle isn't permitted.
+        // Safe to unwrap() as fd numbers don't contain NUL bytes.
+        let fd = CString::new(file.as_raw_fd().to_string()).unwrap();
+        let path = nix::fcntl::readlinkat(self.cache.proc_self_fd.as_raw_fd(), &*fd)
+            .ok()
+            .map(|path| path.into_vec())
+            // Removed files can't be opened by their path anymore, nor can anonymous files.
+            .filter(|path| path.starts_with(b"/") && !path.ends_with(b" (deleted)"));
+        match path {
+            // readlink(2) doesn't return paths with NUL bytes, and do not expect poisoned lock
+            // here, so safe to unwrap().
+            Some(path) => *self.path.lock().unwrap() = Some(CString::new(path).unwrap()),
+            None if self.handle.is_none() => return false,
+            None => {}
+        }
+
+        *guard = None;
+        self.cache.open.fetch_sub(1, Ordering::Relaxed);
+        self.cache.evictions.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+impl Drop for PathFd {
+    fn drop(&mut self) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        if self.file.get_mut().unwrap().is_some() {
+            self.cache.open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
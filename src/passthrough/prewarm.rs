@@ -0,0 +1,77 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Populate the inode map before the first lookups, for `Config::pre_warm_depth`.
+//!
+//! The tree of the shared directory is walked breadth first, and the inodes found are registered
+//! as if looked up once. Lookups still open and stat the names they are given, in case the tree
+//! changed meanwhile, but they find the inodes already known and don't have to set them up.
+
+use std::collections::VecDeque;
+use std::io;
+
+use vm_memory::bitmap::BitmapSlice;
+
+use super::inode_store::InodeId;
+use super::util::read_dir_names;
+use super::{Inode, PassthroughFs};
+use crate::abi::fuse_abi as fuse;
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Register the inodes down to `max_depth` below the shared directory, at most `max_inodes`
+    /// of them, and return how many inodes have been registered. The file system must have been
+    /// imported.
+    ///
+    /// Each inode gets a lookup count of one, so it stays known until the kernel has forgotten it
+    /// once more than it has looked it up. Inodes already known keep their lookup count, and
+    /// entries which can't be opened are skipped.
+    pub fn pre_warm(&self, max_depth: usize, max_inodes: usize) -> io::Result<usize> {
+        let mut added = 0;
+        let mut dirs: VecDeque<(Inode, usize)> = VecDeque::from([(fuse::ROOT_ID, 0)]);
+
+        while let Some((parent, depth)) = dirs.pop_front() {
+            if depth >= max_depth {
+                continue;
+            }
+            let dir = self.inode_map.get(parent)?;
+            let dir_file = dir.get_file()?;
+            let names = match read_dir_names(&dir_file) {
+                Ok(names) => names,
+                Err(e) => {
+                    warn!(
+                        "passthroughfs: pre_warm: failed to read inode {}: {}",
+                        parent, e
+                    );
+                    continue;
+                }
+            };
+
+            for name in names {
+                if added >= max_inodes {
+                    return Ok(added);
+                }
+                // Entries may be removed or replaced meanwhile.
+                let (path_fd, handle_opt, st) = match self.open_file_and_handle(&dir_file, &name) {
+                    Ok(res) => res,
+                    Err(_) => continue,
+                };
+                // Hard links and inodes already looked up keep their lookup count, directories
+                // already looked up are still walked.
+                let id = InodeId::from_stat(&st);
+                let is_dir = st.st.st_mode & libc::S_IFMT == libc::S_IFDIR;
+                let inode = match self.inode_map.get_alt(&id, handle_opt.as_ref()) {
+                    Some(data) => data.inode,
+                    None => {
+                        added += 1;
+                        self.do_lookup_file(path_fd, handle_opt, st)?.inode
+                    }
+                };
+                if is_dir {
+                    dirs.push_back((inode, depth + 1));
+                }
+            }
+        }
+
+        Ok(added)
+    }
+}
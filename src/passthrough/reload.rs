@@ -0,0 +1,149 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reload the options of passthrough file systems which may change at runtime.
+//!
+//! The `SIGUSR2` handler only counts the signals received, as reading a configuration file isn't
+//! async-signal-safe. File systems with a `Config::config_file` reload it when
+//! `PassthroughFs::reload_if_requested()` is next called after a signal.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use vm_memory::bitmap::BitmapSlice;
+
+use super::{CachePolicy, Config, PassthroughFs};
+
+static RELOAD_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Options which may be changed at runtime by `PassthroughFs::apply_config_update()`.
+pub(super) struct Tunables {
+    pub entry_timeout: Duration,
+    pub attr_timeout: Duration,
+    pub dir_entry_timeout: Option<Duration>,
+    pub dir_attr_timeout: Option<Duration>,
+    pub file_entry_timeout: Option<Duration>,
+    pub file_attr_timeout: Option<Duration>,
+    pub symlink_entry_timeout: Option<Duration>,
+    pub symlink_attr_timeout: Option<Duration>,
+    pub cache_policy: CachePolicy,
+    pub xattr: bool,
+}
+
+impl Tunables {
+    pub fn new(cfg: &Config) -> Self {
+        Tunables {
+            entry_timeout: cfg.entry_timeout,
+            attr_timeout: cfg.attr_timeout,
+            dir_entry_timeout: cfg.dir_entry_timeout,
+            dir_attr_timeout: cfg.dir_attr_timeout,
+            file_entry_timeout: cfg.file_entry_timeout,
+            file_attr_timeout: cfg.file_attr_timeout,
+            symlink_entry_timeout: cfg.symlink_entry_timeout,
+            symlink_attr_timeout: cfg.symlink_attr_timeout,
+            cache_policy: cfg.cache_policy.clone(),
+            xattr: cfg.xattr,
+        }
+    }
+}
+
+extern "C" fn handle_reload_signal(_signo: libc::c_int) {
+    RELOAD_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Install the `SIGUSR2` handler requesting configuration files to be reloaded, replacing any
+/// handler set by the application.
+pub(super) fn install_reload_handler() -> io::Result<()> {
+    static INSTALL: Once = Once::new();
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+    INSTALL.call_once(|| {
+        // Don't interrupt the system calls of requests being handled.
+        let action = SigAction::new(
+            SigHandler::Handler(handle_reload_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        // Safe because the handler only updates an atomic counter, so it is async-signal-safe.
+        match unsafe { sigaction(Signal::SIGUSR2, &action) } {
+            Ok(_) => INSTALLED.store(true, Ordering::Release),
+            Err(e) => error!("failed to install SIGUSR2 handler: {}", e),
+        }
+    });
+
+    if INSTALLED.load(Ordering::Acquire) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no SIGUSR2 handler to reload the configuration",
+        ))
+    }
+}
+
+/// Get the number of `SIGUSR2` received since the handler has been installed.
+pub(super) fn reload_requests() -> u64 {
+    RELOAD_REQUESTS.load(Ordering::Relaxed)
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Apply the options of `new_cfg` which may change at runtime: the entry and attribute
+    /// timeouts, `cache_policy` and `xattr`. Other options of `new_cfg` are ignored.
+    ///
+    /// Fail without changing anything if `root_dir` or `do_import` differ from the running
+    /// configuration, or if the new options conflict with the features negotiated by `init`.
+    /// Requests in flight keep the options they started with.
+    pub fn apply_config_update(&self, new_cfg: Config) -> io::Result<()> {
+        let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+
+        if new_cfg.root_dir != self.cfg.root_dir {
+            return invalid("root_dir can't be changed at runtime");
+        }
+        if new_cfg.do_import != self.cfg.do_import {
+            return invalid("do_import can't be changed at runtime");
+        }
+        if self.no_open.load(Ordering::Relaxed) && new_cfg.cache_policy != CachePolicy::Always {
+            return invalid("no_open only works with cache=always");
+        }
+        if self.writeback.load(Ordering::Relaxed) && new_cfg.cache_policy == CachePolicy::Never {
+            return invalid("writeback cache conflicts with cache=none");
+        }
+        if self.posix_acl.load(Ordering::Relaxed) && !new_cfg.xattr {
+            return invalid("POSIX ACLs need xattr");
+        }
+
+        self.tunables.store(Arc::new(Tunables::new(&new_cfg)));
+        Ok(())
+    }
+
+    /// Reload `Config::config_file` if `SIGUSR2` has been received since it was last loaded, and
+    /// apply it with `apply_config_update()`.
+    ///
+    /// Return whether the configuration has been reloaded. The signal handler only records the
+    /// request, the caller is expected to call this regularly, e.g. between requests or from a
+    /// dedicated thread. Requests in flight keep the options they started with.
+    pub fn reload_if_requested(&self) -> io::Result<bool> {
+        let path = match self.cfg.config_file.as_ref() {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        let requests = reload_requests();
+        let seen = self.reloads.load(Ordering::Relaxed);
+        // Only one of the threads racing to handle a request reloads the file.
+        if requests == seen
+            || self
+                .reloads
+                .compare_exchange(seen, requests, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return Ok(false);
+        }
+
+        Config::reload_from_file(path).and_then(|cfg| self.apply_config_update(cfg))?;
+        info!("passthroughfs: reloaded configuration {}", path.display());
+        Ok(true)
+    }
+}
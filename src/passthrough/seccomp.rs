@@ -0,0 +1,291 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Restrict the system calls of threads serving a passthrough file system with seccomp.
+//!
+//! The filter allows the system calls needed to serve requests, depending on the
+//! [Config](../struct.Config.html), and fails the others with `EPERM`. Files are opened relative
+//! to the fds of inodes, so opening paths relative to the working directory is refused as well,
+//! unless an option needs it.
+
+use std::io;
+
+use vm_memory::bitmap::BitmapSlice;
+
+use super::{Config, PassthroughFs};
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// Offsets of the fields of `struct seccomp_data`, the low 32 bits of arguments on little endian.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARG0: u32 = 16;
+
+const RET_ALLOW: u32 = libc::SECCOMP_RET_ALLOW;
+const RET_EPERM: u32 = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+
+// System calls needed whatever the configuration, to serve requests and to run Rust code.
+const BASE_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_preadv,
+    libc::SYS_preadv2,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_pwrite64,
+    libc::SYS_pwritev,
+    libc::SYS_pwritev2,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_openat,
+    libc::SYS_openat2,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fstatfs,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_syncfs,
+    libc::SYS_flock,
+    libc::SYS_fadvise64,
+    libc::SYS_copy_file_range,
+    libc::SYS_splice,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_ctl,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_umask,
+    libc::SYS_setresuid,
+    libc::SYS_setresgid,
+    libc::SYS_getgroups,
+    libc::SYS_capget,
+    libc::SYS_capset,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(feature = "io-uring")]
+    libc::SYS_io_uring_enter,
+    // Interrupted requests are signaled by a thread of their own until they complete, see
+    // `InterruptMap::interrupt()`.
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+];
+
+// System calls watching the directories looked up, with `Config::inotify_invalidate`.
+const INOTIFY_SYSCALLS: &[libc::c_long] =
+    &[libc::SYS_inotify_add_watch, libc::SYS_inotify_rm_watch];
+
+// System calls modifying the shared directory, unless `Config::read_only` is set.
+const WRITE_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_mknodat,
+    libc::SYS_mkdirat,
+    libc::SYS_symlinkat,
+    libc::SYS_linkat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_utimensat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chmod,
+];
+
+// System calls reading extended attributes, also used for ACLs and per-file DAX.
+const XATTR_READ_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_getxattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_fgetxattr,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_flistxattr,
+];
+
+const XATTR_WRITE_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_setxattr,
+    libc::SYS_lsetxattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_removexattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_fremovexattr,
+];
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jeq(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn load(offset: u32) -> libc::sock_filter {
+    stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset)
+}
+
+fn ret(action: u32) -> libc::sock_filter {
+    stmt(libc::BPF_RET | libc::BPF_K, action)
+}
+
+// Allow `nr` if its first argument is `arg0`, or if it isn't when `equal` is false.
+fn allow_arg0(prog: &mut Vec<libc::sock_filter>, nr: libc::c_long, arg0: u32, equal: bool) {
+    let (jt, jf) = if equal { (0, 1) } else { (1, 0) };
+    prog.extend_from_slice(&[
+        jeq(nr as u32, 0, 4),
+        load(DATA_ARG0),
+        jeq(arg0, jt, jf),
+        ret(RET_ALLOW),
+        ret(RET_EPERM),
+        // Restore the system call number for the following checks.
+        load(DATA_NR),
+    ]);
+}
+
+// Build the filter allowing the system calls needed to serve a file system configured with `cfg`.
+fn build_filter(cfg: &Config) -> Vec<libc::sock_filter> {
+    let mut syscalls = BASE_SYSCALLS.to_vec();
+    if !cfg.read_only {
+        syscalls.extend_from_slice(WRITE_SYSCALLS);
+    }
+    // `xattr` may be enabled by reloading the configuration.
+    let xattr = cfg.xattr || cfg.config_file.is_some();
+    if xattr || cfg.posix_acl || cfg.perfile_dax_xattr {
+        syscalls.extend_from_slice(XATTR_READ_SYSCALLS);
+    }
+    if (xattr || cfg.posix_acl) && !cfg.read_only {
+        syscalls.extend_from_slice(XATTR_WRITE_SYSCALLS);
+    }
+    if cfg.supp_groups {
+        syscalls.push(libc::SYS_setgroups);
+    }
+    if cfg.inotify_invalidate {
+        syscalls.extend_from_slice(INOTIFY_SYSCALLS);
+    }
+    // Evicted fds of inodes are reopened by their file handles or their paths.
+    let path_fds = cfg.max_path_fds.is_some();
+    if cfg.inode_file_handles || path_fds {
+        syscalls.push(libc::SYS_open_by_handle_at);
+    }
+    // Mount points are opened by path to open file handles, and supplementary groups and
+    // configuration files are read from files.
+    let open_paths =
+        cfg.inode_file_handles || path_fds || cfg.supp_groups || cfg.config_file.is_some();
+    if !open_paths {
+        syscalls.retain(|nr| *nr != libc::SYS_openat && *nr != libc::SYS_openat2);
+    }
+    syscalls.sort_unstable();
+    syscalls.dedup();
+
+    let mut prog = vec![
+        load(DATA_ARCH),
+        jeq(AUDIT_ARCH, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(DATA_NR),
+    ];
+    if !open_paths {
+        allow_arg0(&mut prog, libc::SYS_openat, libc::AT_FDCWD as u32, false);
+        allow_arg0(&mut prog, libc::SYS_openat2, libc::AT_FDCWD as u32, false);
+    }
+    // Threads only unshare their umask, see `ScopedUmask`.
+    allow_arg0(&mut prog, libc::SYS_unshare, libc::CLONE_FS as u32, true);
+    // Threads spawned to interrupt requests are named.
+    allow_arg0(&mut prog, libc::SYS_prctl, libc::PR_SET_NAME as u32, true);
+    for nr in syscalls {
+        prog.push(jeq(nr as u32, 0, 1));
+        prog.push(ret(RET_ALLOW));
+    }
+    prog.push(ret(RET_EPERM));
+    prog
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Restrict the calling thread to the system calls needed to serve the file system, other
+    /// system calls fail with `EPERM`. `PR_SET_NO_NEW_PRIVS` is set, as needed to install the
+    /// filter without `CAP_SYS_ADMIN`.
+    ///
+    /// The filter is inherited by threads spawned afterwards, and can't be removed. It should be
+    /// installed by each thread serving requests, once the file system has been imported.
+    pub fn install_seccomp_filter(&self) -> io::Result<()> {
+        let mut prog = build_filter(&self.cfg);
+        let fprog = libc::sock_fprog {
+            len: prog.len() as libc::c_ushort,
+            filter: prog.as_mut_ptr(),
+        };
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the kernel only reads the filter, which outlives the call, and we check
+        // the return value.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                0,
+                &fprog as *const libc::sock_fprog,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
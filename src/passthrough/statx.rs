@@ -2,12 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(test)]
+use std::cell::Cell;
 use std::ffi::CStr;
 use std::io;
 use std::mem::MaybeUninit;
 use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use super::os_compat::{statx_st, STATX_BASIC_STATS, STATX_MNT_ID};
+use super::os_compat::{statx_st, STATX_BASIC_STATS, STATX_BTIME, STATX_MNT_ID};
+use super::util::stat_fd;
 use super::FileHandle;
 use crate::api::EMPTY_CSTR;
 
@@ -16,6 +20,19 @@ pub type MountId = u64;
 pub struct StatExt {
     pub st: libc::stat64,
     pub mnt_id: MountId,
+    // Birth time, if recorded by the filesystem.
+    pub btime: Option<libc::timespec>,
+    // Supported `STATX_ATTR_*` flags of the inode.
+    pub attributes: u64,
+}
+
+// Cleared once the running kernel turns out to lack statx() (before 4.11).
+static STATX_SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+#[cfg(test)]
+thread_local! {
+    // Let a test take the fallback path without affecting other tests.
+    pub static FORCE_STAT_FALLBACK: Cell<bool> = const { Cell::new(false) };
 }
 
 /*
@@ -30,6 +47,7 @@ pub struct StatExt {
 trait SafeStatXAccess {
     fn stat64(&self) -> Option<libc::stat64>;
     fn mount_id(&self) -> Option<MountId>;
+    fn birth_time(&self) -> Option<libc::timespec>;
 }
 
 impl SafeStatXAccess for statx_st {
@@ -79,6 +97,17 @@ impl SafeStatXAccess for statx_st {
             None
         }
     }
+
+    fn birth_time(&self) -> Option<libc::timespec> {
+        if self.stx_mask & STATX_BTIME != 0 {
+            Some(libc::timespec {
+                tv_sec: self.stx_btime.tv_sec,
+                tv_nsec: self.stx_btime.tv_nsec as _,
+            })
+        } else {
+            None
+        }
+    }
 }
 
 fn get_mount_id(dir: &impl AsRawFd, path: &CStr) -> Option<MountId> {
@@ -105,13 +134,42 @@ unsafe fn do_statx(
     libc::syscall(libc::SYS_statx, dirfd, pathname, flags, mask, statxbuf) as libc::c_int
 }
 
-/// Execute `statx()` to get extended status with mount id.
+fn statx_supported() -> bool {
+    #[cfg(test)]
+    if FORCE_STAT_FALLBACK.with(|force| force.get()) {
+        return false;
+    }
+    STATX_SUPPORTED.load(Ordering::Relaxed)
+}
+
+// Get the status with `fstatat()` and the mount id with `name_to_handle_at()`, for kernels without
+// `statx()`.
+fn stat_fallback(dir: &impl AsRawFd, path: &CStr) -> io::Result<StatExt> {
+    let st = stat_fd(dir, Some(path))?;
+    let mnt_id = get_mount_id(dir, path).unwrap_or(0);
+
+    Ok(StatExt {
+        st,
+        mnt_id,
+        btime: None,
+        attributes: 0,
+    })
+}
+
+/// Execute `statx()` to get extended status with mount id and birth time.
+///
+/// Fall back to `fstatat()` if the kernel doesn't support `statx()`, the birth time is unknown
+/// then.
 pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
     let mut stx_ui = MaybeUninit::<statx_st>::zeroed();
 
     // Safe because this is a constant value and a valid C string.
     let path = path.unwrap_or_else(|| unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) });
 
+    if !statx_supported() {
+        return stat_fallback(dir, path);
+    }
+
     // Safe because the kernel will only write data in `stx_ui` and we
     // check the return value.
     let res = unsafe {
@@ -119,7 +177,7 @@ pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
             dir.as_raw_fd(),
             path.as_ptr(),
             libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
-            STATX_BASIC_STATS | STATX_MNT_ID,
+            STATX_BASIC_STATS | STATX_BTIME | STATX_MNT_ID,
             stx_ui.as_mut_ptr(),
         )
     };
@@ -138,9 +196,19 @@ pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
             .stat64()
             .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSYS))?;
 
-        Ok(StatExt { st, mnt_id })
+        Ok(StatExt {
+            st,
+            mnt_id,
+            btime: stx.birth_time(),
+            attributes: stx.stx_attributes & stx.stx_attributes_mask,
+        })
     } else {
-        Err(io::Error::last_os_error())
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENOSYS) {
+            STATX_SUPPORTED.store(false, Ordering::Relaxed);
+            return stat_fallback(dir, path);
+        }
+        Err(err)
     }
 }
 
@@ -163,4 +231,23 @@ mod tests {
         assert_eq!(st1.mnt_id, st2.mnt_id);
         assert_eq!(st1.mnt_id, mnt_id);
     }
+
+    #[test]
+    fn test_stat_fallback() {
+        let topdir = env!("CARGO_MANIFEST_DIR");
+        let dir = File::open(topdir).unwrap();
+        let filename = CString::new("build.rs").unwrap();
+
+        let st1 = statx(&dir, Some(&filename)).unwrap();
+        FORCE_STAT_FALLBACK.with(|force| force.set(true));
+        let st2 = statx(&dir, Some(&filename)).unwrap();
+        FORCE_STAT_FALLBACK.with(|force| force.set(false));
+
+        assert_eq!(st1.st.st_ino, st2.st.st_ino);
+        assert_eq!(st1.st.st_dev, st2.st.st_dev);
+        assert_eq!(st1.st.st_size, st2.st.st_size);
+        assert_eq!(st1.mnt_id, st2.mnt_id);
+        assert!(st2.btime.is_none());
+        assert_eq!(st2.attributes, 0);
+    }
 }
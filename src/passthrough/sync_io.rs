@@ -5,6 +5,8 @@
 
 //! Fuse passthrough file system, mirroring an existing FS hierarchy.
 
+use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
@@ -12,65 +14,200 @@ use std::mem::{self, size_of, ManuallyDrop, MaybeUninit};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use super::os_compat::LinuxDirent64;
-use super::util::stat_fd;
+use super::util::{
+    check_fallocate_mode, copy_file_range_all, faccessat2, posix_acl_allows, rwf_flags, stat_fd,
+    transfer_all, ProcFdPath, ScratchBuf, DIRENT_BUF, READLINK_BUF,
+};
+use super::xattrmap::AppliedRule;
 use super::*;
-use crate::abi::fuse_abi::{CreateIn, Opcode, FOPEN_IN_KILL_SUIDGID, WRITE_KILL_PRIV};
+use crate::abi::fuse_abi::{
+    CreateIn, IoctlFlags, Opcode, FOPEN_IN_KILL_SUIDGID, POLL_SCHEDULE_NOTIFY,
+    SETXATTR_ACL_KILL_SGID, WRITE_KILL_PRIV,
+};
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::abi::virtio_fs;
 use crate::api::filesystem::{
-    Context, DirEntry, Entry, FileSystem, FsOptions, GetxattrReply, ListxattrReply, OpenOptions,
-    SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    Context, DirEntry, Entry, FileLock, FileSystem, FsOptions, GetxattrReply, IoctlData,
+    ListxattrReply, OpenOptions, SetattrValid, StatxResult, ZeroCopyReader, ZeroCopyWriter,
 };
 use crate::bytes_to_cstr;
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::transport::FsCacheReqHandler;
+use vmm_sys_util::ioctl::{_IOC_DIRMASK, _IOC_DIRSHIFT, _IOC_SIZEMASK, _IOC_SIZESHIFT, _IOC_WRITE};
+
+// Extended attribute holding the access ACL of an inode.
+const POSIX_ACL_ACCESS_XATTR: &[u8] = b"system.posix_acl_access";
+const SECURITY_CAPABILITY_XATTR: &[u8] = b"security.capability";
+
+// Extended attribute enabling or disabling DAX for a file.
+const DAX_XATTR: &[u8] = b"trusted.dax\0";
 
 impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     fn open_inode(&self, inode: Inode, flags: i32) -> io::Result<File> {
         let data = self.inode_map.get(inode)?;
         if !is_safe_inode(data.mode) {
             Err(ebadf())
+        } else if flags & libc::O_TMPFILE == libc::O_TMPFILE {
+            // Reopening a directory with O_TMPFILE creates a new anonymous inode instead of
+            // opening `inode`, which must go through create() to get registered.
+            Err(einval())
         } else {
             let mut new_flags = self.get_writeback_open_flags(flags);
             if !self.cfg.allow_direct_io && flags & libc::O_DIRECT != 0 {
                 new_flags &= !libc::O_DIRECT;
             }
+            // O_NOATIME fails with EPERM unless the daemon owns the file or has CAP_FOWNER,
+            // which isn't worth checking before each open.
+            if self.cfg.noatime && new_flags & libc::O_NOATIME == 0 {
+                let noatime_flags = new_flags | libc::O_NOATIME | libc::O_CLOEXEC;
+                match data.open_file(noatime_flags, &self.proc_self_fd) {
+                    Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+                    res => return res,
+                }
+            }
             data.open_file(new_flags | libc::O_CLOEXEC, &self.proc_self_fd)
         }
     }
 
-    /// Check the HandleData flags against the flags from the current request
-    /// if these do not match update the file descriptor flags and store the new
-    /// result in the HandleData entry
+    /// Check the HandleData flags against the flags from the current request, and update the file
+    /// descriptor flags if they differ in flags which can't be passed to `preadv2(2)` and
+    /// `pwritev2(2)` along with the request, see `rwf_flags()`. The new flags are stored in the
+    /// HandleData entry.
+    ///
+    /// Requests on the same handle may race while the flags are changed, so only flags which
+    /// change the outcome of the IO are set: `O_NONBLOCK` doesn't apply to regular files, and
+    /// `O_ASYNC` would signal the daemon rather than the client.
     #[inline(always)]
     fn check_fd_flags(&self, data: Arc<HandleData>, fd: RawFd, flags: u32) -> io::Result<()> {
+        let setfl_flags = (libc::O_APPEND | libc::O_DIRECT | libc::O_NOATIME) as u32;
         let open_flags = data.get_flags();
-        if open_flags != flags {
-            let ret = unsafe { libc::fcntl(fd, libc::F_SETFL, flags) };
+        if (open_flags ^ flags) & setfl_flags != 0 {
+            // Apply the same changes to the flags as when opening the handle.
+            let mut new_flags = self.get_writeback_open_flags(flags as i32);
+            if !self.cfg.allow_direct_io {
+                new_flags &= !libc::O_DIRECT;
+            }
+            // Safe because this doesn't modify any memory and we check the return value.
+            let ret = unsafe { libc::fcntl(fd, libc::F_SETFL, new_flags & setfl_flags as i32) };
             if ret != 0 {
                 return Err(io::Error::last_os_error());
             }
-            data.set_flags(flags);
+            data.set_flags((open_flags & !setfl_flags) | (flags & setfl_flags));
         }
         Ok(())
     }
 
+    // Copy a range of data between two files with copy_file_range(2), sharing the extents with
+    // FICLONERANGE first if `clone` is set.
+    #[allow(clippy::too_many_arguments)]
+    fn do_copy_file_range(
+        &self,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+        clone: bool,
+    ) -> io::Result<usize> {
+        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
+        let (data_in, data_out, killpriv) = self
+            .prepare_copy_file_range(inode_in, handle_in, inode_out, handle_out, offset_out, len)?;
+        let fd_in = data_in.borrow_fd();
+        let fd_out = data_out.borrow_fd();
+        // The data read ahead is dropped with _readahead, once the file has been changed.
+        let _readahead = data_out.readahead_guard();
+        // Cap restored when _killpriv is dropped
+        let _killpriv = if killpriv {
+            self::drop_cap_fsetid()?
+        } else {
+            None
+        };
+
+        if clone && len > 0 {
+            let range = libc::file_clone_range {
+                src_fd: fd_in.as_raw_fd() as i64,
+                src_offset: offset_in,
+                src_length: len,
+                dest_offset: offset_out,
+            };
+            // Safe because this only reads `range`, which is owned by us, and we check the return
+            // value.
+            let res = unsafe { libc::ioctl(fd_out.as_raw_fd(), libc::FICLONERANGE, &range) };
+            if res == 0 {
+                return Ok(len as usize);
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // The file system doesn't support clones, the files are on different file
+                // systems, or the range isn't aligned to blocks: copy the data instead.
+                Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL) => {
+                    debug!("fuse: failed to clone range of inode {}, {}", inode_in, e)
+                }
+                _ => return Err(e),
+            }
+        }
+
+        copy_file_range_all(&fd_in, offset_in, &fd_out, offset_out, len, flags)
+    }
+
+    // Check that data may be copied from `handle_in` into `handle_out`, and get their files, for
+    // both the sync and async versions of copy_file_range. Also tell whether the copy must drop
+    // CAP_FSETID, which only applies to the thread dropping it.
+    #[allow(clippy::type_complexity)]
+    pub(super) fn prepare_copy_file_range(
+        &self,
+        inode_in: Inode,
+        handle_in: Handle,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+    ) -> io::Result<(Arc<HandleData>, Arc<HandleData>, bool)> {
+        self.check_writable()?;
+        for inode in [inode_in, inode_out] {
+            if !is_safe_inode(self.inode_map.get(inode)?.mode) {
+                return Err(ebadf());
+            }
+        }
+
+        let data_in = self.get_data(handle_in, inode_in, libc::O_RDONLY)?;
+        let data_out = self.get_data(handle_out, inode_out, libc::O_RDWR)?;
+
+        let seal_size = self.seal_size.load(Ordering::Relaxed);
+        let killpriv_v2 = self.killpriv_v2.load(Ordering::Relaxed);
+        let mut killpriv = false;
+        if seal_size || killpriv_v2 {
+            let st = stat_fd(&data_out.borrow_fd(), None)?;
+            if seal_size {
+                self.seal_size_check(Opcode::CopyFileRange, st.st_size as u64, offset_out, len, 0)?;
+            }
+            // Like write, copying data into a suid/sgid file must clear those bits.
+            killpriv = killpriv_v2 && st.st_mode & (libc::S_ISUID | libc::S_ISGID) != 0;
+        }
+
+        Ok((data_in, data_out, killpriv))
+    }
+
     fn do_readdir(
         &self,
         inode: Inode,
         handle: Handle,
         size: u32,
         offset: u64,
-        add_entry: &mut dyn FnMut(DirEntry, RawFd) -> io::Result<usize>,
+        add_entry: &mut dyn FnMut(DirEntry, &HandleData) -> io::Result<usize>,
     ) -> io::Result<()> {
         if size == 0 {
             return Ok(());
         }
 
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
+        let mut buf =
+            ScratchBuf::take_capped(&DIRENT_BUF, size as usize, self.cfg.readdir_buf_cache_size);
         let data = self.get_dirdata(handle, inode, libc::O_RDONLY)?;
 
         {
@@ -86,8 +223,8 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                 return Err(io::Error::last_os_error());
             }
 
-            // Safe because the kernel guarantees that it will only write to `buf` and we check the
-            // return value.
+            // Safe because the kernel guarantees that it will only write to `buf`, which has room
+            // for at least `size` bytes, and we check the return value.
             let res = unsafe {
                 libc::syscall(
                     libc::SYS_getdents64,
@@ -155,7 +292,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         type_: u32::from(dirent64.d_ty),
                         name,
                     },
-                    data.borrow_fd().as_raw_fd(),
+                    &data,
                 )
             };
 
@@ -185,6 +322,16 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         flags: u32,
         fuse_flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions, Option<u32>)> {
+        if flags & libc::O_DIRECTORY as u32 != 0 {
+            self.check_dir_inode(inode)?;
+        }
+        // Fail as the kernel does for files opened for writing on a read-only mount.
+        if self.cfg.read_only
+            && (flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32
+                || flags & libc::O_TRUNC as u32 != 0)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
         let killpriv = if self.killpriv_v2.load(Ordering::Relaxed)
             && (fuse_flags & FOPEN_IN_KILL_SUIDGID != 0)
         {
@@ -195,12 +342,14 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         let file = self.open_inode(inode, flags as i32)?;
         drop(killpriv);
 
-        let data = HandleData::new(inode, file, flags);
+        let mut data = HandleData::new(inode, file, flags);
+        data.backing = self.open_backing(&data.file, flags);
+        let backing_id = data.backing.as_ref().map(|backing| backing.id());
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-        self.handle_map.insert(handle, data);
+        self.handle_map.try_insert(handle, data)?;
 
         let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
+        match self.tunables.load().cache_policy {
             // We only set the direct I/O option on files.
             CachePolicy::Never => opts.set(
                 OpenOptions::DIRECT_IO,
@@ -222,7 +371,257 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             _ => {}
         };
 
-        Ok((Some(handle), opts, None))
+        if flags & (libc::O_DIRECTORY as u32) == 0
+            && self.cfg.perfile_dax_xattr
+            && self.tunables.load().xattr
+            && self.perfile_dax.load(Ordering::Relaxed)
+        {
+            // DAX bypasses the page cache on its own, so direct I/O is only kept for files
+            // opting out of DAX.
+            if let Some(dax) = self.get_dax_xattr(inode) {
+                opts.set(OpenOptions::DIRECT_IO, !dax);
+            }
+        }
+        // Direct I/O would bypass the backing file.
+        if backing_id.is_some() {
+            opts.remove(OpenOptions::DIRECT_IO);
+            opts |= OpenOptions::PASSTHROUGH;
+        }
+
+        Ok((Some(handle), opts, backing_id))
+    }
+
+    // Get whether the `trusted.dax` xattr of `inode` enables DAX, `None` if it's unset or
+    // invalid.
+    fn get_dax_xattr(&self, inode: Inode) -> Option<bool> {
+        let data = self.inode_map.get(inode).ok()?;
+        let file = data.get_file().ok()?;
+        let proc_path = ProcFdPath::new(file.as_raw_fd());
+        let pathname = proc_path.as_path();
+        let mut buf = [0u8; 1];
+
+        // Safe because this will only modify the contents of `buf`.
+        let res = unsafe {
+            libc::getxattr(
+                pathname.as_ptr(),
+                DAX_XATTR.as_ptr() as *const libc::c_char,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        match (res, buf[0]) {
+            (1, b'1') => Some(true),
+            (1, b'0') => Some(false),
+            _ => None,
+        }
+    }
+
+    fn do_tmpfile(
+        &self,
+        ctx: &Context,
+        dir: &impl AsRawFd,
+        args: &CreateIn,
+    ) -> io::Result<(Entry, File)> {
+        let _groups = self.set_supp_groups(ctx)?;
+        let (_uid, _gid) = self.set_creds(ctx)?;
+        let (mode, _umask) = self.create_mode(args.mode, args.umask)?;
+
+        let flags = self.get_writeback_open_flags(args.flags as i32 | libc::O_TMPFILE);
+        self.create_tmpfile(dir, flags, mode)
+    }
+
+    // Open a handle to a newly created file.
+    fn do_create_open(
+        &self,
+        entry: Entry,
+        file: File,
+        flags: u32,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions, Option<u32>)> {
+        let mut backing_id = None;
+        let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
+            let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+            let mut data = HandleData::new(entry.inode, file, flags);
+            data.backing = self.open_backing(&data.file, flags);
+            backing_id = data.backing.as_ref().map(|backing| backing.id());
+
+            if let Err(e) = self.handle_map.try_insert(handle, data) {
+                // The kernel won't know about the entry, drop the reference taken by the lookup.
+                self.forget_one(entry.inode, 1);
+                return Err(e);
+            }
+            Some(handle)
+        } else {
+            None
+        };
+
+        let mut opts = OpenOptions::empty();
+        match self.tunables.load().cache_policy {
+            CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
+            CachePolicy::Metadata => opts |= OpenOptions::DIRECT_IO,
+            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
+            _ => {}
+        };
+        if backing_id.is_some() {
+            opts.remove(OpenOptions::DIRECT_IO);
+            opts |= OpenOptions::PASSTHROUGH;
+        }
+
+        Ok((entry, ret_handle, opts, backing_id))
+    }
+
+    // Translate the name of an extended attribute from the client into the name on the host.
+    fn map_client_xattrname<'a>(&self, name: &'a CStr) -> io::Result<Cow<'a, CStr>> {
+        let name = match self.cfg.xattr_prefix_map.as_ref() {
+            Some(map) => map.map_client_xattr(name)?,
+            None => Cow::Borrowed(name),
+        };
+        let map = match self.cfg.xattr_permissions.as_ref() {
+            Some(map) => map,
+            None => return Ok(name),
+        };
+
+        match map.map_client_xattr(&name) {
+            Ok(AppliedRule::Pass(Cow::Borrowed(_))) => Ok(name),
+            Ok(AppliedRule::Pass(Cow::Owned(name))) => Ok(Cow::Owned(name)),
+            Ok(AppliedRule::Deny) => Err(eperm()),
+            Ok(AppliedRule::Unsupported) => Err(io::Error::from_raw_os_error(libc::ENOTSUP)),
+            Err(e) => {
+                error!("fuse: failed to map xattr name {:?}, {}", name, e);
+                Err(eperm())
+            }
+        }
+    }
+
+    // Switch to the host credentials of the caller to change the extended attribute `name` on the
+    // host, so that the host kernel enforces ownership and permissions. File capabilities are
+    // removed by the client kernel when a file is written to or its owner changes, on behalf of
+    // callers who may not own the file, so they are changed with the credentials of the daemon.
+    fn set_xattr_creds(
+        &self,
+        ctx: &Context,
+        name: &CStr,
+    ) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
+        if name.to_bytes() == SECURITY_CAPABILITY_XATTR {
+            Ok((None, None))
+        } else {
+            self.set_creds(ctx)
+        }
+    }
+
+    // Whether the extended attribute `name`, as seen by the client, is hidden from the client.
+    fn is_hidden_xattr(&self, name: &[u8]) -> bool {
+        self.cfg
+            .hidden_xattr_prefixes
+            .iter()
+            .any(|prefix| name.starts_with(prefix.as_bytes()))
+    }
+
+    // Check the access `mode` of `uid` and `gid` to `file` against its POSIX ACL, if it has one.
+    fn acl_allows_access(
+        &self,
+        file: &impl AsRawFd,
+        st: &libc::stat64,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        mode: libc::c_int,
+    ) -> Option<bool> {
+        let proc_path = ProcFdPath::new(file.as_raw_fd());
+        let pathname = proc_path.as_path();
+        let name = CString::new(POSIX_ACL_ACCESS_XATTR).ok()?;
+        let mut buf = vec![0u8; 1024];
+        // Safe because this will only modify the contents of `buf`, and we check the return value.
+        let res = unsafe {
+            libc::getxattr(
+                pathname.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if res < 0 {
+            return None;
+        }
+        posix_acl_allows(&buf[..res as usize], st, uid, gid, mode)
+    }
+
+    // Label the entry `name` just created in `dir` with the security contexts sent along with the
+    // request. The entry is removed, passing `unlink_flags` to unlinkat(2), if it can't be labeled,
+    // so that no file is left with the wrong label.
+    fn set_secctx(
+        &self,
+        ctx: &Context,
+        dir: &impl AsRawFd,
+        name: &CStr,
+        file: Option<&File>,
+        unlink_flags: i32,
+    ) -> io::Result<()> {
+        if ctx.secctx.is_empty() {
+            return Ok(());
+        }
+
+        let res = (|| {
+            let path_file;
+            let file = match file {
+                Some(file) => file,
+                None => {
+                    path_file = self.open_file_restricted(dir, name, libc::O_PATH)?;
+                    &path_file
+                }
+            };
+            let proc_path = ProcFdPath::new(file.as_raw_fd());
+            let pathname = proc_path.as_path();
+
+            for secctx in ctx.secctx.iter() {
+                let xattr = self.map_client_xattrname(&secctx.name)?;
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    libc::setxattr(
+                        pathname.as_ptr(),
+                        xattr.as_ptr(),
+                        secctx.value.as_ptr() as *const libc::c_void,
+                        secctx.value.len(),
+                        0,
+                    )
+                };
+                if res != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        })();
+
+        if res.is_err() {
+            // Safe because this doesn't modify any memory, and there's nothing more to do if it
+            // fails.
+            unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), unlink_flags) };
+        }
+        res
+    }
+
+    // Get the names of extended attributes of `path`, or only the size of the names if `size` is
+    // 0.
+    fn listxattr_path(path: &CStr, size: usize) -> io::Result<(usize, Vec<u8>)> {
+        let mut buf = Vec::<u8>::with_capacity(size);
+
+        // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
+        // need to use the {set,get,remove,list}xattr variants.
+        // Safe because this will only modify the contents of `buf`.
+        let res = unsafe {
+            libc::listxattr(
+                path.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                size as libc::size_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if size != 0 {
+            // Safe because we trust the value returned by kernel.
+            unsafe { buf.set_len(res as usize) };
+        }
+        Ok((res as usize, buf))
     }
 
     fn do_getattr(
@@ -241,9 +640,9 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         if !self.no_open.load(Ordering::Relaxed) && handle.is_some() {
             // Safe as we just checked handle
             let hd = self.handle_map.get(handle.unwrap(), inode)?;
-            st = stat_fd(hd.get_file(), None);
+            st = self.stat_file(hd.get_file());
         } else {
-            st = data.handle.stat();
+            st = data.get_file().and_then(|f| self.stat_file(&f));
         }
 
         let st = st.map_err(|e| {
@@ -251,7 +650,38 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             e
         })?;
 
-        Ok((st, self.cfg.attr_timeout))
+        let (_, attr_timeout) = self.timeouts(st.st_mode);
+        Ok((self.map_stat_out(st), attr_timeout))
+    }
+
+    fn do_statx(
+        &self,
+        inode: Inode,
+        handle: Option<Handle>,
+    ) -> io::Result<(StatxResult, Duration)> {
+        let data = self.inode_map.get(inode)?;
+        let st = match handle {
+            Some(handle) if !self.no_open.load(Ordering::Relaxed) => {
+                let hd = self.handle_map.get(handle, inode)?;
+                statx(hd.get_file(), None)
+            }
+            _ => data.get_file().and_then(|f| statx(&f, None)),
+        }?;
+
+        let (_, attr_timeout) = self.timeouts(st.st.st_mode);
+        let res = StatxResult {
+            st: self.map_stat_out(st.st),
+            btime: st.btime,
+        };
+        Ok((res, attr_timeout))
+    }
+
+    fn stat_file(&self, file: &impl AsRawFd) -> io::Result<libc::stat64> {
+        if self.cfg.use_statx {
+            statx(file, None).map(|st| st.st)
+        } else {
+            stat_fd(file, None)
+        }
     }
 
     fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
@@ -259,6 +689,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         let file = data.get_file()?;
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::unlinkat(file.as_raw_fd(), name.as_ptr(), flags) };
+        data.dir_changed();
         if res == 0 {
             Ok(())
         } else {
@@ -266,6 +697,50 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         }
     }
 
+    fn posix_lock_flock(&self, lock: FileLock) -> io::Result<libc::flock> {
+        // Reject ranges ending before they start, or starting beyond the largest file offset.
+        if lock.end.checked_sub(lock.start).is_none() || lock.start > i64::MAX as u64 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let mut fl: libc::flock = lock.into();
+        // Open file description locks must be taken with a zero pid, the pid of the client
+        // process has no meaning on the host anyway.
+        fl.l_pid = 0;
+        Ok(fl)
+    }
+
+    fn do_posix_lock(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        cmd: libc::c_int,
+        fl: &mut libc::flock,
+    ) -> io::Result<()> {
+        let data = self.handle_map.get(handle, inode)?;
+        // Guest processes may share one fuse handle, so lock on a file private to `owner` to make
+        // locks of different owners conflict with each other.
+        let file = data.get_posix_lock_file(owner, &self.proc_self_fd)?;
+
+        // Safe because this only modifies `fl`, which is owned by the caller, and we check the
+        // return value.
+        let res = unsafe { libc::fcntl(file.as_raw_fd(), cmd, fl as *mut libc::flock) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The owner holds no more locks after unlocking the whole file, as the kernel does when
+        // the owner closes the file.
+        if cmd != libc::F_OFD_GETLK
+            && fl.l_type == libc::F_UNLCK as libc::c_short
+            && fl.l_start == 0
+            && fl.l_len == 0
+        {
+            data.release_posix_lock_file(owner);
+        }
+
+        Ok(())
+    }
+
     fn get_dirdata(
         &self,
         handle: Handle,
@@ -281,7 +756,20 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         }
     }
 
-    fn get_data(
+    // Get the state of directory `dir` opened as `data`, see `HandleData::dirplus_get()`.
+    fn dir_state(dir: &InodeData, data: &HandleData) -> io::Result<DirState> {
+        // Changes through the file system are noted after being done, so take the generation
+        // first, in case the directory is changed in between.
+        let gen = dir.dir_gen.load(Ordering::Acquire);
+        let st = stat_fd(&data.borrow_fd(), None)?;
+        Ok(DirState {
+            mtime: (st.st_mtime, st.st_mtime_nsec),
+            ctime: (st.st_ctime, st.st_ctime_nsec),
+            gen,
+        })
+    }
+
+    pub(super) fn get_data(
         &self,
         handle: Handle,
         inode: Inode,
@@ -297,6 +785,84 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     }
 }
 
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Get the extents of an open file within the byte range [`start`, `start` + `len`).
+    ///
+    /// `flags` is a combination of `FIEMAP_FLAG_*`. Holes in the file are not covered by any of
+    /// the returned extents, so sparse files can be copied without reading the holes.
+    pub fn fiemap(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        start: u64,
+        len: u64,
+        flags: u32,
+    ) -> io::Result<Vec<FiemapExtent>> {
+        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
+        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+
+        fiemap::fiemap(&data.borrow_fd(), start, len, flags)
+    }
+
+    /// Announce the access pattern of an open file within [`offset`, `offset` + `len`), to let the
+    /// host kernel tune its readahead and page cache.
+    ///
+    /// `advice` is one of `libc::POSIX_FADV_*`, a `len` of zero extends to the end of the file.
+    pub fn fadvise(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        offset: u64,
+        len: u64,
+        advice: i32,
+    ) -> io::Result<()> {
+        let offset = i64::try_from(offset).map_err(|_| einval())?;
+        let len = i64::try_from(len).map_err(|_| einval())?;
+        // Advice on an O_PATH fd fails with EBADF, so always operate on an opened file.
+        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
+        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res =
+            unsafe { libc::posix_fadvise64(data.borrow_fd().as_raw_fd(), offset, len, advice) };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(res))
+        }
+    }
+
+    /// Get the birth time of an inode, `None` if the underlying filesystem doesn't record it.
+    ///
+    /// The FUSE attributes have no room for the birth time, so it's only available here.
+    pub fn birth_time(&self, inode: Inode) -> io::Result<Option<SystemTime>> {
+        let data = self.inode_map.get(inode)?;
+        let st = data.handle.stat()?;
+
+        Ok(st.btime.map(|t| {
+            if t.tv_sec >= 0 {
+                UNIX_EPOCH + Duration::new(t.tv_sec as u64, t.tv_nsec as u32)
+            } else {
+                UNIX_EPOCH - Duration::from_secs(t.tv_sec.unsigned_abs())
+                    + Duration::from_nanos(t.tv_nsec as u64)
+            }
+        }))
+    }
+
+    /// Wait for files registered by `poll()` with `POLL_SCHEDULE_NOTIFY` to become ready.
+    ///
+    /// Return the kernel poll handles of the ready files, which are forgotten afterwards. The
+    /// caller is expected to send a wakeup notification for each of them through
+    /// `Server::notify_poll_wakeup()`, usually from a dedicated thread calling this in a loop.
+    /// Wait forever if `timeout` is `None`.
+    pub fn wait_poll_handles(&self, timeout: Option<Duration>) -> io::Result<Vec<u64>> {
+        let timeout = timeout
+            .map(|t| t.as_millis().min(i32::MAX as u128) as i32)
+            .unwrap_or(-1);
+        self.poll_handle_map.wait(timeout)
+    }
+}
+
 impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
     type Inode = Inode;
     type Handle = Handle;
@@ -305,8 +871,18 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         if self.cfg.do_import {
             self.import()?;
         }
+        if self.cfg.config_file.is_some() {
+            reload::install_reload_handler()?;
+            self.reloads
+                .store(reload::reload_requests(), Ordering::Relaxed);
+        }
 
-        let mut opts = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
+        // A root file has no entries to list.
+        let mut opts = if self.cfg.root_is_file {
+            FsOptions::empty()
+        } else {
+            FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO
+        };
         // !cfg.do_import means we are under vfs, in which case capable is already
         // negotiated and must be honored.
         if (!self.cfg.do_import || self.cfg.writeback)
@@ -336,15 +912,51 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             self.killpriv_v2.store(true, Ordering::Relaxed);
         }
 
+        // flock(2) and open file description locks belong to the open file description, so they
+        // can only be passed through when every fuse file handle is backed by its own fd.
+        if !self.no_open.load(Ordering::Relaxed) {
+            opts |= capable & (FsOptions::FLOCK_LOCKS | FsOptions::POSIX_LOCKS);
+        }
+
         if capable.contains(FsOptions::PERFILE_DAX) {
             opts |= FsOptions::PERFILE_DAX;
             self.perfile_dax.store(true, Ordering::Relaxed);
         }
+        if self.cfg.posix_acl && capable.contains(FsOptions::POSIX_ACL | FsOptions::DONT_MASK) {
+            opts |= FsOptions::POSIX_ACL | FsOptions::DONT_MASK;
+            opts |= capable & FsOptions::SETXATTR_EXT;
+            self.posix_acl.store(true, Ordering::Relaxed);
+        }
+        // Security contexts are set as extended attributes.
+        if self.tunables.load().xattr {
+            opts |= capable & FsOptions::SECURITY_CTX;
+        }
+        // Reads and writes of files opened with a backing file bypass the daemon.
+        if self.cfg.fuse_passthrough
+            && !self.writeback.load(Ordering::Relaxed)
+            && capable.contains(FsOptions::PASSTHROUGH)
+        {
+            opts |= FsOptions::PASSTHROUGH;
+            self.fuse_passthrough.store(true, Ordering::Relaxed);
+        }
+        // Groups of callers creating files are sent along with the requests.
+        if self.cfg.supp_groups {
+            opts |= capable & FsOptions::CREATE_SUPP_GROUP;
+        }
+        // There is no init flag for O_TMPFILE, tmpfile() fails with ENOSYS instead to let the
+        // kernel know when it's unsupported.
+        self.tmpfile.store(self.probe_tmpfile(), Ordering::Relaxed);
+
+        // Only tells the kernel is able to create submounts, no need to reply.
+        if capable.contains(FsOptions::SUBMOUNTS) {
+            self.submounts.store(true, Ordering::Relaxed);
+        }
 
         Ok(opts)
     }
 
     fn destroy(&self) {
+        self.poll_handle_map.clear();
         self.handle_map.clear();
         self.inode_map.clear();
 
@@ -359,11 +971,15 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         let file = data.get_file()?;
 
         // Safe because this will only modify `out` and we check the return value.
-        match unsafe { libc::fstatvfs64(file.as_raw_fd(), out.as_mut_ptr()) } {
+        let mut out = match unsafe { libc::fstatvfs64(file.as_raw_fd(), out.as_mut_ptr()) } {
             // Safe because the kernel guarantees that `out` has been initialized.
-            0 => Ok(unsafe { out.assume_init() }),
-            _ => Err(io::Error::last_os_error()),
+            0 => unsafe { out.assume_init() },
+            _ => return Err(io::Error::last_os_error()),
+        };
+        if self.cfg.read_only {
+            out.f_flag |= libc::ST_RDONLY;
         }
+        Ok(out)
     }
 
     fn lookup(&self, _ctx: &Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
@@ -371,21 +987,28 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         if name.to_bytes_with_nul().contains(&SLASH_ASCII) {
             return Err(einval());
         }
-        self.do_lookup(parent, name)
+        if let Some(cache) = self.negative_cache.as_ref() {
+            if cache.contains(parent, name) {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+        }
+        match self.do_lookup(parent, name) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                if let Some(cache) = self.negative_cache.as_ref() {
+                    cache.insert(parent, name);
+                }
+                Err(e)
+            }
+            res => res,
+        }
     }
 
     fn forget(&self, _ctx: &Context, inode: Inode, count: u64) {
-        let mut inodes = self.inode_map.get_map_mut();
-
-        self.forget_one(&mut inodes, inode, count)
+        self.forget_one(inode, count)
     }
 
     fn batch_forget(&self, _ctx: &Context, requests: Vec<(Inode, u64)>) {
-        let mut inodes = self.inode_map.get_map_mut();
-
-        for (inode, count) in requests {
-            self.forget_one(&mut inodes, inode, count)
-        }
+        self.forget_many(&requests)
     }
 
     fn opendir(
@@ -426,25 +1049,31 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         mode: u32,
         umask: u32,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         self.validate_path_component(name)?;
 
         let data = self.inode_map.get(parent)?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let _groups = self.set_supp_groups(ctx)?;
+            let (_uid, _gid) = self.set_creds(ctx)?;
+            let (mode, _umask) = self.create_mode(mode, umask)?;
 
             let file = data.get_file()?;
             // Safe because this doesn't modify any memory and we check the return value.
-            unsafe { libc::mkdirat(file.as_raw_fd(), name.as_ptr(), mode & !umask) }
+            unsafe { libc::mkdirat(file.as_raw_fd(), name.as_ptr(), mode) }
         };
+        data.dir_changed();
         if res < 0 {
             return Err(io::Error::last_os_error());
         }
+        self.set_secctx(ctx, &data.get_file()?, name, None, libc::AT_REMOVEDIR)?;
 
         self.do_lookup(parent, name)
     }
 
     fn rmdir(&self, _ctx: &Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         self.validate_path_component(name)?;
         self.do_unlink(parent, name, libc::AT_REMOVEDIR)
     }
@@ -458,25 +1087,31 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
     ) -> io::Result<()> {
+        self.check_dir_inode(inode)?;
         if self.no_readdir.load(Ordering::Relaxed) {
             return Ok(());
         }
-        self.do_readdir(inode, handle, size, offset, &mut |mut dir_entry, _dir| {
-            dir_entry.ino = {
-                // Safe because do_readdir() has ensured dir_entry.name is a
-                // valid [u8] generated by CStr::to_bytes().
-                let name = unsafe {
-                    CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
-                        &dir_entry.name[0],
-                        dir_entry.name.len() + 1,
-                    ))
-                };
+        let fast = self.cfg.readdir_ino == ReaddirInoPolicy::Fast;
+        self.do_readdir(inode, handle, size, offset, &mut |mut dir_entry, data| {
+            // Safe because do_readdir() has ensured dir_entry.name is a
+            // valid [u8] generated by CStr::to_bytes().
+            let name = unsafe {
+                CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
+                    &dir_entry.name[0],
+                    dir_entry.name.len() + 1,
+                ))
+            };
 
+            if fast {
+                // Directories may be covered by mounts, whose roots have other inodes.
+                if dir_entry.type_ == u32::from(libc::DT_DIR) {
+                    dir_entry.ino = stat_fd(&data.borrow_fd(), Some(name))?.st_ino;
+                }
+            } else {
                 let entry = self.do_lookup(inode, name)?;
-                let mut inodes = self.inode_map.get_map_mut();
-                self.forget_one(&mut inodes, entry.inode, 1);
-                entry.inode
-            };
+                self.forget_one(entry.inode, 1);
+                dir_entry.ino = entry.inode;
+            }
 
             add_entry(dir_entry)
         })
@@ -491,10 +1126,13 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
     ) -> io::Result<()> {
+        self.check_dir_inode(inode)?;
         if self.no_readdir.load(Ordering::Relaxed) {
             return Ok(());
         }
-        self.do_readdir(inode, handle, size, offset, &mut |mut dir_entry, _dir| {
+        let dir = self.inode_map.get(inode)?;
+        let mut state = None;
+        self.do_readdir(inode, handle, size, offset, &mut |mut dir_entry, data| {
             // Safe because do_readdir() has ensured dir_entry.name is a
             // valid [u8] generated by CStr::to_bytes().
             let name = unsafe {
@@ -503,16 +1141,37 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                     dir_entry.name.len() + 1,
                 ))
             };
-            let entry = self.do_lookup(inode, name)?;
-            let ino = entry.inode;
-            dir_entry.ino = entry.attr.st_ino;
 
-            add_entry(dir_entry, entry).map(|r| {
-                // true when size is not large enough to hold entry.
+            // Entries looked up by a previous listing are reused while the directory is
+            // unchanged, they only need a new reference and fresh attributes.
+            let state = match state {
+                Some(state) => state,
+                None => *state.insert(Self::dir_state(&dir, data)?),
+            };
+            let cached = data
+                .dirplus_get(&state, name)
+                .and_then(|(ino, attr_flags)| self.relookup(ino, attr_flags));
+            let entry = match cached {
+                Some(entry) => {
+                    self.dirplus_hits.fetch_add(1, Ordering::Relaxed);
+                    entry
+                }
+                None => {
+                    let entry = self.do_lookup(inode, name)?;
+                    self.dirplus_misses.fetch_add(1, Ordering::Relaxed);
+                    let attr_flags = entry.attr_flags & fuse::ATTR_SUBMOUNT;
+                    data.dirplus_insert(&state, name, entry.inode, attr_flags);
+                    entry
+                }
+            };
+            let ino = entry.inode;
+            dir_entry.ino = entry.attr.st_ino;
+
+            add_entry(dir_entry, entry).map(|r| {
+                // true when size is not large enough to hold entry.
                 if r == 0 {
                     // Release the refcount acquired by self.do_lookup().
-                    let mut inodes = self.inode_map.get_map_mut();
-                    self.forget_one(&mut inodes, ino, 1);
+                    self.forget_one(ino, 1);
                 }
                 r
             })
@@ -541,12 +1200,18 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         _flags: u32,
         handle: Handle,
         _flush: bool,
-        _flock_release: bool,
-        _lock_owner: Option<u64>,
+        flock_release: bool,
+        lock_owner: Option<u64>,
     ) -> io::Result<()> {
         if self.no_open.load(Ordering::Relaxed) {
             Err(enosys())
         } else {
+            if let (true, Some(owner)) = (flock_release, lock_owner) {
+                // Closing the owner's file drops its flock(2) lock.
+                if let Ok(data) = self.handle_map.get(handle, inode) {
+                    data.release_flock_file(owner);
+                }
+            }
             self.do_release(inode, handle)
         }
     }
@@ -558,61 +1223,77 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         name: &CStr,
         args: CreateIn,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions, Option<u32>)> {
-        self.validate_path_component(name)?;
-
+        self.check_writable()?;
+        self.check_dir_inode(parent)?;
         let dir = self.inode_map.get(parent)?;
         let dir_file = dir.get_file()?;
 
-        let new_file = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-
-            let flags = self.get_writeback_open_flags(args.flags as i32);
-            Self::create_file_excl(&dir_file, name, flags, args.mode & !(args.umask & 0o777))?
-        };
+        let (entry, file) = if args.flags as i32 & libc::O_TMPFILE == libc::O_TMPFILE {
+            // The file is anonymous, so `name` is meaningless here.
+            self.do_tmpfile(ctx, &dir_file, &args)?
+        } else {
+            self.validate_path_component(name)?;
 
-        let entry = self.do_lookup(parent, name)?;
-        let file = match new_file {
-            // File didn't exist, now created by create_file_excl()
-            Some(f) => f,
-            // File exists, and args.flags doesn't contain O_EXCL. Now let's open it with
-            // open_inode().
-            None => {
-                // Cap restored when _killpriv is dropped
-                let _killpriv = if self.killpriv_v2.load(Ordering::Relaxed)
-                    && (args.fuse_flags & FOPEN_IN_KILL_SUIDGID != 0)
-                {
-                    self::drop_cap_fsetid()?
-                } else {
-                    None
-                };
+            let new_file = {
+                let _groups = self.set_supp_groups(ctx)?;
+                let (_uid, _gid) = self.set_creds(ctx)?;
+                let (mode, _umask) = self.create_mode(args.mode, args.umask)?;
 
-                let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-                self.open_inode(entry.inode, args.flags as i32)?
+                let flags = self.get_writeback_open_flags(args.flags as i32);
+                Self::create_file_excl(&dir_file, name, flags, mode)?
+            };
+            if let Some(file) = new_file.as_ref() {
+                dir.dir_changed();
+                self.set_secctx(ctx, &dir_file, name, Some(file), 0)?;
             }
-        };
 
-        let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
-            let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-            let data = HandleData::new(entry.inode, file, args.flags);
+            let entry = self.do_lookup(parent, name)?;
+            let file = match new_file {
+                // File didn't exist, now created by create_file_excl()
+                Some(f) => f,
+                // File exists, and args.flags doesn't contain O_EXCL. Now let's open it with
+                // open_inode().
+                None => {
+                    // Cap restored when _killpriv is dropped
+                    let _killpriv = if self.killpriv_v2.load(Ordering::Relaxed)
+                        && (args.fuse_flags & FOPEN_IN_KILL_SUIDGID != 0)
+                    {
+                        self::drop_cap_fsetid()?
+                    } else {
+                        None
+                    };
+
+                    let (_uid, _gid) = self.set_creds(ctx)?;
+                    self.open_inode(entry.inode, args.flags as i32)?
+                }
+            };
 
-            self.handle_map.insert(handle, data);
-            Some(handle)
-        } else {
-            None
+            (entry, file)
         };
 
-        let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
-            CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
-            CachePolicy::Metadata => opts |= OpenOptions::DIRECT_IO,
-            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
-            _ => {}
-        };
+        self.do_create_open(entry, file, args.flags)
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions, Option<u32>)> {
+        self.check_writable()?;
+        if !self.tmpfile.load(Ordering::Relaxed) {
+            return Err(enosys());
+        }
 
-        Ok((entry, ret_handle, opts, None))
+        let dir = self.inode_map.get(parent)?;
+        let dir_file = dir.get_file()?;
+        let (entry, file) = self.do_tmpfile(ctx, &dir_file, &args)?;
+
+        self.do_create_open(entry, file, args.flags)
     }
 
     fn unlink(&self, _ctx: &Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         self.validate_path_component(name)?;
         self.do_unlink(parent, name, 0)
     }
@@ -635,6 +1316,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         );
 
         let open_flags = if (flags & virtio_fs::SetupmappingFlags::WRITE.bits()) != 0 {
+            self.check_writable()?;
             libc::O_RDWR
         } else {
             libc::O_RDONLY
@@ -677,7 +1359,27 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
         let mut f = ManuallyDrop::new(f);
 
-        w.write_from(&mut *f, size as usize, offset)
+        // Move the data into the reply without copying it through userspace if possible.
+        // Splicing bypasses O_DIRECT, so those reads go through the usual path.
+        if flags & libc::O_DIRECT as u32 == 0 && w.supports_splice() {
+            match transfer_all(size as usize, offset, |count, off| {
+                w.splice_read(&*f, count, off)
+            }) {
+                Ok(n) => return Ok(n),
+                Err(e) => debug!("fuse: failed to splice read of inode {}, {}", inode, e),
+            }
+        }
+
+        // Keep reading after short reads, the client would take them for the end of file.
+        #[cfg(feature = "io-uring")]
+        if self.cfg.use_io_uring {
+            return transfer_all(size as usize, offset, |count, off| {
+                w.write_from(&mut UringFile::new(&mut f, rwf_flags(flags)), count, off)
+            });
+        }
+        transfer_all(size as usize, offset, |count, off| {
+            w.write_from_vectored(&mut f, count, off, rwf_flags(flags))
+        })
     }
 
     fn write(
@@ -693,12 +1395,15 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         flags: u32,
         fuse_flags: u32,
     ) -> io::Result<usize> {
+        self.check_writable()?;
         let data = self.get_data(handle, inode, libc::O_RDWR)?;
 
         // Manually implement File::try_clone() by borrowing fd of data.file instead of dup().
         // It's safe because the `data` variable's lifetime spans the whole function,
         // so data.file won't be closed.
         let f = unsafe { File::from_raw_fd(data.borrow_fd().as_raw_fd()) };
+        // The data read ahead is dropped with _readahead, once the file has been changed.
+        let _readahead = data.readahead_guard();
 
         self.check_fd_flags(data.clone(), f.as_raw_fd(), flags)?;
 
@@ -717,7 +1422,31 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                 None
             };
 
-        r.read_to(&mut *f, size as usize, offset)
+        // Move the data into the file without copying it through userspace if possible. The
+        // data is written to the file before the reply is sent, so pages of the client's
+        // writeback cache spliced with the request are never kept past the request. Splicing
+        // bypasses O_DIRECT and fails on O_APPEND files, so those writes go through the usual
+        // path.
+        if flags & (libc::O_DIRECT | libc::O_APPEND) as u32 == 0 && r.supports_splice() {
+            match transfer_all(size as usize, offset, |count, off| {
+                r.splice_write(&*f, count, off)
+            }) {
+                Ok(n) => return Ok(n),
+                Err(e) => debug!("fuse: failed to splice write of inode {}, {}", inode, e),
+            }
+        }
+
+        // Keep writing after short writes, the client assumes the data it doesn't get an
+        // error for has been written.
+        #[cfg(feature = "io-uring")]
+        if self.cfg.use_io_uring {
+            return transfer_all(size as usize, offset, |count, off| {
+                r.read_to(&mut UringFile::new(&mut f, rwf_flags(flags)), count, off)
+            });
+        }
+        transfer_all(size as usize, offset, |count, off| {
+            r.read_to_vectored(&mut f, count, off, rwf_flags(flags))
+        })
     }
 
     fn getattr(
@@ -729,6 +1458,17 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         self.do_getattr(inode, handle)
     }
 
+    fn statx(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Option<Handle>,
+        _flags: u32,
+        _mask: u32,
+    ) -> io::Result<(StatxResult, Duration)> {
+        self.do_statx(inode, handle)
+    }
+
     fn setattr(
         &self,
         _ctx: &Context,
@@ -737,17 +1477,17 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
+        self.check_writable()?;
         let inode_data = self.inode_map.get(inode)?;
 
         enum Data {
             Handle(Arc<HandleData>),
-            ProcPath(CString),
+            ProcPath(ProcFdPath),
         }
 
         let file = inode_data.get_file()?;
         let data = if self.no_open.load(Ordering::Relaxed) {
-            let pathname = CString::new(format!("{}", file.as_raw_fd()))
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let pathname = ProcFdPath::new(file.as_raw_fd());
             Data::ProcPath(pathname)
         } else {
             // If we have a handle then use it otherwise get a new fd from the inode.
@@ -755,8 +1495,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                 let hd = self.handle_map.get(handle, inode)?;
                 Data::Handle(hd)
             } else {
-                let pathname = CString::new(format!("{}", file.as_raw_fd()))
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let pathname = ProcFdPath::new(file.as_raw_fd());
                 Data::ProcPath(pathname)
             }
         };
@@ -770,9 +1509,12 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             let res = unsafe {
                 match data {
                     Data::Handle(ref h) => libc::fchmod(h.borrow_fd().as_raw_fd(), attr.st_mode),
-                    Data::ProcPath(ref p) => {
-                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), attr.st_mode, 0)
-                    }
+                    Data::ProcPath(ref p) => libc::fchmodat(
+                        self.proc_self_fd.as_raw_fd(),
+                        p.as_name().as_ptr(),
+                        attr.st_mode,
+                        0,
+                    ),
                 }
             };
             if res < 0 {
@@ -782,13 +1524,13 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                self.owner_uid_in(attr.st_uid)
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                self.owner_gid_in(attr.st_gid)
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
@@ -822,6 +1564,11 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                 None
             };
 
+            // The data read ahead is dropped with _readahead, once the file has been changed.
+            let _readahead = match data {
+                Data::Handle(ref h) => Some(h.readahead_guard()),
+                _ => None,
+            };
             // Safe because this doesn't modify any memory and we check the return value.
             let res = match data {
                 Data::Handle(ref h) => unsafe {
@@ -870,7 +1617,12 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                     libc::futimens(h.borrow_fd().as_raw_fd(), tvs.as_ptr())
                 },
                 Data::ProcPath(ref p) => unsafe {
-                    libc::utimensat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), tvs.as_ptr(), 0)
+                    libc::utimensat(
+                        self.proc_self_fd.as_raw_fd(),
+                        p.as_name().as_ptr(),
+                        tvs.as_ptr(),
+                        0,
+                    )
                 },
             };
             if res < 0 {
@@ -883,13 +1635,14 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
     fn rename(
         &self,
-        _ctx: &Context,
+        ctx: &Context,
         olddir: Inode,
         oldname: &CStr,
         newdir: Inode,
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
+        self.check_writable()?;
         self.validate_path_component(oldname)?;
         self.validate_path_component(newname)?;
 
@@ -898,6 +1651,39 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         let old_file = old_inode.get_file()?;
         let new_file = new_inode.get_file()?;
 
+        let valid_flags = libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE | libc::RENAME_WHITEOUT;
+        if flags & !valid_flags != 0 {
+            return Err(einval());
+        }
+        // RENAME_EXCHANGE swaps two existing entries, so it can't be combined with flags that
+        // expect the target to be absent or replaced.
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            if flags & (libc::RENAME_NOREPLACE | libc::RENAME_WHITEOUT) != 0 {
+                return Err(einval());
+            }
+            stat_fd(&old_file, Some(oldname))?;
+            stat_fd(&new_file, Some(newname))?;
+        }
+        // The entry exchanged with the old one takes its name, which doesn't go away.
+        let notifier = self
+            .delete_notifier
+            .load_full()
+            .filter(|_| self.cfg.notify_on_rename && flags & libc::RENAME_EXCHANGE == 0);
+        // Only entries known to the kernel have something to drop.
+        let child = notifier.as_ref().and_then(|_| {
+            let st = statx(&old_file, Some(oldname)).ok()?;
+            self.inode_map.get_alt(&InodeId::from_stat(&st), None)
+        });
+
+        // Creating the whiteout device requires CAP_MKNOD, which is lost after switching to the
+        // caller's credentials.
+        let (_uid, _gid, _cap_mknod) = if flags & libc::RENAME_WHITEOUT != 0 {
+            let (uid, gid) = self.set_creds(ctx)?;
+            (uid, gid, raise_cap_mknod()?)
+        } else {
+            (None, None, None)
+        };
+
         // Safe because this doesn't modify any memory and we check the return value.
         // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
         // and we have glibc 2.28.
@@ -911,7 +1697,22 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                 flags,
             )
         };
+        old_inode.dir_changed();
+        new_inode.dir_changed();
         if res == 0 {
+            // Renamed entries aren't looked up, and a whiteout may replace the old name.
+            if let Some(cache) = self.negative_cache.as_ref() {
+                cache.remove(newdir, newname);
+                cache.remove(olddir, oldname);
+            }
+            if let (Some(notifier), Some(child)) = (notifier, child) {
+                if let Err(e) = notifier(olddir, child.inode, oldname) {
+                    debug!(
+                        "fuse: failed to notify the removal of inode {}, {}",
+                        child.inode, e
+                    );
+                }
+            }
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -927,27 +1728,32 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         rdev: u32,
         umask: u32,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         self.validate_path_component(name)?;
 
         let data = self.inode_map.get(parent)?;
         let file = data.get_file()?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let _groups = self.set_supp_groups(ctx)?;
+            let (_uid, _gid) = self.set_creds(ctx)?;
+            let (mode, _umask) = self.create_mode(mode, umask)?;
 
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe {
                 libc::mknodat(
                     file.as_raw_fd(),
                     name.as_ptr(),
-                    (mode & !umask) as libc::mode_t,
+                    mode as libc::mode_t,
                     u64::from(rdev),
                 )
             }
         };
+        data.dir_changed();
         if res < 0 {
             Err(io::Error::last_os_error())
         } else {
+            self.set_secctx(ctx, &file, name, None, 0)?;
             self.do_lookup(parent, name)
         }
     }
@@ -959,6 +1765,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         self.validate_path_component(newname)?;
 
         let data = self.inode_map.get(inode)?;
@@ -979,6 +1786,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                 libc::AT_EMPTY_PATH,
             )
         };
+        new_inode.dir_changed();
         if res == 0 {
             self.do_lookup(newparent, newname)
         } else {
@@ -993,18 +1801,22 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         parent: Inode,
         name: &CStr,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         self.validate_path_component(name)?;
 
         let data = self.inode_map.get(parent)?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let _groups = self.set_supp_groups(ctx)?;
+            let (_uid, _gid) = self.set_creds(ctx)?;
 
             let file = data.get_file()?;
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe { libc::symlinkat(linkname.as_ptr(), file.as_raw_fd(), name.as_ptr()) }
         };
+        data.dir_changed();
         if res == 0 {
+            self.set_secctx(ctx, &data.get_file()?, name, None, 0)?;
             self.do_lookup(parent, name)
         } else {
             Err(io::Error::last_os_error())
@@ -1014,11 +1826,12 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
     fn readlink(&self, _ctx: &Context, inode: Inode) -> io::Result<Vec<u8>> {
         // Safe because this is a constant value and a valid C string.
         let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
-        let mut buf = Vec::<u8>::with_capacity(libc::PATH_MAX as usize);
+        let mut buf = ScratchBuf::take(&READLINK_BUF, libc::PATH_MAX as usize);
         let data = self.inode_map.get(inode)?;
         let file = data.get_file()?;
 
-        // Safe because this will only modify the contents of `buf` and we check the return value.
+        // Safe because this will only modify the contents of `buf`, which has room for at
+        // least `PATH_MAX` bytes, and we check the return value.
         let res = unsafe {
             libc::readlinkat(
                 file.as_raw_fd(),
@@ -1034,7 +1847,8 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         // Safe because we trust the value returned by kernel.
         unsafe { buf.set_len(res as usize) };
 
-        Ok(buf)
+        // Copy the target into a buffer of its size, the scratch buffer is kept for later.
+        Ok(buf.to_vec())
     }
 
     fn flush(
@@ -1049,6 +1863,9 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         }
 
         let data = self.handle_map.get(handle, inode)?;
+        if self.cfg.sync_on_close {
+            return data.sync_on_close();
+        }
 
         // Since this method is called whenever an fd is closed in the client, we can emulate that
         // behavior by doing the same thing (dup-ing the fd and then immediately closing it). Safe
@@ -1102,9 +1919,32 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         self.fsync(ctx, inode, datasync, handle)
     }
 
+    fn syncfs(&self, _ctx: &Context, inode: Inode) -> io::Result<()> {
+        // Sync the shared directory if no specific inode is given. Only the filesystem backing
+        // `inode` is synced: for the root that's the device backing the shared directory, and
+        // mounts below it are only synced if the kernel sends separate requests for them as
+        // submounts.
+        let inode = if inode == 0 { fuse::ROOT_ID } else { inode };
+        let data = self.inode_map.get(inode)?;
+        // syncfs(2) doesn't accept O_PATH file descriptors.
+        let file = data.open_file(
+            libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            &self.proc_self_fd,
+        )?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::syncfs(file.as_raw_fd()) };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
     fn access(&self, ctx: &Context, inode: Inode, mask: u32) -> io::Result<()> {
         let data = self.inode_map.get(inode)?;
-        let st = stat_fd(&data.get_file()?, None)?;
+        let file = data.get_file()?;
+        let st = stat_fd(&file, None)?;
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -1112,19 +1952,53 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             return Ok(());
         }
 
+        // Let the host kernel check the access with the credentials of the caller, so POSIX
+        // ACLs, capabilities and read-only mounts are accounted for.
+        if self.has_faccessat2.load(Ordering::Relaxed) {
+            let pathname = ProcFdPath::new(file.as_raw_fd());
+            let res = {
+                let _groups = self.set_supp_groups(ctx)?;
+                let (_uid, _gid) = self.set_creds(ctx)?;
+                faccessat2(
+                    &self.proc_self_fd,
+                    pathname.as_name(),
+                    mode,
+                    libc::AT_EACCESS,
+                )
+            };
+            match res {
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                    warn!("fuse: faccessat2(2) is not supported by the host kernel, fall back to permission bits");
+                    self.has_faccessat2.store(false, Ordering::Relaxed);
+                }
+                res => return res,
+            }
+        }
+
+        let (uid, gid) = self.host_creds(ctx)?;
+        if uid != 0 {
+            if let Some(allowed) = self.acl_allows_access(&file, &st, uid, gid, mode) {
+                return if allowed {
+                    Ok(())
+                } else {
+                    Err(io::Error::from_raw_os_error(libc::EACCES))
+                };
+            }
+        }
+
         if (mode & libc::R_OK) != 0
-            && ctx.uid != 0
-            && (st.st_uid != ctx.uid || st.st_mode & 0o400 == 0)
-            && (st.st_gid != ctx.gid || st.st_mode & 0o040 == 0)
+            && uid != 0
+            && (st.st_uid != uid || st.st_mode & 0o400 == 0)
+            && (st.st_gid != gid || st.st_mode & 0o040 == 0)
             && st.st_mode & 0o004 == 0
         {
             return Err(io::Error::from_raw_os_error(libc::EACCES));
         }
 
         if (mode & libc::W_OK) != 0
-            && ctx.uid != 0
-            && (st.st_uid != ctx.uid || st.st_mode & 0o200 == 0)
-            && (st.st_gid != ctx.gid || st.st_mode & 0o020 == 0)
+            && uid != 0
+            && (st.st_uid != uid || st.st_mode & 0o200 == 0)
+            && (st.st_gid != gid || st.st_mode & 0o020 == 0)
             && st.st_mode & 0o002 == 0
         {
             return Err(io::Error::from_raw_os_error(libc::EACCES));
@@ -1133,9 +2007,9 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         // root can only execute something if it is executable by one of the owner, the group, or
         // everyone.
         if (mode & libc::X_OK) != 0
-            && (ctx.uid != 0 || st.st_mode & 0o111 == 0)
-            && (st.st_uid != ctx.uid || st.st_mode & 0o100 == 0)
-            && (st.st_gid != ctx.gid || st.st_mode & 0o010 == 0)
+            && (uid != 0 || st.st_mode & 0o111 == 0)
+            && (st.st_uid != uid || st.st_mode & 0o100 == 0)
+            && (st.st_gid != gid || st.st_mode & 0o010 == 0)
             && st.st_mode & 0o001 == 0
         {
             return Err(io::Error::from_raw_os_error(libc::EACCES));
@@ -1146,38 +2020,64 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
     fn setxattr(
         &self,
-        _ctx: &Context,
+        ctx: &Context,
         inode: Inode,
         name: &CStr,
         value: &[u8],
         flags: u32,
+        setxattr_flags: u32,
     ) -> io::Result<()> {
-        if !self.cfg.xattr {
+        self.check_writable()?;
+        if !self.tunables.load().xattr {
             return Err(enosys());
         }
 
+        if self.is_hidden_xattr(name.to_bytes()) {
+            return Err(eperm());
+        }
+        let name = self.map_client_xattrname(name)?;
+
         let data = self.inode_map.get(inode)?;
         let file = data.get_file()?;
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let proc_path = ProcFdPath::new(file.as_raw_fd());
+        let pathname = proc_path.as_path();
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
-        // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            libc::setxattr(
-                pathname.as_ptr(),
-                name.as_ptr(),
-                value.as_ptr() as *const libc::c_void,
-                value.len(),
-                flags as libc::c_int,
-            )
+        // need to use the {set,get,remove,list}xattr variants. The fds of the process can still
+        // be resolved through /proc/self/fd after switching credentials.
+        let res = {
+            let (_uid, _gid) = self.set_xattr_creds(ctx, &name)?;
+            // Safe because this doesn't modify any memory and we check the return value.
+            unsafe {
+                libc::setxattr(
+                    pathname.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    flags as libc::c_int,
+                )
+            }
         };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The kernel asks to clear the setgid bit when the caller is neither in the owning group
+        // nor has CAP_FSETID, which the host kernel can't tell as we run with CAP_FSETID.
+        if setxattr_flags & SETXATTR_ACL_KILL_SGID != 0 && name.to_bytes() == POSIX_ACL_ACCESS_XATTR
+        {
+            let st = stat_fd(&file, None)?;
+            if st.st_mode & libc::S_ISGID != 0 {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res =
+                    unsafe { libc::chmod(pathname.as_ptr(), st.st_mode & 0o7777 & !libc::S_ISGID) };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
         }
+
+        Ok(())
     }
 
     fn getxattr(
@@ -1187,15 +2087,20 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         name: &CStr,
         size: u32,
     ) -> io::Result<GetxattrReply> {
-        if !self.cfg.xattr {
+        if !self.tunables.load().xattr {
             return Err(enosys());
         }
 
+        if self.is_hidden_xattr(name.to_bytes()) {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA));
+        }
+        let name = self.map_client_xattrname(name)?;
+
         let data = self.inode_map.get(inode)?;
         let file = data.get_file()?;
         let mut buf = Vec::<u8>::with_capacity(size as usize);
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd(),))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let proc_path = ProcFdPath::new(file.as_raw_fd());
+        let pathname = proc_path.as_path();
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
@@ -1222,53 +2127,88 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
     }
 
     fn listxattr(&self, _ctx: &Context, inode: Inode, size: u32) -> io::Result<ListxattrReply> {
-        if !self.cfg.xattr {
+        if !self.tunables.load().xattr {
             return Err(enosys());
         }
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file()?;
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let proc_path = ProcFdPath::new(file.as_raw_fd());
+        let pathname = proc_path.as_path();
 
-        // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
-        // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this will only modify the contents of `buf`.
-        let res = unsafe {
-            libc::listxattr(
-                pathname.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                size as libc::size_t,
-            )
+        if self.cfg.xattr_permissions.is_none()
+            && self.cfg.xattr_prefix_map.is_none()
+            && self.cfg.hidden_xattr_prefixes.is_empty()
+        {
+            let (res, buf) = Self::listxattr_path(pathname, size as usize)?;
+            return if size == 0 {
+                Ok(ListxattrReply::Count(res as u32))
+            } else {
+                Ok(ListxattrReply::Names(buf))
+            };
+        }
+
+        // Filtering changes the size of the list, so get the whole list even if the client only
+        // asks for its size. Retry if attributes are added in between.
+        let names = loop {
+            let (len, _) = Self::listxattr_path(pathname, 0)?;
+            match Self::listxattr_path(pathname, len) {
+                Ok((_, names)) => break names,
+                Err(e) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+                Err(e) => return Err(e),
+            }
         };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
+        let mut names = match self.cfg.xattr_permissions.as_ref() {
+            Some(map) => map.map_server_xattrlist(names).map_err(|e| {
+                error!("fuse: failed to map xattr names, {}", e);
+                eperm()
+            })?,
+            None => names,
+        };
+        if let Some(map) = self.cfg.xattr_prefix_map.as_ref() {
+            names = map.map_host_xattrlist(&names);
+        }
+        if !self.cfg.hidden_xattr_prefixes.is_empty() {
+            names = names
+                .split_inclusive(|b| *b == 0)
+                .filter(|name| !self.is_hidden_xattr(name))
+                .flatten()
+                .copied()
+                .collect();
         }
 
         if size == 0 {
-            Ok(ListxattrReply::Count(res as u32))
+            Ok(ListxattrReply::Count(names.len() as u32))
+        } else if names.len() > size as usize {
+            Err(io::Error::from_raw_os_error(libc::ERANGE))
         } else {
-            // Safe because we trust the value returned by kernel.
-            unsafe { buf.set_len(res as usize) };
-            Ok(ListxattrReply::Names(buf))
+            Ok(ListxattrReply::Names(names))
         }
     }
 
-    fn removexattr(&self, _ctx: &Context, inode: Inode, name: &CStr) -> io::Result<()> {
-        if !self.cfg.xattr {
+    fn removexattr(&self, ctx: &Context, inode: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
+        if !self.tunables.load().xattr {
             return Err(enosys());
         }
 
+        if self.is_hidden_xattr(name.to_bytes()) {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA));
+        }
+        let name = self.map_client_xattrname(name)?;
+
         let data = self.inode_map.get(inode)?;
         let file = data.get_file()?;
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let proc_path = ProcFdPath::new(file.as_raw_fd());
+        let pathname = proc_path.as_path();
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::removexattr(pathname.as_ptr(), name.as_ptr()) };
+        let res = {
+            let (_uid, _gid) = self.set_xattr_creds(ctx, &name)?;
+            // Safe because this doesn't modify any memory and we check the return value.
+            unsafe { libc::removexattr(pathname.as_ptr(), name.as_ptr()) }
+        };
         if res == 0 {
             Ok(())
         } else {
@@ -1285,9 +2225,13 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        self.check_writable()?;
+        check_fallocate_mode(mode as i32)?;
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
         let data = self.get_data(handle, inode, libc::O_RDWR)?;
         let fd = data.borrow_fd();
+        // The data read ahead is dropped with _readahead, once the file has been changed.
+        let _readahead = data.readahead_guard();
 
         if self.seal_size.load(Ordering::Relaxed) {
             let st = stat_fd(&fd, None)?;
@@ -1324,11 +2268,19 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         offset: u64,
         whence: u32,
     ) -> io::Result<u64> {
+        match whence as libc::c_int {
+            libc::SEEK_SET | libc::SEEK_CUR | libc::SEEK_END => {}
+            // Let the guest probe the layout of sparse files.
+            libc::SEEK_DATA | libc::SEEK_HOLE => {}
+            _ => return Err(einval()),
+        }
+
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
         let data = self.handle_map.get(handle, inode)?;
 
         // Acquire the lock to get exclusive access, otherwise it may break do_readdir().
         let (_guard, file) = data.get_file_mut();
+        data.invalidate_readahead();
 
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
@@ -1339,86 +2291,334 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             )
         };
         if res < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(res as u64)
+            return Err(io::Error::last_os_error());
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::convert::TryInto;
-
-    use super::*;
-    use crate::abi::fuse_abi::ROOT_ID;
-    use std::path::Path;
-    use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
+        // The size of the file can't change with seal_size, so there is no hole to find past
+        // its end.
+        if whence as libc::c_int == libc::SEEK_HOLE && self.seal_size.load(Ordering::Relaxed) {
+            let st = stat_fd(file, None)?;
+            return Ok((res as u64).min(st.st_size as u64));
+        }
+        Ok(res as u64)
+    }
 
-    fn prepare_fs_tmpdir() -> (PassthroughFs, TempDir) {
-        let source = TempDir::new().expect("Cannot create temporary directory.");
-        let fs_cfg = Config {
-            writeback: true,
-            do_import: true,
-            no_open: false,
-            no_readdir: false,
-            inode_file_handles: true,
-            xattr: true,
-            killpriv_v2: true, //enable killpriv_v2
-            root_dir: source
-                .as_path()
-                .to_str()
-                .expect("source path to string")
-                .to_string(),
-            ..Default::default()
+    fn ioctl<'a>(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData<'a>,
+        out_size: u32,
+    ) -> io::Result<IoctlData<'a>> {
+        let enotty = || io::Error::from_raw_os_error(libc::ENOTTY);
+
+        let allowed = match self.cfg.ioctl_allowlist.as_ref() {
+            Some(allowlist) => allowlist.contains(&cmd),
+            None => cfg!(feature = "unsafe-ioctl"),
         };
-        let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
-        fs.import().unwrap();
+        if !allowed {
+            return Err(enotty());
+        }
+        // The layout of ioctl data may differ for 32-bit clients, don't try to interpret it.
+        if flags & (IoctlFlags::IOCTL_COMPAT | IoctlFlags::IOCTL_COMPAT_X32).bits() != 0 {
+            return Err(enotty());
+        }
+        // ioctls passing data to the host file may change it, like FS_IOC_SETFLAGS does.
+        if (cmd >> _IOC_DIRSHIFT) & _IOC_DIRMASK & _IOC_WRITE != 0 {
+            self.check_writable()?;
+        }
 
-        // enable all fuse options
-        let opt = FsOptions::all();
-        fs.init(opt).unwrap();
+        // Only well-formed ioctls are supported, whose data size is encoded in `cmd`.
+        let size = ((cmd >> _IOC_SIZESHIFT) & _IOC_SIZEMASK) as usize;
+        let in_data = data.data.as_deref().unwrap_or(&[]);
+        if in_data.len() > size || out_size as usize > size {
+            return Err(einval());
+        }
+        // Reserve room for at least a pointer sized value, for ioctls which don't encode the size
+        // of the data but still access their argument.
+        let mut buf = vec![0u8; std::cmp::max(size, size_of::<u64>())];
+        buf[..in_data.len()].copy_from_slice(in_data);
 
-        (fs, source)
-    }
+        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
+        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+        let fd = data.borrow_fd();
 
-    fn prepare_context() -> Context {
-        Context {
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
-            pid: unsafe { libc::getpid() },
-            ..Default::default()
+        // Safe because the kernel accesses at most `size` bytes of `buf`, as encoded in `cmd`, and
+        // we check the return value.
+        let res = unsafe { libc::ioctl(fd.as_raw_fd(), cmd as _, buf.as_mut_ptr()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
         }
+
+        buf.truncate(out_size as usize);
+        Ok(IoctlData {
+            result: res,
+            data: if buf.is_empty() {
+                None
+            } else {
+                Some(Cow::Owned(buf))
+            },
+        })
     }
 
-    fn create_file_with_sugid(ctx: &Context, fs: &PassthroughFs<()>) -> (Entry, Handle) {
-        let fname = CString::new("testfile").unwrap();
-        let args = CreateIn {
-            flags: libc::O_WRONLY as u32,
-            mode: 0o6777,
-            umask: 0,
-            fuse_flags: 0,
+    fn poll(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        khandle: Handle,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
+        let data = self.handle_map.get(handle, inode)?;
+        let mut pollfd = libc::pollfd {
+            fd: data.borrow_fd().as_raw_fd(),
+            events: events as libc::c_short,
+            revents: 0,
         };
-        let (test_entry, handle, _, _) = fs.create(&ctx, ROOT_ID, &fname, args).unwrap();
-
-        (test_entry, handle.unwrap())
-    }
 
-    #[test]
-    fn test_dir_operations() {
-        let (fs, _source) = prepare_fs_tmpdir();
-        let ctx = prepare_context();
+        // Safe because this only modifies `pollfd`, which is owned by us, and we check the return
+        // value. A zero timeout makes it a non-blocking query.
+        let res = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
 
-        let dir = CString::new("testdir").unwrap();
-        fs.mkdir(&ctx, ROOT_ID, &dir, 0o755, 0).unwrap();
+        // The kernel wants to be notified once the file becomes ready, watch it.
+        if pollfd.revents == 0 && flags & POLL_SCHEDULE_NOTIFY != 0 {
+            self.poll_handle_map
+                .insert(khandle, inode, handle, data.borrow_fd(), events)?;
+        }
 
-        let (handle, _) = fs.opendir(&ctx, ROOT_ID, libc::O_RDONLY as u32).unwrap();
+        Ok(pollfd.revents as u16 as u32)
+    }
 
-        assert!(fs
-            .readdir(&ctx, ROOT_ID, handle.unwrap(), 10, 0, &mut |_| Ok(1))
-            .is_err());
+    fn getlk(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<FileLock> {
+        let mut fl = self.posix_lock_flock(lock)?;
+        self.do_posix_lock(inode, handle, owner, libc::F_OFD_GETLK, &mut fl)?;
+        Ok(fl.into())
+    }
 
-        assert!(fs
+    fn setlk(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<()> {
+        let mut fl = self.posix_lock_flock(lock)?;
+        self.do_posix_lock(inode, handle, owner, libc::F_OFD_SETLK, &mut fl)
+    }
+
+    fn setlkw(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<()> {
+        let mut fl = self.posix_lock_flock(lock)?;
+        // This blocks the calling worker thread until the lock is granted, requests keep being
+        // served by the other worker threads in the meantime.
+        self.do_posix_lock(inode, handle, owner, libc::F_OFD_SETLKW, &mut fl)
+    }
+
+    fn flock(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        operation: i32,
+    ) -> io::Result<()> {
+        let data = self.handle_map.get(handle, inode)?;
+        // Guest processes may share one fuse handle, so lock on a file private to `owner` to make
+        // locks of different owners conflict with each other.
+        let file = data.get_flock_file(owner, &self.proc_self_fd)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::flock(file.as_raw_fd(), operation) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if operation & libc::LOCK_UN != 0 {
+            data.release_flock_file(owner);
+        }
+
+        Ok(())
+    }
+
+    fn seal(&self, _ctx: &Context, inode: Inode, handle: Handle, seals: u32) -> io::Result<()> {
+        // FUSE has no opcode for seals, account them as ioctls.
+        // Seals are kept by the host file, so they modify the shared directory too.
+        self.check_writable()?;
+        let data = self.handle_map.get(handle, inode)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::fcntl(data.borrow_fd().as_raw_fd(), libc::F_ADD_SEALS, seals) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn get_seals(&self, _ctx: &Context, inode: Inode, handle: Handle) -> io::Result<u32> {
+        let data = self.handle_map.get(handle, inode)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::fcntl(data.borrow_fd().as_raw_fd(), libc::F_GET_SEALS) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as u32)
+    }
+
+    fn copy_file_range(
+        &self,
+        _ctx: &Context,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        self.do_copy_file_range(
+            inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len, flags, false,
+        )
+    }
+
+    fn clone_range(
+        &self,
+        _ctx: &Context,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+    ) -> io::Result<usize> {
+        self.do_copy_file_range(
+            inode_in,
+            handle_in,
+            offset_in,
+            inode_out,
+            handle_out,
+            offset_out,
+            len,
+            0,
+            self.cfg.allow_clone_range,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::abi::fuse_abi::ROOT_ID;
+    use crate::api::filesystem::SecContext;
+    use crate::file_traits::FileReadWriteVolatile;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+    use std::path::Path;
+    use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr};
+    use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
+
+    // Create a file system on `source` configured by `f`, and import it.
+    fn prepare_fs_in(source: &TempDir, f: impl FnOnce(&mut Config)) -> PassthroughFs {
+        let mut fs_cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            do_import: true,
+            ..Default::default()
+        };
+        f(&mut fs_cfg);
+        let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+        fs.import().unwrap();
+        fs
+    }
+
+    // Create a file system on a new temporary directory configured by `f`, and import it.
+    fn prepare_fs_with(f: impl FnOnce(&mut Config)) -> (PassthroughFs, TempDir) {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs = prepare_fs_in(&source, f);
+        (fs, source)
+    }
+
+    fn prepare_fs_tmpdir() -> (PassthroughFs, TempDir) {
+        let (fs, source) = prepare_fs_with(|cfg| {
+            cfg.writeback = true;
+            cfg.no_open = false;
+            cfg.no_readdir = false;
+            cfg.inode_file_handles = true;
+            cfg.xattr = true;
+            cfg.killpriv_v2 = true; //enable killpriv_v2
+        });
+
+        // enable all fuse options
+        let opt = FsOptions::all();
+        fs.init(opt).unwrap();
+
+        (fs, source)
+    }
+
+    fn prepare_context() -> Context {
+        Context {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            pid: unsafe { libc::getpid() },
+            ..Default::default()
+        }
+    }
+
+    fn create_file_with_sugid(ctx: &Context, fs: &PassthroughFs<()>) -> (Entry, Handle) {
+        let fname = CString::new("testfile").unwrap();
+        let args = CreateIn {
+            flags: libc::O_WRONLY as u32,
+            mode: 0o6777,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (test_entry, handle, _, _) = fs.create(&ctx, ROOT_ID, &fname, args).unwrap();
+
+        (test_entry, handle.unwrap())
+    }
+
+    #[test]
+    fn test_dir_operations() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let dir = CString::new("testdir").unwrap();
+        fs.mkdir(&ctx, ROOT_ID, &dir, 0o755, 0).unwrap();
+
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, libc::O_RDONLY as u32).unwrap();
+
+        assert!(fs
+            .readdir(&ctx, ROOT_ID, handle.unwrap(), 10, 0, &mut |_| Ok(1))
+            .is_err());
+
+        assert!(fs
             .readdirplus(&ctx, ROOT_ID, handle.unwrap(), 10, 0, &mut |_, _| Ok(1))
             .is_err());
 
@@ -1632,4 +2832,4178 @@ mod tests {
         let statfs = fs.statfs(&ctx, ROOT_ID).unwrap();
         assert_eq!(statfs.f_namemax, 255);
     }
+
+    #[test]
+    fn test_copy_file_range() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        std::fs::write(source.as_path().join("src"), b"hello world").unwrap();
+        let src_name = CString::new("src").unwrap();
+        let src_entry = fs.lookup(&ctx, ROOT_ID, &src_name).unwrap();
+        let (src_handle, _, _) = fs
+            .open(&ctx, src_entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+
+        let dst_name = CString::new("dst").unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (dst_entry, dst_handle, _, _) = fs.create(&ctx, ROOT_ID, &dst_name, args).unwrap();
+
+        let copied = fs
+            .copy_file_range(
+                &ctx,
+                src_entry.inode,
+                src_handle.unwrap(),
+                6,
+                dst_entry.inode,
+                dst_handle.unwrap(),
+                0,
+                5,
+                0,
+            )
+            .unwrap();
+        assert_eq!(copied, 5);
+        assert_eq!(
+            std::fs::read(source.as_path().join("dst")).unwrap(),
+            b"world"
+        );
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn test_async_copy_file_range() {
+        use crate::api::filesystem::AsyncFileSystem;
+
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        std::fs::write(source.as_path().join("src"), b"hello world").unwrap();
+        let src_name = CString::new("src").unwrap();
+        let src_entry = fs.lookup(&ctx, ROOT_ID, &src_name).unwrap();
+        let (src_handle, _, _) = fs
+            .open(&ctx, src_entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+
+        let dst_name = CString::new("dst").unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (dst_entry, dst_handle, _, _) = fs.create(&ctx, ROOT_ID, &dst_name, args).unwrap();
+
+        // The copy runs on a blocking thread, which current-thread runtimes have as well.
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let copied = rt
+            .block_on(fs.async_copy_file_range(
+                &ctx,
+                src_entry.inode,
+                src_handle.unwrap(),
+                0,
+                dst_entry.inode,
+                dst_handle.unwrap(),
+                0,
+                64,
+                0,
+            ))
+            .unwrap();
+        assert_eq!(copied, 11);
+        assert_eq!(
+            std::fs::read(source.as_path().join("dst")).unwrap(),
+            b"hello world"
+        );
+
+        // Copies are denied like the sync ones once the size of files is sealed.
+        fs.seal_size.store(true, Ordering::Relaxed);
+        let err = rt
+            .block_on(fs.async_copy_file_range(
+                &ctx,
+                src_entry.inode,
+                src_handle.unwrap(),
+                0,
+                dst_entry.inode,
+                dst_handle.unwrap(),
+                11,
+                11,
+                0,
+            ))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn test_async_read_readahead() {
+        use crate::api::filesystem::{AsyncFileSystem, AsyncZeroCopyWriter};
+        use crate::file_traits::AsyncFileReadWriteVolatile;
+
+        // Collects the replies of reads.
+        struct VecWriter(Vec<u8>);
+
+        impl io::Write for VecWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl ZeroCopyWriter for VecWriter {
+            fn write_from(
+                &mut self,
+                _f: &mut dyn FileReadWriteVolatile,
+                _count: usize,
+                _off: u64,
+            ) -> io::Result<usize> {
+                unimplemented!()
+            }
+
+            fn available_bytes(&self) -> usize {
+                usize::MAX
+            }
+        }
+
+        #[async_trait::async_trait(?Send)]
+        impl AsyncZeroCopyWriter for VecWriter {
+            async fn async_write_from(
+                &mut self,
+                _f: Arc<dyn AsyncFileReadWriteVolatile>,
+                _count: usize,
+                _off: u64,
+            ) -> io::Result<usize> {
+                unimplemented!()
+            }
+        }
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let content: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.as_path().join("file"), &content).unwrap();
+        let fs = prepare_fs_in(&source, |cfg| cfg.readahead = 16384);
+        let ctx = prepare_context();
+        let inode = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap()
+            .inode;
+        let handle = fs
+            .open(&ctx, inode, libc::O_RDWR as u32, 0)
+            .unwrap()
+            .0
+            .unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let read = |offset: u64, size: u32| {
+            let mut w = VecWriter(Vec::new());
+            let n = rt
+                .block_on(fs.async_read(&ctx, inode, handle, &mut w, size, offset, None, 0))
+                .unwrap();
+            assert_eq!(n, w.0.len());
+            w.0
+        };
+        let buffered = || {
+            let data = fs.handle_map.get(handle, inode).unwrap();
+            let len = data.readahead.lock().unwrap().data.len();
+            len
+        };
+
+        // The first read isn't known to be sequential, the second one reads ahead, and the next
+        // ones are served from the buffer, up to the end of the file.
+        let mut out = Vec::new();
+        for i in 0..10 {
+            out.extend(read(i * 4096, 4096));
+            if i == 0 {
+                assert_eq!(buffered(), 0);
+            } else {
+                assert!(buffered() > 0);
+            }
+        }
+        assert_eq!(out, content);
+        assert!(read(40000, 4096).is_empty());
+
+        // Writes through the handle drop the buffer, so that reads don't return stale data.
+        read(0, 4096);
+        read(4096, 4096);
+        assert_eq!(buffered(), 16384);
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&[0xffu8; 4096]).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        fs.write(
+            &ctx, inode, handle, &mut file, 4096, 8192, None, false, 0, 0,
+        )
+        .unwrap();
+        assert_eq!(buffered(), 0);
+        assert_eq!(read(8192, 4096), vec![0xffu8; 4096]);
+
+        // Random reads don't read ahead.
+        read(32768, 100);
+        read(100, 100);
+        assert_eq!(buffered(), 0);
+    }
+
+    #[test]
+    fn test_copy_file_range_drop_priv() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        std::fs::write(source.as_path().join("src"), vec![0xa5u8; 8192]).unwrap();
+        let src_name = CString::new("src").unwrap();
+        let src_entry = fs.lookup(&ctx, ROOT_ID, &src_name).unwrap();
+        let (src_handle, _, _) = fs
+            .open(&ctx, src_entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+
+        let (test_entry, handle) = create_file_with_sugid(&ctx, &fs);
+
+        // Ask for more than the source holds, only the available bytes are copied.
+        let copied = fs
+            .copy_file_range(
+                &ctx,
+                src_entry.inode,
+                src_handle.unwrap(),
+                0,
+                test_entry.inode,
+                handle,
+                0,
+                16384,
+                0,
+            )
+            .unwrap();
+        assert_eq!(copied, 8192);
+
+        let (att, _) = fs.getattr(&ctx, test_entry.inode, None).unwrap();
+        assert_eq!(att.st_size, 8192);
+        // suid/sgid dropped because of killpriv_v2
+        assert_eq!(att.st_mode, 0o100777);
+    }
+
+    #[test]
+    fn test_copy_file_range_exdev() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        // Put the destination on a different filesystem inside the export.
+        let mnt = source.as_path().join("mnt");
+        std::fs::create_dir(&mnt).unwrap();
+        if nix::mount::mount(
+            Some("none"),
+            &mnt,
+            Some("tmpfs"),
+            nix::mount::MsFlags::empty(),
+            None::<&str>,
+        )
+        .is_err()
+        {
+            // Not privileged enough to mount tmpfs, nothing to test.
+            return;
+        }
+
+        std::fs::write(source.as_path().join("src"), b"hello world").unwrap();
+        let src_name = CString::new("src").unwrap();
+        let src_entry = fs.lookup(&ctx, ROOT_ID, &src_name).unwrap();
+        let (src_handle, _, _) = fs
+            .open(&ctx, src_entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+
+        let mnt_name = CString::new("mnt").unwrap();
+        let mnt_entry = fs.lookup(&ctx, ROOT_ID, &mnt_name).unwrap();
+        let dst_name = CString::new("dst").unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (dst_entry, dst_handle, _, _) =
+            fs.create(&ctx, mnt_entry.inode, &dst_name, args).unwrap();
+
+        let res = fs.copy_file_range(
+            &ctx,
+            src_entry.inode,
+            src_handle.unwrap(),
+            0,
+            dst_entry.inode,
+            dst_handle.unwrap(),
+            0,
+            11,
+            0,
+        );
+        fs.release(
+            &ctx,
+            dst_entry.inode,
+            0,
+            dst_handle.unwrap(),
+            false,
+            false,
+            None,
+        )
+        .unwrap();
+        nix::mount::umount2(&mnt, nix::mount::MntFlags::MNT_DETACH).unwrap();
+
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EXDEV));
+    }
+
+    #[test]
+    fn test_flock() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let (test_entry, handle) = create_file_with_sugid(&ctx, &fs);
+        let (handle2, _, _) = fs
+            .open(&ctx, test_entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let handle2 = handle2.unwrap();
+
+        fs.flock(
+            &ctx,
+            test_entry.inode,
+            handle,
+            1,
+            libc::LOCK_EX | libc::LOCK_NB,
+        )
+        .unwrap();
+        // An exclusive lock held through one handle blocks the other one.
+        let err = fs
+            .flock(
+                &ctx,
+                test_entry.inode,
+                handle2,
+                2,
+                libc::LOCK_SH | libc::LOCK_NB,
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EWOULDBLOCK));
+
+        fs.flock(&ctx, test_entry.inode, handle, 1, libc::LOCK_UN)
+            .unwrap();
+        fs.flock(
+            &ctx,
+            test_entry.inode,
+            handle2,
+            2,
+            libc::LOCK_SH | libc::LOCK_NB,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_posix_lock() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let (test_entry, handle) = create_file_with_sugid(&ctx, &fs);
+        let (handle2, _, _) = fs
+            .open(&ctx, test_entry.inode, libc::O_RDWR as u32, 0)
+            .unwrap();
+        let handle2 = handle2.unwrap();
+
+        let lock = FileLock {
+            start: 0,
+            end: 99,
+            lock_type: libc::F_WRLCK as u32,
+            pid: 1,
+        };
+        fs.setlk(&ctx, test_entry.inode, handle, 1, lock, 0)
+            .unwrap();
+
+        let probe = FileLock {
+            start: 50,
+            end: 59,
+            lock_type: libc::F_RDLCK as u32,
+            pid: 2,
+        };
+        let conflict = fs
+            .getlk(&ctx, test_entry.inode, handle2, 2, probe, 0)
+            .unwrap();
+        assert_eq!(conflict.lock_type, libc::F_WRLCK as u32);
+        assert_eq!(conflict.start, 0);
+        assert_eq!(conflict.end, 99);
+
+        let err = fs
+            .setlk(&ctx, test_entry.inode, handle2, 2, probe, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+
+        let unlock = FileLock {
+            lock_type: libc::F_UNLCK as u32,
+            ..lock
+        };
+        fs.setlk(&ctx, test_entry.inode, handle, 1, unlock, 0)
+            .unwrap();
+        let free = fs
+            .getlk(&ctx, test_entry.inode, handle2, 2, probe, 0)
+            .unwrap();
+        assert_eq!(free.lock_type, libc::F_UNLCK as u32);
+        fs.setlkw(&ctx, test_entry.inode, handle2, 2, probe, 0)
+            .unwrap();
+
+        // Ranges ending before they start are invalid.
+        let reversed = FileLock {
+            start: 100,
+            end: 99,
+            ..lock
+        };
+        let err = fs
+            .getlk(&ctx, test_entry.inode, handle, 1, reversed, 0)
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = fs
+            .setlk(&ctx, test_entry.inode, handle, 1, reversed, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = fs
+            .setlkw(&ctx, test_entry.inode, handle, 1, reversed, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_create_tmpfile_and_link() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let args = CreateIn {
+            flags: (libc::O_TMPFILE | libc::O_RDWR) as u32,
+            mode: 0o600,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let empty = CString::new("").unwrap();
+        let (entry, handle, _, _) = fs.create(&ctx, ROOT_ID, &empty, args).unwrap();
+        assert_ne!(entry.inode, ROOT_ID);
+        assert_eq!(entry.attr.st_nlink, 0);
+
+        let data = b"hello tmpfile";
+        let mut buffer_file = TempFile::new().unwrap().into_file();
+        buffer_file.write_all(data).unwrap();
+        buffer_file.seek(SeekFrom::Start(0)).unwrap();
+        let written = fs
+            .write(
+                &ctx,
+                entry.inode,
+                handle.unwrap(),
+                &mut buffer_file,
+                data.len() as u32,
+                0,
+                None,
+                false,
+                args.flags,
+                0,
+            )
+            .unwrap();
+        assert_eq!(written, data.len());
+
+        let name = CString::new("linked").unwrap();
+        let linked = fs.link(&ctx, entry.inode, ROOT_ID, &name).unwrap();
+        assert_eq!(linked.inode, entry.inode);
+        assert_eq!(
+            std::fs::read(source.as_path().join("linked")).unwrap(),
+            data
+        );
+
+        // Directories can't be reopened with O_TMPFILE behind create()'s back.
+        assert!(fs.open(&ctx, ROOT_ID, args.flags, 0).is_err());
+    }
+
+    #[test]
+    fn test_flock_owners() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let (test_entry, handle) = create_file_with_sugid(&ctx, &fs);
+
+        // Two owners sharing the same handle.
+        fs.flock(
+            &ctx,
+            test_entry.inode,
+            handle,
+            1,
+            libc::LOCK_EX | libc::LOCK_NB,
+        )
+        .unwrap();
+        let err = fs
+            .flock(
+                &ctx,
+                test_entry.inode,
+                handle,
+                2,
+                libc::LOCK_EX | libc::LOCK_NB,
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EWOULDBLOCK));
+        // Taking the lock again by the same owner is fine.
+        fs.flock(
+            &ctx,
+            test_entry.inode,
+            handle,
+            1,
+            libc::LOCK_EX | libc::LOCK_NB,
+        )
+        .unwrap();
+
+        fs.flock(&ctx, test_entry.inode, handle, 1, libc::LOCK_UN)
+            .unwrap();
+        fs.flock(
+            &ctx,
+            test_entry.inode,
+            handle,
+            2,
+            libc::LOCK_EX | libc::LOCK_NB,
+        )
+        .unwrap();
+
+        // Releasing the handle with flock_release drops the owner's lock.
+        let (handle2, _, _) = fs
+            .open(&ctx, test_entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let handle2 = handle2.unwrap();
+        fs.release(&ctx, test_entry.inode, 0, handle, false, true, Some(2))
+            .unwrap();
+        fs.flock(
+            &ctx,
+            test_entry.inode,
+            handle2,
+            3,
+            libc::LOCK_EX | libc::LOCK_NB,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_fiemap_sparse_file() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let path = source.as_path().join("sparse");
+        let file = std::fs::File::create(&path).unwrap();
+        file.write_all_at(&[0x5a; 4096], 0).unwrap();
+        file.write_all_at(&[0x5a; 4096], 1 << 20).unwrap();
+        drop(file);
+
+        let name = CString::new("sparse").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let (handle, _, _) = fs
+            .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+
+        let extents = fs
+            .fiemap(entry.inode, handle.unwrap(), 0, u64::MAX, FIEMAP_FLAG_SYNC)
+            .unwrap();
+        assert!(!extents.is_empty());
+        assert_eq!(extents[0].fe_logical, 0);
+        assert_ne!(extents.last().unwrap().fe_flags & FIEMAP_EXTENT_LAST, 0);
+        // No extent covers the hole between the two written blocks.
+        for e in extents.iter() {
+            assert!(e.fe_logical + e.fe_length <= 4096 || e.fe_logical >= 1 << 20);
+        }
+        assert!(extents
+            .iter()
+            .any(|e| e.fe_logical <= 1 << 20 && e.fe_logical + e.fe_length > 1 << 20));
+    }
+
+    #[test]
+    fn test_ioctl_allowlist() {
+        ioctl_ior_nr!(FS_IOC_GETFLAGS, b'f' as u32, 1, libc::c_long);
+        ioctl_iow_nr!(FS_IOC_SETFLAGS, b'f' as u32, 2, libc::c_long);
+        const FS_IMMUTABLE_FL: u32 = 0x10;
+
+        let (fs, _source) = prepare_fs_with(|cfg| {
+            cfg.ioctl_allowlist = Some(vec![FS_IOC_GETFLAGS() as u32, FS_IOC_SETFLAGS() as u32])
+        });
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = prepare_context();
+
+        let (entry, handle) = create_file_with_sugid(&ctx, &fs);
+        let get_flags = |fs: &PassthroughFs<()>| {
+            let out = fs
+                .ioctl(
+                    &ctx,
+                    entry.inode,
+                    handle,
+                    0,
+                    FS_IOC_GETFLAGS() as u32,
+                    IoctlData::default(),
+                    8,
+                )
+                .unwrap();
+            let data = out.data.unwrap();
+            assert_eq!(data.len(), 8);
+            u32::from_ne_bytes(data[..4].try_into().unwrap())
+        };
+        let set_flags = |fs: &PassthroughFs<()>, flags: u32| {
+            let mut buf = [0u8; 8];
+            buf[..4].copy_from_slice(&flags.to_ne_bytes());
+            let data = IoctlData {
+                result: 0,
+                data: Some(Cow::Borrowed(&buf[..])),
+            };
+            fs.ioctl(
+                &ctx,
+                entry.inode,
+                handle,
+                0,
+                FS_IOC_SETFLAGS() as u32,
+                data,
+                0,
+            )
+            .map(|_| ())
+        };
+
+        let flags = get_flags(&fs);
+        // The backing filesystem may not support the immutable bit, or we may lack the privilege.
+        if set_flags(&fs, flags | FS_IMMUTABLE_FL).is_ok() {
+            assert_ne!(get_flags(&fs) & FS_IMMUTABLE_FL, 0);
+            set_flags(&fs, flags).unwrap();
+            assert_eq!(get_flags(&fs) & FS_IMMUTABLE_FL, 0);
+        }
+
+        // ioctls not in the allowlist are rejected.
+        let err = fs
+            .ioctl(
+                &ctx,
+                entry.inode,
+                handle,
+                0,
+                libc::FIONREAD as u32,
+                IoctlData::default(),
+                4,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+    }
+
+    #[cfg(not(feature = "unsafe-ioctl"))]
+    #[test]
+    fn test_ioctl_no_allowlist() {
+        // Without an allowlist, nothing is passed through unless explicitly built to do so.
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let (entry, handle) = create_file_with_sugid(&ctx, &fs);
+        let err = fs
+            .ioctl(
+                &ctx,
+                entry.inode,
+                handle,
+                0,
+                libc::FIONREAD as u32,
+                IoctlData::default(),
+                0,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+    }
+
+    #[test]
+    fn test_poll() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let (entry, _) = create_file_with_sugid(&ctx, &fs);
+
+        // Serve the read end of a pipe through a fresh handle.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (rx, mut tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let handle = fs.next_handle.fetch_add(1, Ordering::Relaxed);
+        fs.handle_map
+            .try_insert(
+                handle,
+                HandleData::new(entry.inode, rx, libc::O_RDONLY as u32),
+            )
+            .unwrap();
+
+        let events = libc::POLLIN as u32;
+        let revents = fs
+            .poll(&ctx, entry.inode, handle, 10, POLL_SCHEDULE_NOTIFY, events)
+            .unwrap();
+        assert_eq!(revents, 0);
+        let revents = fs
+            .poll(&ctx, entry.inode, handle, 11, POLL_SCHEDULE_NOTIFY, events)
+            .unwrap();
+        assert_eq!(revents, 0);
+        let timeout = Some(Duration::from_millis(0));
+        assert!(fs.wait_poll_handles(timeout).unwrap().is_empty());
+
+        tx.write_all(b"ping").unwrap();
+        let mut khs = fs.wait_poll_handles(timeout).unwrap();
+        khs.sort_unstable();
+        assert_eq!(khs, vec![10, 11]);
+        assert!(fs.wait_poll_handles(timeout).unwrap().is_empty());
+
+        // Ready files are not watched.
+        let revents = fs
+            .poll(&ctx, entry.inode, handle, 12, POLL_SCHEDULE_NOTIFY, events)
+            .unwrap();
+        assert_eq!(revents, libc::POLLIN as u32);
+        assert!(fs.wait_poll_handles(timeout).unwrap().is_empty());
+
+        // Released handles are not watched anymore.
+        let mut buf = [0u8; 4];
+        let data = fs.handle_map.get(handle, entry.inode).unwrap();
+        std::io::Read::read_exact(&mut data.get_file(), &mut buf).unwrap();
+        fs.poll(&ctx, entry.inode, handle, 13, POLL_SCHEDULE_NOTIFY, events)
+            .unwrap();
+        fs.release(&ctx, entry.inode, 0, handle, false, false, None)
+            .unwrap();
+        tx.write_all(b"ping").unwrap();
+        assert!(fs.wait_poll_handles(timeout).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_poll_notify_wakeup() {
+        use crate::abi::fuse_abi::{NotifyOpcode, NotifyPollWakeupOut, OutHeader};
+        use crate::api::server::Server;
+        use crate::transport::FuseDevWriter;
+
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        let (entry, _) = create_file_with_sugid(&ctx, &fs);
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (rx, mut tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let handle = fs.next_handle.fetch_add(1, Ordering::Relaxed);
+        fs.handle_map
+            .try_insert(
+                handle,
+                HandleData::new(entry.inode, rx, libc::O_RDONLY as u32),
+            )
+            .unwrap();
+        let revents = fs
+            .poll(
+                &ctx,
+                entry.inode,
+                handle,
+                0x1234,
+                POLL_SCHEDULE_NOTIFY,
+                libc::POLLIN as u32,
+            )
+            .unwrap();
+        assert_eq!(revents, 0);
+
+        // Forward wakeups to a pipe standing in for the fuse device.
+        let fs = Arc::new(fs);
+        let server = Server::new(fs.clone(), None);
+        let (mut dev_rx, dev_tx) = {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+        };
+        let notifier = std::thread::spawn(move || {
+            let khs = fs.wait_poll_handles(None).unwrap();
+            for kh in khs {
+                let mut buf = vec![0u8; 64];
+                let writer = FuseDevWriter::<()>::new(dev_tx.as_raw_fd(), &mut buf).unwrap();
+                server.notify_poll_wakeup(writer.into(), kh).unwrap();
+            }
+        });
+
+        tx.write_all(b"ping").unwrap();
+        notifier.join().unwrap();
+
+        let mut msg = [0u8; 24];
+        std::io::Read::read_exact(&mut dev_rx, &mut msg).unwrap();
+        let header = OutHeader::from_slice(&msg[..16]).unwrap();
+        assert_eq!(header.len, 24);
+        assert_eq!(header.error, NotifyOpcode::Poll as i32);
+        assert_eq!(header.unique, 0);
+        let out = NotifyPollWakeupOut::from_slice(&msg[16..]).unwrap();
+        assert_eq!(out.kh, 0x1234);
+    }
+
+    #[test]
+    fn test_rename_exchange() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let mut names = Vec::new();
+        for (name, content) in [("file_a", b"aaaa"), ("file_b", b"bbbb")] {
+            let name = CString::new(name).unwrap();
+            let args = CreateIn {
+                flags: libc::O_RDWR as u32,
+                mode: 0o644,
+                umask: 0,
+                fuse_flags: 0,
+            };
+            let (entry, handle, _, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+            let data = fs.handle_map.get(handle.unwrap(), entry.inode).unwrap();
+            data.get_file().write_all_at(content, 0).unwrap();
+            names.push((name, entry));
+        }
+        let (name_a, entry_a) = &names[0];
+        let (name_b, entry_b) = &names[1];
+
+        fs.rename(
+            &ctx,
+            ROOT_ID,
+            name_a,
+            ROOT_ID,
+            name_b,
+            libc::RENAME_EXCHANGE,
+        )
+        .unwrap();
+        let lookup_a = fs.lookup(&ctx, ROOT_ID, name_a).unwrap();
+        let lookup_b = fs.lookup(&ctx, ROOT_ID, name_b).unwrap();
+        assert_eq!(lookup_a.inode, entry_b.inode);
+        assert_eq!(lookup_b.inode, entry_a.inode);
+
+        // Exchanging with a missing entry fails before reaching the host.
+        let missing = CString::new("missing").unwrap();
+        let err = fs
+            .rename(
+                &ctx,
+                ROOT_ID,
+                name_a,
+                ROOT_ID,
+                &missing,
+                libc::RENAME_EXCHANGE,
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        for flags in [
+            libc::RENAME_EXCHANGE | libc::RENAME_NOREPLACE,
+            libc::RENAME_EXCHANGE | libc::RENAME_WHITEOUT,
+            0x80,
+        ] {
+            let err = fs
+                .rename(&ctx, ROOT_ID, name_a, ROOT_ID, name_b, flags)
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        }
+        assert!(fs.lookup(&ctx, ROOT_ID, name_a).is_ok());
+        assert!(fs.lookup(&ctx, ROOT_ID, name_b).is_ok());
+    }
+
+    #[test]
+    fn test_rename_whiteout() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        create_file_with_sugid(&ctx, &fs);
+
+        let oldname = CString::new("testfile").unwrap();
+        let newname = CString::new("renamed").unwrap();
+        match fs.rename(
+            &ctx,
+            ROOT_ID,
+            &oldname,
+            ROOT_ID,
+            &newname,
+            libc::RENAME_WHITEOUT,
+        ) {
+            Ok(()) => {}
+            // The backing filesystem doesn't support whiteouts.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("failed to rename with RENAME_WHITEOUT: {}", e),
+        }
+
+        // A whiteout is a 0/0 character device left in place of the source.
+        let whiteout = std::fs::symlink_metadata(source.as_path().join("testfile")).unwrap();
+        assert!(whiteout.file_type().is_char_device());
+        assert_eq!(whiteout.rdev(), 0);
+        assert!(source.as_path().join("renamed").exists());
+    }
+
+    #[test]
+    fn test_fadvise() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let name = CString::new("large").unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, handle, _, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        let handle = handle.unwrap();
+        let data = fs.handle_map.get(handle, entry.inode).unwrap();
+        let chunk = vec![0xa5u8; 1 << 20];
+        for i in 0..8 {
+            data.get_file().write_all_at(&chunk, i << 20).unwrap();
+        }
+        data.get_file().sync_all().unwrap();
+
+        fs.fadvise(entry.inode, handle, 0, 0, libc::POSIX_FADV_DONTNEED)
+            .unwrap();
+        fs.fadvise(entry.inode, handle, 0, 8 << 20, libc::POSIX_FADV_SEQUENTIAL)
+            .unwrap();
+        let mut buf = vec![0u8; 4096];
+        data.get_file().read_exact_at(&mut buf, 4 << 20).unwrap();
+        assert_eq!(buf, chunk[..4096]);
+
+        let err = fs.fadvise(entry.inode, handle, 0, 0, 0x1000).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = fs
+            .fadvise(entry.inode, handle, u64::MAX, 0, libc::POSIX_FADV_DONTNEED)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = fs
+            .fadvise(entry.inode, handle + 1, 0, 0, libc::POSIX_FADV_DONTNEED)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn test_getattr_stat_fallback() {
+        use super::statx::FORCE_STAT_FALLBACK;
+
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        let (entry, handle) = create_file_with_sugid(&ctx, &fs);
+
+        let (st1, _) = fs.getattr(&ctx, entry.inode, Some(handle)).unwrap();
+        let (st2, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        if let Some(btime) = fs.birth_time(entry.inode).unwrap() {
+            assert!(btime <= SystemTime::now());
+        }
+
+        // Pretend the kernel has no statx().
+        FORCE_STAT_FALLBACK.with(|force| force.set(true));
+        let (st3, _) = fs.getattr(&ctx, entry.inode, Some(handle)).unwrap();
+        let (st4, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        let btime = fs.birth_time(entry.inode).unwrap();
+        let name = CString::new("fallback").unwrap();
+        let fallback = fs.mkdir(&ctx, ROOT_ID, &name, 0o755, 0).unwrap();
+        let lookup = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        FORCE_STAT_FALLBACK.with(|force| force.set(false));
+
+        assert!(btime.is_none());
+        for st in [st2, st3, st4] {
+            assert_eq!(st.st_ino, st1.st_ino);
+            assert_eq!(st.st_dev, st1.st_dev);
+            assert_eq!(st.st_mode, st1.st_mode);
+            assert_eq!(st.st_size, st1.st_size);
+        }
+        assert_eq!(lookup.inode, fallback.inode);
+        assert_eq!(lookup.attr.st_mode & libc::S_IFMT, libc::S_IFDIR);
+    }
+
+    #[test]
+    fn test_getattr_use_statx() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        let (entry, handle) = create_file_with_sugid(&ctx, &fs);
+
+        // The birth time is reported whenever the backing filesystem records it.
+        let dir = File::open(source.as_path()).unwrap();
+        let name = CString::new("testfile").unwrap();
+        let stx = statx(&dir, Some(&name)).unwrap();
+        let btime = fs.birth_time(entry.inode).unwrap();
+        assert_eq!(btime.is_some(), stx.btime.is_some());
+        if let (Some(btime), Some(t)) = (btime, stx.btime) {
+            assert_eq!(
+                btime,
+                UNIX_EPOCH + Duration::new(t.tv_sec as u64, t.tv_nsec as u32)
+            );
+        }
+
+        // Without statx, getattr reports the same attributes.
+        let fs2 = prepare_fs_in(&source, |cfg| cfg.use_statx = false);
+        fs2.init(FsOptions::all()).unwrap();
+        let entry2 = fs2.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let (st1, _) = fs.getattr(&ctx, entry.inode, Some(handle)).unwrap();
+        let (st2, _) = fs2.getattr(&ctx, entry2.inode, None).unwrap();
+        assert_eq!(st1.st_ino, st2.st_ino);
+        assert_eq!(st1.st_mode, st2.st_mode);
+        assert_eq!(st1.st_mtime, st2.st_mtime);
+        assert_eq!(st1.st_mtime_nsec, st2.st_mtime_nsec);
+    }
+
+    #[test]
+    fn test_announce_submounts() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+        let mnt = source.as_path().join("mnt");
+        std::fs::create_dir(&mnt).unwrap();
+        if nix::mount::mount(
+            Some("none"),
+            &mnt,
+            Some("tmpfs"),
+            nix::mount::MsFlags::empty(),
+            None::<&str>,
+        )
+        .is_err()
+        {
+            // Not privileged enough to mount tmpfs, nothing to test.
+            return;
+        }
+        std::fs::create_dir(mnt.join("dir")).unwrap();
+
+        let lookup_flags = |announce_submounts, use_statx, parent: &str, name: &str| {
+            let fs = prepare_fs_in(&source, |cfg| {
+                cfg.announce_submounts = announce_submounts;
+                cfg.use_statx = use_statx;
+            });
+            fs.init(FsOptions::all()).unwrap();
+            let ctx = prepare_context();
+            let mut inode = ROOT_ID;
+            for name in parent.split('/').filter(|n| !n.is_empty()) {
+                let name = CString::new(name).unwrap();
+                inode = fs.lookup(&ctx, inode, &name).unwrap().inode;
+            }
+            let name = CString::new(name).unwrap();
+            fs.lookup(&ctx, inode, &name).unwrap().attr_flags & fuse::ATTR_SUBMOUNT
+        };
+
+        // The mount point is detected by statx(2), or by its st_dev otherwise.
+        assert_ne!(lookup_flags(true, true, "", "mnt"), 0);
+        assert_ne!(lookup_flags(true, false, "", "mnt"), 0);
+        assert_eq!(lookup_flags(false, true, "", "mnt"), 0);
+        assert_eq!(lookup_flags(true, true, "", "file"), 0);
+        assert_eq!(lookup_flags(true, true, "mnt", "dir"), 0);
+        // Neither the root nor the way back from a mount point are submounts.
+        assert_eq!(lookup_flags(true, false, "", ".."), 0);
+        assert_eq!(lookup_flags(true, false, "mnt", ".."), 0);
+
+        nix::mount::umount2(&mnt, nix::mount::MntFlags::MNT_DETACH).unwrap();
+    }
+
+    #[test]
+    fn test_tmpfile() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o640,
+            umask: 0o022,
+            fuse_flags: 0,
+        };
+        let (entry, handle, _, _) = fs.tmpfile(&ctx, ROOT_ID, args).unwrap();
+        assert_ne!(entry.inode, ROOT_ID);
+        assert_eq!(entry.attr.st_nlink, 0);
+        assert_eq!(entry.attr.st_mode & 0o777, 0o640);
+
+        let data = b"hello tmpfile";
+        let mut buffer_file = TempFile::new().unwrap().into_file();
+        buffer_file.write_all(data).unwrap();
+        buffer_file.seek(SeekFrom::Start(0)).unwrap();
+        let written = fs
+            .write(
+                &ctx,
+                entry.inode,
+                handle.unwrap(),
+                &mut buffer_file,
+                data.len() as u32,
+                0,
+                None,
+                false,
+                args.flags,
+                0,
+            )
+            .unwrap();
+        assert_eq!(written, data.len());
+
+        let name = CString::new("linked").unwrap();
+        let linked = fs.link(&ctx, entry.inode, ROOT_ID, &name).unwrap();
+        assert_eq!(linked.inode, entry.inode);
+        let found = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(found.inode, entry.inode);
+        assert_eq!(found.attr.st_nlink, 1);
+        assert_eq!(found.attr.st_size, data.len() as i64);
+        assert_eq!(
+            std::fs::read(source.as_path().join("linked")).unwrap(),
+            data
+        );
+
+        // The linked file is read back through a handle opened by its name.
+        let (handle, _, _) = fs
+            .open(&ctx, found.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let mut out_file = TempFile::new().unwrap().into_file();
+        let read = fs
+            .read(
+                &ctx,
+                found.inode,
+                handle.unwrap(),
+                &mut out_file,
+                4096,
+                0,
+                None,
+                0,
+            )
+            .unwrap();
+        assert_eq!(read, data.len());
+        out_file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+        out_file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // Pretend the shared directory doesn't support O_TMPFILE.
+        fs.tmpfile.store(false, Ordering::Relaxed);
+        let err = fs.tmpfile(&ctx, ROOT_ID, args).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    }
+
+    #[test]
+    fn test_read_write_4k() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        check_read_write_4k(&fs);
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn test_read_write_io_uring() {
+        let (fs, source) = prepare_fs_with(|cfg| {
+            // Let the write-only handle of the file be read too.
+            cfg.writeback = true;
+            cfg.use_io_uring = true;
+        });
+        fs.init(FsOptions::all()).unwrap();
+        check_read_write_4k(&fs);
+    }
+
+    // Write a page to a new file and read it back.
+    fn check_read_write_4k(fs: &PassthroughFs) {
+        let ctx = prepare_context();
+        let (entry, handle) = create_file_with_sugid(&ctx, fs);
+
+        let data: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+        let mut buffer_file = TempFile::new().unwrap().into_file();
+        buffer_file.write_all(&data).unwrap();
+        buffer_file.seek(SeekFrom::Start(0)).unwrap();
+        let written = fs
+            .write(
+                &ctx,
+                entry.inode,
+                handle,
+                &mut buffer_file,
+                4096,
+                0,
+                None,
+                false,
+                0,
+                0,
+            )
+            .unwrap();
+        assert_eq!(written, 4096);
+
+        let mut out_file = TempFile::new().unwrap().into_file();
+        let read = fs
+            .read(&ctx, entry.inode, handle, &mut out_file, 4096, 0, None, 0)
+            .unwrap();
+        assert_eq!(read, 4096);
+        out_file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+        out_file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    // Create a file system shared with everyone, with the same mapping for uids and gids.
+    fn prepare_fs_id_map(map: UidGidMap) -> (PassthroughFs, TempDir) {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::set_permissions(source.as_path(), PermissionsExt::from_mode(0o777)).unwrap();
+        let fs = prepare_fs_in(&source, |cfg| {
+            cfg.uid_map = Some(map.clone());
+            cfg.gid_map = Some(map);
+        });
+        fs.init(FsOptions::all()).unwrap();
+
+        (fs, source)
+    }
+
+    #[test]
+    fn test_uid_gid_map() {
+        let map = UidGidMap::new(vec![(0, 0, 1), (1000, 2000, 10)]);
+        let (fs, source) = prepare_fs_id_map(map);
+
+        // The caller's credentials are translated into host ids, and back in replies.
+        let ctx = Context {
+            uid: 1000,
+            gid: 1001,
+            ..Default::default()
+        };
+        let dir = CString::new("testdir").unwrap();
+        let entry = fs.mkdir(&ctx, ROOT_ID, &dir, 0o755, 0).unwrap();
+        assert_eq!(entry.attr.st_uid, 1000);
+        assert_eq!(entry.attr.st_gid, 1001);
+        let st = std::fs::metadata(source.as_path().join("testdir")).unwrap();
+        assert_eq!(st.uid(), 2000);
+        assert_eq!(st.gid(), 2001);
+
+        // Owners are translated in setattr requests too.
+        let root_ctx = Context::default();
+        let (mut attr, _) = fs.getattr(&root_ctx, entry.inode, None).unwrap();
+        attr.st_uid = 1005;
+        attr.st_gid = 0;
+        let (attr, _) = fs
+            .setattr(
+                &root_ctx,
+                entry.inode,
+                attr,
+                None,
+                SetattrValid::UID | SetattrValid::GID,
+            )
+            .unwrap();
+        assert_eq!(attr.st_uid, 1005);
+        assert_eq!(attr.st_gid, 0);
+        let st = std::fs::metadata(source.as_path().join("testdir")).unwrap();
+        assert_eq!(st.uid(), 2005);
+        assert_eq!(st.gid(), 0);
+
+        // Host ids without a mapping show up as the overflow id.
+        std::os::unix::fs::chown(source.as_path().join("testdir"), Some(3000), Some(3000)).unwrap();
+        let (attr, _) = fs.getattr(&root_ctx, entry.inode, None).unwrap();
+        assert_eq!(attr.st_uid, OVERFLOW_ID);
+        assert_eq!(attr.st_gid, OVERFLOW_ID);
+
+        // Container ids without a mapping are squashed to the overflow id on the host.
+        let mut attr = attr;
+        attr.st_uid = 2000;
+        fs.setattr(&root_ctx, entry.inode, attr, None, SetattrValid::UID)
+            .unwrap();
+        let st = std::fs::metadata(source.as_path().join("testdir")).unwrap();
+        assert_eq!(st.uid(), OVERFLOW_ID);
+        let ctx = Context {
+            uid: 7,
+            gid: 0,
+            ..Default::default()
+        };
+        let dir = CString::new("testdir2").unwrap();
+        let entry = fs.mkdir(&ctx, ROOT_ID, &dir, 0o755, 0).unwrap();
+        assert_eq!(entry.attr.st_uid, OVERFLOW_ID);
+        assert_eq!(entry.attr.st_gid, 0);
+        let st = std::fs::metadata(source.as_path().join("testdir2")).unwrap();
+        assert_eq!(st.uid(), OVERFLOW_ID);
+        assert_eq!(st.gid(), 0);
+    }
+
+    #[test]
+    fn test_id_map_overflow_id() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::set_permissions(source.as_path(), PermissionsExt::from_mode(0o777)).unwrap();
+        let map = UidGidMap::new(vec![(1000, 2000, 10)]);
+        let fs = prepare_fs_in(&source, |cfg| {
+            cfg.uid_map = Some(map.clone());
+            cfg.gid_map = Some(map);
+            cfg.overflow_uid = 1234;
+            cfg.overflow_gid = 1235;
+        });
+        fs.init(FsOptions::all()).unwrap();
+
+        // A file created by a mapped caller belongs to the guest-side ids.
+        let ctx = Context {
+            uid: 1003,
+            gid: 1004,
+            ..Default::default()
+        };
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let name = CString::new("mapped").unwrap();
+        let (entry, _, _, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        let (attr, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!((attr.st_uid, attr.st_gid), (1003, 1004));
+        let st = std::fs::metadata(source.as_path().join("mapped")).unwrap();
+        assert_eq!((st.uid(), st.gid()), (2003, 2004));
+
+        // A caller without a mapping creates files as the overflow ids of the host, which are
+        // reported as the overflow ids again.
+        let ctx = Context {
+            uid: 5,
+            gid: 6,
+            ..Default::default()
+        };
+        let name = CString::new("squashed").unwrap();
+        let (entry, _, _, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!((entry.attr.st_uid, entry.attr.st_gid), (1234, 1235));
+        let st = std::fs::metadata(source.as_path().join("squashed")).unwrap();
+        assert_eq!((st.uid(), st.gid()), (1234, 1235));
+
+        // readdirplus reports the owners in guest-side ids too.
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, libc::O_RDONLY as u32).unwrap();
+        let mut owners = Vec::new();
+        fs.readdirplus(&ctx, ROOT_ID, handle.unwrap(), 4096, 0, &mut |d, e| {
+            owners.push((d.name.to_vec(), e.attr.st_uid, e.attr.st_gid));
+            Ok(1)
+        })
+        .unwrap();
+        owners.sort_unstable();
+        assert_eq!(
+            owners,
+            vec![
+                (b"mapped".to_vec(), 1003, 1004),
+                (b"squashed".to_vec(), 1234, 1235),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_id_shifted_create() {
+        let (fs, source) = prepare_fs_id_map(UidGidMap::new(vec![(0, 100000, 65536)]));
+        let ctx = Context {
+            uid: 0,
+            gid: 10,
+            ..Default::default()
+        };
+        let check_owner = |entry: &Entry, name: &str| {
+            assert_eq!(entry.attr.st_uid, 0);
+            assert_eq!(entry.attr.st_gid, 10);
+            let st = std::fs::symlink_metadata(source.as_path().join(name)).unwrap();
+            assert_eq!(st.uid(), 100000);
+            assert_eq!(st.gid(), 100010);
+        };
+
+        let name = CString::new("dir").unwrap();
+        let entry = fs.mkdir(&ctx, ROOT_ID, &name, 0o755, 0).unwrap();
+        check_owner(&entry, "dir");
+
+        let name = CString::new("file").unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, _, _, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        check_owner(&entry, "file");
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        check_owner(&entry, "file");
+
+        let name = CString::new("fifo").unwrap();
+        let entry = fs
+            .mknod(&ctx, ROOT_ID, &name, libc::S_IFIFO | 0o644, 0, 0)
+            .unwrap();
+        check_owner(&entry, "fifo");
+
+        let name = CString::new("symlink").unwrap();
+        let target = CString::new("file").unwrap();
+        let entry = fs.symlink(&ctx, &target, ROOT_ID, &name).unwrap();
+        check_owner(&entry, "symlink");
+
+        // Files of the host outside the range belong to the overflow id.
+        std::fs::write(source.as_path().join("hostfile"), b"").unwrap();
+        let name = CString::new("hostfile").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(entry.attr.st_uid, OVERFLOW_ID);
+        assert_eq!(entry.attr.st_gid, OVERFLOW_ID);
+
+        // chown() passes host ids to fchownat().
+        let (mut attr, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        attr.st_uid = 42;
+        attr.st_gid = 43;
+        let (attr, _) = fs
+            .setattr(
+                &ctx,
+                entry.inode,
+                attr,
+                None,
+                SetattrValid::UID | SetattrValid::GID,
+            )
+            .unwrap();
+        assert_eq!(attr.st_uid, 42);
+        assert_eq!(attr.st_gid, 43);
+        let st = std::fs::metadata(source.as_path().join("hostfile")).unwrap();
+        assert_eq!(st.uid(), 100042);
+        assert_eq!(st.gid(), 100043);
+    }
+
+    #[test]
+    fn test_syncfs() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        let (entry, _) = create_file_with_sugid(&ctx, &fs);
+
+        fs.syncfs(&ctx, 0).unwrap();
+        fs.syncfs(&ctx, ROOT_ID).unwrap();
+        fs.syncfs(&ctx, entry.inode).unwrap();
+        let err = fs.syncfs(&ctx, entry.inode + 100).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn test_xattr_permissions() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let rules = ":bad:all:trusted.:trusted.: :prefix:all:security.:user.virtiofs.: \
+                     :bad:server::security.: :bad:client:user.virtiofs.:: :ok:all:::";
+        let fs = prepare_fs_in(&source, |cfg| {
+            cfg.xattr = true;
+            cfg.xattr_permissions = Some(XattrMap::try_from(rules).unwrap());
+        });
+        fs.init(FsOptions::all()).unwrap();
+        let ctx = prepare_context();
+        let (entry, _) = create_file_with_sugid(&ctx, &fs);
+        let inode = entry.inode;
+
+        let path = CString::new(source.as_path().join("testfile").to_str().unwrap()).unwrap();
+        let host_setxattr = |name: &str| {
+            let name = CString::new(name).unwrap();
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res =
+                unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"h".as_ptr() as _, 1, 0) };
+            assert_eq!(res, 0, "{}", io::Error::last_os_error());
+        };
+        let host_getxattr = |name: &str| {
+            let name = CString::new(name).unwrap();
+            // Safe because this doesn't modify any memory and we check the return value.
+            unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) }
+        };
+        host_setxattr("trusted.secret");
+        host_setxattr("user.plain");
+
+        // security. names from the client are stored under user.virtiofs. on the host.
+        let name = CString::new("security.test").unwrap();
+        fs.setxattr(&ctx, inode, &name, b"v", 0, 0).unwrap();
+        assert_eq!(host_getxattr("user.virtiofs.security.test"), 1);
+        match fs.getxattr(&ctx, inode, &name, 64).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"v"),
+            _ => panic!("unexpected getxattr reply"),
+        }
+
+        // Denied names are hidden from the list, and the size reflects the filtered list.
+        let expected = b"security.test\0user.plain\0".len() as u32;
+        match fs.listxattr(&ctx, inode, 0).unwrap() {
+            ListxattrReply::Count(c) => assert_eq!(c, expected),
+            _ => panic!("unexpected listxattr reply"),
+        }
+        match fs.listxattr(&ctx, inode, 4096).unwrap() {
+            ListxattrReply::Names(names) => {
+                let mut names: Vec<&[u8]> =
+                    names.split(|b| *b == 0).filter(|n| !n.is_empty()).collect();
+                names.sort_unstable();
+                assert_eq!(names, vec![&b"security.test"[..], &b"user.plain"[..]]);
+            }
+            _ => panic!("unexpected listxattr reply"),
+        }
+        let err = fs.listxattr(&ctx, inode, expected - 1).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ERANGE));
+
+        for name in ["trusted.secret", "user.virtiofs.security.test"] {
+            let name = CString::new(name).unwrap();
+            let err = fs.getxattr(&ctx, inode, &name, 64).err().unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+            let err = fs.setxattr(&ctx, inode, &name, b"v", 0, 0).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+            let err = fs.removexattr(&ctx, inode, &name).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        }
+
+        fs.removexattr(&ctx, inode, &name).unwrap();
+        assert!(host_getxattr("user.virtiofs.security.test") < 0);
+        assert_eq!(host_getxattr("trusted.secret"), 1);
+    }
+
+    // Encode a POSIX ACL xattr of `ACL_USER_OBJ`, `ACL_GROUP_OBJ` and `ACL_OTHER` entries.
+    fn posix_acl_xattr(user: u16, group: u16, other: u16) -> Vec<u8> {
+        let mut buf = 2u32.to_le_bytes().to_vec();
+        for (tag, perm) in [(0x01u16, user), (0x04, group), (0x20, other)] {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&perm.to_le_bytes());
+            buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        }
+        buf
+    }
+
+    fn prepare_fs_posix_acl() -> (PassthroughFs, TempDir) {
+        let (fs, source) = prepare_fs_with(|cfg| {
+            cfg.xattr = true;
+            cfg.posix_acl = true;
+        });
+        let opts = fs.init(FsOptions::all()).unwrap();
+        assert!(opts.contains(FsOptions::POSIX_ACL | FsOptions::DONT_MASK));
+
+        (fs, source)
+    }
+
+    #[test]
+    fn test_posix_acl_create() {
+        let (fs, source) = prepare_fs_posix_acl();
+        let ctx = prepare_context();
+
+        let dir = source.as_path().join("acl");
+        std::fs::create_dir(&dir).unwrap();
+        let acl = posix_acl_xattr(7, 7, 0);
+        let path = CString::new(dir.to_str().unwrap()).unwrap();
+        let name = CString::new("system.posix_acl_default").unwrap();
+        let res = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                acl.as_ptr() as *const libc::c_void,
+                acl.len(),
+                0,
+            )
+        };
+        assert_eq!(res, 0, "{}", io::Error::last_os_error());
+
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o666,
+            umask: 0o077,
+            fuse_flags: 0,
+        };
+        // The umask is ignored for a parent directory with a default ACL, the group permissions
+        // come from the ACL instead.
+        let dir_entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("acl").unwrap())
+            .unwrap();
+        let fname = CString::new("file").unwrap();
+        let (entry, _, _, _) = fs.create(&ctx, dir_entry.inode, &fname, args).unwrap();
+        assert_eq!(entry.attr.st_mode & 0o777, 0o660);
+        let meta = std::fs::metadata(dir.join("file")).unwrap();
+        assert_eq!(meta.mode() & 0o777, 0o660);
+
+        let entry = fs
+            .mkdir(
+                &ctx,
+                dir_entry.inode,
+                &CString::new("subdir").unwrap(),
+                0o777,
+                0o077,
+            )
+            .unwrap();
+        assert_eq!(entry.attr.st_mode & 0o777, 0o770);
+
+        // The umask still applies without a default ACL.
+        let (entry, _, _, _) = fs.create(&ctx, ROOT_ID, &fname, args).unwrap();
+        assert_eq!(entry.attr.st_mode & 0o777, 0o600);
+
+        // ACL xattrs go through the xattr path.
+        match fs.getxattr(&ctx, dir_entry.inode, &name, 1024).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, acl),
+            GetxattrReply::Count(_) => panic!("unexpected count reply"),
+        }
+    }
+
+    #[test]
+    fn test_posix_acl_kill_sgid() {
+        let (fs, source) = prepare_fs_posix_acl();
+        let ctx = prepare_context();
+
+        let (entry, _) = create_file_with_sugid(&ctx, &fs);
+        let path = source.as_path().join("testfile");
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o2770)).unwrap();
+
+        let name = CString::new("system.posix_acl_access").unwrap();
+        let acl = posix_acl_xattr(7, 5, 0);
+        fs.setxattr(&ctx, entry.inode, &name, &acl, 0, 0).unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o2750);
+
+        fs.setxattr(&ctx, entry.inode, &name, &acl, 0, SETXATTR_ACL_KILL_SGID)
+            .unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o750);
+    }
+
+    #[test]
+    fn test_perfile_dax_xattr() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for (name, value) in [("dax", Some("1")), ("nodax", Some("0")), ("plain", None)] {
+            let path = source.as_path().join(name);
+            std::fs::write(&path, b"data").unwrap();
+            if let Some(value) = value {
+                let path = CString::new(path.to_str().unwrap()).unwrap();
+                let res = unsafe {
+                    libc::setxattr(
+                        path.as_ptr(),
+                        DAX_XATTR.as_ptr() as *const libc::c_char,
+                        value.as_ptr() as *const libc::c_void,
+                        value.len(),
+                        0,
+                    )
+                };
+                assert_eq!(res, 0, "{}", io::Error::last_os_error());
+            }
+        }
+
+        let open = |xattr: bool, cache_policy: CachePolicy, name: &str| {
+            let fs = prepare_fs_in(&source, |cfg| {
+                cfg.xattr = xattr;
+                cfg.perfile_dax_xattr = true;
+                cfg.cache_policy = cache_policy;
+            });
+            fs.init(FsOptions::all()).unwrap();
+            let ctx = prepare_context();
+
+            let entry = fs
+                .lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+                .unwrap();
+            let (_, opts, _) = fs
+                .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+                .unwrap();
+            opts.contains(OpenOptions::DIRECT_IO)
+        };
+
+        // DAX files skip direct I/O even if the cache policy asks for it, and the other way round.
+        assert!(!open(true, CachePolicy::Never, "dax"));
+        assert!(open(true, CachePolicy::Always, "nodax"));
+        assert!(open(true, CachePolicy::Never, "plain"));
+        assert!(!open(true, CachePolicy::Always, "plain"));
+        // Fall back to the cache policy without xattr support.
+        assert!(open(false, CachePolicy::Never, "dax"));
+    }
+
+    #[test]
+    fn test_max_inodes() {
+        let (fs, _source) = prepare_fs_with(|cfg| cfg.max_inodes = Some(10));
+        fs.init(FsOptions::all()).unwrap();
+        let ctx = prepare_context();
+
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let mut open = Vec::new();
+        let mut forgotten = Vec::new();
+        for i in 0..20 {
+            let name = CString::new(format!("file{}", i)).unwrap();
+            let (entry, handle, _, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+            // Keep the first few files open, let the kernel forget the others.
+            if i < 5 {
+                open.push((entry.inode, handle.unwrap()));
+            } else {
+                fs.release(&ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+                    .unwrap();
+                fs.forget(&ctx, entry.inode, 1);
+                forgotten.push((name, entry.inode));
+            }
+            assert!(fs.inode_map.len() <= 10);
+        }
+
+        for (inode, handle) in open {
+            fs.getattr(&ctx, inode, Some(handle)).unwrap();
+            fs.release(&ctx, inode, 0, handle, false, false, None)
+                .unwrap();
+        }
+
+        // Recently forgotten files get their inode numbers back, the oldest ones new numbers.
+        let (name, inode) = forgotten.pop().unwrap();
+        assert_eq!(fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode, inode);
+        let (name, inode) = forgotten.remove(0);
+        assert_ne!(fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode, inode);
+    }
+
+    #[test]
+    fn test_inode_alloc_policy() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for name in ["a", "b", "c"] {
+            std::fs::write(source.as_path().join(name), b"data").unwrap();
+        }
+        let ctx = prepare_context();
+        let name = |n: &str| CString::new(n).unwrap();
+
+        for policy in [InodeAllocPolicy::Monotonic, InodeAllocPolicy::FreeList] {
+            let fs = prepare_fs_in(&source, |cfg| cfg.inode_alloc_policy = policy);
+            let lookup = |n: &str| fs.lookup(&ctx, ROOT_ID, &name(n)).unwrap().inode;
+
+            // Inode numbers still referenced by the kernel are never reused.
+            let a = lookup("a");
+            assert_eq!(lookup("a"), a);
+            fs.forget(&ctx, a, 1);
+            let b = lookup("b");
+            assert_ne!(b, a);
+            assert_eq!(fs.inode_map.allocator.next(), b + 1);
+
+            // Once forgotten, the inode number of a file is kept for it, or recycled.
+            fs.forget(&ctx, a, 1);
+            assert!(fs.inode_map.get(a).is_err());
+            let c = lookup("c");
+            match policy {
+                InodeAllocPolicy::Monotonic => {
+                    assert_eq!(c, b + 1);
+                    let entry = fs.lookup(&ctx, ROOT_ID, &name("c")).unwrap();
+                    assert_eq!(entry.generation, 0);
+                    assert_eq!(lookup("a"), a);
+                }
+                InodeAllocPolicy::FreeList => {
+                    assert_eq!(c, a);
+                    // The kernel may not mistake the new file for the old one.
+                    let entry = fs.lookup(&ctx, ROOT_ID, &name("c")).unwrap();
+                    assert_eq!((entry.inode, entry.generation), (a, 1));
+                    assert_eq!(lookup("a"), b + 1);
+                    // The recycled number refers to the new file.
+                    let st = std::fs::metadata(source.as_path().join("c")).unwrap();
+                    assert_eq!(fs.inode_map.get(c).unwrap().id.ino, st.ino());
+                }
+            }
+        }
+
+        assert_eq!("free_list".parse(), Ok(InodeAllocPolicy::FreeList));
+        assert!("random".parse::<InodeAllocPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_case_insensitive_lookup() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file.txt"), b"data").unwrap();
+        let ctx = prepare_context();
+        let upper = CString::new("FILE.TXT").unwrap();
+
+        for case_insensitive in [false, true] {
+            let fs = prepare_fs_in(&source, |cfg| cfg.case_insensitive = case_insensitive);
+
+            if !case_insensitive {
+                let err = fs.lookup(&ctx, ROOT_ID, &upper).unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+                continue;
+            }
+            let entry = fs.lookup(&ctx, ROOT_ID, &upper).unwrap();
+            let exact = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("file.txt").unwrap())
+                .unwrap();
+            assert_eq!(entry.inode, exact.inode);
+            assert_eq!(entry.attr.st_size, 4);
+
+            let err = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("FILE.DAT").unwrap())
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        }
+    }
+
+    fn prepare_fs_max_handles(policy: HandleLimitPolicy) -> (PassthroughFs, TempDir, Inode) {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        let fs = prepare_fs_in(&source, |cfg| {
+            cfg.max_handles = Some(8);
+            cfg.handle_limit_policy = policy;
+        });
+        fs.init(FsOptions::all()).unwrap();
+        let ctx = prepare_context();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+
+        (fs, source, entry.inode)
+    }
+
+    #[test]
+    fn test_max_handles_reject() {
+        let (fs, _source, inode) = prepare_fs_max_handles(HandleLimitPolicy::RejectNew);
+        let ctx = prepare_context();
+        let flags = libc::O_RDONLY as u32;
+
+        let handles: Vec<Handle> = (0..8)
+            .map(|_| fs.open(&ctx, inode, flags, 0).unwrap().0.unwrap())
+            .collect();
+        let err = fs.open(&ctx, inode, flags, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+
+        // Creating a file fails the same way, without leaking a reference to its inode.
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let name = CString::new("new").unwrap();
+        let err = fs.create(&ctx, ROOT_ID, &name, args).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+        let new = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let data = fs.inode_map.get(new.inode).unwrap();
+        assert_eq!(data.refcount.load(Ordering::Relaxed), 1);
+
+        fs.release(&ctx, inode, 0, handles[3], false, false, None)
+            .unwrap();
+        let (handle, _, _) = fs.open(&ctx, inode, flags, 0).unwrap();
+        fs.getattr(&ctx, inode, handle).unwrap();
+        for h in handles.iter().filter(|h| **h != handles[3]) {
+            fs.getattr(&ctx, inode, Some(*h)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_max_handles_concurrent() {
+        let (fs, _source, inode) = prepare_fs_max_handles(HandleLimitPolicy::RejectNew);
+        let ctx = prepare_context();
+        let flags = libc::O_RDONLY as u32;
+
+        let opened: usize = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        (0..4)
+                            .filter(|_| match fs.open(&ctx, inode, flags, 0) {
+                                Ok(_) => true,
+                                Err(e) => {
+                                    assert_eq!(e.raw_os_error(), Some(libc::EMFILE));
+                                    false
+                                }
+                            })
+                            .count()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+        assert_eq!(opened, 8);
+    }
+
+    #[test]
+    fn test_max_handles_evict() {
+        let (fs, _source, inode) = prepare_fs_max_handles(HandleLimitPolicy::EvictOldest);
+        let ctx = prepare_context();
+        let flags = libc::O_RDONLY as u32;
+
+        let handles: Vec<Handle> = (0..8)
+            .map(|_| fs.open(&ctx, inode, flags, 0).unwrap().0.unwrap())
+            .collect();
+        // The first handle is used again, and the second one is held by an ongoing request.
+        fs.getattr(&ctx, inode, Some(handles[0])).unwrap();
+        let busy = fs.handle_map.get(handles[1], inode).unwrap();
+
+        let (new, _, _) = fs.open(&ctx, inode, flags, 0).unwrap();
+        fs.getattr(&ctx, inode, new).unwrap();
+        assert!(fs.handle_map.get(handles[0], inode).is_ok());
+        assert!(fs.handle_map.get(handles[1], inode).is_ok());
+        let err = fs.handle_map.get(handles[2], inode).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        drop(busy);
+    }
+
+    #[test]
+    fn test_posix_lock_owners() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        let (entry, handle) = create_file_with_sugid(&ctx, &fs);
+        let inode = entry.inode;
+
+        let lock = FileLock {
+            start: 0,
+            end: 99,
+            lock_type: libc::F_WRLCK as u32,
+            pid: 1,
+        };
+        fs.setlk(&ctx, inode, handle, 1, lock, 0).unwrap();
+        // The owner of a lock may change it.
+        fs.setlk(&ctx, inode, handle, 1, lock, 0).unwrap();
+
+        // Owners sharing the handle conflict with each other.
+        let err = fs.setlk(&ctx, inode, handle, 2, lock, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+        let conflict = fs.getlk(&ctx, inode, handle, 2, lock, 0).unwrap();
+        assert_eq!(conflict.lock_type, libc::F_WRLCK as u32);
+
+        // Unlocking the whole file drops all locks of the owner.
+        let unlock = FileLock {
+            start: 0,
+            end: i64::MAX as u64,
+            lock_type: libc::F_UNLCK as u32,
+            pid: 1,
+        };
+        fs.setlk(&ctx, inode, handle, 1, unlock, 0).unwrap();
+        fs.setlk(&ctx, inode, handle, 2, lock, 0).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_restricted() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::os::unix::fs::symlink("/etc/passwd", source.as_path().join("passwd")).unwrap();
+        let mnt = source.as_path().join("mnt");
+        std::fs::create_dir(&mnt).unwrap();
+        let fs = prepare_fs_in(&source, |cfg| cfg.no_xdev = true);
+        fs.init(FsOptions::all()).unwrap();
+        let ctx = prepare_context();
+
+        // Lookup gets the symlink itself, not the file it points to outside of the root.
+        let name = CString::new("passwd").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFLNK);
+        let name = CString::new("..").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(entry.inode, ROOT_ID);
+
+        if nix::mount::mount(
+            Some("none"),
+            &mnt,
+            Some("tmpfs"),
+            nix::mount::MsFlags::empty(),
+            None::<&str>,
+        )
+        .is_err()
+        {
+            // Not privileged enough to mount tmpfs, nothing more to test.
+            return;
+        }
+        let name = CString::new("mnt").unwrap();
+        let res = fs.lookup(&ctx, ROOT_ID, &name);
+        nix::mount::umount2(&mnt, nix::mount::MntFlags::MNT_DETACH).unwrap();
+        if fs.has_openat2.load(Ordering::Relaxed) {
+            assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EXDEV));
+        }
+    }
+
+    #[test]
+    fn test_resolve_beneath() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::os::unix::fs::symlink("/etc", source.as_path().join("abs")).unwrap();
+        std::os::unix::fs::symlink("../..", source.as_path().join("rel")).unwrap();
+        let new_fs =
+            |resolve_beneath| prepare_fs_in(&source, |cfg| cfg.resolve_beneath = resolve_beneath);
+        let open = |fs: &PassthroughFs, path: &str| {
+            let root = fs.inode_map.get(ROOT_ID).unwrap();
+            let dir = root.get_file().unwrap();
+            let path = CString::new(path).unwrap();
+            fs.open_file_restricted(&dir, &path, libc::O_PATH)
+        };
+
+        // Symlinks leading out of the shared directory fail to resolve.
+        let fs = new_fs(true);
+        let res = open(&fs, "abs/passwd");
+        if fs.has_openat2.load(Ordering::Relaxed) {
+            assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EXDEV));
+            let err = open(&fs, "rel/etc").unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+        }
+        // The symlink itself can still be looked up.
+        let ctx = prepare_context();
+        let name = CString::new("abs").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFLNK);
+
+        // Without the restriction, only the last component isn't followed.
+        let fs = new_fs(false);
+        open(&fs, "abs/passwd").unwrap();
+    }
+
+    #[test]
+    fn test_supp_groups() {
+        use std::os::unix::process::CommandExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let shared = source.as_path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::os::unix::fs::chown(&shared, Some(0), Some(4242)).unwrap();
+        std::fs::set_permissions(&shared, PermissionsExt::from_mode(0o770)).unwrap();
+
+        // A caller whose only way into the directory is its supplementary group.
+        let mut cmd = std::process::Command::new("sleep");
+        cmd.arg("10");
+        // Safe because the closure only calls async-signal-safe functions.
+        unsafe {
+            cmd.pre_exec(|| {
+                let groups = [4242 as libc::gid_t];
+                if libc::setgroups(1, groups.as_ptr()) != 0
+                    || libc::setgid(1000) != 0
+                    || libc::setuid(1000) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        };
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            // Not privileged enough to change credentials, nothing to test.
+            Err(_) => return,
+        };
+        let ctx = Context {
+            uid: 1000,
+            gid: 1000,
+            pid: child.id() as libc::pid_t,
+            ..Default::default()
+        };
+
+        let mkdir_shared = |supp_groups| {
+            let fs = prepare_fs_in(&source, |cfg| cfg.supp_groups = supp_groups);
+            fs.init(FsOptions::all()).unwrap();
+            let name = CString::new("shared").unwrap();
+            let parent = fs.lookup(&Context::default(), ROOT_ID, &name).unwrap();
+            let name = CString::new(format!("dir{}", supp_groups)).unwrap();
+            fs.mkdir(&ctx, parent.inode, &name, 0o755, 0)
+        };
+
+        let err = mkdir_shared(false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        let groups_before = nix::unistd::getgroups().unwrap();
+        let entry = mkdir_shared(true).unwrap();
+        assert_eq!(entry.attr.st_uid, 1000);
+        assert_eq!(nix::unistd::getgroups().unwrap(), groups_before);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // Without a pid, groups are only those sent by the kernel with FUSE_CREATE_SUPP_GROUP.
+        let fs = prepare_fs_in(&source, |cfg| cfg.supp_groups = true);
+        fs.init(FsOptions::all()).unwrap();
+        let name = CString::new("shared").unwrap();
+        let parent = fs.lookup(&Context::default(), ROOT_ID, &name).unwrap();
+        let mut ctx = Context {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        let name = CString::new("ext").unwrap();
+        let err = fs.mkdir(&ctx, parent.inode, &name, 0o755, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        ctx.supp_groups = vec![4242];
+        let entry = fs.mkdir(&ctx, parent.inode, &name, 0o755, 0).unwrap();
+        assert_eq!(entry.attr.st_uid, 1000);
+        assert_eq!(nix::unistd::getgroups().unwrap(), groups_before);
+    }
+
+    #[test]
+    fn test_read_only() {
+        ioctl_ior_nr!(FS_IOC_GETFLAGS, b'f' as u32, 1, libc::c_long);
+        ioctl_iow_nr!(FS_IOC_SETFLAGS, b'f' as u32, 2, libc::c_long);
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        let fs = prepare_fs_in(&source, |cfg| {
+            cfg.read_only = true;
+            cfg.xattr = true;
+            cfg.ioctl_allowlist = Some(vec![FS_IOC_GETFLAGS() as u32, FS_IOC_SETFLAGS() as u32]);
+        });
+        fs.init(FsOptions::all()).unwrap();
+        let ctx = prepare_context();
+        let assert_erofs = |res: io::Result<()>| {
+            assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EROFS));
+        };
+
+        let name = CString::new("file").unwrap();
+        let dir = CString::new("dir").unwrap();
+        let new_name = CString::new("new").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let dir_entry = fs.lookup(&ctx, ROOT_ID, &dir).unwrap();
+
+        // Files can't be opened for writing, nor truncated.
+        for flags in [libc::O_WRONLY, libc::O_RDWR, libc::O_RDONLY | libc::O_TRUNC] {
+            assert_erofs(fs.open(&ctx, entry.inode, flags as u32, 0).map(|_| ()));
+        }
+        let (handle, _, _) = fs
+            .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let handle = handle.unwrap();
+
+        let mut in_file = TempFile::new().unwrap().into_file();
+        in_file.write_all(b"new").unwrap();
+        assert_erofs(
+            fs.write(
+                &ctx,
+                entry.inode,
+                handle,
+                &mut in_file,
+                3,
+                0,
+                None,
+                false,
+                0,
+                0,
+            )
+            .map(|_| ()),
+        );
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_mode = 0o600;
+        for valid in [
+            SetattrValid::SIZE,
+            SetattrValid::MODE,
+            SetattrValid::UID | SetattrValid::GID,
+            SetattrValid::ATIME | SetattrValid::MTIME,
+        ] {
+            assert_erofs(
+                fs.setattr(&ctx, entry.inode, attr, Some(handle), valid)
+                    .map(|_| ()),
+            );
+        }
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        assert_erofs(fs.create(&ctx, ROOT_ID, &new_name, args).map(|_| ()));
+        assert_erofs(fs.mkdir(&ctx, ROOT_ID, &new_name, 0o755, 0).map(|_| ()));
+        assert_erofs(
+            fs.mknod(&ctx, ROOT_ID, &new_name, libc::S_IFIFO | 0o644, 0, 0)
+                .map(|_| ()),
+        );
+        assert_erofs(fs.unlink(&ctx, ROOT_ID, &name));
+        assert_erofs(fs.rmdir(&ctx, ROOT_ID, &dir));
+        assert_erofs(fs.rename(&ctx, ROOT_ID, &name, dir_entry.inode, &name, 0));
+        assert_erofs(
+            fs.link(&ctx, entry.inode, dir_entry.inode, &name)
+                .map(|_| ()),
+        );
+        assert_erofs(fs.symlink(&ctx, &name, ROOT_ID, &new_name).map(|_| ()));
+        let xattr = CString::new("user.test").unwrap();
+        assert_erofs(fs.setxattr(&ctx, entry.inode, &xattr, b"value", 0, 0));
+        assert_erofs(fs.removexattr(&ctx, entry.inode, &xattr));
+        assert_erofs(fs.fallocate(&ctx, entry.inode, handle, 0, 0, 4096));
+        assert_erofs(
+            fs.copy_file_range(
+                &ctx,
+                entry.inode,
+                handle,
+                0,
+                entry.inode,
+                handle,
+                4096,
+                4,
+                0,
+            )
+            .map(|_| ()),
+        );
+        // So do ioctls passing data to the host, while the others are still allowed.
+        let flags = [0u8; 8];
+        let data = IoctlData {
+            result: 0,
+            data: Some(Cow::Borrowed(&flags[..])),
+        };
+        assert_erofs(
+            fs.ioctl(
+                &ctx,
+                entry.inode,
+                handle,
+                0,
+                FS_IOC_SETFLAGS() as u32,
+                data,
+                0,
+            )
+            .map(|_| ()),
+        );
+        let res = fs.ioctl(
+            &ctx,
+            entry.inode,
+            handle,
+            0,
+            FS_IOC_GETFLAGS() as u32,
+            IoctlData::default(),
+            8,
+        );
+        // The backing filesystem may not support file flags.
+        if let Err(e) = res {
+            assert_ne!(e.raw_os_error(), Some(libc::EROFS));
+        }
+
+        // Reads still work, and nothing has changed on the host.
+        let mut out = TempFile::new().unwrap().into_file();
+        let n = fs
+            .read(&ctx, entry.inode, handle, &mut out, 4, 0, None, 0)
+            .unwrap();
+        assert_eq!(n, 4);
+        out.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+        out.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"data");
+        assert_eq!(
+            std::fs::read(source.as_path().join("file")).unwrap(),
+            b"data"
+        );
+        let mut names: Vec<_> = std::fs::read_dir(source.as_path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["dir", "file"]);
+
+        let statfs = fs.statfs(&ctx, ROOT_ID).unwrap();
+        assert_ne!(statfs.f_flag & libc::ST_RDONLY, 0);
+    }
+
+    #[test]
+    fn test_secctx() {
+        let (fs, source) = prepare_fs_with(|cfg| cfg.xattr = true);
+        let opts = fs.init(FsOptions::all()).unwrap();
+        assert!(opts.contains(FsOptions::SECURITY_CTX));
+        let mut ctx = prepare_context();
+        ctx.secctx = vec![SecContext {
+            name: CString::new("user.label").unwrap(),
+            value: b"label".to_vec(),
+        }];
+        let get_xattr = |name: &str, xattr: &str| {
+            let mut buf = [0u8; 16];
+            let path = CString::new(source.as_path().join(name).to_str().unwrap()).unwrap();
+            let label = CString::new(xattr).unwrap();
+            // Safe because this only writes into buf and we check the return value.
+            let res = unsafe {
+                libc::lgetxattr(
+                    path.as_ptr(),
+                    label.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            assert!(res >= 0, "{}", io::Error::last_os_error());
+            buf[..res as usize].to_vec()
+        };
+        let get_label = |name: &str| get_xattr(name, "user.label");
+
+        let dir = CString::new("dir").unwrap();
+        fs.mkdir(&ctx, ROOT_ID, &dir, 0o755, 0).unwrap();
+        assert_eq!(get_label("dir"), b"label");
+        let file = CString::new("file").unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            ..Default::default()
+        };
+        fs.create(&ctx, ROOT_ID, &file, args).unwrap();
+        assert_eq!(get_label("file"), b"label");
+
+        // Only regular files and directories can have user xattrs, use trusted ones for others.
+        ctx.secctx[0].name = CString::new("trusted.label").unwrap();
+        let fifo = CString::new("fifo").unwrap();
+        fs.mknod(&ctx, ROOT_ID, &fifo, libc::S_IFIFO | 0o644, 0, 0)
+            .unwrap();
+        assert_eq!(get_xattr("fifo", "trusted.label"), b"label");
+        let link = CString::new("link").unwrap();
+        fs.symlink(&ctx, &file, ROOT_ID, &link).unwrap();
+        assert_eq!(get_xattr("link", "trusted.label"), b"label");
+
+        // Files which can't be labeled are removed.
+        ctx.secctx[0].name = CString::new("invalid.label").unwrap();
+        let file = CString::new("unlabeled").unwrap();
+        fs.create(&ctx, ROOT_ID, &file, args).unwrap_err();
+        assert!(!source.as_path().join("unlabeled").exists());
+        fs.mkdir(&ctx, ROOT_ID, &file, 0o755, 0).unwrap_err();
+        assert!(!source.as_path().join("unlabeled").exists());
+        fs.mknod(&ctx, ROOT_ID, &file, libc::S_IFIFO | 0o644, 0, 0)
+            .unwrap_err();
+        assert!(!source.as_path().join("unlabeled").exists());
+        fs.symlink(&ctx, &dir, ROOT_ID, &file).unwrap_err();
+        assert!(std::fs::symlink_metadata(source.as_path().join("unlabeled")).is_err());
+
+        // Without xattr, the kernel doesn't send security contexts.
+        let fs = prepare_fs_in(&source, |_| {});
+        let opts = fs.init(FsOptions::all()).unwrap();
+        assert!(!opts.contains(FsOptions::SECURITY_CTX));
+    }
+
+    #[test]
+    fn test_hidden_xattr_prefixes() {
+        let (fs, source) = prepare_fs_with(|cfg| {
+            cfg.xattr = true;
+            cfg.hidden_xattr_prefixes = vec!["trusted.".to_string(), "user.hidden.".to_string()];
+        });
+        fs.init(FsOptions::all()).unwrap();
+        let ctx = prepare_context();
+        let (entry, _) = create_file_with_sugid(&ctx, &fs);
+        let inode = entry.inode;
+
+        let path = CString::new(source.as_path().join("testfile").to_str().unwrap()).unwrap();
+        for name in ["trusted.secret", "user.hidden.a", "user.plain"] {
+            let name = CString::new(name).unwrap();
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res =
+                unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"h".as_ptr() as _, 1, 0) };
+            assert_eq!(res, 0, "{}", io::Error::last_os_error());
+        }
+
+        // The size probe accounts for the filtered list, so a buffer of that size fits the names.
+        let size = match fs.listxattr(&ctx, inode, 0).unwrap() {
+            ListxattrReply::Count(c) => c,
+            _ => panic!("unexpected listxattr reply"),
+        };
+        assert_eq!(size, b"user.plain\0".len() as u32);
+        match fs.listxattr(&ctx, inode, size).unwrap() {
+            ListxattrReply::Names(names) => assert_eq!(names, b"user.plain\0"),
+            _ => panic!("unexpected listxattr reply"),
+        }
+        let err = fs.listxattr(&ctx, inode, size - 1).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ERANGE));
+
+        // Hidden names look like missing attributes.
+        let name = CString::new("trusted.secret").unwrap();
+        let err = fs.getxattr(&ctx, inode, &name, 64).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+        let err = fs.removexattr(&ctx, inode, &name).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+        let err = fs.setxattr(&ctx, inode, &name, b"v", 0, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        let name = CString::new("user.plain").unwrap();
+        match fs.getxattr(&ctx, inode, &name, 64).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"h"),
+            _ => panic!("unexpected getxattr reply"),
+        }
+    }
+
+    #[test]
+    fn test_setxattr_creds() {
+        // Checking the credentials of the caller needs to switch to them.
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+
+        let (fs, source) = prepare_fs_tmpdir();
+        let file = source.as_path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        std::os::unix::fs::chown(&file, Some(3000), Some(3000)).unwrap();
+        std::fs::set_permissions(&file, PermissionsExt::from_mode(0o644)).unwrap();
+        let dir = source.as_path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        std::os::unix::fs::chown(&dir, Some(3000), Some(3000)).unwrap();
+        std::fs::set_permissions(&dir, PermissionsExt::from_mode(0o1777)).unwrap();
+
+        let lookup = |name: &str| {
+            let name = CString::new(name).unwrap();
+            fs.lookup(&Context::default(), ROOT_ID, &name)
+                .unwrap()
+                .inode
+        };
+        let other = Context {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        let owner = Context {
+            uid: 3000,
+            gid: 3000,
+            ..Default::default()
+        };
+        let name = CString::new("user.test").unwrap();
+
+        // Only the owner may set user xattrs on a sticky directory.
+        let inode = lookup("dir");
+        let err = fs.setxattr(&other, inode, &name, b"v", 0, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        fs.setxattr(&owner, inode, &name, b"v", 0, 0).unwrap();
+        let err = fs.removexattr(&other, inode, &name).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        fs.removexattr(&owner, inode, &name).unwrap();
+
+        // Setting user xattrs on a file needs write permission.
+        let inode = lookup("file");
+        let err = fs.setxattr(&other, inode, &name, b"v", 0, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        fs.setxattr(&owner, inode, &name, b"v", 0, 0).unwrap();
+    }
+
+    #[test]
+    fn test_inode_file_handles_cross_device() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let mut mounts = Vec::new();
+        for name in ["a", "b"] {
+            let mnt = source.as_path().join(name);
+            std::fs::create_dir(&mnt).unwrap();
+            if nix::mount::mount(
+                Some("none"),
+                &mnt,
+                Some("tmpfs"),
+                nix::mount::MsFlags::empty(),
+                None::<&str>,
+            )
+            .is_err()
+            {
+                // Not privileged enough to mount tmpfs, nothing to test.
+                return;
+            }
+            std::fs::write(mnt.join("file"), name).unwrap();
+            mounts.push(mnt);
+        }
+
+        let fs = prepare_fs_in(&source, |cfg| cfg.inode_file_handles = true);
+        fs.init(FsOptions::all()).unwrap();
+        let ctx = prepare_context();
+        let file = CString::new("file").unwrap();
+        let lookup = |dir: &str| {
+            let dir = CString::new(dir).unwrap();
+            let parent = fs.lookup(&ctx, ROOT_ID, &dir).unwrap();
+            fs.lookup(&ctx, parent.inode, &file).unwrap()
+        };
+
+        // Fresh tmpfs instances number their inodes alike, only the device tells them apart.
+        let a = lookup("a");
+        let b = lookup("b");
+        assert_eq!(a.attr.st_ino, b.attr.st_ino);
+        assert_ne!(a.attr.st_dev, b.attr.st_dev);
+        assert_ne!(a.inode, b.inode);
+        let handle = |inode| {
+            fs.inode_map
+                .get(inode)
+                .unwrap()
+                .handle
+                .file_handle()
+                .cloned()
+        };
+        let (ha, hb) = (handle(a.inode).unwrap(), handle(b.inode).unwrap());
+        assert_ne!(ha.mnt_id, hb.mnt_id);
+        assert_eq!(lookup("a").inode, a.inode);
+        assert_eq!(lookup("b").inode, b.inode);
+
+        for mnt in mounts {
+            nix::mount::umount2(&mnt, nix::mount::MntFlags::MNT_DETACH).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_access_posix_acl() {
+        // Checking the credentials of the caller needs to switch to them.
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+
+        let (fs, source) = prepare_fs_tmpdir();
+        std::fs::set_permissions(source.as_path(), PermissionsExt::from_mode(0o755)).unwrap();
+        let file = source.as_path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        std::os::unix::fs::chown(&file, Some(3000), Some(3000)).unwrap();
+        // Only a named user entry grants read access to uid 1000.
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, 1000),
+            (0x04, 0, u32::MAX),
+            (0x10, 4, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&perm.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+        let name = CString::new("system.posix_acl_access").unwrap();
+        let res = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                acl.as_ptr() as *const libc::c_void,
+                acl.len(),
+                0,
+            )
+        };
+        if res < 0 {
+            // POSIX ACLs aren't supported by the file system of the temporary directory.
+            return;
+        }
+        assert_eq!(
+            std::fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+
+        // Check the access on the host, as uid 1000.
+        let host_access = |mode: libc::c_int| {
+            let path = path.clone();
+            std::thread::spawn(move || unsafe {
+                assert_eq!(libc::syscall(libc::SYS_setresgid, -1, 1000, -1), 0);
+                assert_eq!(libc::syscall(libc::SYS_setresuid, -1, 1000, -1), 0);
+                libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode, libc::AT_EACCESS) == 0
+            })
+            .join()
+            .unwrap()
+        };
+        let inode = fs
+            .lookup(&Context::default(), ROOT_ID, &CString::new("file").unwrap())
+            .unwrap()
+            .inode;
+        let ctx = Context {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+
+        for faccessat2 in [true, false] {
+            fs.has_faccessat2.store(faccessat2, Ordering::Relaxed);
+            for mode in [libc::R_OK, libc::W_OK, libc::X_OK, libc::R_OK | libc::W_OK] {
+                let res = fs.access(&ctx, inode, mode as u32);
+                assert_eq!(res.is_ok(), host_access(mode));
+                assert_eq!(res.is_ok(), mode == libc::R_OK);
+                if let Err(e) = res {
+                    assert_eq!(e.raw_os_error(), Some(libc::EACCES));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_squash() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::set_permissions(source.as_path(), PermissionsExt::from_mode(0o777)).unwrap();
+        let new_fs = |squash| {
+            let fs = prepare_fs_in(&source, |cfg| {
+                cfg.squash = squash;
+                cfg.anon_uid = 4000;
+                cfg.anon_gid = 4001;
+            });
+            fs.init(FsOptions::all()).unwrap();
+            fs
+        };
+        let owner = |name: &str| {
+            let st = std::fs::metadata(source.as_path().join(name)).unwrap();
+            (st.uid(), st.gid())
+        };
+        let root_ctx = Context::default();
+        let user_ctx = Context {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        let args = CreateIn {
+            flags: libc::O_WRONLY as u32,
+            mode: 0o600,
+            umask: 0,
+            fuse_flags: 0,
+        };
+
+        // Root creates files as the anonymous user, other callers are left alone.
+        let fs = new_fs(SquashPolicy::RootSquash);
+        let name = CString::new("root").unwrap();
+        let (entry, _, _, _) = fs.create(&root_ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!((entry.attr.st_uid, entry.attr.st_gid), (4000, 4001));
+        assert_eq!(owner("root"), (4000, 4001));
+        let name = CString::new("user").unwrap();
+        let (user, _, _, _) = fs.create(&user_ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!(owner("user"), (1000, 1000));
+
+        // Squashed root can't bypass permissions.
+        let err = fs
+            .access(&root_ctx, user.inode, libc::R_OK as u32)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        fs.access(&user_ctx, user.inode, libc::R_OK as u32).unwrap();
+
+        // Files can't be given to root.
+        let (mut attr, _) = fs.getattr(&root_ctx, user.inode, None).unwrap();
+        attr.st_uid = 0;
+        attr.st_gid = 0;
+        let valid = SetattrValid::UID | SetattrValid::GID;
+        fs.setattr(&root_ctx, user.inode, attr, None, valid)
+            .unwrap();
+        assert_eq!(owner("user"), (4000, 4001));
+        attr.st_uid = 1000;
+        attr.st_gid = 1000;
+        fs.setattr(&root_ctx, user.inode, attr, None, valid)
+            .unwrap();
+        assert_eq!(owner("user"), (1000, 1000));
+
+        // All callers are squashed.
+        let fs = new_fs(SquashPolicy::AllSquash);
+        let name = CString::new("all").unwrap();
+        fs.create(&user_ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!(owner("all"), (4000, 4001));
+
+        // Without squashing, root creates files as root.
+        let fs = new_fs(SquashPolicy::None);
+        let name = CString::new("none").unwrap();
+        fs.create(&root_ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!(owner("none"), (0, 0));
+    }
+
+    #[test]
+    fn test_batch_forget() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+
+        let mut inodes = Vec::new();
+        for i in 0..1000 {
+            let name = format!("f{}", i);
+            std::fs::write(source.as_path().join(&name), b"").unwrap();
+            let name = CString::new(name).unwrap();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            inodes.push(fs.inode_map.get(entry.inode).unwrap());
+        }
+        assert_eq!(fs.inode_map.len(), 1001);
+
+        // The root inode and unknown inodes are ignored.
+        let mut requests: Vec<_> = inodes.iter().map(|data| (data.inode, 2)).collect();
+        requests.push((ROOT_ID, 1));
+        requests.push((u64::MAX, 1));
+        fs.batch_forget(&ctx, requests);
+
+        for data in inodes.iter() {
+            assert_eq!(data.refcount.load(Ordering::Relaxed), 0);
+            assert!(fs.inode_map.get(data.inode).is_err());
+        }
+        assert_eq!(fs.inode_map.len(), 1);
+        assert!(fs.inode_map.get(ROOT_ID).is_ok());
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let (fs, source) =
+            prepare_fs_with(|cfg| cfg.negative_cache_ttl = Some(Duration::from_millis(200)));
+        let ctx = prepare_context();
+        let missing = CString::new("missing").unwrap();
+
+        let err = fs.lookup(&ctx, ROOT_ID, &missing).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        // Create the file behind the back of the file system, lookups don't go to the host until
+        // the name expires.
+        std::fs::write(source.as_path().join("missing"), b"data").unwrap();
+        for _ in 0..1000 {
+            let err = fs.lookup(&ctx, ROOT_ID, &missing).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        }
+        std::thread::sleep(Duration::from_millis(250));
+        fs.lookup(&ctx, ROOT_ID, &missing).unwrap();
+
+        // Names created through the file system are found right away.
+        let dir = CString::new("dir").unwrap();
+        fs.lookup(&ctx, ROOT_ID, &dir).unwrap_err();
+        fs.mkdir(&ctx, ROOT_ID, &dir, 0o755, 0).unwrap();
+        fs.lookup(&ctx, ROOT_ID, &dir).unwrap();
+
+        let renamed = CString::new("renamed").unwrap();
+        fs.lookup(&ctx, ROOT_ID, &renamed).unwrap_err();
+        fs.rename(&ctx, ROOT_ID, &missing, ROOT_ID, &renamed, 0)
+            .unwrap();
+        fs.lookup(&ctx, ROOT_ID, &renamed).unwrap();
+
+        // Names missing from a directory are remembered as long as the directory is known.
+        let other = CString::new("other").unwrap();
+        std::fs::create_dir(source.as_path().join("other")).unwrap();
+        let inode = fs.lookup(&ctx, ROOT_ID, &other).unwrap().inode;
+        fs.lookup(&ctx, ROOT_ID, &other).unwrap();
+        fs.lookup(&ctx, inode, &missing).unwrap_err();
+        std::fs::write(source.as_path().join("other/missing"), b"data").unwrap();
+        fs.forget(&ctx, inode, 1);
+        fs.lookup(&ctx, inode, &missing).unwrap_err();
+        fs.forget(&ctx, inode, 1);
+        let inode = fs.lookup(&ctx, ROOT_ID, &other).unwrap().inode;
+        fs.lookup(&ctx, inode, &missing).unwrap();
+    }
+
+    fn prepare_fs_readdir(policy: ReaddirInoPolicy, files: usize) -> (PassthroughFs, TempDir) {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for i in 0..files {
+            std::fs::write(source.as_path().join(format!("f{}", i)), b"").unwrap();
+        }
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        let fs = prepare_fs_in(&source, |cfg| cfg.readdir_ino = policy);
+        (fs, source)
+    }
+
+    // Read all entries of the root directory, returning their names and inode numbers.
+    fn readdir_all(fs: &PassthroughFs) -> Vec<(String, u64)> {
+        let ctx = prepare_context();
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, libc::O_RDONLY as u32).unwrap();
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut added = 0;
+            fs.readdir(
+                &ctx,
+                ROOT_ID,
+                handle.unwrap(),
+                64 * 1024,
+                offset,
+                &mut |d| {
+                    entries.push((String::from_utf8(d.name.to_vec()).unwrap(), d.ino));
+                    offset = d.offset;
+                    added += 1;
+                    Ok(1)
+                },
+            )
+            .unwrap();
+            if added == 0 {
+                break;
+            }
+        }
+        fs.releasedir(&ctx, ROOT_ID, 0, handle.unwrap()).unwrap();
+        entries
+    }
+
+    #[test]
+    fn test_readdir_ino_policy() {
+        let (fs, _source) = prepare_fs_readdir(ReaddirInoPolicy::Exact, 100);
+        let next = fs.inode_map.allocator.next();
+        let entries = readdir_all(&fs);
+        assert_eq!(entries.len(), 101);
+        // Every entry has been looked up, and got an inode number of the file system.
+        assert_eq!(fs.inode_map.allocator.next(), next + 101);
+        for (name, ino) in entries {
+            let entry = fs
+                .lookup(&prepare_context(), ROOT_ID, &CString::new(name).unwrap())
+                .unwrap();
+            assert_eq!(entry.inode, ino);
+        }
+
+        let (fs, source) = prepare_fs_readdir(ReaddirInoPolicy::Fast, 100);
+        let next = fs.inode_map.allocator.next();
+        let entries = readdir_all(&fs);
+        assert_eq!(entries.len(), 101);
+        // No entry has been looked up, the inode numbers are those of the host.
+        assert_eq!(fs.inode_map.allocator.next(), next);
+        for (name, ino) in entries {
+            let st = std::fs::symlink_metadata(source.as_path().join(&name)).unwrap();
+            assert_eq!(st.ino(), ino);
+        }
+
+        assert_eq!("fast".parse(), Ok(ReaddirInoPolicy::Fast));
+        assert!("slow".parse::<ReaddirInoPolicy>().is_err());
+    }
+
+    // Compare the time to list a large directory with both policies, with
+    // `cargo test -- --ignored --nocapture bench_readdir_ino_policy`.
+    #[test]
+    #[ignore]
+    fn bench_readdir_ino_policy() {
+        const FILES: usize = 100_000;
+
+        for policy in [ReaddirInoPolicy::Exact, ReaddirInoPolicy::Fast] {
+            let (fs, _source) = prepare_fs_readdir(policy, FILES);
+            let next = fs.inode_map.allocator.next();
+            let start = std::time::Instant::now();
+            let entries = readdir_all(&fs);
+            let elapsed = start.elapsed();
+            assert_eq!(entries.len(), FILES + 1);
+            println!(
+                "{:?}: {} entries in {:?}, {} lookups",
+                policy,
+                entries.len(),
+                elapsed,
+                fs.inode_map.allocator.next() - next
+            );
+        }
+    }
+
+    #[test]
+    fn test_readdirplus_cache() {
+        let (fs, source) = prepare_fs_readdir(ReaddirInoPolicy::Exact, 10);
+        let ctx = prepare_context();
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, libc::O_RDONLY as u32).unwrap();
+        let handle = handle.unwrap();
+        let list = || {
+            let mut entries = Vec::new();
+            fs.readdirplus(&ctx, ROOT_ID, handle, 64 * 1024, 0, &mut |d, e| {
+                entries.push((String::from_utf8(d.name.to_vec()).unwrap(), e));
+                Ok(1)
+            })
+            .unwrap();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        };
+        let refcount = |inode| {
+            fs.inode_map
+                .get(inode)
+                .unwrap()
+                .refcount
+                .load(Ordering::Relaxed)
+        };
+
+        let first = list();
+        assert_eq!(first.len(), 11);
+        let stats = fs.readdirplus_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (0, 11));
+
+        // Listing again reuses the entries, and takes a reference for each of them as well.
+        std::fs::write(source.as_path().join("f0"), b"data").unwrap();
+        let second = list();
+        let stats = fs.readdirplus_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (11, 11));
+        for ((name, a), (_, b)) in first.iter().zip(second.iter()) {
+            assert_eq!(a.inode, b.inode);
+            assert_eq!(refcount(a.inode), 2);
+            // Attributes are fresh.
+            if name == "f0" {
+                assert_eq!(b.attr.st_size, 4);
+            }
+        }
+
+        // Removing an entry drops the remembered entries.
+        fs.unlink(&ctx, ROOT_ID, &CString::new("f1").unwrap())
+            .unwrap();
+        let third = list();
+        assert_eq!(third.len(), 10);
+        assert!(third.iter().all(|(name, _)| name != "f1"));
+        let stats = fs.readdirplus_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (11, 21));
+
+        fs.releasedir(&ctx, ROOT_ID, 0, handle).unwrap();
+    }
+
+    #[test]
+    fn test_attr_timeout_by_type() {
+        let (fs, _source) = prepare_fs_with(|cfg| {
+            cfg.attr_timeout = Duration::from_secs(1);
+            cfg.file_attr_timeout = Some(Duration::from_secs(2));
+            cfg.dir_attr_timeout = Some(Duration::from_secs(3));
+        });
+        let ctx = prepare_context();
+
+        let (file, handle) = create_file_with_sugid(&ctx, &fs);
+        assert_eq!(file.attr_timeout, Duration::from_secs(2));
+        let dir = fs
+            .mkdir(&ctx, ROOT_ID, &CString::new("dir").unwrap(), 0o755, 0)
+            .unwrap();
+        assert_eq!(dir.attr_timeout, Duration::from_secs(3));
+
+        let (mut attr, _) = fs.getattr(&ctx, file.inode, None).unwrap();
+        for (inode, handle, timeout) in [
+            (file.inode, None, 2),
+            (file.inode, Some(handle), 2),
+            (dir.inode, None, 3),
+            (ROOT_ID, None, 3),
+        ] {
+            let (_, attr_timeout) = fs.getattr(&ctx, inode, handle).unwrap();
+            assert_eq!(attr_timeout, Duration::from_secs(timeout));
+        }
+
+        // Attributes returned by setattr carry the same timeout.
+        attr.st_mode = 0o600;
+        let (_, attr_timeout) = fs
+            .setattr(&ctx, file.inode, attr, Some(handle), SetattrValid::MODE)
+            .unwrap();
+        assert_eq!(attr_timeout, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let conf_dir = TempDir::new().expect("Cannot create temporary directory.");
+        let root_dir = source.as_path().to_str().unwrap().to_string();
+        let conf = conf_dir.as_path().join("passthroughfs.conf");
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+
+        let fs_cfg = Config {
+            root_dir: root_dir.clone(),
+            cache_policy: CachePolicy::Never,
+            config_file: Some(conf.clone()),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = prepare_context();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        let flags = libc::O_RDONLY as u32;
+        let (_, opts, _) = fs.open(&ctx, entry.inode, flags, 0).unwrap();
+        assert_eq!(opts, OpenOptions::DIRECT_IO);
+
+        // The file is only reloaded on SIGUSR2, once asked to.
+        std::fs::write(
+            &conf,
+            format!(
+                "# Cache everything.\nroot_dir = {}\ncache_policy = always\nattr_timeout = 1.5\n",
+                root_dir
+            ),
+        )
+        .unwrap();
+        let (_, opts, _) = fs.open(&ctx, entry.inode, flags, 0).unwrap();
+        assert_eq!(opts, OpenOptions::DIRECT_IO);
+
+        // Safe because this doesn't modify any memory, and init installed a handler.
+        assert_eq!(unsafe { libc::raise(libc::SIGUSR2) }, 0);
+        let (_, opts, _) = fs.open(&ctx, entry.inode, flags, 0).unwrap();
+        assert_eq!(opts, OpenOptions::DIRECT_IO);
+        assert!(fs.reload_if_requested().unwrap());
+        assert!(!fs.reload_if_requested().unwrap());
+        let (_, opts, _) = fs.open(&ctx, entry.inode, flags, 0).unwrap();
+        assert_eq!(opts, OpenOptions::KEEP_CACHE);
+        let (_, timeout) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!(timeout, Duration::from_millis(1500));
+
+        // Options which can't change at runtime must be left alone.
+        std::fs::write(&conf, "root_dir = /\ncache_policy = never\n").unwrap();
+        let new_cfg = Config::reload_from_file(&conf).unwrap();
+        let err = fs.apply_config_update(new_cfg).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // Safe because this doesn't modify any memory.
+        assert_eq!(unsafe { libc::raise(libc::SIGUSR2) }, 0);
+        fs.reload_if_requested().unwrap_err();
+        let (_, opts, _) = fs.open(&ctx, entry.inode, flags, 0).unwrap();
+        assert_eq!(opts, OpenOptions::KEEP_CACHE);
+
+        std::fs::write(&conf, "cache_policy = sometimes\n").unwrap();
+        let err = Config::reload_from_file(&conf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::write(&conf, "no_such_option = 1\n").unwrap();
+        let err = Config::reload_from_file(&conf).unwrap_err();
+        assert!(err.to_string().ends_with(":1: unknown option"));
+    }
+
+    #[test]
+    fn test_release_while_reading() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        let fs = prepare_fs_in(&source, |cfg| cfg.handle_map_shards = 4);
+        let ctx = prepare_context();
+        let inode = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap()
+            .inode;
+        let handles: Vec<Handle> = (0..64)
+            .map(|_| {
+                fs.open(&ctx, inode, libc::O_RDONLY as u32, 0)
+                    .unwrap()
+                    .0
+                    .unwrap()
+            })
+            .collect();
+
+        // Reads either complete, or fail with EBADF once the handle is released.
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (fs, ctx, handles) = (&fs, &ctx, &handles);
+                s.spawn(move || {
+                    let mut out_file = TempFile::new().unwrap().into_file();
+                    for i in 0..1024 {
+                        let handle = handles[(i + t * 16) % handles.len()];
+                        match fs.read(ctx, inode, handle, &mut out_file, 4, 0, None, 0) {
+                            Ok(n) => assert_eq!(n, 4),
+                            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EBADF)),
+                        }
+                    }
+                });
+            }
+            for handle in handles.iter() {
+                fs.release(&ctx, inode, 0, *handle, false, false, None)
+                    .unwrap();
+            }
+        });
+
+        assert_eq!(fs.handle_map.len.load(Ordering::Relaxed), 0);
+        let mut out_file = TempFile::new().unwrap().into_file();
+        let err = fs
+            .read(&ctx, inode, handles[0], &mut out_file, 4, 0, None, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn test_seccomp_filter() {
+        let (fs, source) = prepare_fs_with(|cfg| cfg.inotify_invalidate = true);
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        let mut out_file = TempFile::new().unwrap().into_file();
+
+        // Filters belong to threads, so install it in a thread of its own.
+        std::thread::spawn(move || {
+            fs.install_seccomp_filter().unwrap();
+
+            // Requests are still served.
+            let ctx = prepare_context();
+            let entry = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+                .unwrap();
+            let (handle, _, _) = fs
+                .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+                .unwrap();
+            let n = fs
+                .read(
+                    &ctx,
+                    entry.inode,
+                    handle.unwrap(),
+                    &mut out_file,
+                    4,
+                    0,
+                    None,
+                    0,
+                )
+                .unwrap();
+            assert_eq!(n, 4);
+            let (created, _) = create_file_with_sugid(&ctx, &fs);
+            fs.getattr(&ctx, created.inode, None).unwrap();
+
+            // Directories are watched while the kernel knows them.
+            let watcher = fs.inotify.as_ref().unwrap();
+            let dir = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+                .unwrap();
+            assert_eq!(watcher.watched(), 2);
+            fs.forget(&ctx, dir.inode, 1);
+            assert_eq!(watcher.watched(), 1);
+
+            // Interrupted requests are signaled, and signaled again until they complete.
+            #[cfg(feature = "fusedev")]
+            {
+                let map = crate::transport::InterruptMap::new();
+                let guard = map.register(1);
+                assert!(map.interrupt(1).unwrap());
+                for _ in 0..2 {
+                    // Safe because no memory is passed.
+                    let res = unsafe { libc::poll(std::ptr::null_mut(), 0, 5000) };
+                    assert_eq!(res, -1);
+                    assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EINTR));
+                }
+                drop(guard);
+            }
+
+            // Files can't be opened by path.
+            let err = std::fs::File::open("/etc/passwd").unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+            #[cfg(target_arch = "x86_64")]
+            {
+                let path = CString::new("/etc/passwd").unwrap();
+                // Safe because this doesn't modify any memory.
+                let res = unsafe { libc::syscall(libc::SYS_open, path.as_ptr(), libc::O_RDONLY) };
+                assert_eq!(res, -1);
+                assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+            }
+
+            // Nor can sockets be created, or extended attributes set without `xattr`.
+            // Safe because this doesn't modify any memory.
+            let res = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) };
+            assert_eq!(res, -1);
+            assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+            let name = CString::new("user.test").unwrap();
+            // Safe because this doesn't modify any memory.
+            let res = unsafe {
+                libc::fsetxattr(
+                    out_file.as_raw_fd(),
+                    name.as_ptr(),
+                    b"v".as_ptr() as *const libc::c_void,
+                    1,
+                    0,
+                )
+            };
+            assert_eq!(res, -1);
+            assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_save_restore_state() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("dir/file"), b"data").unwrap();
+        let fs_cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            do_import: true,
+            inode_file_handles: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(fs_cfg.clone()).unwrap();
+        fs.import().unwrap();
+        let ctx = prepare_context();
+        let dir = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap()
+            .inode;
+        let file = fs
+            .lookup(&ctx, dir, &CString::new("file").unwrap())
+            .unwrap()
+            .inode;
+        fs.lookup(&ctx, dir, &CString::new("file").unwrap())
+            .unwrap();
+        let handle = fs
+            .open(&ctx, file, libc::O_RDWR as u32, 0)
+            .unwrap()
+            .0
+            .unwrap();
+        let (dir_handle, _) = fs.opendir(&ctx, dir, 0).unwrap();
+        let dir_handle = dir_handle.unwrap();
+
+        let state = fs.save_state().unwrap();
+        assert_eq!(state.inodes.len(), 3);
+        assert_eq!(state.handles.len(), 2);
+        drop(fs);
+
+        let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+        fs.import().unwrap();
+        fs.restore_state(&state).unwrap();
+        assert_eq!(fs.save_state().unwrap(), state);
+        // Restoring twice would clobber the inodes in use.
+        let err = fs.restore_state(&state).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+
+        // Saved inodes and handles are usable, and new ones don't collide with them.
+        let mut out_file = TempFile::new().unwrap().into_file();
+        let n = fs
+            .read(&ctx, file, handle, &mut out_file, 4, 0, None, 0)
+            .unwrap();
+        assert_eq!(n, 4);
+        fs.getattr(&ctx, dir, Some(dir_handle)).unwrap();
+        let new_handle = fs
+            .open(&ctx, file, libc::O_RDONLY as u32, 0)
+            .unwrap()
+            .0
+            .unwrap();
+        assert!(new_handle > dir_handle && new_handle > handle);
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        assert_eq!(entry.inode, dir);
+        std::fs::write(source.as_path().join("other"), b"").unwrap();
+        let other = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("other").unwrap())
+            .unwrap()
+            .inode;
+        assert!(other > file);
+
+        // Refcounts survived, so the file is forgotten after as many forgets as lookups.
+        fs.release(&ctx, file, 0, handle, false, false, None)
+            .unwrap();
+        fs.release(&ctx, file, 0, new_handle, false, false, None)
+            .unwrap();
+        fs.forget(&ctx, file, 1);
+        fs.getattr(&ctx, file, None).unwrap();
+        fs.forget(&ctx, file, 1);
+        let err = fs.getattr(&ctx, file, None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        // File handles of removed files are stale.
+        std::fs::remove_file(source.as_path().join("dir/file")).unwrap();
+        let fs = PassthroughFs::<()>::new(fs.cfg.clone()).unwrap();
+        fs.import().unwrap();
+        let err = fs.restore_state(&state).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+        assert_eq!(fs.inode_map.live.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("dir/file"), b"data").unwrap();
+        let fs_cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(fs_cfg.clone()).unwrap();
+        fs.import().unwrap();
+        let ctx = prepare_context();
+        let dir = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap()
+            .inode;
+        let file = fs
+            .lookup(&ctx, dir, &CString::new("file").unwrap())
+            .unwrap()
+            .inode;
+        let handle = fs
+            .open(&ctx, file, libc::O_RDWR as u32, 0)
+            .unwrap()
+            .0
+            .unwrap();
+
+        let mut checkpoint = Vec::new();
+        fs.checkpoint(&mut checkpoint).unwrap();
+        // The files are reopened through the fds of the old instance, and the file is unlinked
+        // meanwhile, so it can't be found by path any more.
+        std::fs::remove_file(source.as_path().join("dir/file")).unwrap();
+        let new_fs = PassthroughFs::<()>::restore(fs_cfg.clone(), &mut &checkpoint[..]).unwrap();
+        drop(fs);
+
+        // Previously open handles are still usable, and so are the inodes.
+        let mut out_file = TempFile::new().unwrap().into_file();
+        let n = new_fs
+            .read(&ctx, file, handle, &mut out_file, 4, 0, None, 0)
+            .unwrap();
+        assert_eq!(n, 4);
+        new_fs.getattr(&ctx, dir, None).unwrap();
+        let (attr, _) = new_fs.getattr(&ctx, file, None).unwrap();
+        assert_eq!(attr.st_nlink, 0);
+        let new_handle = new_fs
+            .open(&ctx, file, libc::O_RDONLY as u32, 0)
+            .unwrap()
+            .0
+            .unwrap();
+        assert!(new_handle > handle);
+
+        // Checkpoints need inodes held by fds.
+        let fs_cfg = Config {
+            inode_file_handles: true,
+            ..fs_cfg
+        };
+        let fs = PassthroughFs::<()>::new(fs_cfg.clone()).unwrap();
+        fs.import().unwrap();
+        let err = fs.checkpoint(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = PassthroughFs::<()>::restore(fs_cfg, &mut &b"garbage"[..])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_path_fd_eviction() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for i in 0..8 {
+            std::fs::write(source.as_path().join(format!("file{}", i)), b"data").unwrap();
+        }
+        let fs = prepare_fs_in(&source, |cfg| cfg.max_path_fds = Some(2));
+        let ctx = prepare_context();
+        let entries: Vec<Entry> = (0..8)
+            .map(|i| {
+                let name = CString::new(format!("file{}", i)).unwrap();
+                fs.lookup(&ctx, ROOT_ID, &name).unwrap()
+            })
+            .collect();
+        let stats = fs.path_fd_stats();
+        assert!(stats.open <= 2);
+        assert!(stats.evictions >= 6);
+        assert_eq!(stats.reopens, 0);
+        let handle = fs
+            .open(&ctx, entries[0].inode, libc::O_RDONLY as u32, 0)
+            .unwrap()
+            .0
+            .unwrap();
+
+        // Evicted inodes are reopened, and open handles keep their own fds.
+        for entry in entries.iter() {
+            let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+            assert_eq!(st.st_ino, entry.attr.st_ino);
+        }
+        assert!(fs.path_fd_stats().reopens > 0);
+        assert!(fs.path_fd_stats().open <= 2);
+        let mut out_file = TempFile::new().unwrap().into_file();
+        let n = fs
+            .read(&ctx, entries[0].inode, handle, &mut out_file, 4, 0, None, 0)
+            .unwrap();
+        assert_eq!(n, 4);
+
+        // Concurrent lookups of inodes being evicted find them again.
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (fs, ctx, entries) = (&fs, &ctx, &entries);
+                s.spawn(move || {
+                    for i in 0..256 {
+                        let entry = &entries[(i * 3 + t) % entries.len()];
+                        let name = CString::new(format!("file{}", (i * 3 + t) % 8)).unwrap();
+                        let found = fs.lookup(ctx, ROOT_ID, &name).unwrap();
+                        assert_eq!(found.inode, entry.inode);
+                        fs.getattr(ctx, entry.inode, None).unwrap();
+                        fs.forget(ctx, entry.inode, 1);
+                    }
+                });
+            }
+        });
+        assert!(fs.path_fd_stats().open <= 2);
+
+        // A file replaced while its fd was closed is stale.
+        let path = source.as_path().join("file1");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, b"other").unwrap();
+        for entry in entries.iter().skip(2) {
+            fs.getattr(&ctx, entry.inode, None).unwrap();
+        }
+        let err = fs.getattr(&ctx, entries[1].inode, None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+    }
+
+    #[test]
+    fn test_xattr_prefix_map() {
+        let (fs, source) = prepare_fs_with(|cfg| {
+            cfg.xattr = true;
+            cfg.xattr_prefix_map = Some(XattrPrefixMap {
+                allow: vec!["user.".to_string()],
+                remap: vec![("user.".to_string(), "trusted.".to_string())],
+            });
+        });
+        fs.init(FsOptions::all()).unwrap();
+        let ctx = prepare_context();
+        let (entry, _) = create_file_with_sugid(&ctx, &fs);
+        let inode = entry.inode;
+
+        let path = CString::new(source.as_path().join("testfile").to_str().unwrap()).unwrap();
+        let host_getxattr = |name: &str| {
+            let name = CString::new(name).unwrap();
+            let mut buf = [0u8; 16];
+            // Safe because this only modifies `buf` and we check the return value.
+            let res = unsafe {
+                libc::getxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            (res >= 0).then(|| buf[..res as usize].to_vec())
+        };
+        let name = CString::new("user.plain").unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"h".as_ptr() as _, 1, 0) };
+        assert_eq!(res, 0, "{}", io::Error::last_os_error());
+
+        // user. names from the client are trusted. names on the host.
+        let name = CString::new("user.foo").unwrap();
+        fs.setxattr(&ctx, inode, &name, b"bar", 0, 0).unwrap();
+        assert_eq!(host_getxattr("trusted.foo"), Some(b"bar".to_vec()));
+        assert_eq!(host_getxattr("user.foo"), None);
+        match fs.getxattr(&ctx, inode, &name, 64).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"bar"),
+            _ => panic!("unexpected getxattr reply"),
+        }
+
+        // user.plain on the host can't be addressed by the client, so it isn't listed.
+        match fs.listxattr(&ctx, inode, 4096).unwrap() {
+            ListxattrReply::Names(names) => assert_eq!(names, b"user.foo\0"),
+            _ => panic!("unexpected listxattr reply"),
+        }
+
+        // Other namespaces are denied.
+        let name = CString::new("trusted.foo").unwrap();
+        let err = fs.getxattr(&ctx, inode, &name, 64).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        let err = fs.setxattr(&ctx, inode, &name, b"v", 0, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+
+        let name = CString::new("user.foo").unwrap();
+        fs.removexattr(&ctx, inode, &name).unwrap();
+        assert_eq!(host_getxattr("trusted.foo"), None);
+    }
+
+    #[test]
+    fn test_seal() {
+        let (fs, _source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let name = CString::new("sealed").unwrap();
+        let (entry, handle, _, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        let handle = handle.unwrap();
+
+        // Only shmem files support seals.
+        let err = fs.get_seals(&ctx, entry.inode, handle).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = fs
+            .seal(&ctx, entry.inode, handle, libc::F_SEAL_GROW as u32)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        let source = match TempDir::new_with_prefix("/dev/shm/fuse-backend-rs") {
+            Ok(dir) => dir,
+            Err(_) => return,
+        };
+        let fs = prepare_fs_in(&source, |_| {});
+        let (entry, handle, _, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        let handle = handle.unwrap();
+
+        // Files of tmpfs are created sealed against further seals, unlike memfds.
+        let seals = fs.get_seals(&ctx, entry.inode, handle).unwrap();
+        assert_eq!(seals, libc::F_SEAL_SEAL as u32);
+        let err = fs
+            .seal(&ctx, entry.inode, handle, libc::F_SEAL_GROW as u32)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    }
+
+    #[test]
+    fn test_fd_flags() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        std::fs::write(source.as_path().join("file"), b"hello").unwrap();
+        let name = CString::new("file").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let flags = libc::O_RDWR as u32;
+        let nonblock = flags | libc::O_NONBLOCK as u32;
+        let (handle1, _, _) = fs.open(&ctx, entry.inode, flags, 0).unwrap();
+        let (handle2, _, _) = fs.open(&ctx, entry.inode, nonblock, 0).unwrap();
+        let handles = [handle1.unwrap(), handle2.unwrap()];
+        let fd_flags = |handle| {
+            let data = fs.handle_map.get(handle, entry.inode).unwrap();
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::fcntl(data.borrow_fd().as_raw_fd(), libc::F_GETFL) };
+            assert!(res >= 0);
+            res
+        };
+        let before = [fd_flags(handles[0]), fd_flags(handles[1])];
+
+        // Reads with and without O_NONBLOCK on either handle don't change the flags of the fds.
+        for (i, handle) in handles.iter().chain(handles.iter().rev()).enumerate() {
+            let flags = if i % 2 == 0 { flags } else { nonblock };
+            let mut out_file = TempFile::new().unwrap().into_file();
+            let n = fs
+                .read(&ctx, entry.inode, *handle, &mut out_file, 5, 0, None, flags)
+                .unwrap();
+            assert_eq!(n, 5);
+        }
+        assert_eq!([fd_flags(handles[0]), fd_flags(handles[1])], before);
+
+        // Flags changing the outcome of IO are still applied to the fd.
+        let mut out_file = TempFile::new().unwrap().into_file();
+        let noatime = flags | libc::O_NOATIME as u32;
+        fs.read(
+            &ctx,
+            entry.inode,
+            handles[0],
+            &mut out_file,
+            5,
+            0,
+            None,
+            noatime,
+        )
+        .unwrap();
+        assert_ne!(fd_flags(handles[0]) & libc::O_NOATIME, 0);
+        assert_eq!(fd_flags(handles[1]), before[1]);
+    }
+
+    #[test]
+    fn test_clone_range() {
+        let (fs, source) = prepare_fs_with(|cfg| cfg.allow_clone_range = true);
+        let ctx = prepare_context();
+
+        let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.as_path().join("src"), &data).unwrap();
+        let src_entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("src").unwrap())
+            .unwrap();
+        let (src_handle, _, _) = fs
+            .open(&ctx, src_entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let dst_name = CString::new("dst").unwrap();
+        let (dst_entry, dst_handle, _, _) = fs.create(&ctx, ROOT_ID, &dst_name, args).unwrap();
+
+        // Extents are shared on btrfs and XFS, other file systems copy the data instead.
+        let cloned = fs
+            .clone_range(
+                &ctx,
+                src_entry.inode,
+                src_handle.unwrap(),
+                0,
+                dst_entry.inode,
+                dst_handle.unwrap(),
+                0,
+                data.len() as u64,
+            )
+            .unwrap();
+        assert_eq!(cloned, data.len());
+        assert_eq!(std::fs::read(source.as_path().join("dst")).unwrap(), data);
+
+        // Modifying the clone leaves the source unchanged.
+        let clone = std::fs::OpenOptions::new()
+            .write(true)
+            .open(source.as_path().join("dst"))
+            .unwrap();
+        clone.write_all_at(&[0xff], 4096).unwrap();
+        assert_eq!(std::fs::read(source.as_path().join("src")).unwrap(), data);
+    }
+
+    #[test]
+    #[cfg(feature = "fusedev")]
+    fn test_fuse_passthrough() {
+        use crate::api::server::Server;
+        use crate::transport::FuseSession;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let mountpoint = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        let fs = Arc::new(prepare_fs_in(&source, |cfg| cfg.fuse_passthrough = true));
+
+        let mut se = FuseSession::new(mountpoint.as_path(), "passthrough_test", "", false).unwrap();
+        // Mounting needs privileges, nothing to test without.
+        if se.mount().is_err() {
+            return;
+        }
+        fs.set_fuse_dev(se.get_fuse_file().unwrap().try_clone().unwrap());
+        let server = Server::new(fs.clone(), None);
+        let mut ch = se.new_channel().unwrap();
+        let (reader, writer) = ch.get_request().unwrap().unwrap();
+        server
+            .handle_message(reader, writer.into(), None, None)
+            .unwrap();
+
+        // The kernel doesn't support FUSE_PASSTHROUGH, or isn't built with it.
+        if fs.fuse_passthrough.load(Ordering::Relaxed) {
+            let ctx = Context::default();
+            let name = CString::new("file").unwrap();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let (handle, opts, backing_id) =
+                fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+            assert!(backing_id.is_some());
+            assert!(opts.contains(OpenOptions::PASSTHROUGH));
+            fs.release(&ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+                .unwrap();
+
+            // Directories are served by the daemon.
+            let (_, opts, backing_id) =
+                fs.open(&ctx, ROOT_ID, libc::O_DIRECTORY as u32, 0).unwrap();
+            assert!(backing_id.is_none());
+            assert!(!opts.contains(OpenOptions::PASSTHROUGH));
+        }
+
+        se.umount().unwrap();
+        se.wake().unwrap();
+    }
+
+    #[test]
+    fn test_inotify_unwatch_on_forget() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        let fs = prepare_fs_in(&source, |cfg| cfg.inotify_invalidate = true);
+        let ctx = prepare_context();
+        let watcher = fs.inotify.as_ref().unwrap();
+        assert_eq!(watcher.watched(), 1);
+
+        let name = CString::new("dir").unwrap();
+        let dir = fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode;
+        fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(watcher.watched(), 2);
+        // The directory is watched as long as the kernel knows it.
+        fs.forget(&ctx, dir, 1);
+        assert_eq!(watcher.watched(), 2);
+        fs.forget(&ctx, dir, 1);
+        assert_eq!(watcher.watched(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "fusedev")]
+    fn test_inotify_invalidate() {
+        use crate::api::server::Server;
+        use crate::transport::{notify_inval_inode, FuseSession};
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let mountpoint = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("dir/file"), b"old data").unwrap();
+        let fs_cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            do_import: true,
+            cache_policy: CachePolicy::Always,
+            inotify_invalidate: true,
+            ..Default::default()
+        };
+        let fs = Arc::new(PassthroughFs::<()>::new(fs_cfg).unwrap());
+        let err = fs.invalidate_inode(ROOT_ID, 0, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTCONN));
+
+        let mut se = FuseSession::new(mountpoint.as_path(), "passthrough_test", "", false).unwrap();
+        // Mounting needs privileges, nothing to test without.
+        if se.mount().is_err() {
+            return;
+        }
+        let dev = se.get_fuse_file().unwrap().try_clone().unwrap();
+        fs.set_inval_notifier(Box::new(move |inode, offset, len| {
+            notify_inval_inode(&dev, inode, offset, len)
+        }));
+        let server = Arc::new(Server::new(fs.clone(), None));
+        let mut ch = se.new_channel().unwrap();
+        let thread = std::thread::spawn(move || {
+            while let Ok(Some((reader, writer))) = ch.get_request() {
+                server
+                    .handle_message(reader, writer.into(), None, None)
+                    .unwrap();
+            }
+        });
+
+        let path = mountpoint.as_path().join("dir/file");
+        assert_eq!(std::fs::read(&path).unwrap(), b"old data");
+        // Same size, so only the cached data is stale.
+        std::fs::write(source.as_path().join("dir/file"), b"new data").unwrap();
+        // Files unknown to the kernel have nothing to invalidate.
+        std::fs::write(source.as_path().join("dir/other"), b"data").unwrap();
+        let mut invalidated = 0;
+        while invalidated == 0 {
+            invalidated = fs
+                .handle_inotify_events(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        assert_eq!(invalidated, 1);
+        assert_eq!(std::fs::read(&path).unwrap(), b"new data");
+
+        se.umount().unwrap();
+        se.wake().unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "fusedev")]
+    fn test_notify_inval_entry() {
+        use crate::api::server::Server;
+        use crate::transport::FuseSession;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let mountpoint = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("old"), b"data").unwrap();
+        let fs_cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            do_import: true,
+            entry_timeout: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let fs = Arc::new(PassthroughFs::<()>::new(fs_cfg).unwrap());
+
+        let mut se = FuseSession::new(mountpoint.as_path(), "passthrough_test", "", false).unwrap();
+        // Mounting needs privileges, nothing to test without.
+        if se.mount().is_err() {
+            return;
+        }
+        let server = Arc::new(Server::new(fs.clone(), None));
+        let mut ch = se.new_channel().unwrap();
+        let thread = std::thread::spawn(move || {
+            while let Ok(Some((reader, writer))) = ch.get_request() {
+                server
+                    .handle_message(reader, writer.into(), None, None)
+                    .unwrap();
+            }
+        });
+
+        // The dentry of the old name is cached until invalidated.
+        let old = mountpoint.as_path().join("old");
+        let new = mountpoint.as_path().join("new");
+        std::fs::metadata(&old).unwrap();
+        std::fs::rename(source.as_path().join("old"), source.as_path().join("new")).unwrap();
+        std::fs::metadata(&old).unwrap();
+        se.notify_inval_entry(ROOT_ID, &CString::new("old").unwrap())
+            .unwrap();
+        let err = std::fs::metadata(&old).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        // Same for deletions, which need the inode of the entry.
+        std::fs::metadata(&new).unwrap();
+        let root = fs.inode_map.get(ROOT_ID).unwrap();
+        let st = statx(
+            &root.get_file().unwrap(),
+            Some(&CString::new("new").unwrap()),
+        )
+        .unwrap();
+        let child = fs
+            .inode_map
+            .get_alt(&InodeId::from_stat(&st), None)
+            .unwrap()
+            .inode;
+        std::fs::remove_file(source.as_path().join("new")).unwrap();
+        std::fs::metadata(&new).unwrap();
+        se.notify_delete(ROOT_ID, child, &CString::new("new").unwrap())
+            .unwrap();
+        let err = std::fs::metadata(&new).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        se.umount().unwrap();
+        se.wake().unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_notify_on_rename() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for name in ["a", "b", "c"] {
+            std::fs::write(source.as_path().join(name), b"data").unwrap();
+        }
+        let fs = prepare_fs_in(&source, |cfg| cfg.notify_on_rename = true);
+        let ctx = prepare_context();
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let sent = notified.clone();
+        fs.set_delete_notifier(Box::new(move |parent, child, name| {
+            sent.lock().unwrap().push((parent, child, name.to_owned()));
+            Ok(())
+        }));
+        let name = |n: &str| CString::new(n).unwrap();
+
+        let inode = fs.lookup(&ctx, ROOT_ID, &name("a")).unwrap().inode;
+        fs.rename(&ctx, ROOT_ID, &name("a"), ROOT_ID, &name("d"), 0)
+            .unwrap();
+        assert_eq!(
+            notified.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [(ROOT_ID, inode, name("a"))]
+        );
+
+        // Entries unknown to the kernel, and exchanged entries, keep the old name.
+        fs.rename(&ctx, ROOT_ID, &name("b"), ROOT_ID, &name("e"), 0)
+            .unwrap();
+        fs.lookup(&ctx, ROOT_ID, &name("c")).unwrap();
+        fs.rename(
+            &ctx,
+            ROOT_ID,
+            &name("c"),
+            ROOT_ID,
+            &name("d"),
+            libc::RENAME_EXCHANGE,
+        )
+        .unwrap();
+        assert!(notified.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lseek_sparse() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        // A 1 MiB hole followed by a block of data.
+        let file = std::fs::File::create(source.as_path().join("sparse")).unwrap();
+        file.write_all_at(&[1u8; 4096], 1 << 20).unwrap();
+        drop(file);
+
+        for seal_size in [false, true] {
+            let fs = prepare_fs_in(&source, |cfg| cfg.seal_size = seal_size);
+            fs.init(FsOptions::empty()).unwrap();
+            let ctx = prepare_context();
+            let name = CString::new("sparse").unwrap();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let (handle, _, _) = fs
+                .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+                .unwrap();
+            let handle = handle.unwrap();
+            let lseek = |offset, whence: libc::c_int| {
+                fs.lseek(&ctx, entry.inode, handle, offset, whence as u32)
+            };
+
+            assert_eq!(lseek(0, libc::SEEK_DATA).unwrap(), 1 << 20);
+            assert_eq!(lseek(0, libc::SEEK_HOLE).unwrap(), 0);
+            assert_eq!(lseek(1 << 20, libc::SEEK_HOLE).unwrap(), (1 << 20) + 4096);
+            let err = lseek((1 << 20) + 4096, libc::SEEK_DATA).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENXIO));
+            assert_eq!(lseek(16, libc::SEEK_SET).unwrap(), 16);
+            assert_eq!(lseek(0, libc::SEEK_END).unwrap(), (1 << 20) + 4096);
+
+            // Unknown values of whence are rejected.
+            let err = lseek(0, 5).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        }
+    }
+
+    #[test]
+    fn test_bind_mount_inodes() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let root = source.as_path().to_path_buf();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/file"), b"data").unwrap();
+        std::fs::create_dir(root.join("bind")).unwrap();
+
+        std::thread::spawn(move || {
+            // Mount in a namespace private to this thread, so nothing leaks to the host. Skip
+            // without the privileges to do so.
+            // Safe because this doesn't modify any memory and we check the return value.
+            if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+                return;
+            }
+            let flags = nix::mount::MsFlags::MS_REC | nix::mount::MsFlags::MS_PRIVATE;
+            nix::mount::mount(None::<&str>, "/", None::<&str>, flags, None::<&str>).unwrap();
+            let flags = nix::mount::MsFlags::MS_BIND;
+            let (dir, bind) = (root.join("dir"), root.join("bind"));
+            if nix::mount::mount(Some(&dir), &bind, None::<&str>, flags, None::<&str>).is_err() {
+                return;
+            }
+
+            // Without file handles, which are opened on mounts found in the mount table of the
+            // process rather than of this thread.
+            let fs_cfg = Config {
+                root_dir: root.to_str().unwrap().to_string(),
+                do_import: true,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+            fs.import().unwrap();
+            fs.init(FsOptions::empty()).unwrap();
+            let ctx = prepare_context();
+            let lookup = |path: &[&str]| {
+                path.iter().fold(ROOT_ID, |parent, name| {
+                    let name = CString::new(*name).unwrap();
+                    fs.lookup(&ctx, parent, &name).unwrap().inode
+                })
+            };
+
+            // The same file through two mounts gets two inodes, while it stays the same inode
+            // through the same mount.
+            let file = lookup(&["dir", "file"]);
+            let bound = lookup(&["bind", "file"]);
+            assert_ne!(file, bound);
+            assert_eq!(lookup(&["dir", "file"]), file);
+            assert_ne!(lookup(&["dir"]), lookup(&["bind"]));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_readdir_buf_cache() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for i in 0..200 {
+            std::fs::write(source.as_path().join(format!("file{}", i)), b"").unwrap();
+        }
+        let cap = 16 * 1024;
+        let fs = prepare_fs_in(&source, |cfg| cfg.readdir_buf_cache_size = cap);
+        let ctx = prepare_context();
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, libc::O_RDONLY as u32).unwrap();
+        let handle = handle.unwrap();
+        let cached = || {
+            DIRENT_BUF.with(|b| {
+                let buf = b.take();
+                let capacity = buf.capacity();
+                b.set(buf);
+                capacity
+            })
+        };
+
+        for i in 0..100 {
+            let size = [1024, 4096, cap, 64 * 1024, 1 << 20][i % 5];
+            let mut entries = 0;
+            fs.readdir(&ctx, ROOT_ID, handle, size as u32, 0, &mut |_| {
+                entries += 1;
+                Ok(1)
+            })
+            .unwrap();
+            assert!(entries > 0);
+            // Buffers are kept for the following requests, up to the cap.
+            assert!(cached() <= cap);
+            if size <= cap {
+                assert!(cached() >= size);
+            }
+        }
+        fs.releasedir(&ctx, ROOT_ID, 0, handle).unwrap();
+    }
+
+    #[test]
+    fn test_fallocate_seal_size() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("sealed"), [1u8; 8192]).unwrap();
+        let fs = prepare_fs_in(&source, |cfg| cfg.seal_size = true);
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = prepare_context();
+        let name = CString::new("sealed").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let (handle, _, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        let handle = handle.unwrap();
+        let fallocate = |mode: libc::c_int, offset: u64, length: u64| {
+            fs.fallocate(&ctx, entry.inode, handle, mode as u32, offset, length)
+                .map_err(|e| e.raw_os_error().unwrap())
+        };
+
+        let keep = libc::FALLOC_FL_KEEP_SIZE;
+        let punch = libc::FALLOC_FL_PUNCH_HOLE;
+        let zero = libc::FALLOC_FL_ZERO_RANGE;
+        // Allocating and zeroing within the file, or past its end while keeping its size.
+        assert_eq!(fallocate(0, 0, 8192), Ok(()));
+        assert_eq!(fallocate(0, 4096, 8192), Err(libc::EPERM));
+        assert_eq!(fallocate(keep, 4096, 8192), Ok(()));
+        assert_eq!(fallocate(zero, 0, 4096), Ok(()));
+        assert_eq!(fallocate(zero, 4096, 8192), Err(libc::EPERM));
+        assert_eq!(fallocate(zero | keep, 4096, 8192), Ok(()));
+        // Punching holes always keeps the size, and must say so.
+        assert_eq!(fallocate(punch | keep, 4096, 8192), Ok(()));
+        assert_eq!(fallocate(punch, 0, 4096), Err(libc::EOPNOTSUPP));
+        // Collapsing and inserting ranges change the size.
+        assert_eq!(
+            fallocate(libc::FALLOC_FL_COLLAPSE_RANGE, 0, 4096),
+            Err(libc::EPERM)
+        );
+        assert_eq!(
+            fallocate(libc::FALLOC_FL_INSERT_RANGE, 0, 4096),
+            Err(libc::EPERM)
+        );
+
+        // Unsupported combinations are rejected before reaching the host.
+        assert_eq!(
+            fallocate(punch | zero | keep, 0, 4096),
+            Err(libc::EOPNOTSUPP)
+        );
+        assert_eq!(
+            fallocate(libc::FALLOC_FL_COLLAPSE_RANGE | keep, 0, 4096),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(
+            fallocate(libc::FALLOC_FL_UNSHARE_RANGE | zero, 0, 4096),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(fallocate(0x1000, 0, 4096), Err(libc::EOPNOTSUPP));
+
+        let (attr, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!(attr.st_size, 8192);
+    }
+
+    #[test]
+    fn test_statx() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        let (entry, handle) = create_file_with_sugid(&ctx, &fs);
+
+        let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        let (res, _) = fs
+            .statx(&ctx, entry.inode, Some(handle), 0, libc::STATX_ALL)
+            .unwrap();
+        assert_eq!(res.st.st_ino, st.st_ino);
+        assert_eq!(res.st.st_mode, st.st_mode);
+        assert_eq!(res.st.st_mtime, st.st_mtime);
+        assert_eq!(res.st.st_mtime_nsec, st.st_mtime_nsec);
+
+        // A newly created file has a birth time if the backing filesystem records it.
+        let dir = File::open(source.as_path()).unwrap();
+        let name = CString::new("testfile").unwrap();
+        let stx = statx(&dir, Some(&name)).unwrap();
+        assert_eq!(res.btime.is_some(), stx.btime.is_some());
+        if let Some(btime) = res.btime {
+            assert!(btime.tv_sec != 0 || btime.tv_nsec != 0);
+            assert_eq!(btime.tv_sec, stx.btime.unwrap().tv_sec);
+            assert_eq!(btime.tv_nsec, stx.btime.unwrap().tv_nsec);
+        }
+    }
+
+    // Transfers at most `chunk` bytes at a time, and fails with EINTR first.
+    struct ShortIo {
+        file: File,
+        chunk: usize,
+        interrupted: bool,
+    }
+
+    impl ShortIo {
+        fn new(file: File, chunk: usize) -> Self {
+            ShortIo {
+                file,
+                chunk,
+                interrupted: false,
+            }
+        }
+
+        fn interrupt(&mut self) -> io::Result<()> {
+            if self.interrupted {
+                Ok(())
+            } else {
+                self.interrupted = true;
+                Err(io::Error::from_raw_os_error(libc::EINTR))
+            }
+        }
+    }
+
+    impl io::Read for ShortIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.file.read(buf)
+        }
+    }
+
+    impl io::Write for ShortIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl ZeroCopyReader for ShortIo {
+        fn read_to(
+            &mut self,
+            f: &mut dyn FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> io::Result<usize> {
+            self.interrupt()?;
+            self.file.read_to(f, count.min(self.chunk), off)
+        }
+    }
+
+    impl ZeroCopyWriter for ShortIo {
+        fn write_from(
+            &mut self,
+            f: &mut dyn FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> io::Result<usize> {
+            self.interrupt()?;
+            self.file.write_from(f, count.min(self.chunk), off)
+        }
+
+        fn available_bytes(&self) -> usize {
+            usize::MAX
+        }
+    }
+
+    #[test]
+    fn test_short_read_write() {
+        let (fs, source) = prepare_fs_tmpdir();
+        let ctx = prepare_context();
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+        let name = CString::new("file").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let (handle, _, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        let handle = handle.unwrap();
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+
+        // The whole request is written, whatever the size of each write.
+        let mut src = TempFile::new().unwrap().into_file();
+        src.write_all(&data).unwrap();
+        src.seek(SeekFrom::Start(0)).unwrap();
+        let mut r = ShortIo::new(src, 1000);
+        let n = fs
+            .write(
+                &ctx,
+                entry.inode,
+                handle,
+                &mut r,
+                10000,
+                0,
+                None,
+                false,
+                0,
+                0,
+            )
+            .unwrap();
+        assert_eq!(n, 10000);
+        assert_eq!(std::fs::read(source.as_path().join("file")).unwrap(), data);
+
+        // Reads go on until the whole request or the end of the file.
+        let mut w = ShortIo::new(TempFile::new().unwrap().into_file(), 1000);
+        let n = fs
+            .read(&ctx, entry.inode, handle, &mut w, 8192, 1000, None, 0)
+            .unwrap();
+        assert_eq!(n, 8192);
+        let n = fs
+            .read(&ctx, entry.inode, handle, &mut w, 4096, 9192, None, 0)
+            .unwrap();
+        assert_eq!(n, 808);
+        let mut out = Vec::new();
+        w.file.seek(SeekFrom::Start(0)).unwrap();
+        w.file.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data[1000..]);
+    }
+
+    #[test]
+    fn test_root_is_file() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("image");
+        std::fs::write(&path, b"hello").unwrap();
+        let new_fs = |root_dir: &std::path::Path, root_is_file| {
+            let cfg = Config {
+                root_dir: root_dir.to_str().unwrap().to_string(),
+                root_is_file,
+                ..Default::default()
+            };
+            PassthroughFs::<()>::new(cfg).unwrap()
+        };
+        // The root must be of the expected type.
+        let err = new_fs(source.as_path(), true).import().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+        let err = new_fs(&path, false).import().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+
+        let fs = new_fs(&path, true);
+        let ctx = prepare_context();
+        let opts = fs.init(FsOptions::all()).unwrap();
+        assert!(!opts.contains(FsOptions::DO_READDIRPLUS));
+        let (st, _) = fs.getattr(&ctx, ROOT_ID, None).unwrap();
+        assert_eq!(st.st_mode & libc::S_IFMT, libc::S_IFREG);
+        assert_eq!(st.st_size, 5);
+
+        // The root handle reads and writes the file.
+        let (handle, _, _) = fs.open(&ctx, ROOT_ID, libc::O_RDWR as u32, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut in_file = TempFile::new().unwrap().into_file();
+        in_file.write_all(b"world").unwrap();
+        in_file.seek(SeekFrom::Start(0)).unwrap();
+        let written = fs
+            .write(
+                &ctx,
+                ROOT_ID,
+                handle,
+                &mut in_file,
+                5,
+                5,
+                None,
+                false,
+                libc::O_RDWR as u32,
+                0,
+            )
+            .unwrap();
+        assert_eq!(written, 5);
+        let mut out_file = TempFile::new().unwrap().into_file();
+        let read = fs
+            .read(&ctx, ROOT_ID, handle, &mut out_file, 4096, 0, None, 0)
+            .unwrap();
+        assert_eq!(read, 10);
+        out_file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+        out_file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"helloworld");
+        assert_eq!(std::fs::read(&path).unwrap(), b"helloworld");
+
+        // The root has no entries.
+        let name = CString::new("foo").unwrap();
+        let enotdir = |res: io::Result<()>| {
+            assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+        };
+        enotdir(fs.lookup(&ctx, ROOT_ID, &name).map(|_| ()));
+        enotdir(fs.opendir(&ctx, ROOT_ID, 0).map(|_| ()));
+        enotdir(
+            fs.open(&ctx, ROOT_ID, libc::O_DIRECTORY as u32, 0)
+                .map(|_| ()),
+        );
+        enotdir(fs.readdir(&ctx, ROOT_ID, handle, 4096, 0, &mut |_| Ok(1)));
+        enotdir(fs.readdirplus(&ctx, ROOT_ID, handle, 4096, 0, &mut |_, _| Ok(1)));
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        enotdir(fs.create(&ctx, ROOT_ID, &name, args).map(|_| ()));
+        enotdir(fs.mkdir(&ctx, ROOT_ID, &name, 0o755, 0).map(|_| ()));
+    }
+
+    #[test]
+    fn test_noatime() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("file");
+        std::fs::write(&path, b"data").unwrap();
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o644)).unwrap();
+        let fs = prepare_fs_in(&source, |cfg| cfg.noatime = true);
+        let ctx = prepare_context();
+        let inode = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap()
+            .inode;
+        let open_flags = || {
+            let handle = fs
+                .open(&ctx, inode, libc::O_RDONLY as u32, 0)
+                .unwrap()
+                .0
+                .unwrap();
+            let data = fs.handle_map.get(handle, inode).unwrap();
+            // Safe because this doesn't modify any memory and we check the return value.
+            let flags = unsafe { libc::fcntl(data.borrow_fd().as_raw_fd(), libc::F_GETFL) };
+            assert!(flags >= 0);
+            flags
+        };
+
+        // The file is owned by the daemon.
+        assert_ne!(open_flags() & libc::O_NOATIME, 0);
+
+        // Without CAP_FOWNER, files owned by others are opened without O_NOATIME. Only root can
+        // own files for others.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _creds = set_creds(1000, 1000).unwrap();
+                assert_eq!(open_flags() & libc::O_NOATIME, 0);
+            });
+        });
+    }
+
+    #[test]
+    fn test_sync_on_close() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+        let fs = prepare_fs_in(&source, |cfg| cfg.sync_on_close = true);
+        let ctx = prepare_context();
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode;
+
+        let (handle, _, _) = fs.open(&ctx, inode, libc::O_WRONLY as u32, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut src = TempFile::new().unwrap().into_file();
+        src.write_all(&[0x5au8; 8192]).unwrap();
+        src.seek(SeekFrom::Start(0)).unwrap();
+        fs.write(&ctx, inode, handle, &mut src, 8192, 0, None, false, 0, 0)
+            .unwrap();
+        fs.flush(&ctx, inode, handle, 0).unwrap();
+        fs.release(&ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+        assert_eq!(
+            std::fs::read(source.as_path().join("file")).unwrap(),
+            vec![0x5au8; 8192]
+        );
+        let err = fs.flush(&ctx, inode, handle, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        // Handles opened for reading have nothing to sync.
+        let (handle, _, _) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+        let handle = handle.unwrap();
+        fs.flush(&ctx, inode, handle, 0).unwrap();
+        fs.release(&ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+    }
 }
@@ -0,0 +1,301 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Submit file reads and writes through a per thread io_uring instance.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use libc::{c_int, c_void, size_t};
+
+use crate::file_buf::FileVolatileSlice;
+use crate::file_traits::{FileReadWriteVolatile, RwfFile};
+
+// Number of submission queue entries of each ring, there's one request in flight at most.
+const RING_ENTRIES: u32 = 4;
+
+thread_local! {
+    // The ring of the current worker thread, `None` if io_uring is unavailable.
+    static ENGINE: RefCell<Option<IoUringEngine>> = RefCell::new(IoUringEngine::new().ok());
+}
+
+/// An io_uring instance to issue `IORING_OP_READ(V)`/`IORING_OP_WRITE(V)` requests.
+///
+/// Each worker thread lazily creates its own ring on first use, see
+/// [`IoUringEngine::with_thread_engine`].
+pub struct IoUringEngine {
+    ring: IoUring,
+    // Tag of the last request submitted, to find its completion.
+    next_user_data: u64,
+}
+
+impl IoUringEngine {
+    /// Create a new io_uring instance.
+    ///
+    /// Fails on kernels without io_uring (older than 5.1), or without `IORING_FEAT_NODROP` and
+    /// the read/write opcodes, so callers can fall back to blocking system calls.
+    pub fn new() -> io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+
+        // Completions may get lost on overflow without IORING_FEAT_NODROP.
+        if !ring.params().is_feature_nodrop() {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        for code in [
+            opcode::Read::CODE,
+            opcode::Readv::CODE,
+            opcode::Write::CODE,
+            opcode::Writev::CODE,
+        ] {
+            if !probe.is_supported(code) {
+                return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+            }
+        }
+
+        Ok(IoUringEngine {
+            ring,
+            next_user_data: 0,
+        })
+    }
+
+    /// Run `f` with the ring of the current thread, or return `None` if io_uring is unavailable.
+    pub fn with_thread_engine<R>(f: impl FnOnce(&mut IoUringEngine) -> R) -> Option<R> {
+        ENGINE.with(|e| e.borrow_mut().as_mut().map(f))
+    }
+
+    /// Read from `fd` at `offset` into `bufs` with the `RWF_*` flags `flags`, returning the number
+    /// of bytes read.
+    pub fn read_vectored_at(
+        &mut self,
+        fd: RawFd,
+        bufs: &[FileVolatileSlice],
+        offset: u64,
+        flags: c_int,
+    ) -> io::Result<usize> {
+        let entry = match bufs {
+            [] => return Ok(0),
+            [buf] => opcode::Read::new(types::Fd(fd), buf.as_ptr(), buf.len() as u32)
+                .offset64(offset as libc::off64_t)
+                .rw_flags(flags)
+                .build(),
+            _ => {
+                let iovecs = Self::iovecs(bufs);
+                // The iovecs must stay alive until the request completes.
+                return self.submit_and_wait(
+                    opcode::Readv::new(types::Fd(fd), iovecs.as_ptr(), iovecs.len() as u32)
+                        .offset64(offset as libc::off64_t)
+                        .rw_flags(flags)
+                        .build(),
+                );
+            }
+        };
+
+        self.submit_and_wait(entry)
+    }
+
+    /// Write `bufs` to `fd` at `offset` with the `RWF_*` flags `flags`, returning the number of
+    /// bytes written.
+    pub fn write_vectored_at(
+        &mut self,
+        fd: RawFd,
+        bufs: &[FileVolatileSlice],
+        offset: u64,
+        flags: c_int,
+    ) -> io::Result<usize> {
+        let entry = match bufs {
+            [] => return Ok(0),
+            [buf] => opcode::Write::new(types::Fd(fd), buf.as_ptr(), buf.len() as u32)
+                .offset64(offset as libc::off64_t)
+                .rw_flags(flags)
+                .build(),
+            _ => {
+                let iovecs = Self::iovecs(bufs);
+                // The iovecs must stay alive until the request completes.
+                return self.submit_and_wait(
+                    opcode::Writev::new(types::Fd(fd), iovecs.as_ptr(), iovecs.len() as u32)
+                        .offset64(offset as libc::off64_t)
+                        .rw_flags(flags)
+                        .build(),
+                );
+            }
+        };
+
+        self.submit_and_wait(entry)
+    }
+
+    fn iovecs(bufs: &[FileVolatileSlice]) -> Vec<libc::iovec> {
+        bufs.iter()
+            .map(|s| libc::iovec {
+                iov_base: s.as_ptr() as *mut c_void,
+                iov_len: s.len() as size_t,
+            })
+            .collect()
+    }
+
+    fn submit_and_wait(&mut self, entry: squeue::Entry) -> io::Result<usize> {
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+        let user_data = self.next_user_data;
+        let entry = entry.user_data(user_data);
+
+        // Safe because the buffers referenced by `entry` outlive the request, which is reaped
+        // below before returning.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))?;
+
+        // The buffers may only be released once the kernel is done with them, so keep waiting
+        // until the completion of this very request shows up, even if interrupted by signals.
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // Out of resources, or the completion queue is full, try again.
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EBUSY)) => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+
+            // Completions of earlier requests have all been reaped, skip any stray one.
+            if let Some(cqe) = self
+                .ring
+                .completion()
+                .find(|cqe| cqe.user_data() == user_data)
+            {
+                return if cqe.result() < 0 {
+                    Err(io::Error::from_raw_os_error(-cqe.result()))
+                } else {
+                    Ok(cqe.result() as usize)
+                };
+            }
+        }
+    }
+}
+
+/// A [`File`] wrapper doing positioned IO through the io_uring of the current thread, passing the
+/// `RWF_*` flags given at creation.
+///
+/// Falls back to blocking system calls, with the same flags, if io_uring is unavailable.
+pub(crate) struct UringFile<'a> {
+    file: &'a mut File,
+    flags: c_int,
+}
+
+impl<'a> UringFile<'a> {
+    /// Wrap `file` to pass `flags` to positioned IO.
+    ///
+    /// `RWF_HIPRI` is only passed to the fallback, rings without `IORING_SETUP_IOPOLL` reject it.
+    pub fn new(file: &'a mut File, flags: c_int) -> Self {
+        UringFile { file, flags }
+    }
+}
+
+impl FileReadWriteVolatile for UringFile<'_> {
+    fn read_volatile(&mut self, slice: FileVolatileSlice) -> io::Result<usize> {
+        self.file.read_volatile(slice)
+    }
+
+    fn read_vectored_volatile(&mut self, bufs: &[FileVolatileSlice]) -> io::Result<usize> {
+        self.file.read_vectored_volatile(bufs)
+    }
+
+    fn write_volatile(&mut self, slice: FileVolatileSlice) -> io::Result<usize> {
+        self.file.write_volatile(slice)
+    }
+
+    fn write_vectored_volatile(&mut self, bufs: &[FileVolatileSlice]) -> io::Result<usize> {
+        self.file.write_vectored_volatile(bufs)
+    }
+
+    fn read_at_volatile(&mut self, slice: FileVolatileSlice, offset: u64) -> io::Result<usize> {
+        self.read_vectored_at_volatile(&[slice], offset)
+    }
+
+    fn read_vectored_at_volatile(
+        &mut self,
+        bufs: &[FileVolatileSlice],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let fd = self.file.as_raw_fd();
+        let flags = self.flags & !libc::RWF_HIPRI;
+        match IoUringEngine::with_thread_engine(|e| e.read_vectored_at(fd, bufs, offset, flags)) {
+            Some(res) => res,
+            None => RwfFile::new(self.file, self.flags).read_vectored_at_volatile(bufs, offset),
+        }
+    }
+
+    fn write_at_volatile(&mut self, slice: FileVolatileSlice, offset: u64) -> io::Result<usize> {
+        self.write_vectored_at_volatile(&[slice], offset)
+    }
+
+    fn write_vectored_at_volatile(
+        &mut self,
+        bufs: &[FileVolatileSlice],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let fd = self.file.as_raw_fd();
+        let flags = self.flags & !libc::RWF_HIPRI;
+        match IoUringEngine::with_thread_engine(|e| e.write_vectored_at(fd, bufs, offset, flags)) {
+            Some(res) => res,
+            None => RwfFile::new(self.file, self.flags).write_vectored_at_volatile(bufs, offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_uring_file_read_write() {
+        let mut file = TempFile::new().unwrap().into_file();
+        let data: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+        file.write_all(&data).unwrap();
+
+        let mut buf = vec![0u8; 4096];
+        let slice = unsafe { FileVolatileSlice::from_raw_ptr(buf.as_mut_ptr(), buf.len()) };
+        let cnt = UringFile::new(&mut file, 0)
+            .read_at_volatile(slice, 0)
+            .unwrap();
+        assert_eq!(cnt, 4096);
+        assert_eq!(buf, data);
+
+        // Split the read across two buffers to go through IORING_OP_READV.
+        let mut buf1 = vec![0u8; 1000];
+        let mut buf2 = vec![0u8; 3096];
+        let bufs = unsafe {
+            [
+                FileVolatileSlice::from_raw_ptr(buf1.as_mut_ptr(), buf1.len()),
+                FileVolatileSlice::from_raw_ptr(buf2.as_mut_ptr(), buf2.len()),
+            ]
+        };
+        let cnt = UringFile::new(&mut file, 0)
+            .read_vectored_at_volatile(&bufs, 0)
+            .unwrap();
+        assert_eq!(cnt, 4096);
+        assert_eq!(buf1[..], data[..1000]);
+        assert_eq!(buf2[..], data[1000..]);
+
+        let mut new = vec![0xa5u8; 4096];
+        let slice = unsafe { FileVolatileSlice::from_raw_ptr(new.as_mut_ptr(), new.len()) };
+        let cnt = UringFile::new(&mut file, 0)
+            .write_at_volatile(slice, 4096)
+            .unwrap();
+        assert_eq!(cnt, 4096);
+        assert_eq!(file.metadata().unwrap().len(), 8192);
+
+        // The flags of the request are kept, RWF_HIPRI only applies to the fallback.
+        let cnt = UringFile::new(&mut file, libc::RWF_DSYNC | libc::RWF_HIPRI)
+            .write_at_volatile(slice, 8192)
+            .unwrap();
+        assert_eq!(cnt, 4096);
+        assert_eq!(file.metadata().unwrap().len(), 12288);
+    }
+}
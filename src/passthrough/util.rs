@@ -2,19 +2,26 @@
 // found in the LICENSE-BSD-3-Clause file.
 // Copyright (C) 2023 Alibaba Cloud. All rights reserved.
 
+use std::cell::Cell;
 use std::collections::{btree_map, BTreeMap};
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
-use std::mem::MaybeUninit;
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::mem::{self, size_of, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::Mutex;
+use std::thread::LocalKey;
+
+use vm_memory::ByteValued;
 
 use super::inode_store::InodeId;
+use super::os_compat::{LinuxDirent64, OpenHow};
 use super::MAX_HOST_INO;
 use crate::abi::fuse_abi as fuse;
-use crate::api::EMPTY_CSTR;
+use crate::api::{CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR};
+use crate::bytes_to_cstr;
 
 /// the 56th bit used to set the inode to 1 indicates virtual inode
 const VIRTUAL_INODE_FLAG: u64 = 1 << 55;
@@ -142,8 +149,9 @@ pub fn openat(
     // - we check the return value
     // We do not check `flags` because if the kernel cannot handle poorly specified flags then we
     // have much bigger problems.
-    let fd = if flags & libc::O_CREAT == libc::O_CREAT {
-        // The mode argument is used only when O_CREAT is specified
+    let fd = if flags & libc::O_CREAT == libc::O_CREAT || flags & libc::O_TMPFILE == libc::O_TMPFILE
+    {
+        // The mode argument is used only when O_CREAT or O_TMPFILE is specified
         unsafe { libc::openat(dir_fd.as_raw_fd(), path.as_ptr(), flags, mode) }
     } else {
         unsafe { libc::openat(dir_fd.as_raw_fd(), path.as_ptr(), flags) }
@@ -156,6 +164,304 @@ pub fn openat(
     }
 }
 
+// Size of the buffer to read directory entries.
+const DIRENT_BUF_SIZE: usize = 32 * 1024;
+
+/// Read the names of the entries of the directory `dir`, which may be an `O_PATH` fd, except `.`
+/// and `..`.
+pub fn read_dir_names(dir: &impl AsRawFd) -> io::Result<Vec<CString>> {
+    // Safe as this is a constant value and a valid C string.
+    let cur = CStr::from_bytes_with_nul(CURRENT_DIR_CSTR).unwrap();
+    let dir = openat(
+        dir,
+        cur,
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        0,
+    )?;
+
+    let mut names = Vec::new();
+    let mut buf = vec![0u8; DIRENT_BUF_SIZE];
+    loop {
+        // Safe because the kernel guarantees that it will only write to `buf` and we check the
+        // return value.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir.as_raw_fd(),
+                buf.as_mut_ptr() as *mut LinuxDirent64,
+                buf.len() as libc::c_int,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        } else if res == 0 {
+            break;
+        }
+
+        let mut rem = &buf[..res as usize];
+        while rem.len() >= size_of::<LinuxDirent64>() {
+            let (front, back) = rem.split_at(size_of::<LinuxDirent64>());
+            let dirent64 = LinuxDirent64::from_slice(front)
+                .expect("fuse: unable to get LinuxDirent64 from slice");
+            let reclen = dirent64.d_reclen as usize;
+            let namelen = reclen - size_of::<LinuxDirent64>();
+            let name = &back[..namelen];
+            rem = &rem[reclen..];
+
+            if name.starts_with(CURRENT_DIR_CSTR) || name.starts_with(PARENT_DIR_CSTR) {
+                continue;
+            }
+            // The name is padded with '\0' bytes up to 8-byte alignment.
+            let name = match bytes_to_cstr(name) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            names.push(name.to_owned());
+        }
+    }
+
+    Ok(names)
+}
+
+/// Safe wrapper around openat2(2), restricting the resolution of `path` by the `RESOLVE_*` flags
+/// in `resolve`.
+///
+/// Fails with `ENOSYS` if the kernel doesn't support openat2(2), which is available since Linux
+/// 5.6.
+pub fn safe_openat2(
+    dir_fd: &impl AsRawFd,
+    path: &CStr,
+    flags: libc::c_int,
+    resolve: u64,
+) -> io::Result<File> {
+    let how = OpenHow {
+        flags: flags as u64,
+        mode: 0,
+        resolve,
+    };
+    // Safe because `path` is a valid NUL-terminated string, the kernel only reads `how` and we
+    // check the return value.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir_fd.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const OpenHow,
+            size_of::<OpenHow>(),
+        )
+    };
+    if fd >= 0 {
+        // Safe because we just opened this fd
+        Ok(unsafe { File::from_raw_fd(fd as libc::c_int) })
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Safe wrapper around faccessat2(2), checking the access `mode` to `path` under `dir_fd` with the
+/// `AT_*` flags in `flags`.
+///
+/// Fails with `ENOSYS` if the kernel doesn't support faccessat2(2), which is available since Linux
+/// 5.8.
+pub fn faccessat2(
+    dir_fd: &impl AsRawFd,
+    path: &CStr,
+    mode: libc::c_int,
+    flags: libc::c_int,
+) -> io::Result<()> {
+    // Safe because `path` is a valid NUL-terminated string and we check the return value.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_faccessat2,
+            dir_fd.as_raw_fd(),
+            path.as_ptr(),
+            mode,
+            flags,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Tags of POSIX ACL xattr entries, see include/uapi/linux/posix_acl.h.
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// Check whether the POSIX ACL xattr `acl` of the file `st` grants the access `mode` to `uid` and
+/// `gid`, by the access check algorithm of acl(5).
+///
+/// Return `None` if `acl` isn't a valid ACL xattr.
+pub fn posix_acl_allows(
+    acl: &[u8],
+    st: &libc::stat64,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    mode: libc::c_int,
+) -> Option<bool> {
+    // Each entry is a little-endian (tag: u16, perm: u16, id: u32), after a u32 version 2.
+    if acl.len() < 4 || acl[..4] != 2u32.to_le_bytes() {
+        return None;
+    }
+    let chunks = acl[4..].chunks_exact(8);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let entries: Vec<(u16, u32, u32)> = chunks
+        .map(|e| {
+            (
+                u16::from_le_bytes([e[0], e[1]]),
+                u16::from_le_bytes([e[2], e[3]]) as u32,
+                u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+            )
+        })
+        .collect();
+    let perm = |tag| entries.iter().find(|e| e.0 == tag).map(|e| e.1);
+    let mode = mode as u32 & 0o7;
+    let mask = perm(ACL_MASK).unwrap_or(0o7);
+
+    if st.st_uid == uid {
+        return Some(perm(ACL_USER_OBJ)? & mode == mode);
+    }
+    if let Some(e) = entries.iter().find(|e| e.0 == ACL_USER && e.2 == uid) {
+        return Some(e.1 & mask & mode == mode);
+    }
+    // If any group entry matches, access is granted if one of them has all the permissions.
+    let groups: Vec<u32> = entries
+        .iter()
+        .filter(|e| (e.0 == ACL_GROUP_OBJ && st.st_gid == gid) || (e.0 == ACL_GROUP && e.2 == gid))
+        .map(|e| e.1)
+        .collect();
+    if !groups.is_empty() {
+        return Some(groups.iter().any(|p| p & mask & mode == mode));
+    }
+    Some(perm(ACL_OTHER)? & mode == mode)
+}
+
+const PROC_SELF_FD: &[u8] = b"/proc/self/fd/";
+// Room for `/proc/self/fd/`, the digits of the largest fd and a NUL byte.
+const PROC_FD_PATH_LEN: usize = PROC_SELF_FD.len() + 10 + 1;
+
+/// The path of an fd in `/proc/self/fd`, formatted on the stack to save allocating a `CString`
+/// on each request.
+pub struct ProcFdPath {
+    buf: [u8; PROC_FD_PATH_LEN],
+    len: usize,
+}
+
+impl ProcFdPath {
+    pub fn new(fd: RawFd) -> Self {
+        debug_assert!(fd >= 0);
+        let mut digits = [0u8; 10];
+        let mut n = fd as u32;
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut buf = [0u8; PROC_FD_PATH_LEN];
+        let digits = &digits[start..];
+        buf[..PROC_SELF_FD.len()].copy_from_slice(PROC_SELF_FD);
+        buf[PROC_SELF_FD.len()..PROC_SELF_FD.len() + digits.len()].copy_from_slice(digits);
+        // The byte following the digits is already the NUL terminator.
+        ProcFdPath {
+            buf,
+            len: PROC_SELF_FD.len() + digits.len() + 1,
+        }
+    }
+
+    /// The absolute path, `/proc/self/fd/{fd}`.
+    pub fn as_path(&self) -> &CStr {
+        // Safe because the buffer holds a single NUL byte, at its end.
+        unsafe { CStr::from_bytes_with_nul_unchecked(&self.buf[..self.len]) }
+    }
+
+    /// The name of the fd, relative to `/proc/self/fd`.
+    pub fn as_name(&self) -> &CStr {
+        // Safe because the buffer holds a single NUL byte, at its end.
+        unsafe { CStr::from_bytes_with_nul_unchecked(&self.buf[PROC_SELF_FD.len()..self.len]) }
+    }
+}
+
+/// Size of the largest buffer kept by a cache for later requests, unless configured otherwise.
+pub const MAX_SCRATCH_BUF_SIZE: usize = 1 << 20;
+
+thread_local! {
+    /// Buffer for the entries returned by `getdents64(2)`.
+    pub static DIRENT_BUF: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+    /// Buffer for the targets returned by `readlinkat(2)`.
+    pub static READLINK_BUF: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+/// An empty buffer taken from a thread-local cache, to save allocating one on each request. The
+/// buffer is given back to the cache when dropped.
+///
+/// A request taking the buffer of a cache while another one holds it gets a new buffer.
+pub struct ScratchBuf {
+    buf: Vec<u8>,
+    cache: &'static LocalKey<Cell<Vec<u8>>>,
+    max_size: usize,
+}
+
+impl ScratchBuf {
+    /// Take the buffer of `cache`, with room for at least `capacity` bytes.
+    pub fn take(cache: &'static LocalKey<Cell<Vec<u8>>>, capacity: usize) -> Self {
+        Self::take_capped(cache, capacity, MAX_SCRATCH_BUF_SIZE)
+    }
+
+    /// Take the buffer of `cache` like `take()`, giving it back only if it holds at most
+    /// `max_size` bytes.
+    pub fn take_capped(
+        cache: &'static LocalKey<Cell<Vec<u8>>>,
+        capacity: usize,
+        max_size: usize,
+    ) -> Self {
+        let mut buf = cache.try_with(|c| c.take()).unwrap_or_default();
+        buf.clear();
+        buf.reserve(capacity);
+        ScratchBuf {
+            buf,
+            cache,
+            max_size,
+        }
+    }
+}
+
+impl Deref for ScratchBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for ScratchBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for ScratchBuf {
+    fn drop(&mut self) {
+        if self.buf.capacity() <= self.max_size {
+            let buf = mem::take(&mut self.buf);
+            // The cache is gone if the thread is exiting, just drop the buffer then.
+            let _ = self.cache.try_with(|c| c.set(buf));
+        }
+    }
+}
+
 /// Open `/proc/self/fd/{fd}` with the given flags to effectively duplicate the given `fd` with new
 /// flags (e.g. to turn an `O_PATH` file descriptor into one that can be used for I/O).
 pub fn reopen_fd_through_proc(
@@ -163,12 +469,12 @@ pub fn reopen_fd_through_proc(
     flags: libc::c_int,
     proc_self_fd: &impl AsRawFd,
 ) -> io::Result<File> {
-    let name = CString::new(format!("{}", fd.as_raw_fd()).as_str())?;
+    let name = ProcFdPath::new(fd.as_raw_fd());
     // Clear the `O_NOFOLLOW` flag if it is set since we need to follow the `/proc/self/fd` symlink
     // to get the file.
     openat(
         proc_self_fd,
-        &name,
+        name.as_name(),
         flags & !libc::O_NOFOLLOW & !libc::O_CREAT,
         0,
     )
@@ -197,6 +503,127 @@ pub fn stat_fd(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<libc::stat
     }
 }
 
+/// Get the `RWF_*` flags of `preadv2(2)` and `pwritev2(2)` for a file opened with `flags`.
+///
+/// Direct IO polls for completion with `RWF_HIPRI`, which only applies to direct IO. Synchronous
+/// writes are requested with `RWF_SYNC` or `RWF_DSYNC`, as the flags of an fd can't be changed
+/// to `O_SYNC` or `O_DSYNC`. `O_NONBLOCK` isn't turned into `RWF_NOWAIT`, which fails reads
+/// missing the page cache with `EAGAIN`, while regular files never block.
+pub fn rwf_flags(flags: u32) -> i32 {
+    let flags = flags as i32;
+    let mut rwf = 0;
+    if flags & libc::O_DIRECT != 0 {
+        rwf |= libc::RWF_HIPRI;
+    }
+    if flags & libc::O_SYNC == libc::O_SYNC {
+        rwf |= libc::RWF_SYNC;
+    } else if flags & libc::O_DSYNC != 0 {
+        rwf |= libc::RWF_DSYNC;
+    }
+    rwf
+}
+
+/// Transfer `count` bytes at offset `off` by calling `op` with the count and offset of the bytes
+/// left, until all of them are transferred or `op` returns 0 at the end of the file.
+///
+/// Short transfers happen with files on network or FUSE file systems, and must not be mistaken
+/// for the whole request by the client. `EINTR` is retried, and errors after some bytes have
+/// been transferred are left for the next request, like a short `read(2)` or `write(2)`.
+pub fn transfer_all(
+    count: usize,
+    off: u64,
+    mut op: impl FnMut(usize, u64) -> io::Result<usize>,
+) -> io::Result<usize> {
+    let mut done = 0;
+    while done < count {
+        match op(count - done, off + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+/// Copy `len` bytes from `fd_in` at `offset_in` to `fd_out` at `offset_out` with
+/// `copy_file_range(2)`, and return the number of bytes copied, which is less than `len` at the
+/// end of the source file, or if an error happened after some bytes have been copied.
+pub fn copy_file_range_all(
+    fd_in: &impl AsRawFd,
+    offset_in: u64,
+    fd_out: &impl AsRawFd,
+    offset_out: u64,
+    len: u64,
+    flags: u64,
+) -> io::Result<usize> {
+    let mut off_in = offset_in as libc::off64_t;
+    let mut off_out = offset_out as libc::off64_t;
+    let mut copied = 0usize;
+    while (copied as u64) < len {
+        // Safe because this doesn't modify any memory other than the offsets, which are owned
+        // by us, and we check the return value. Kernels without copy_file_range(2) fail with
+        // ENOSYS, which makes the fuse client fall back to read/write.
+        let res = unsafe {
+            libc::copy_file_range(
+                fd_in.as_raw_fd(),
+                &mut off_in,
+                fd_out.as_raw_fd(),
+                &mut off_out,
+                (len - copied as u64) as usize,
+                flags as libc::c_uint,
+            )
+        };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            // Report the partial copy so that the client retries the remainder.
+            if copied > 0 {
+                break;
+            }
+            return Err(e);
+        } else if res == 0 {
+            // Reached the end of the source file.
+            break;
+        }
+        copied += res as usize;
+    }
+
+    Ok(copied)
+}
+
+/// Check the mode of `fallocate(2)`, failing like the host kernel would on combinations of flags
+/// which aren't supported, before any file system specific error.
+pub fn check_fallocate_mode(mode: i32) -> io::Result<()> {
+    let supported = libc::FALLOC_FL_KEEP_SIZE
+        | libc::FALLOC_FL_PUNCH_HOLE
+        | libc::FALLOC_FL_COLLAPSE_RANGE
+        | libc::FALLOC_FL_ZERO_RANGE
+        | libc::FALLOC_FL_INSERT_RANGE
+        | libc::FALLOC_FL_UNSHARE_RANGE;
+    let eopnotsupp = || io::Error::from_raw_os_error(libc::EOPNOTSUPP);
+    let exclusive = |flag: i32, allowed: i32| mode & flag != 0 && mode & !(flag | allowed) != 0;
+
+    if mode & !supported != 0 {
+        return Err(eopnotsupp());
+    }
+    // Punching holes never changes the size, and can't zero ranges at the same time.
+    let punch_zero = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE;
+    if mode & punch_zero == punch_zero
+        || (mode & libc::FALLOC_FL_PUNCH_HOLE != 0 && mode & libc::FALLOC_FL_KEEP_SIZE == 0)
+    {
+        return Err(eopnotsupp());
+    }
+    if exclusive(libc::FALLOC_FL_COLLAPSE_RANGE, 0)
+        || exclusive(libc::FALLOC_FL_INSERT_RANGE, 0)
+        || exclusive(libc::FALLOC_FL_UNSHARE_RANGE, libc::FALLOC_FL_KEEP_SIZE)
+    {
+        return Err(einval());
+    }
+
+    Ok(())
+}
+
 /// Returns true if it's safe to open this inode without O_PATH.
 pub fn is_safe_inode(mode: u32) -> bool {
     // Only regular files and directories are considered safe to be opened from the file
@@ -204,11 +631,6 @@ pub fn is_safe_inode(mode: u32) -> bool {
     matches!(mode & libc::S_IFMT, libc::S_IFREG | libc::S_IFDIR)
 }
 
-/// Returns true if the mode is for a directory.
-pub fn is_dir(mode: u32) -> bool {
-    (mode & libc::S_IFMT) == libc::S_IFDIR
-}
-
 pub fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
@@ -228,6 +650,10 @@ pub fn eperm() -> io::Error {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::passthrough::os_compat::{RESOLVE_BENEATH, RESOLVE_NO_MAGICLINKS};
+    use std::ffi::CString;
+    use std::io::Read;
+    use vmm_sys_util::tempdir::TempDir;
 
     #[test]
     fn test_is_safe_inode() {
@@ -253,15 +679,6 @@ mod tests {
         assert!(!is_safe_inode(mode));
     }
 
-    #[test]
-    fn test_is_dir() {
-        let mode = libc::S_IFREG;
-        assert!(!is_dir(mode));
-
-        let mode = libc::S_IFDIR;
-        assert!(is_dir(mode));
-    }
-
     #[test]
     fn test_generate_unique_inode() {
         // use normal inode format
@@ -370,6 +787,123 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_transfer_all() {
+        let mut calls = Vec::new();
+        let mut results = vec![
+            Ok(3),
+            Err(io::Error::from_raw_os_error(libc::EINTR)),
+            Ok(2),
+            Ok(5),
+        ]
+        .into_iter();
+        let res = transfer_all(10, 100, |count, off| {
+            calls.push((count, off));
+            results.next().unwrap()
+        });
+        assert_eq!(res.unwrap(), 10);
+        assert_eq!(calls, vec![(10, 100), (7, 103), (7, 103), (5, 105)]);
+
+        // Stop at the end of the file, or on an error after a short transfer.
+        let mut results = vec![Ok(4), Ok(0)].into_iter();
+        assert_eq!(
+            transfer_all(10, 0, |_, _| results.next().unwrap()).unwrap(),
+            4
+        );
+        let mut results = vec![Ok(4), Err(io::Error::from_raw_os_error(libc::EIO))].into_iter();
+        assert_eq!(
+            transfer_all(10, 0, |_, _| results.next().unwrap()).unwrap(),
+            4
+        );
+        let err = transfer_all(10, 0, |_, _| Err(io::Error::from_raw_os_error(libc::EIO)));
+        assert_eq!(err.unwrap_err().raw_os_error(), Some(libc::EIO));
+    }
+
+    #[test]
+    #[cfg(not(feature = "io-uring"))]
+    fn test_rwf_flags() {
+        assert_eq!(rwf_flags(libc::O_RDWR as u32), 0);
+        assert_eq!(rwf_flags(libc::O_NONBLOCK as u32), 0);
+        assert_eq!(rwf_flags(libc::O_DIRECT as u32), libc::RWF_HIPRI);
+        assert_eq!(rwf_flags(libc::O_DSYNC as u32), libc::RWF_DSYNC);
+        assert_eq!(
+            rwf_flags((libc::O_SYNC | libc::O_DIRECT) as u32),
+            libc::RWF_SYNC | libc::RWF_HIPRI
+        );
+    }
+
+    #[test]
+    fn test_proc_fd_path() {
+        for fd in [0, 7, 10, 1234, i32::MAX] {
+            let path = ProcFdPath::new(fd);
+            assert_eq!(
+                path.as_path().to_str().unwrap(),
+                format!("/proc/self/fd/{}", fd)
+            );
+            assert_eq!(path.as_name().to_str().unwrap(), fd.to_string());
+        }
+
+        let file = File::open("/dev/null").unwrap();
+        let path = ProcFdPath::new(file.as_raw_fd());
+        let target = std::fs::read_link(path.as_path().to_str().unwrap()).unwrap();
+        assert_eq!(target, std::path::Path::new("/dev/null"));
+    }
+
+    #[test]
+    fn test_scratch_buf() {
+        thread_local! {
+            static BUF: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+        }
+
+        let mut buf = ScratchBuf::take(&BUF, 100);
+        assert!(buf.capacity() >= 100);
+        buf.extend_from_slice(b"data");
+        let ptr = buf.as_ptr();
+        // The buffer is taken, so another request gets its own.
+        let other = ScratchBuf::take(&BUF, 10);
+        assert_ne!(other.as_ptr(), ptr);
+        drop(other);
+        drop(buf);
+
+        // The buffer is reused, emptied.
+        let buf = ScratchBuf::take(&BUF, 10);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.is_empty());
+        drop(buf);
+
+        // Large buffers aren't kept.
+        let buf = ScratchBuf::take(&BUF, MAX_SCRATCH_BUF_SIZE + 1);
+        drop(buf);
+        assert_eq!(BUF.with(|b| b.take().capacity()), 0);
+
+        // Nor are buffers larger than the cap of the cache.
+        let buf = ScratchBuf::take_capped(&BUF, 4096, 1024);
+        drop(buf);
+        assert_eq!(BUF.with(|b| b.take().capacity()), 0);
+    }
+
+    // Compare the time to format paths of fds with `format!()` and `ProcFdPath`, with
+    // `cargo test -- --ignored --nocapture bench_proc_fd_path`.
+    #[test]
+    #[ignore]
+    fn bench_proc_fd_path() {
+        const ROUNDS: i32 = 1_000_000;
+
+        let start = std::time::Instant::now();
+        for fd in 0..ROUNDS {
+            let path = CString::new(format!("/proc/self/fd/{}", fd)).unwrap();
+            std::hint::black_box(path);
+        }
+        println!("format!(): {:?}", start.elapsed());
+
+        let start = std::time::Instant::now();
+        for fd in 0..ROUNDS {
+            let path = ProcFdPath::new(fd);
+            std::hint::black_box(path.as_path());
+        }
+        println!("ProcFdPath: {:?}", start.elapsed());
+    }
+
     #[test]
     fn test_stat_fd() {
         let topdir = env!("CARGO_MANIFEST_DIR");
@@ -382,4 +916,33 @@ mod tests {
         assert_eq!(st1.st_dev, st2.st_dev);
         assert_ne!(st1.st_ino, st2.st_ino);
     }
+
+    #[test]
+    fn test_safe_openat2() {
+        let dir = TempDir::new().unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.as_path().join("passwd")).unwrap();
+        std::fs::create_dir(dir.as_path().join("etc")).unwrap();
+        std::fs::write(dir.as_path().join("etc/passwd"), b"inside").unwrap();
+        let dir_file = File::open(dir.as_path()).unwrap();
+        let name = CString::new("passwd").unwrap();
+
+        let open =
+            |resolve| safe_openat2(&dir_file, &name, libc::O_RDONLY | libc::O_CLOEXEC, resolve);
+        match open(0) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => return,
+            res => assert!(res.is_ok()),
+        }
+
+        // The symlink points outside of `dir`, so RESOLVE_BENEATH and RESOLVE_NO_SYMLINKS fail.
+        let err = open(RESOLVE_BENEATH).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+        let err = open(0x04).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+
+        // Absolute symlinks are resolved relative to `dir` with RESOLVE_IN_ROOT instead.
+        let mut file = open(0x10 | RESOLVE_NO_MAGICLINKS).unwrap();
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "inside");
+    }
 }
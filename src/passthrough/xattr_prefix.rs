@@ -0,0 +1,132 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Allow and rename extended attributes by the prefixes of their names.
+//!
+//! Unlike the rules of the `xattrmap` module, which prepend to names, a remapped prefix is
+//! replaced, e.g. `user.foo` from the client is `trusted.foo` on the host.
+
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::io;
+
+/// Prefixes of extended attribute names to allow and rename, see `Config::xattr_prefix_map`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct XattrPrefixMap {
+    /// Prefixes of the names the client may use. All names are allowed if empty.
+    pub allow: Vec<String>,
+    /// Pairs of a prefix of names from the client and the prefix replacing it on the host. The
+    /// first pair matching a name applies.
+    pub remap: Vec<(String, String)>,
+}
+
+impl XattrPrefixMap {
+    fn is_allowed(&self, name: &[u8]) -> bool {
+        self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|prefix| name.starts_with(prefix.as_bytes()))
+    }
+
+    fn to_host<'a>(&self, name: &'a [u8]) -> Cow<'a, [u8]> {
+        for (client, host) in self.remap.iter() {
+            if let Some(rest) = name.strip_prefix(client.as_bytes()) {
+                return Cow::Owned([host.as_bytes(), rest].concat());
+            }
+        }
+        Cow::Borrowed(name)
+    }
+
+    /// Translate the name of an extended attribute from the client into the name on the host,
+    /// failing with `EPERM` if the name isn't allowed.
+    pub fn map_client_xattr<'a>(&self, name: &'a CStr) -> io::Result<Cow<'a, CStr>> {
+        if !self.is_allowed(name.to_bytes()) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        match self.to_host(name.to_bytes()) {
+            Cow::Borrowed(_) => Ok(Cow::Borrowed(name)),
+            // The prefixes are strings without NUL bytes, so safe to unwrap().
+            Cow::Owned(host) => Ok(Cow::Owned(CString::new(host).unwrap())),
+        }
+    }
+
+    /// Translate the name of an extended attribute on the host into the name seen by the client,
+    /// or `None` if the client can't use it, either because it isn't allowed, or because the
+    /// name seen by the client would be translated into another name on the host.
+    pub fn map_host_xattr<'a>(&self, name: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        let client = self
+            .remap
+            .iter()
+            .find_map(|(client, host)| {
+                let rest = name.strip_prefix(host.as_bytes())?;
+                Some(Cow::Owned([client.as_bytes(), rest].concat()))
+            })
+            .unwrap_or(Cow::Borrowed(name));
+
+        (self.is_allowed(&client) && self.to_host(&client) == name).then_some(client)
+    }
+
+    /// Translate a list of NUL-terminated names on the host, as returned by `listxattr(2)`, into
+    /// the names seen by the client.
+    pub fn map_host_xattrlist(&self, names: &[u8]) -> Vec<u8> {
+        let mut list = Vec::with_capacity(names.len());
+        for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+            if let Some(name) = self.map_host_xattr(name) {
+                list.extend_from_slice(&name);
+                list.push(0);
+            }
+        }
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xattr_prefix_map() {
+        let map = XattrPrefixMap {
+            allow: vec!["user.".to_string(), "security.".to_string()],
+            remap: vec![
+                ("user.".to_string(), "trusted.".to_string()),
+                ("security.guest.".to_string(), "user.guest.".to_string()),
+            ],
+        };
+        let name = |n: &str| CString::new(n).unwrap();
+
+        let host = |n: &str| map.map_client_xattr(&name(n)).map(|host| host.into_owned());
+        assert_eq!(host("user.foo").unwrap(), name("trusted.foo"));
+        assert_eq!(host("security.guest.x").unwrap(), name("user.guest.x"));
+        assert_eq!(host("security.selinux").unwrap(), name("security.selinux"));
+        let err = host("system.posix_acl_access").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+
+        assert_eq!(
+            map.map_host_xattr(b"trusted.foo").as_deref(),
+            Some(&b"user.foo"[..])
+        );
+        // user.guest.x is seen as security.guest.x, and user.plain can't be addressed.
+        assert_eq!(
+            map.map_host_xattr(b"user.guest.x").as_deref(),
+            Some(&b"security.guest.x"[..])
+        );
+        assert_eq!(map.map_host_xattr(b"user.plain"), None);
+        assert_eq!(map.map_host_xattr(b"system.posix_acl_access"), None);
+
+        let list = map.map_host_xattrlist(b"trusted.foo\0user.plain\0security.selinux\0");
+        assert_eq!(list, b"user.foo\0security.selinux\0");
+
+        // Without allowed prefixes, names are only remapped.
+        let map = XattrPrefixMap {
+            allow: Vec::new(),
+            remap: vec![("user.".to_string(), "trusted.".to_string())],
+        };
+        assert!(map.map_client_xattr(&name("system.foo")).is_ok());
+        assert_eq!(
+            map.map_host_xattrlist(b"trusted.foo\0system.foo\0"),
+            b"user.foo\0system.foo\0"
+        );
+    }
+}
@@ -99,6 +99,7 @@
 #![deny(missing_docs)]
 
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
 use std::fmt;
@@ -407,13 +408,21 @@ impl XattrMap {
     ///
     /// Client request -> listxattr() -> this method -> server response
     ///
+    /// Names that don't map to anything, i.e. match no rule or are stripped down to an empty
+    /// name, are hidden from the client. When several names map to the same name, the name is
+    /// only listed once.
+    ///
     /// See also: listxattr(2)
     pub fn map_server_xattrlist(&self, xattr_names: Vec<u8>) -> Result<Vec<u8>, Error> {
         let mut filtered = Vec::with_capacity(xattr_names.len());
+        let mut seen = HashSet::new();
         let unprocessed = xattr_names.split(|b| *b == 0).filter(|bs| !bs.is_empty());
 
         for xattr_name in unprocessed {
-            let rule = self.find_rule(Scope::SERVER, xattr_name)?;
+            let rule = match self.find_rule(Scope::SERVER, xattr_name) {
+                Ok(rule) => rule,
+                Err(_) => continue, // hide names without a mapping from the client
+            };
 
             let processed = match rule.type_ {
                 Type::Bad | Type::Unsupported => continue, // hide this from the client
@@ -421,15 +430,14 @@ impl XattrMap {
                 Type::Prefix => &xattr_name[rule.prepend.as_bytes().len()..], // strip prefix
                 Type::Map => panic!("Unexpanded MAP rule was found."),
             };
+            if processed.is_empty() || !seen.insert(processed) {
+                continue;
+            }
 
             filtered.extend_from_slice(processed);
             filtered.push(0);
         }
 
-        if filtered.is_empty() {
-            filtered.push(0);
-        }
-
         filtered.shrink_to_fit();
 
         Ok(filtered)
@@ -851,4 +859,69 @@ mod tests {
         let actual = map.map_server_xattrlist(list).unwrap();
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_rule_bad_hides_all_xattr_names_from_client() {
+        let map = XattrMap {
+            rules: vec![Rule {
+                type_: Type::Bad,
+                scope: Scope::SERVER,
+                key: CString::new("").unwrap(),
+                prepend: CString::new("").unwrap(),
+            }],
+        };
+
+        let list = b"security.secret\x00trusted.secret\x00".to_vec();
+        let actual = map.map_server_xattrlist(list).unwrap();
+        assert!(actual.is_empty());
+    }
+
+    #[test]
+    fn test_rule_unmatched_xattr_names_are_hidden_from_client() {
+        let map = XattrMap {
+            rules: vec![Rule {
+                type_: Type::Prefix,
+                scope: Scope::CLIENT | Scope::SERVER,
+                key: CString::new("").unwrap(),
+                prepend: CString::new("user.virtiofs.").unwrap(),
+            }],
+        };
+
+        // Names without a rule, and names stripped down to nothing are hidden.
+        let list = b"user.virtiofs.x\x00security.selinux\x00user.virtiofs.\x00".to_vec();
+        let actual = map.map_server_xattrlist(list).unwrap();
+        assert_eq!(actual, b"x\x00".to_vec());
+    }
+
+    #[test]
+    fn test_rule_colliding_xattr_names_are_listed_once() {
+        let map = XattrMap {
+            rules: vec![
+                Rule {
+                    type_: Type::Prefix,
+                    scope: Scope::CLIENT | Scope::SERVER,
+                    key: CString::new("trusted.").unwrap(),
+                    prepend: CString::new("user.virtiofs.").unwrap(),
+                },
+                Rule {
+                    type_: Type::Okay,
+                    scope: Scope::CLIENT | Scope::SERVER,
+                    key: CString::new("").unwrap(),
+                    prepend: CString::new("").unwrap(),
+                },
+            ],
+        };
+
+        // Both names show up as "trusted.x" on the client.
+        let list = b"trusted.x\x00user.virtiofs.trusted.x\x00user.y\x00".to_vec();
+        let actual = map.map_server_xattrlist(list).unwrap();
+        assert_eq!(actual, b"trusted.x\x00user.y\x00".to_vec());
+
+        // A remapped name resolves to the prefixed name on the host.
+        let input = CString::new("trusted.x").unwrap();
+        let actual = map.map_client_xattr(&input).unwrap();
+        let expected =
+            AppliedRule::Pass(Cow::Owned(CString::new("user.virtiofs.trusted.x").unwrap()));
+        assert_eq!(actual, expected);
+    }
 }
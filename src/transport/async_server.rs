@@ -0,0 +1,1364 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serve FUSE requests with a `TokioFileSystem` on a multi-threaded tokio runtime.
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use vm_memory::ByteValued;
+
+use super::{FuseBuf, FuseChannel, FuseDevWriter, Reader};
+use crate::abi::fuse_abi::{
+    stat64, statvfs64, AttrOut, CreateIn, FlushIn, FsOptions, FsyncIn, GetattrIn, InHeader, Opcode,
+    OpenIn, OpenOptions, OpenOut, OutHeader, ReadIn, ReleaseIn, SetattrValid, WriteIn, WriteOut,
+    GETATTR_FH, READ_LOCKOWNER, RELEASE_FLOCK_UNLOCK, RELEASE_FLUSH, WRITE_CACHE, WRITE_LOCKOWNER,
+};
+#[cfg(feature = "virtiofs")]
+use crate::abi::virtio_fs::RemovemappingOne;
+use crate::api::async_filesystem::TokioFileSystem;
+use crate::api::filesystem::{
+    Context, DirEntry, Entry, FileLock, FileSystem, GetxattrReply, IoctlData, ListxattrReply,
+    ZeroCopyReader, ZeroCopyWriter,
+};
+use crate::api::server::{read_obj_at, Server, MAX_BUFFER_SIZE};
+use crate::file_traits::{FileReadWriteVolatile, RwfFile};
+#[cfg(feature = "virtiofs")]
+use crate::transport::FsCacheReqHandler;
+use crate::{encode_io_error_kind, Error, Result};
+
+// Size of the buffer for replies, large enough for the biggest read.
+const REPLY_BUFFER_SIZE: usize = MAX_BUFFER_SIZE as usize + 0x1000;
+
+// Default number of requests handled at the same time by `AsyncServer::serve()`.
+const DEFAULT_MAX_REQUESTS: usize = 64;
+
+// Requests handled by tasks running the futures of the file system, the others are handled by the
+// `Server` on the blocking threads of the runtime.
+const TASK_OPCODES: [Opcode; 7] = [
+    Opcode::Getattr,
+    Opcode::Open,
+    Opcode::Read,
+    Opcode::Write,
+    Opcode::Flush,
+    Opcode::Fsync,
+    Opcode::Release,
+];
+
+// Buffers of a request and its reply, reused by the following requests.
+#[derive(Default)]
+struct Buffers {
+    request: Vec<u8>,
+    reply: Vec<u8>,
+}
+
+/// Serve FUSE requests with a [`TokioFileSystem`] on a multi-threaded tokio runtime.
+///
+/// Every request is handled by a task of its own, so a request waiting for IO doesn't hold up the
+/// others. Requests on open files, i.e. getattr, open, read, write, flush, fsync and release, are
+/// decoded by tasks spawned onto the runtime, which run the futures of the file system and reply
+/// to them. Other requests are decoded by a [`Server`] on the blocking threads of the runtime,
+/// which then wait for the futures of the file system.
+///
+/// ## Examples
+/// ```ignore
+/// let server = AsyncServer::new(fs, 4)?;
+/// let mut channel = session.new_channel()?;
+/// server.serve(&mut channel)?;
+/// ```
+pub struct AsyncServer<F: TokioFileSystem + 'static> {
+    fs: Arc<F>,
+    server: Arc<Server<BlockOn<F>>>,
+    runtime: Runtime,
+    buffers: Arc<Mutex<Vec<Buffers>>>,
+    max_requests: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl<F: TokioFileSystem + 'static> AsyncServer<F> {
+    /// Create a server for `fs`, with a runtime of `threads` worker threads.
+    pub fn new(fs: F, threads: usize) -> io::Result<Self> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name("fuse-async")
+            .build()?;
+        let fs = Arc::new(fs);
+        let block_on = BlockOn {
+            fs: fs.clone(),
+            rt: runtime.handle().clone(),
+        };
+
+        Ok(AsyncServer {
+            fs,
+            server: Arc::new(Server::new(block_on, None)),
+            runtime,
+            buffers: Arc::new(Mutex::new(Vec::new())),
+            max_requests: DEFAULT_MAX_REQUESTS,
+            permits: Arc::new(Semaphore::new(DEFAULT_MAX_REQUESTS)),
+        })
+    }
+
+    /// Set how many requests `serve()` handles at the same time, further requests are only read
+    /// from the channel once earlier ones have been replied to.
+    ///
+    /// Each request in flight holds a reply buffer of about `MAX_BUFFER_SIZE` bytes, and those
+    /// handled by the `Server` hold a blocking thread of the runtime. The default value is 64.
+    pub fn set_max_requests(&mut self, max: usize) {
+        let max = max.max(1);
+        self.max_requests = max;
+        self.permits = Arc::new(Semaphore::new(max));
+    }
+
+    /// Get the runtime handling requests, e.g. to spawn tasks of the file system on it.
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    /// Handle the FUSE request `request` in the background, replying to the fuse device `fd`.
+    ///
+    /// The returned handle resolves to the size of the reply.
+    pub fn spawn_request(&self, fd: RawFd, request: Vec<u8>) -> JoinHandle<Result<usize>> {
+        let buffers = Buffers {
+            request,
+            ..self.take_buffers()
+        };
+        self.spawn_buffers(fd, buffers)
+    }
+
+    /// Serve requests from `channel` until the session is shut down.
+    ///
+    /// At most `set_max_requests()` requests are handled at the same time. Return once all the
+    /// requests read from `channel` have been replied to. This must not be called from a thread of
+    /// the runtime.
+    pub fn serve(&self, channel: &mut FuseChannel) -> super::Result<()> {
+        let res = self.serve_requests(channel);
+        // The semaphore is never closed, so safe to unwrap().
+        let _all = self
+            .runtime
+            .block_on(self.permits.acquire_many(self.max_requests as u32))
+            .unwrap();
+        res
+    }
+
+    fn serve_requests(&self, channel: &mut FuseChannel) -> super::Result<()> {
+        let fd = channel.as_raw_fd();
+        loop {
+            // The semaphore is never closed, so safe to unwrap().
+            let permit = self
+                .runtime
+                .block_on(self.permits.clone().acquire_owned())
+                .unwrap();
+            let mut reader = match channel.get_request()? {
+                Some((reader, _)) => reader,
+                None => return Ok(()),
+            };
+            let mut buffers = self.take_buffers();
+            buffers.request.clear();
+            buffers.request.resize(reader.available_bytes(), 0);
+            reader
+                .read_exact(&mut buffers.request)
+                .map_err(super::Error::IoError)?;
+            let task = self.spawn_buffers(fd, buffers);
+            self.runtime.spawn(async move {
+                match task.await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => error!("fuse: failed to handle request: {}", e),
+                    Err(e) => error!("fuse: request task failed: {}", e),
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    // Take buffers left by earlier requests, or new ones.
+    fn take_buffers(&self) -> Buffers {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    // Handle the request in `buffers.request` in the background, then keep the buffers for the
+    // following requests.
+    fn spawn_buffers(&self, fd: RawFd, mut buffers: Buffers) -> JoinHandle<Result<usize>> {
+        let pool = self.buffers.clone();
+        let max_buffers = self.max_requests;
+        let keep = move |buffers: Buffers| {
+            // Do not expect poisoned lock here, so safe to unwrap().
+            let mut pool = pool.lock().unwrap();
+            if pool.len() < max_buffers {
+                pool.push(buffers);
+            }
+        };
+        if buffers.reply.len() < REPLY_BUFFER_SIZE {
+            buffers.reply.resize(REPLY_BUFFER_SIZE, 0);
+        }
+
+        let opcode = read_obj_at::<InHeader>(&buffers.request, 0).map(|h| h.opcode);
+        if TASK_OPCODES.iter().any(|&op| Some(op as u32) == opcode) {
+            let fs = self.fs.clone();
+            self.runtime.spawn(async move {
+                let res = handle_task_request(&*fs, fd, &mut buffers).await;
+                keep(buffers);
+                res
+            })
+        } else {
+            let server = self.server.clone();
+            self.runtime.spawn_blocking(move || {
+                let res = handle_request(&server, fd, &mut buffers);
+                keep(buffers);
+                res
+            })
+        }
+    }
+}
+
+fn handle_request<F: TokioFileSystem>(
+    server: &Server<BlockOn<F>>,
+    fd: RawFd,
+    buffers: &mut Buffers,
+) -> Result<usize> {
+    let invalid = |e| Error::InvalidMessage(io::Error::new(io::ErrorKind::InvalidData, e));
+    let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut buffers.request))
+        .map_err(|e| invalid(e.to_string()))?;
+    let writer =
+        FuseDevWriter::<()>::new(fd, &mut buffers.reply).map_err(|e| invalid(e.to_string()))?;
+    server.handle_message(reader, writer.into(), None, None)
+}
+
+// Handle a request of `TASK_OPCODES` with the futures of `fs`, the same way as the `Server` does.
+async fn handle_task_request<F: TokioFileSystem>(
+    fs: &F,
+    fd: RawFd,
+    buffers: &mut Buffers,
+) -> Result<usize> {
+    let einval = || Error::DecodeMessage(io::Error::from_raw_os_error(libc::EINVAL));
+    let in_header: InHeader = read_obj_at(&buffers.request, 0).ok_or_else(einval)?;
+    let body = buffers
+        .request
+        .get_mut(size_of::<InHeader>()..in_header.len as usize)
+        .ok_or_else(einval)?;
+    let mut w = FuseDevWriter::<()>::new(fd, &mut buffers.reply).map_err(|e| {
+        Error::InvalidMessage(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    })?;
+    let mut ctx = Context::from(&in_header);
+    fs.id_remap(&mut ctx)
+        .await
+        .map_err(|_| Error::FailedToRemapID((ctx.uid, ctx.gid)))?;
+    let unique = in_header.unique;
+    let inode = in_header.nodeid.into();
+
+    trace!(
+        "fuse: new req {:?}: {:?}",
+        Opcode::from(in_header.opcode),
+        in_header
+    );
+
+    match in_header.opcode {
+        x if x == Opcode::Getattr as u32 => {
+            let GetattrIn { flags, fh, .. } = read_obj_at(body, 0).ok_or_else(einval)?;
+            let handle = if (flags & GETATTR_FH) != 0 {
+                Some(fh.into())
+            } else {
+                None
+            };
+            match fs.getattr(&ctx, inode, handle).await {
+                Ok((st, timeout)) => {
+                    let out = AttrOut {
+                        attr_valid: timeout.as_secs(),
+                        attr_valid_nsec: timeout.subsec_nanos(),
+                        dummy: 0,
+                        attr: st.into(),
+                    };
+                    reply_ok(&mut w, unique, out.as_slice())
+                }
+                Err(e) => reply_error(&mut w, unique, e),
+            }
+        }
+        x if x == Opcode::Open as u32 => {
+            let OpenIn { flags, fuse_flags } = read_obj_at(body, 0).ok_or_else(einval)?;
+            match fs.open(&ctx, inode, flags, fuse_flags).await {
+                Ok((handle, opts, passthrough)) => {
+                    let out = OpenOut {
+                        fh: handle.map(Into::into).unwrap_or(0),
+                        open_flags: opts.bits(),
+                        passthrough: passthrough.unwrap_or_default(),
+                    };
+                    reply_ok(&mut w, unique, out.as_slice())
+                }
+                Err(e) => reply_error(&mut w, unique, e),
+            }
+        }
+        x if x == Opcode::Read as u32 => {
+            let ReadIn {
+                fh,
+                offset,
+                size,
+                read_flags,
+                lock_owner,
+                flags,
+                ..
+            } = read_obj_at(body, 0).ok_or_else(einval)?;
+            let owner = if read_flags & READ_LOCKOWNER != 0 {
+                Some(lock_owner)
+            } else {
+                None
+            };
+
+            // Split the writer into 2 pieces: one for the `OutHeader` and the rest for the data.
+            let mut data_writer = DataWriter(
+                w.split_at(size_of::<OutHeader>())
+                    .map_err(|_| Error::InvalidHeaderLength)?,
+            );
+            let res = fs
+                .read(
+                    &ctx,
+                    inode,
+                    fh.into(),
+                    &mut data_writer,
+                    size,
+                    offset,
+                    owner,
+                    flags,
+                )
+                .await;
+            match res {
+                Ok(count) => {
+                    let out = OutHeader {
+                        len: (size_of::<OutHeader>() + count) as u32,
+                        error: 0,
+                        unique,
+                    };
+                    w.write_all(out.as_slice()).map_err(Error::EncodeMessage)?;
+                    w.commit(Some(&data_writer.0.into()))
+                        .map_err(Error::EncodeMessage)?;
+                    Ok(out.len as usize)
+                }
+                Err(e) => reply_error(&mut w, unique, e),
+            }
+        }
+        x if x == Opcode::Write as u32 => {
+            let WriteIn {
+                fh,
+                offset,
+                size,
+                fuse_flags,
+                lock_owner,
+                flags,
+                ..
+            } = read_obj_at(body, 0).ok_or_else(einval)?;
+            let owner = if fuse_flags & WRITE_LOCKOWNER != 0 {
+                Some(lock_owner)
+            } else {
+                None
+            };
+            let delayed_write = fuse_flags & WRITE_CACHE != 0;
+
+            let data = &mut body[size_of::<WriteIn>()..];
+            let mut data_reader = DataReader(
+                Reader::<()>::from_fuse_buffer(FuseBuf::new(data)).map_err(|e| {
+                    Error::InvalidMessage(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+                })?,
+            );
+            let res = fs
+                .write(
+                    &ctx,
+                    inode,
+                    fh.into(),
+                    &mut data_reader,
+                    size,
+                    offset,
+                    owner,
+                    delayed_write,
+                    flags,
+                    fuse_flags,
+                )
+                .await;
+            match res {
+                Ok(count) => {
+                    let out = WriteOut {
+                        size: count as u32,
+                        ..Default::default()
+                    };
+                    reply_ok(&mut w, unique, out.as_slice())
+                }
+                Err(e) => reply_error(&mut w, unique, e),
+            }
+        }
+        x if x == Opcode::Flush as u32 => {
+            let FlushIn { fh, lock_owner, .. } = read_obj_at(body, 0).ok_or_else(einval)?;
+            match fs.flush(&ctx, inode, fh.into(), lock_owner).await {
+                Ok(()) => reply_ok(&mut w, unique, &[]),
+                Err(e) => reply_error(&mut w, unique, e),
+            }
+        }
+        x if x == Opcode::Fsync as u32 => {
+            let FsyncIn {
+                fh, fsync_flags, ..
+            } = read_obj_at(body, 0).ok_or_else(einval)?;
+            let datasync = fsync_flags & 0x1 != 0;
+            match fs.fsync(&ctx, inode, datasync, fh.into()).await {
+                Ok(()) => reply_ok(&mut w, unique, &[]),
+                Err(e) => reply_error(&mut w, unique, e),
+            }
+        }
+        x if x == Opcode::Release as u32 => {
+            let ReleaseIn {
+                fh,
+                flags,
+                release_flags,
+                lock_owner,
+            } = read_obj_at(body, 0).ok_or_else(einval)?;
+            let flush = release_flags & RELEASE_FLUSH != 0;
+            let flock_release = release_flags & RELEASE_FLOCK_UNLOCK != 0;
+            let lock_owner = if flush || flock_release {
+                Some(lock_owner)
+            } else {
+                None
+            };
+            let res = fs
+                .release(
+                    &ctx,
+                    inode,
+                    flags,
+                    fh.into(),
+                    flush,
+                    flock_release,
+                    lock_owner,
+                )
+                .await;
+            match res {
+                Ok(()) => reply_ok(&mut w, unique, &[]),
+                Err(e) => reply_error(&mut w, unique, e),
+            }
+        }
+        _ => reply_error(&mut w, unique, io::Error::from_raw_os_error(libc::ENOSYS)),
+    }
+}
+
+fn reply_ok(w: &mut FuseDevWriter<'_>, unique: u64, out: &[u8]) -> Result<usize> {
+    let header = OutHeader {
+        len: (size_of::<OutHeader>() + out.len()) as u32,
+        error: 0,
+        unique,
+    };
+    trace!("fuse: new reply {:?}", header);
+
+    w.write_vectored(&[IoSlice::new(header.as_slice()), IoSlice::new(out)])
+        .map_err(Error::EncodeMessage)?;
+    Ok(w.bytes_written())
+}
+
+fn reply_error(w: &mut FuseDevWriter<'_>, unique: u64, err: io::Error) -> Result<usize> {
+    let header = OutHeader {
+        len: size_of::<OutHeader>() as u32,
+        error: -err
+            .raw_os_error()
+            .unwrap_or_else(|| encode_io_error_kind(err.kind())),
+        unique,
+    };
+    trace!("fuse: reply error header {:?}, error {:?}", header, err);
+
+    w.write_all(header.as_slice())
+        .map_err(Error::EncodeMessage)?;
+    // Commit header if it is buffered otherwise kernel gets nothing back.
+    w.commit(None)
+        .map(|_| w.bytes_written())
+        .map_err(Error::EncodeMessage)
+}
+
+// Writer for the data of a read request.
+struct DataWriter<'a>(FuseDevWriter<'a>);
+
+impl ZeroCopyWriter for DataWriter<'_> {
+    fn write_from(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        self.0.write_from_at(f, count, off)
+    }
+
+    fn available_bytes(&self) -> usize {
+        self.0.available_bytes()
+    }
+
+    fn supports_splice(&self) -> bool {
+        self.0.supports_splice()
+    }
+
+    fn splice_read(&mut self, f: &dyn AsRawFd, count: usize, off: u64) -> io::Result<usize> {
+        self.0.splice_from(f.as_raw_fd(), count, off)
+    }
+
+    fn write_from_vectored(
+        &mut self,
+        f: &mut File,
+        count: usize,
+        off: u64,
+        flags: i32,
+    ) -> io::Result<usize> {
+        self.0.write_from_at(RwfFile::new(f, flags), count, off)
+    }
+}
+
+impl io::Write for DataWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Reader for the data of a write request.
+struct DataReader<'a>(Reader<'a>);
+
+// The underlying VolatileSlices only point into the request buffer, which is owned by the task
+// handling the request, and only used by that task whatever the worker thread it runs on.
+unsafe impl Send for DataReader<'_> {}
+
+impl ZeroCopyReader for DataReader<'_> {
+    fn read_to(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        self.0.read_to_at(f, count, off)
+    }
+
+    fn read_to_vectored(
+        &mut self,
+        f: &mut File,
+        count: usize,
+        off: u64,
+        flags: i32,
+    ) -> io::Result<usize> {
+        self.0.read_to_at(RwfFile::new(f, flags), count, off)
+    }
+}
+
+impl io::Read for DataReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+// Arguments of a request handled by the `Server`, borrowed by the futures of `BlockOn`.
+struct Unshared<T>(T);
+
+// `BlockOn` polls the futures borrowing the arguments on the thread handling the request only.
+unsafe impl<T> Send for Unshared<T> {}
+
+impl ZeroCopyWriter for Unshared<&mut dyn ZeroCopyWriter> {
+    fn write_from(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        self.0.write_from(f, count, off)
+    }
+
+    fn available_bytes(&self) -> usize {
+        self.0.available_bytes()
+    }
+
+    fn supports_splice(&self) -> bool {
+        self.0.supports_splice()
+    }
+
+    fn splice_read(&mut self, f: &dyn AsRawFd, count: usize, off: u64) -> io::Result<usize> {
+        self.0.splice_read(f, count, off)
+    }
+
+    fn write_from_vectored(
+        &mut self,
+        f: &mut File,
+        count: usize,
+        off: u64,
+        flags: i32,
+    ) -> io::Result<usize> {
+        self.0.write_from_vectored(f, count, off, flags)
+    }
+}
+
+impl io::Write for Unshared<&mut dyn ZeroCopyWriter> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl ZeroCopyReader for Unshared<&mut dyn ZeroCopyReader> {
+    fn read_to(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        self.0.read_to(f, count, off)
+    }
+
+    fn supports_splice(&self) -> bool {
+        self.0.supports_splice()
+    }
+
+    fn splice_write(&mut self, f: &dyn AsRawFd, count: usize, off: u64) -> io::Result<usize> {
+        self.0.splice_write(f, count, off)
+    }
+
+    fn read_to_vectored(
+        &mut self,
+        f: &mut File,
+        count: usize,
+        off: u64,
+        flags: i32,
+    ) -> io::Result<usize> {
+        self.0.read_to_vectored(f, count, off, flags)
+    }
+}
+
+impl io::Read for Unshared<&mut dyn ZeroCopyReader> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Unshared<&mut dyn FnMut(DirEntry) -> io::Result<usize>> {
+    fn call(&mut self, entry: DirEntry) -> io::Result<usize> {
+        (self.0)(entry)
+    }
+}
+
+impl Unshared<&mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>> {
+    fn call(&mut self, entry: DirEntry, attr: Entry) -> io::Result<usize> {
+        (self.0)(entry, attr)
+    }
+}
+
+// Run the futures of a `TokioFileSystem` to completion on the current thread, which must not be a
+// worker thread of the runtime.
+struct BlockOn<F> {
+    fs: Arc<F>,
+    rt: Handle,
+}
+
+impl<F: TokioFileSystem> FileSystem for BlockOn<F> {
+    type Inode = F::Inode;
+    type Handle = F::Handle;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        self.rt.block_on(TokioFileSystem::init(&*self.fs, capable))
+    }
+
+    fn destroy(&self) {
+        self.rt.block_on(TokioFileSystem::destroy(&*self.fs))
+    }
+
+    fn lookup(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
+        self.rt
+            .block_on(TokioFileSystem::lookup(&*self.fs, ctx, parent, name))
+    }
+
+    fn forget(&self, ctx: &Context, inode: Self::Inode, count: u64) {
+        self.rt
+            .block_on(TokioFileSystem::forget(&*self.fs, ctx, inode, count))
+    }
+
+    fn batch_forget(&self, ctx: &Context, requests: Vec<(Self::Inode, u64)>) {
+        self.rt
+            .block_on(TokioFileSystem::batch_forget(&*self.fs, ctx, requests))
+    }
+
+    fn getattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(stat64, Duration)> {
+        self.rt
+            .block_on(TokioFileSystem::getattr(&*self.fs, ctx, inode, handle))
+    }
+
+    fn setattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        attr: stat64,
+        handle: Option<Self::Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        self.rt.block_on(TokioFileSystem::setattr(
+            &*self.fs, ctx, inode, attr, handle, valid,
+        ))
+    }
+
+    fn readlink(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        self.rt
+            .block_on(TokioFileSystem::readlink(&*self.fs, ctx, inode))
+    }
+
+    fn symlink(
+        &self,
+        ctx: &Context,
+        linkname: &CStr,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        self.rt.block_on(TokioFileSystem::symlink(
+            &*self.fs, ctx, linkname, parent, name,
+        ))
+    }
+
+    fn mknod(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.rt.block_on(TokioFileSystem::mknod(
+            &*self.fs, ctx, inode, name, mode, rdev, umask,
+        ))
+    }
+
+    fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.rt.block_on(TokioFileSystem::mkdir(
+            &*self.fs, ctx, parent, name, mode, umask,
+        ))
+    }
+
+    fn unlink(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.rt
+            .block_on(TokioFileSystem::unlink(&*self.fs, ctx, parent, name))
+    }
+
+    fn rmdir(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.rt
+            .block_on(TokioFileSystem::rmdir(&*self.fs, ctx, parent, name))
+    }
+
+    fn rename(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::rename(
+            &*self.fs, ctx, olddir, oldname, newdir, newname, flags,
+        ))
+    }
+
+    fn link(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        newparent: Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        self.rt.block_on(TokioFileSystem::link(
+            &*self.fs, ctx, inode, newparent, newname,
+        ))
+    }
+
+    fn open(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        self.rt.block_on(TokioFileSystem::open(
+            &*self.fs, ctx, inode, flags, fuse_flags,
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn create(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        self.rt
+            .block_on(TokioFileSystem::create(&*self.fs, ctx, parent, name, args))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        self.rt
+            .block_on(TokioFileSystem::tmpfile(&*self.fs, ctx, parent, args))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn read(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        self.rt.block_on(TokioFileSystem::read(
+            &*self.fs,
+            ctx,
+            inode,
+            handle,
+            &mut Unshared(w),
+            size,
+            offset,
+            lock_owner,
+            flags,
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        self.rt.block_on(TokioFileSystem::write(
+            &*self.fs,
+            ctx,
+            inode,
+            handle,
+            &mut Unshared(r),
+            size,
+            offset,
+            lock_owner,
+            delayed_write,
+            flags,
+            fuse_flags,
+        ))
+    }
+
+    fn flush(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::flush(
+            &*self.fs, ctx, inode, handle, lock_owner,
+        ))
+    }
+
+    fn fsync(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::fsync(
+            &*self.fs, ctx, inode, datasync, handle,
+        ))
+    }
+
+    fn fallocate(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::fallocate(
+            &*self.fs, ctx, inode, handle, mode, offset, length,
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn release(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::release(
+            &*self.fs,
+            ctx,
+            inode,
+            flags,
+            handle,
+            flush,
+            flock_release,
+            lock_owner,
+        ))
+    }
+
+    fn statfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<statvfs64> {
+        self.rt
+            .block_on(TokioFileSystem::statfs(&*self.fs, ctx, inode))
+    }
+
+    fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+        setxattr_flags: u32,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::setxattr(
+            &*self.fs,
+            ctx,
+            inode,
+            name,
+            value,
+            flags,
+            setxattr_flags,
+        ))
+    }
+
+    fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        self.rt
+            .block_on(TokioFileSystem::getxattr(&*self.fs, ctx, inode, name, size))
+    }
+
+    fn listxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        self.rt
+            .block_on(TokioFileSystem::listxattr(&*self.fs, ctx, inode, size))
+    }
+
+    fn removexattr(&self, ctx: &Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.rt
+            .block_on(TokioFileSystem::removexattr(&*self.fs, ctx, inode, name))
+    }
+
+    fn opendir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        self.rt
+            .block_on(TokioFileSystem::opendir(&*self.fs, ctx, inode, flags))
+    }
+
+    fn readdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        let mut add_entry = Unshared(add_entry);
+        self.rt.block_on(TokioFileSystem::readdir(
+            &*self.fs,
+            ctx,
+            inode,
+            handle,
+            size,
+            offset,
+            &mut |entry| add_entry.call(entry),
+        ))
+    }
+
+    fn readdirplus(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        let mut add_entry = Unshared(add_entry);
+        self.rt.block_on(TokioFileSystem::readdirplus(
+            &*self.fs,
+            ctx,
+            inode,
+            handle,
+            size,
+            offset,
+            &mut |entry, attr| add_entry.call(entry, attr),
+        ))
+    }
+
+    fn fsyncdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::fsyncdir(
+            &*self.fs, ctx, inode, datasync, handle,
+        ))
+    }
+
+    fn syncfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        self.rt
+            .block_on(TokioFileSystem::syncfs(&*self.fs, ctx, inode))
+    }
+
+    fn releasedir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::releasedir(
+            &*self.fs, ctx, inode, flags, handle,
+        ))
+    }
+
+    #[cfg(feature = "virtiofs")]
+    #[allow(clippy::too_many_arguments)]
+    fn setupmapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::setupmapping(
+            &*self.fs, ctx, inode, handle, foffset, len, flags, moffset, vu_req,
+        ))
+    }
+
+    #[cfg(feature = "virtiofs")]
+    fn removemapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::removemapping(
+            &*self.fs, ctx, inode, requests, vu_req,
+        ))
+    }
+
+    fn access(&self, ctx: &Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
+        self.rt
+            .block_on(TokioFileSystem::access(&*self.fs, ctx, inode, mask))
+    }
+
+    fn lseek(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        self.rt.block_on(TokioFileSystem::lseek(
+            &*self.fs, ctx, inode, handle, offset, whence,
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn copy_file_range(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        self.rt.block_on(TokioFileSystem::copy_file_range(
+            &*self.fs, ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len,
+            flags,
+        ))
+    }
+
+    fn getlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        self.rt.block_on(TokioFileSystem::getlk(
+            &*self.fs, ctx, inode, handle, owner, lock, flags,
+        ))
+    }
+
+    fn setlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::setlk(
+            &*self.fs, ctx, inode, handle, owner, lock, flags,
+        ))
+    }
+
+    fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::setlkw(
+            &*self.fs, ctx, inode, handle, owner, lock, flags,
+        ))
+    }
+
+    fn flock(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        operation: i32,
+    ) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::flock(
+            &*self.fs, ctx, inode, handle, owner, operation,
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn ioctl<'a>(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData<'a>,
+        out_size: u32,
+    ) -> io::Result<IoctlData<'a>> {
+        self.rt.block_on(TokioFileSystem::ioctl(
+            &*self.fs, ctx, inode, handle, flags, cmd, data, out_size,
+        ))
+    }
+
+    fn bmap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        block: u64,
+        blocksize: u32,
+    ) -> io::Result<u64> {
+        self.rt.block_on(TokioFileSystem::bmap(
+            &*self.fs, ctx, inode, block, blocksize,
+        ))
+    }
+
+    fn poll(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        khandle: Self::Handle,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        self.rt.block_on(TokioFileSystem::poll(
+            &*self.fs, ctx, inode, handle, khandle, flags, events,
+        ))
+    }
+
+    fn notify_reply(&self) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::notify_reply(&*self.fs))
+    }
+
+    fn id_remap(&self, ctx: &mut Context) -> io::Result<()> {
+        self.rt.block_on(TokioFileSystem::id_remap(&*self.fs, ctx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+    use std::sync::mpsc;
+
+    use async_trait::async_trait;
+    use tokio::sync::Barrier;
+    use vm_memory::ByteValued;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::abi::fuse_abi::{AttrOut, GetattrIn, Kstatfs, OutHeader, ROOT_ID};
+    use crate::passthrough::{Config, PassthroughFs};
+
+    struct BarrierFs {
+        barrier: Barrier,
+    }
+
+    #[async_trait]
+    impl TokioFileSystem for BarrierFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        async fn read(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            _w: &mut (dyn ZeroCopyWriter + Send),
+            _size: u32,
+            _offset: u64,
+            _lock_owner: Option<u64>,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            // Only completes once reads of both inodes are in flight.
+            self.barrier.wait().await;
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_async_server_concurrent_requests() {
+        let fs = BarrierFs {
+            barrier: Barrier::new(2),
+        };
+        // Both reads only complete if their futures run concurrently on the single worker thread.
+        let server = AsyncServer::new(fs, 1).unwrap();
+        let file = TempFile::new().unwrap().into_file();
+
+        let tasks: Vec<_> = [2u64, 3]
+            .iter()
+            .map(|&inode| {
+                let header = InHeader {
+                    len: (size_of::<InHeader>() + size_of::<ReadIn>()) as u32,
+                    opcode: Opcode::Read as u32,
+                    unique: inode,
+                    nodeid: inode,
+                    ..Default::default()
+                };
+                let read = ReadIn {
+                    fh: 1,
+                    size: 4096,
+                    ..Default::default()
+                };
+                let mut request = header.as_slice().to_vec();
+                request.extend_from_slice(read.as_slice());
+                server.spawn_request(file.as_raw_fd(), request)
+            })
+            .collect();
+
+        let (tx, rx) = mpsc::channel();
+        let handle = server.runtime().handle().clone();
+        std::thread::spawn(move || {
+            for task in tasks {
+                let _ = tx.send(handle.block_on(task).unwrap());
+            }
+        });
+        for _ in 0..2 {
+            let res = rx
+                .recv_timeout(Duration::from_secs(10))
+                .expect("requests blocked each other");
+            assert!(res.unwrap() > 0);
+        }
+    }
+
+    #[test]
+    fn test_async_server_sync_fs() {
+        let source = TempDir::new().unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let server = AsyncServer::new(fs, 1).unwrap();
+        let file = TempFile::new().unwrap().into_file();
+
+        let request = |opcode: Opcode, body: &[u8]| {
+            let header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid: ROOT_ID,
+                ..Default::default()
+            };
+            let mut request = header.as_slice().to_vec();
+            request.extend_from_slice(body);
+            let task = server.spawn_request(file.as_raw_fd(), request);
+            server.runtime().block_on(task).unwrap().unwrap()
+        };
+
+        // Handled by a task of the runtime.
+        let res = request(Opcode::Getattr, GetattrIn::default().as_slice());
+        assert_eq!(res, size_of::<OutHeader>() + size_of::<AttrOut>());
+        // Handled by the server on a blocking thread.
+        let res = request(Opcode::Statfs, &[]);
+        assert_eq!(res, size_of::<OutHeader>() + size_of::<Kstatfs>());
+    }
+}
@@ -8,13 +8,18 @@
 //! sequentially. A FUSE session is a connection from a FUSE mountpoint to a FUSE server daemon.
 //! A FUSE session can have multiple FUSE channels so that FUSE requests are handled in parallel.
 
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
 use std::ops::Deref;
 use std::os::unix::fs::PermissionsExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
 
 use mio::{Events, Poll, Token, Waker};
 use nix::errno::Errno;
@@ -22,8 +27,15 @@ use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
 use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::epoll::{epoll_ctl, EpollEvent, EpollFlags, EpollOp};
+use nix::sys::pthread::{pthread_kill, pthread_self, Pthread};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::unistd::{getgid, getuid, read};
 
+use crate::api::server::{
+    encode_notify_delete, encode_notify_inval_entry, encode_notify_inval_inode,
+};
+
+use super::splice::{read_request, Pipe};
 use super::{
     super::pagesize,
     Error::{IoError, SessionFailure},
@@ -238,6 +250,28 @@ impl FuseSession {
         }
     }
 
+    /// Create a new fuse message channel on a clone of the session file, see
+    /// `clone_fuse_file()`.
+    ///
+    /// Unlike channels created by `new_channel()`, which share the session file, each cloned
+    /// channel has a queue of its own in the kernel for the requests it has read, so channels can
+    /// be served by different threads without contending on a single queue. Requests, including
+    /// `INIT`, may be read from any channel of the session, and the reply is sent on the channel
+    /// the request has been read from.
+    ///
+    /// The session must be mounted. Cloned channels stop receiving requests once the session is
+    /// unmounted, and are woken up by `wake()` as other channels.
+    pub fn clone_channel(&self) -> Result<FuseChannel> {
+        let file = self.clone_fuse_file()?;
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(|e| SessionFailure(format!("set fd nonblocking: {e}")))?;
+        let channel = FuseChannel::new(file, self.bufsize)?;
+        let waker = channel.get_waker();
+        self.add_waker(waker)?;
+
+        Ok(channel)
+    }
+
     /// Create a new fuse message channel with a specific buffer size.
     pub fn with_writer<F>(&mut self, f: F)
     where
@@ -265,6 +299,34 @@ impl FuseSession {
         Ok(())
     }
 
+    /// Send a notification to the kernel to invalidate the cached attributes of `inode` and its
+    /// cached data in the range of `len` bytes at `offset`, see `Server::notify_inval_inode()`.
+    pub fn notify_inval_inode(&self, inode: u64, offset: i64, len: i64) -> Result<()> {
+        let file = self.file.as_ref().ok_or(SessionFailure(
+            "fuse session file doesn't exist".to_string(),
+        ))?;
+        notify_inval_inode(file, inode, offset, len).map_err(IoError)
+    }
+
+    /// Send a notification to the kernel to invalidate the cached dentry `name` in the directory
+    /// `parent`, e.g. after the entry has been renamed or removed out-of-band.
+    pub fn notify_inval_entry(&self, parent: u64, name: &CStr) -> Result<()> {
+        let file = self.file.as_ref().ok_or(SessionFailure(
+            "fuse session file doesn't exist".to_string(),
+        ))?;
+        notify_inval_entry(file, parent, name).map_err(IoError)
+    }
+
+    /// Send a notification to the kernel that the entry `name` of `child` in the directory
+    /// `parent` has been removed. Unlike `notify_inval_entry()`, this also drops the dentry if it
+    /// is in use, e.g. as the current directory of a process.
+    pub fn notify_delete(&self, parent: u64, child: u64, name: &CStr) -> Result<()> {
+        let file = self.file.as_ref().ok_or(SessionFailure(
+            "fuse session file doesn't exist".to_string(),
+        ))?;
+        notify_delete(file, parent, child, name).map_err(IoError)
+    }
+
     fn add_waker(&self, waker: Arc<Waker>) -> Result<()> {
         let mut wakers = self
             .wakers
@@ -275,6 +337,41 @@ impl FuseSession {
     }
 }
 
+/// Send a notification to the kernel to invalidate the cached attributes of `inode` and its
+/// cached data, like `FuseSession::notify_inval_inode()`, through the fuse device `dev`, e.g. a
+/// duplicate of the file of the session held by a thread watching for changes.
+///
+/// The kernel fails with `ENOENT` if it has already forgotten `inode`.
+pub fn notify_inval_inode(dev: &File, inode: u64, offset: i64, len: i64) -> io::Result<()> {
+    write_notification(dev, &encode_notify_inval_inode(inode, offset, len))
+}
+
+/// Send a notification to the kernel to invalidate the cached dentry `name` in the directory
+/// `parent`, like `FuseSession::notify_inval_entry()`, through the fuse device `dev`.
+///
+/// The kernel fails with `ENOENT` if it has already forgotten `parent`.
+pub fn notify_inval_entry(dev: &File, parent: u64, name: &CStr) -> io::Result<()> {
+    write_notification(dev, &encode_notify_inval_entry(parent, name))
+}
+
+/// Send a notification to the kernel that the entry `name` of `child` in the directory `parent`
+/// has been removed, like `FuseSession::notify_delete()`, through the fuse device `dev`.
+///
+/// The kernel locks `parent` to drop the dentry, so this must not be called while handling a
+/// request about `parent`, which the kernel may hold locked until the reply.
+pub fn notify_delete(dev: &File, parent: u64, child: u64, name: &CStr) -> io::Result<()> {
+    write_notification(dev, &encode_notify_delete(parent, child, name))
+}
+
+// Write the encoded notification `msg` to the fuse device `dev`, which must take it at once.
+fn write_notification(dev: &File, msg: &[u8]) -> io::Result<()> {
+    let n = (&*dev).write(msg)?;
+    if n != msg.len() {
+        return Err(io::Error::from_raw_os_error(libc::EIO));
+    }
+    Ok(())
+}
+
 impl Drop for FuseSession {
     fn drop(&mut self) {
         let _ = self.umount();
@@ -289,6 +386,8 @@ pub struct FuseChannel {
     poll: Poll,
     waker: Arc<Waker>,
     buf: Vec<u8>,
+    // Pipe to splice requests through, see `enable_splice_write()`.
+    pipe: Option<Pipe>,
 }
 
 impl FuseChannel {
@@ -316,6 +415,7 @@ impl FuseChannel {
             poll,
             waker,
             buf: vec![0x0u8; bufsize],
+            pipe: None,
         })
     }
 
@@ -323,6 +423,18 @@ impl FuseChannel {
         self.waker.clone()
     }
 
+    /// Splice requests from the FUSE device through a pipe, so the data of large write requests
+    /// can be spliced into files without being copied through userspace.
+    ///
+    /// The pipe must hold a whole request, so this fails if it can't be grown to the size of the
+    /// channel buffer, e.g. above `/proc/sys/fs/pipe-max-size` without `CAP_SYS_RESOURCE`.
+    pub fn enable_splice_write(&mut self) -> Result<()> {
+        let pipe = Pipe::new().map_err(IoError)?;
+        pipe.reserve(self.buf.len() + pagesize()).map_err(IoError)?;
+        self.pipe = Some(pipe);
+        Ok(())
+    }
+
     /// Get next available FUSE request from the underlying fuse device file.
     ///
     /// Returns:
@@ -367,8 +479,13 @@ impl FuseChannel {
             }
             if fusereq_available {
                 let fd = self.file.as_raw_fd();
-                match read(fd, &mut self.buf) {
-                    Ok(len) => {
+                let res = match self.pipe.as_ref() {
+                    Some(pipe) => read_request(fd, pipe, &mut self.buf)
+                        .map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(libc::EIO))),
+                    None => read(fd, &mut self.buf).map(|len| (len, 0)),
+                };
+                match res {
+                    Ok((len, spliced)) => {
                         // ###############################################
                         // Note: it's a heavy hack to reuse the same underlying data
                         // buffer for both Reader and Writer, in order to reduce memory
@@ -379,8 +496,16 @@ impl FuseChannel {
                             std::slice::from_raw_parts_mut(self.buf.as_mut_ptr(), self.buf.len())
                         };
                         // Reader::new() and Writer::new() should always return success.
-                        let reader =
-                            Reader::from_fuse_buffer(FuseBuf::new(&mut self.buf[..len])).unwrap();
+                        let reader = match self.pipe.as_ref() {
+                            Some(pipe) if spliced > 0 => Reader::from_spliced_fuse_buffer(
+                                FuseBuf::new(&mut self.buf[..len + spliced]),
+                                pipe.reader(),
+                                spliced,
+                            )
+                            .unwrap(),
+                            _ => Reader::from_fuse_buffer(FuseBuf::new(&mut self.buf[..len]))
+                                .unwrap(),
+                        };
                         let writer = FuseDevWriter::new(fd, buf).unwrap();
                         return Ok(Some((reader, writer)));
                     }
@@ -413,6 +538,196 @@ impl FuseChannel {
     }
 }
 
+impl AsRawFd for FuseChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+// Maximum number of interrupts kept for requests which haven't been dispatched yet.
+const MAX_EARLY_INTERRUPTS: usize = 64;
+// Interval between signals sent to the thread handling an interrupted request.
+const INTERRUPT_RESIGNAL_INTERVAL: Duration = Duration::from_millis(10);
+
+// A request being handled by a worker thread.
+struct InFlight {
+    thread: Pthread,
+    interrupted: bool,
+}
+
+#[derive(Default)]
+struct InterruptMapInner {
+    map: Mutex<HashMap<u64, InFlight>>,
+    // Interrupts of requests which weren't in flight, oldest first. Always locked after `map`.
+    early: Mutex<VecDeque<u64>>,
+}
+
+impl InterruptMapInner {
+    // Signal the thread handling the request `unique` until the request completes, as the signal
+    // may arrive before the thread blocks, e.g. right before `fcntl(F_OFD_SETLKW)` is called.
+    fn signal_until_done(&self, unique: u64) {
+        loop {
+            // Keep the lock while signaling, so the thread can't move on to another request.
+            let map = self.map.lock().unwrap();
+            let thread = match map.get(&unique) {
+                Some(req) => req.thread,
+                None => return,
+            };
+            if let Err(e) = pthread_kill(thread, Signal::SIGUSR1) {
+                warn!("fuse: failed to signal request {}, {}", unique, e);
+                return;
+            }
+            drop(map);
+            std::thread::sleep(INTERRUPT_RESIGNAL_INTERVAL);
+        }
+    }
+}
+
+/// A map of in-flight FUSE requests, keyed by the request unique id.
+///
+/// It records the worker thread handling each request, so that a `FUSE_INTERRUPT` request can
+/// cancel a blocked operation by signaling the thread.
+#[derive(Default)]
+pub struct InterruptMap {
+    inner: Arc<InterruptMapInner>,
+}
+
+impl InterruptMap {
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the current thread as the one handling the request `unique`.
+    ///
+    /// The request is forgotten when the returned guard is dropped. If the request has been
+    /// interrupted before, see `interrupt()`, the guard tells so and the request shouldn't be
+    /// handled at all.
+    pub fn register(&self, unique: u64) -> InterruptGuard<'_> {
+        let mut map = self.inner.map.lock().unwrap();
+        let mut early = self.inner.early.lock().unwrap();
+        let interrupted = match early.iter().position(|u| *u == unique) {
+            Some(pos) => early.remove(pos).is_some(),
+            None => false,
+        };
+        map.insert(
+            unique,
+            InFlight {
+                thread: pthread_self(),
+                interrupted,
+            },
+        );
+        InterruptGuard {
+            map: self,
+            unique,
+            interrupted,
+        }
+    }
+
+    /// Cancel the request `unique`, return false if the request is not in flight.
+    ///
+    /// The thread handling the request receives `SIGUSR1`, which makes blocking system calls,
+    /// such as `fcntl(F_OFD_SETLKW)`, fail with `EINTR`. The signal is sent again every few
+    /// milliseconds until the request completes, so that it isn't lost if it arrives before the
+    /// thread blocks. Other system calls of the interrupted request may fail with `EINTR` too,
+    /// which is a valid reply to an interrupted request.
+    ///
+    /// A no-op handler without `SA_RESTART` is installed for `SIGUSR1` on first use. Only threads
+    /// handling interrupted requests are signaled. Interrupting requests fails if the application
+    /// has installed its own `SIGUSR1` handler, which is left untouched.
+    ///
+    /// The kernel may send the interrupt before the request has been picked up by a worker
+    /// thread. The interrupt is then remembered, and the request is reported as interrupted by
+    /// `register()`. Only the most recent interrupts are remembered, as those of requests which
+    /// have completed already are never claimed.
+    pub fn interrupt(&self, unique: u64) -> io::Result<bool> {
+        install_interrupt_handler()?;
+
+        let mut map = self.inner.map.lock().unwrap();
+        match map.get_mut(&unique) {
+            // The kernel resends interrupts, the request is being signaled already.
+            Some(req) if req.interrupted => Ok(true),
+            Some(req) => {
+                req.interrupted = true;
+                let inner = self.inner.clone();
+                std::thread::Builder::new()
+                    .name("fuse_interrupt".to_string())
+                    .spawn(move || inner.signal_until_done(unique))?;
+                Ok(true)
+            }
+            None => {
+                let mut early = self.inner.early.lock().unwrap();
+                if !early.contains(&unique) {
+                    if early.len() >= MAX_EARLY_INTERRUPTS {
+                        early.pop_front();
+                    }
+                    early.push_back(unique);
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Guard returned by [`InterruptMap::register`], forgets the request when dropped.
+pub struct InterruptGuard<'a> {
+    map: &'a InterruptMap,
+    unique: u64,
+    interrupted: bool,
+}
+
+impl InterruptGuard<'_> {
+    /// Whether the request was interrupted before it got registered.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted
+    }
+}
+
+impl Drop for InterruptGuard<'_> {
+    fn drop(&mut self) {
+        self.map.inner.map.lock().unwrap().remove(&self.unique);
+    }
+}
+
+extern "C" fn handle_interrupt_signal(_signo: libc::c_int) {}
+
+fn install_interrupt_handler() -> io::Result<()> {
+    static INSTALL: Once = Once::new();
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+    INSTALL.call_once(|| {
+        // Leave out SA_RESTART, so that blocking system calls return EINTR.
+        let action = SigAction::new(
+            SigHandler::Handler(handle_interrupt_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        // Safe because the handler does nothing, so it is async-signal-safe.
+        match unsafe { sigaction(Signal::SIGUSR1, &action) } {
+            Ok(old) if old.handler() == SigHandler::SigDfl => {
+                INSTALLED.store(true, Ordering::Release)
+            }
+            // Give the signal back to the application.
+            // Safe because this restores the action installed by the application.
+            Ok(old) => match unsafe { sigaction(Signal::SIGUSR1, &old) } {
+                Ok(_) => warn!("SIGUSR1 is handled by the application, can't interrupt requests"),
+                Err(e) => error!("failed to restore SIGUSR1 handler: {}", e),
+            },
+            Err(e) => error!("failed to install SIGUSR1 handler: {}", e),
+        }
+    });
+
+    // Signaling without the handler would terminate the process.
+    if INSTALLED.load(Ordering::Acquire) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no SIGUSR1 handler to interrupt requests",
+        ))
+    }
+}
+
 /// Mount a fuse file system
 #[allow(clippy::too_many_arguments)]
 fn fuse_kern_mount(
@@ -699,6 +1014,54 @@ mod tests {
         assert_eq!(se.get_fusermount(), "fusermount");
     }
 
+    #[test]
+    fn test_clone_channel() {
+        use crate::api::server::Server;
+        use crate::passthrough::{Config, PassthroughFs};
+
+        let dir = TempDir::new().unwrap();
+        let mut se = FuseSession::new(dir.as_path(), "foo", "bar", false).unwrap();
+        assert!(se.clone_channel().is_err());
+        se.mount().unwrap();
+
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        let fs = PassthroughFs::<()>::new(Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let server = Arc::new(Server::new(fs, None));
+
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let mut ch = se.clone_channel().unwrap();
+                let server = server.clone();
+                std::thread::spawn(move || {
+                    let mut handled = 0;
+                    while let Ok(Some((reader, writer))) = ch.get_request() {
+                        server
+                            .handle_message(reader, writer.into(), None, None)
+                            .unwrap();
+                        handled += 1;
+                    }
+                    handled
+                })
+            })
+            .collect();
+
+        let path = dir.as_path().join("file");
+        for _ in 0..16 {
+            assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        }
+
+        // Unmounting stops the cloned channels.
+        se.umount().unwrap();
+        let handled: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        // At least INIT, LOOKUP, OPEN and READ.
+        assert!(handled >= 4);
+    }
+
     #[test]
     fn test_clone_fuse_file() {
         let dir = TempDir::new().unwrap();
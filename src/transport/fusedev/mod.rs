@@ -17,6 +17,8 @@ use nix::sys::uio::writev;
 use nix::unistd::write;
 use vm_memory::{ByteValued, VolatileMemory, VolatileSlice};
 
+#[cfg(target_os = "linux")]
+use super::SplicedData;
 use super::{Error, FileReadWriteVolatile, IoBuffers, Reader, Result, Writer};
 use crate::file_buf::FileVolatileSlice;
 use crate::BitmapSlice;
@@ -24,6 +26,8 @@ use crate::BitmapSlice;
 #[cfg(target_os = "linux")]
 mod linux_session;
 #[cfg(target_os = "linux")]
+mod splice;
+#[cfg(target_os = "linux")]
 pub use linux_session::*;
 
 #[cfg(all(target_os = "macos", not(feature = "fuse-t")))]
@@ -71,8 +75,34 @@ impl<'a, S: BitmapSlice + Default> Reader<'a, S> {
                 buffers,
                 bytes_consumed: 0,
             },
+            spliced: None,
         })
     }
+
+    /// Construct a new Reader over a request whose tail of `len` bytes is left in `pipe`.
+    ///
+    /// `buf`: Fuse request read from /dev/fuse, followed by room for the tail of the request, which
+    /// is read into it if the tail isn't spliced.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn from_spliced_fuse_buffer(
+        buf: FuseBuf<'a>,
+        pipe: RawFd,
+        len: usize,
+    ) -> Result<Reader<'a, S>> {
+        let head = buf
+            .mem
+            .len()
+            .checked_sub(len)
+            .ok_or(Error::InvalidParameter)?;
+        let mut reader = Self::from_fuse_buffer(buf)?;
+        let front = reader.buffers.buffers[0].clone();
+        let mem = front.offset(head).map_err(Error::VolatileMemoryError)?;
+        reader.buffers.buffers[0] = front
+            .subslice(0, head)
+            .map_err(Error::VolatileMemoryError)?;
+        reader.spliced = Some(SplicedData { pipe, len, mem });
+        Ok(reader)
+    }
 }
 
 /// Writer to send FUSE reply to the FUSE driver.
@@ -87,6 +117,8 @@ pub struct FuseDevWriter<'a, S: BitmapSlice = ()> {
     fd: RawFd,
     buffered: bool,
     buf: ManuallyDrop<Vec<u8>>,
+    // Bytes of file data spliced into the reply instead of being buffered, see `splice_from()`.
+    spliced: usize,
     bitmapslice: S,
     phantom: PhantomData<&'a mut [S]>,
 }
@@ -99,6 +131,7 @@ impl<'a, S: BitmapSlice + Default> FuseDevWriter<'a, S> {
             fd,
             buffered: false,
             buf: ManuallyDrop::new(buf),
+            spliced: 0,
             bitmapslice: S::default(),
             phantom: PhantomData,
         })
@@ -133,6 +166,7 @@ impl<'a, S: BitmapSlice> FuseDevWriter<'a, S> {
             fd: self.fd,
             buffered: true,
             buf,
+            spliced: 0,
             bitmapslice: self.bitmapslice.clone(),
             phantom: PhantomData,
         })
@@ -145,6 +179,14 @@ impl<'a, S: BitmapSlice> FuseDevWriter<'a, S> {
         }
 
         let o = match other {
+            #[cfg(target_os = "linux")]
+            Some(Writer::FuseDev(w)) if w.spliced > 0 => {
+                // Spliced data can't be mixed with buffered data, see `splice_from()`.
+                if self.spliced > 0 || !w.buf.is_empty() {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                return splice::send_reply(self.fd, self.buf.as_slice(), w.spliced);
+            }
             Some(Writer::FuseDev(w)) => w.buf.as_slice(),
             _ => &[],
         };
@@ -168,7 +210,32 @@ impl<'a, S: BitmapSlice> FuseDevWriter<'a, S> {
 
     /// Return number of bytes available for writing.
     pub fn available_bytes(&self) -> usize {
-        self.buf.capacity() - self.buf.len()
+        self.buf.capacity() - self.buf.len() - self.spliced
+    }
+
+    /// Whether `splice_from()` is supported, which is only the case for the data part of a split
+    /// writer on Linux.
+    pub fn supports_splice(&self) -> bool {
+        cfg!(target_os = "linux") && self.buffered
+    }
+
+    /// Move at most `count` bytes of the file `fd` at offset `off` into the writer with
+    /// `splice(2)`, without copying them through userspace.
+    ///
+    /// The data is sent by `commit()` of the writer this one has been split from, on the same
+    /// thread. Nothing else can be written into the writer before or after spliced data. If an
+    /// error is returned, no data has been moved and the caller may fall back to
+    /// `write_from_at()`.
+    #[cfg(target_os = "linux")]
+    pub fn splice_from(&mut self, fd: RawFd, count: usize, off: u64) -> io::Result<usize> {
+        if !self.supports_splice() || !self.buf.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.check_available_space(count)?;
+
+        let cnt = splice::splice_from(fd, count, off, self.spliced > 0)?;
+        self.spliced += cnt;
+        Ok(cnt)
     }
 
     fn account_written(&mut self, count: usize) {
@@ -906,6 +973,49 @@ mod tests {
         assert_eq!(writer.bytes_written(), 40);
     }
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn splice_from_split() {
+        let mut dev = TempFile::new().unwrap().into_file();
+        let mut buf = vec![0x0u8; 64];
+        let mut writer = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut buf).unwrap();
+        let mut other = writer.split_at(8).unwrap();
+        assert!(other.supports_splice());
+
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(b"0123456789abcdef").unwrap();
+        assert_eq!(other.splice_from(file.as_raw_fd(), 8, 4).unwrap(), 8);
+        // Data past the end of file is short.
+        assert_eq!(other.splice_from(file.as_raw_fd(), 8, 12).unwrap(), 4);
+        assert_eq!(other.available_bytes(), 44);
+        other
+            .splice_from(file.as_raw_fd(), 48, 0)
+            .expect_err("splicing more data than capacity");
+
+        writer.write_all(b"header\0\0").unwrap();
+        assert_eq!(writer.commit(Some(&other.into())).unwrap(), 20);
+        let mut out = Vec::new();
+        dev.seek(SeekFrom::Start(0)).unwrap();
+        dev.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"header\x00\x00456789abcdef");
+
+        // Data spliced for a reply which is never sent doesn't leak into the next one.
+        let mut buf = vec![0x0u8; 64];
+        let mut writer = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut buf).unwrap();
+        let mut other = writer.split_at(8).unwrap();
+        other.splice_from(file.as_raw_fd(), 4, 0).unwrap();
+        let mut buf = vec![0x0u8; 64];
+        let mut writer = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut buf).unwrap();
+        let mut other = writer.split_at(8).unwrap();
+        assert_eq!(other.splice_from(file.as_raw_fd(), 2, 8).unwrap(), 2);
+        writer.write_all(b"HEADER\0\0").unwrap();
+        assert_eq!(writer.commit(Some(&other.into())).unwrap(), 10);
+        let mut out = Vec::new();
+        dev.seek(SeekFrom::Start(20)).unwrap();
+        dev.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"HEADER\0\089");
+    }
+
     #[test]
     fn write_from_at_split() {
         let file1 = TempFile::new().unwrap().into_file();
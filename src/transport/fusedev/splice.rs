@@ -0,0 +1,244 @@
+// Copyright (C) 2023 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Move file data into FUSE replies with `splice(2)`, without copying it through userspace.
+//!
+//! File data is spliced into a data pipe while the file system handles a read request. The reply
+//! is then assembled in a message pipe, header first and the file data moved in from the data
+//! pipe, and spliced to `/dev/fuse` at once. Pipes are cached per thread, so the reply must be
+//! committed on the thread which spliced its data, before the data of another reply is spliced.
+//!
+//! Requests may also be spliced from `/dev/fuse` into a pipe of the channel, see
+//! [read_request](fn.read_request.html), so the data of large write requests can be spliced into
+//! files without being copied through userspace.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use vm_memory::ByteValued;
+
+use super::super::pagesize;
+use crate::abi::fuse_abi::{InHeader, Opcode, WriteIn};
+
+thread_local! {
+    static PIPES: RefCell<Option<SplicePipes>> = const { RefCell::new(None) };
+}
+
+pub(super) struct Pipe {
+    r: File,
+    w: File,
+}
+
+impl Pipe {
+    pub(super) fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // Safe because the kernel only writes the two fds into `fds` and we check the return value.
+        let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the newly created fds.
+        Ok(unsafe {
+            Pipe {
+                r: File::from_raw_fd(fds[0]),
+                w: File::from_raw_fd(fds[1]),
+            }
+        })
+    }
+
+    // Grow the pipe to hold at least `size` bytes.
+    pub(super) fn reserve(&self, size: usize) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let cur = unsafe { libc::fcntl(self.w.as_raw_fd(), libc::F_GETPIPE_SZ) };
+        if cur < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if cur as usize >= size {
+            return Ok(());
+        }
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res =
+            unsafe { libc::fcntl(self.w.as_raw_fd(), libc::F_SETPIPE_SZ, size as libc::c_int) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Get the read end of the pipe.
+    pub(super) fn reader(&self) -> RawFd {
+        self.r.as_raw_fd()
+    }
+}
+
+struct SplicePipes {
+    data: Pipe,
+    msg: Pipe,
+    // Bytes spliced into the data pipe which haven't been sent yet.
+    pending: usize,
+}
+
+impl SplicePipes {
+    fn new() -> io::Result<Self> {
+        Ok(SplicePipes {
+            data: Pipe::new()?,
+            msg: Pipe::new()?,
+            pending: 0,
+        })
+    }
+}
+
+// Run `f` with the pipes of the current thread. The pipes are dropped if `f` fails, as they may
+// be left with data of a failed reply.
+fn with_pipes<T>(f: impl FnOnce(&mut SplicePipes) -> io::Result<T>) -> io::Result<T> {
+    PIPES.with(|pipes| {
+        let mut pipes = pipes.borrow_mut();
+        let mut p = match pipes.take() {
+            Some(p) => p,
+            None => SplicePipes::new()?,
+        };
+        let res = f(&mut p);
+        if res.is_ok() {
+            *pipes = Some(p);
+        }
+        res
+    })
+}
+
+fn splice(fd_in: RawFd, off_in: Option<&mut i64>, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let off_in = match off_in {
+        Some(off) => off as *mut i64,
+        None => std::ptr::null_mut(),
+    };
+    // Safe because this only modifies `off_in`, which is valid if not null, and we check the
+    // return value.
+    let res = unsafe {
+        libc::splice(
+            fd_in,
+            off_in,
+            fd_out,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE,
+        )
+    };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Splice at most `count` bytes of the file `fd` at offset `off` into the data pipe of the current
+/// thread, appending to the data spliced for the reply being built if `append` is true.
+///
+/// If an error is returned, no data has been spliced.
+pub(super) fn splice_from(fd: RawFd, count: usize, off: u64, append: bool) -> io::Result<usize> {
+    with_pipes(|p| {
+        // Leftovers of a reply which has never been sent.
+        if p.pending != 0 && !append {
+            *p = SplicePipes::new()?;
+        }
+        p.data.reserve(p.pending + count)?;
+
+        let mut off = off as i64;
+        let mut done = 0;
+        while done < count {
+            match splice(fd, Some(&mut off), p.data.w.as_raw_fd(), count - done) {
+                Ok(0) => break,
+                Ok(n) => {
+                    done += n;
+                    p.pending += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(done)
+    })
+}
+
+/// Send a reply made of `header` followed by the `len` bytes spliced into the data pipe of the
+/// current thread to `fd`.
+pub(super) fn send_reply(fd: RawFd, header: &[u8], len: usize) -> io::Result<usize> {
+    with_pipes(|p| {
+        if p.pending != len {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let total = header.len() + len;
+        p.msg.reserve(total)?;
+
+        let mut written = 0;
+        while written < header.len() {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::write(
+                    p.msg.w.as_raw_fd(),
+                    header[written..].as_ptr() as *const libc::c_void,
+                    header.len() - written,
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            written += res as usize;
+        }
+        while p.pending > 0 {
+            let n = splice(p.data.r.as_raw_fd(), None, p.msg.w.as_raw_fd(), p.pending)?;
+            p.pending -= n;
+        }
+
+        // The FUSE device takes the whole message in a single call.
+        let mut sent = 0;
+        while sent < total {
+            match splice(p.msg.r.as_raw_fd(), None, fd, total - sent)? {
+                0 => return Err(io::Error::from_raw_os_error(libc::EIO)),
+                n => sent += n,
+            }
+        }
+        Ok(sent)
+    })
+}
+
+/// Read a request from the FUSE device `fd` through `pipe`.
+///
+/// The request is read into `buf`, except for the data of write requests of at least a page, which
+/// is left in `pipe` to be spliced into the file. Return the length of the request read into `buf`
+/// and the length of the data left in `pipe`.
+pub(super) fn read_request(fd: RawFd, pipe: &Pipe, buf: &mut [u8]) -> io::Result<(usize, usize)> {
+    // Drop the leftovers of a request whose data has never been consumed.
+    let mut left: libc::c_int = 0;
+    // Safe because the kernel only writes to `left` and we check the return value.
+    if unsafe { libc::ioctl(pipe.r.as_raw_fd(), libc::FIONREAD, &mut left) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    while left > 0 {
+        let n = cmp::min(left as usize, buf.len());
+        let n = (&pipe.r).read(&mut buf[..n])?;
+        left -= n as libc::c_int;
+    }
+
+    let len = splice(fd, None, pipe.w.as_raw_fd(), buf.len())?;
+    let header_len = size_of::<InHeader>();
+    if len < header_len {
+        return Err(io::Error::from_raw_os_error(libc::EIO));
+    }
+    (&pipe.r).read_exact(&mut buf[..header_len])?;
+    // Safe because `buf` holds at least an `InHeader`.
+    let header = InHeader::from_slice(&buf[..header_len]).unwrap();
+
+    let write_len = header_len + size_of::<WriteIn>();
+    let read_len = if header.opcode == Opcode::Write as u32 && len >= write_len + pagesize() {
+        write_len
+    } else {
+        len
+    };
+    (&pipe.r).read_exact(&mut buf[header_len..read_len])?;
+    Ok((read_len, len - read_len))
+}
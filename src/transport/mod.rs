@@ -19,6 +19,7 @@ use std::collections::VecDeque;
 use std::io::{self, IoSlice, Read};
 use std::marker::PhantomData;
 use std::mem::{size_of, MaybeUninit};
+use std::os::unix::io::RawFd;
 use std::ptr::copy_nonoverlapping;
 use std::{cmp, fmt};
 
@@ -34,13 +35,21 @@ use crate::file_traits::AsyncFileReadWriteVolatile;
 use crate::file_traits::FileReadWriteVolatile;
 use crate::BitmapSlice;
 
+#[cfg(all(feature = "async-io", feature = "fusedev", target_os = "linux"))]
+mod async_server;
 mod fs_cache_req_handler;
 #[cfg(feature = "fusedev")]
 mod fusedev;
 #[cfg(feature = "virtiofs")]
 mod virtiofs;
 
+#[cfg(all(feature = "async-io", feature = "fusedev", target_os = "linux"))]
+pub use self::async_server::AsyncServer;
 pub use self::fs_cache_req_handler::FsCacheReqHandler;
+#[cfg(all(feature = "fusedev", target_os = "linux"))]
+pub use self::fusedev::{
+    notify_delete, notify_inval_entry, notify_inval_inode, InterruptGuard, InterruptMap,
+};
 #[cfg(feature = "fusedev")]
 pub use self::fusedev::{FuseBuf, FuseChannel, FuseDevWriter, FuseSession};
 #[cfg(feature = "virtiofs")]
@@ -363,12 +372,24 @@ impl<S: BitmapSlice> IoBuffers<'_, S> {
 #[derive(Clone)]
 pub struct Reader<'a, S = ()> {
     buffers: IoBuffers<'a, S>,
+    spliced: Option<SplicedData<'a, S>>,
+}
+
+// The tail of a request left in a pipe by the transport, to be spliced into its destination
+// without copying it through userspace, see `Reader::splice_to()`.
+#[derive(Clone)]
+struct SplicedData<'a, S> {
+    pipe: RawFd,
+    len: usize,
+    // Memory to read the data into if it's read instead of spliced.
+    mem: VolatileSlice<'a, S>,
 }
 
 impl<S: BitmapSlice> Default for Reader<'_, S> {
     fn default() -> Self {
         Reader {
             buffers: IoBuffers::default(),
+            spliced: None,
         }
     }
 }
@@ -400,6 +421,7 @@ impl<S: BitmapSlice> Reader<'_, S> {
         mut dst: F,
         count: usize,
     ) -> io::Result<usize> {
+        self.fill_spliced()?;
         self.buffers
             .consume_for_read(count, |bufs| dst.write_vectored_volatile(bufs))
     }
@@ -414,6 +436,7 @@ impl<S: BitmapSlice> Reader<'_, S> {
         count: usize,
         off: u64,
     ) -> io::Result<usize> {
+        self.fill_spliced()?;
         self.buffers
             .consume_for_read(count, |bufs| dst.write_vectored_at_volatile(bufs, off))
     }
@@ -446,7 +469,7 @@ impl<S: BitmapSlice> Reader<'_, S> {
     /// May return an error if the combined lengths of all the buffers in the DescriptorChain
     /// would cause an integer overflow.
     pub fn available_bytes(&self) -> usize {
-        self.buffers.available_bytes()
+        self.buffers.available_bytes() + self.spliced.as_ref().map_or(0, |s| s.len)
     }
 
     /// Returns number of bytes already read from the descriptor chain buffer.
@@ -454,19 +477,121 @@ impl<S: BitmapSlice> Reader<'_, S> {
         self.buffers.bytes_consumed()
     }
 
+    /// Whether the rest of the request can be moved into a file with `splice_to()`.
+    pub fn supports_splice(&self) -> bool {
+        self.spliced.is_some() && self.buffers.available_bytes() == 0
+    }
+
+    /// Moves at most `count` bytes of the rest of the request into the file `fd` at offset `off`
+    /// with `splice(2)`, without copying them through userspace.
+    ///
+    /// Returns the number of bytes moved. If an error is returned, no data has been moved and it
+    /// can still be read with the other methods.
+    #[cfg(target_os = "linux")]
+    pub fn splice_to(&mut self, fd: RawFd, count: usize, off: u64) -> io::Result<usize> {
+        let spliced = match self.spliced.as_mut() {
+            Some(spliced) if self.buffers.available_bytes() == 0 => spliced,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        let count = cmp::min(count, spliced.len);
+        let mut off = off as i64;
+        let mut done = 0;
+        while done < count {
+            // Safe because this only modifies `off`, and we check the return value.
+            let res = unsafe {
+                libc::splice(
+                    spliced.pipe,
+                    std::ptr::null_mut(),
+                    fd,
+                    &mut off,
+                    count - done,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                } else if done == 0 {
+                    return Err(e);
+                }
+                break;
+            } else if res == 0 {
+                break;
+            }
+            done += res as usize;
+        }
+
+        spliced.len -= done;
+        spliced.mem = spliced
+            .mem
+            .offset(done)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.buffers.bytes_consumed += done;
+        Ok(done)
+    }
+
+    // Read the rest of the request left in a pipe, once the buffered part has been consumed.
+    fn fill_spliced(&mut self) -> io::Result<()> {
+        if self.buffers.available_bytes() != 0 {
+            return Ok(());
+        }
+        self.unsplice()
+    }
+
+    // Read the rest of the request left in a pipe into memory, after the buffered part.
+    fn unsplice(&mut self) -> io::Result<()> {
+        let spliced = match self.spliced.take() {
+            Some(spliced) => spliced,
+            None => return Ok(()),
+        };
+
+        let mut done = 0;
+        while done < spliced.len {
+            // Safe because `mem` is valid for `len` bytes and we check the return value.
+            let res = unsafe {
+                libc::read(
+                    spliced.pipe,
+                    spliced.mem.as_ptr().add(done) as *mut libc::c_void,
+                    spliced.len - done,
+                )
+            };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            } else if res == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            done += res as usize;
+        }
+        let mem = spliced
+            .mem
+            .subslice(0, done)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.buffers.buffers.push_back(mem);
+        Ok(())
+    }
+
     /// Splits this `Reader` into two at the given offset in the `DescriptorChain` buffer.
     /// After the split, `self` will be able to read up to `offset` bytes while the returned
     /// `Reader` can read up to `available_bytes() - offset` bytes.  Returns an error if
     /// `offset > self.available_bytes()`.
     pub fn split_at(&mut self, offset: usize) -> Result<Self> {
-        self.buffers
-            .split_at(offset)
-            .map(|buffers| Reader { buffers })
+        self.unsplice().map_err(Error::IoError)?;
+        self.buffers.split_at(offset).map(|buffers| Reader {
+            buffers,
+            spliced: None,
+        })
     }
 }
 
 impl<S: BitmapSlice> io::Read for Reader<'_, S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill_spliced()?;
         self.buffers.consume_for_read(buf.len(), |bufs| {
             let mut rem = buf;
             let mut total = 0;
@@ -500,6 +625,7 @@ mod async_io {
             count: usize,
             off: u64,
         ) -> io::Result<usize> {
+            self.fill_spliced()?;
             // Safe because `bufs` doesn't out-live `self`.
             let bufs = unsafe { self.buffers.prepare_io_buf(count) };
             if bufs.is_empty() {
@@ -549,6 +675,28 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         }
     }
 
+    /// Whether `splice_from()` is supported.
+    pub fn supports_splice(&self) -> bool {
+        match self {
+            #[cfg(all(feature = "fusedev", target_os = "linux"))]
+            Writer::FuseDev(w) => w.supports_splice(),
+            _ => false,
+        }
+    }
+
+    /// Move at most `count` bytes of the file `fd` at offset `off` into the writer with
+    /// `splice(2)`, without copying them through userspace.
+    ///
+    /// Return the number of bytes moved into the writer.
+    #[allow(unused_variables)]
+    pub fn splice_from(&mut self, fd: RawFd, count: usize, off: u64) -> io::Result<usize> {
+        match self {
+            #[cfg(all(feature = "fusedev", target_os = "linux"))]
+            Writer::FuseDev(w) => w.splice_from(fd, count, off),
+            _ => Err(std::io::Error::from_raw_os_error(libc::ENOSYS)),
+        }
+    }
+
     /// Split this `Writer` into two at the given offset in the `DescriptorChain` buffer.
     ///
     /// After the split, `self` will be able to write up to `offset` bytes while the returned
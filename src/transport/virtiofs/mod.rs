@@ -102,6 +102,7 @@ impl<'a> Reader<'a> {
                 buffers,
                 bytes_consumed: 0,
             },
+            spliced: None,
         })
     }
 }
@@ -181,7 +181,7 @@ fn main() -> Result<()> {
     se.mount().unwrap();
 
     let mut server = FuseServer {
-        server: Arc::new(Server::new(Arc::new(fs))),
+        server: Arc::new(Server::new(Arc::new(fs), None)),
         ch: se.new_channel().unwrap(),
     };
 
@@ -46,7 +46,7 @@ impl Daemon {
 
         Ok(Daemon {
             mountpoint: mountpoint.to_string(),
-            server: Arc::new(Server::new(Arc::new(vfs))),
+            server: Arc::new(Server::new(Arc::new(vfs), None)),
             thread_cnt,
             session: None,
         })